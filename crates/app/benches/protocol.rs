@@ -0,0 +1,93 @@
+use std::hint::black_box;
+
+use app::protocol::{
+    Framing, RunOverrides, SandboxRunRequest, SandboxRunResult, WorkerRequest, WorkerResponse,
+    encode_frame, read_frame,
+};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::Value;
+
+const KB: usize = 1024;
+
+fn run_request(context_bytes: usize) -> WorkerRequest {
+    WorkerRequest::Run(Box::new(SandboxRunRequest {
+        initialize: true,
+        query: "What is the magic number?".to_owned(),
+        context: Some(Value::String("x".repeat(context_bytes))),
+        context_path: None,
+        staged_context: false,
+        code: None,
+        request_id: None,
+        trace_context: None,
+        include_trace: false,
+        deadline_ms: None,
+        overrides: RunOverrides::default(),
+    }))
+}
+
+fn run_result(response_bytes: usize) -> WorkerResponse {
+    WorkerResponse::RunResult(SandboxRunResult {
+        response: Some("y".repeat(response_bytes)),
+        stdout: None,
+        stderr: None,
+        locals: None,
+        trace: None,
+        finish_reason: None,
+        used_fallback: false,
+        llm_subcalls: 0,
+        rlm_subcalls: 0,
+        rss_bytes: None,
+        usage: Default::default(),
+        stats: None,
+    })
+}
+
+fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> T {
+    read_frame(&mut &bytes[..])
+        .expect("read frame")
+        .expect("frame present")
+        .decode()
+        .expect("decode frame")
+}
+
+fn round_trip(
+    request: &WorkerRequest,
+    response: &WorkerResponse,
+    framing: Framing,
+) -> WorkerResponse {
+    let bytes = encode_frame(request, framing).expect("encode request");
+    black_box(decode::<WorkerRequest>(&bytes));
+    let bytes = encode_frame(response, framing).expect("encode response");
+    decode(&bytes)
+}
+
+fn bench_round_trip(c: &mut Criterion) {
+    for framing in [Framing::JsonLines, Framing::MessagePack] {
+        let mut group = c.benchmark_group(format!("worker_protocol_{framing}"));
+        group.bench_function("ping", |b| {
+            b.iter(|| {
+                round_trip(
+                    black_box(&WorkerRequest::Ping),
+                    &WorkerResponse::Pong,
+                    framing,
+                )
+            })
+        });
+        for size in [KB, 1024 * KB, 10 * 1024 * KB] {
+            let request = run_request(size);
+            let response = run_result(KB);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new("run", size / KB),
+                &(request, response),
+                |b, (request, response)| {
+                    b.iter(|| round_trip(black_box(request), response, framing))
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
@@ -0,0 +1,208 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rlm::trace::CompletionTrace;
+use serde::Serialize;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const AUDIT_OUTPUT_CHARS: usize = 2000;
+const FILE_PREFIX: &str = "audit-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+/// One executed code block.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    pub timestamp_ms: u64,
+    pub session_id: &'a str,
+    pub request_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<&'a str>,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iteration: Option<usize>,
+    pub code: &'a str,
+    pub output: String,
+    /// `ok`, or how the run failed, e.g. `timeout` or `upstream`.
+    pub outcome: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+/// Append-only JSONL log of executed sandbox code, one file per UTC day.
+#[derive(Debug)]
+pub struct AuditLog {
+    dir: PathBuf,
+    retention_days: u64,
+    current: Mutex<Option<(u64, File)>>,
+}
+
+impl AuditLog {
+    /// `retention_days` of 0 keeps every file.
+    pub fn open(dir: PathBuf, retention_days: u64) -> Result<Self, String> {
+        fs::create_dir_all(&dir)
+            .map_err(|err| format!("failed to create {}: {err}", dir.display()))?;
+        let log = Self {
+            dir,
+            retention_days,
+            current: Mutex::new(None),
+        };
+        log.prune(current_day());
+        Ok(log)
+    }
+
+    pub fn append(&self, records: &[AuditRecord<'_>]) -> Result<(), String> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut payload = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut payload, record).map_err(|err| err.to_string())?;
+            payload.push(b'\n');
+        }
+        let today = current_day();
+        let mut current = self.current.lock().expect("audit log lock poisoned");
+        if current.as_ref().is_none_or(|(day, _)| *day != today) {
+            let path = self.dir.join(file_name(today));
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|err| format!("failed to open {}: {err}", path.display()))?;
+            *current = Some((today, file));
+            self.prune(today);
+        }
+        let (_, file) = current.as_mut().expect("audit file opened");
+        file.write_all(&payload)
+            .map_err(|err| format!("audit write failed: {err}"))
+    }
+
+    fn prune(&self, today: u64) {
+        if self.retention_days == 0 {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let oldest_kept = file_name(today.saturating_sub(self.retention_days - 1));
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // Zero-padded ISO dates order lexically.
+            if name.starts_with(FILE_PREFIX)
+                && name.ends_with(FILE_SUFFIX)
+                && name < oldest_kept.as_str()
+                && let Err(err) = fs::remove_file(entry.path())
+            {
+                tracing::warn!(file = name, error = %err, "failed to prune audit log");
+            }
+        }
+    }
+}
+
+/// Where the code of one run is logged. The session writes the entries when
+/// the run ends, so runs that fail or outlive their caller are logged too.
+#[derive(Debug, Clone)]
+pub struct RunAudit {
+    pub log: Arc<AuditLog>,
+    pub session_id: String,
+    pub request_id: String,
+    pub tenant: Option<String>,
+}
+
+impl RunAudit {
+    /// Logs code run as sent, with what it printed.
+    pub fn execute(&self, code: &str, output: &str, outcome: &str, error: Option<&str>) {
+        self.append(&[self.record("execute", None, code, output, outcome, error)]);
+    }
+
+    /// Logs each block of a completion's trace, as far as the run got.
+    pub fn completion(&self, trace: &CompletionTrace, outcome: &str, error: Option<&str>) {
+        let records: Vec<_> = trace
+            .iterations
+            .iter()
+            .flat_map(|iteration| {
+                iteration.code_blocks.iter().map(|block| {
+                    self.record(
+                        "completion",
+                        Some(iteration.iteration),
+                        &block.code,
+                        &block.output_excerpt,
+                        outcome,
+                        error,
+                    )
+                })
+            })
+            .collect();
+        self.append(&records);
+    }
+
+    fn record<'a>(
+        &'a self,
+        kind: &'static str,
+        iteration: Option<usize>,
+        code: &'a str,
+        output: &str,
+        outcome: &'a str,
+        error: Option<&'a str>,
+    ) -> AuditRecord<'a> {
+        AuditRecord {
+            timestamp_ms: unix_millis(),
+            session_id: &self.session_id,
+            request_id: &self.request_id,
+            tenant: self.tenant.as_deref(),
+            kind,
+            iteration,
+            code,
+            output: truncate_output(output),
+            outcome,
+            error,
+        }
+    }
+
+    fn append(&self, records: &[AuditRecord<'_>]) {
+        if let Err(err) = self.log.append(records) {
+            tracing::error!(error = %err, "failed to write audit log");
+        }
+    }
+}
+
+pub fn truncate_output(output: &str) -> String {
+    match output.char_indices().nth(AUDIT_OUTPUT_CHARS) {
+        Some((end, _)) => format!("{}...", &output[..end]),
+        None => output.to_owned(),
+    }
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+fn current_day() -> u64 {
+    unix_millis() / 1000 / SECONDS_PER_DAY
+}
+
+fn file_name(day: u64) -> String {
+    let (year, month, day) = civil_from_days(day as i64);
+    format!("{FILE_PREFIX}{year:04}-{month:02}-{day:02}{FILE_SUFFIX}")
+}
+
+// Howard Hinnant's days-to-civil conversion.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,148 @@
+//! Serves sandbox workers to remote servers: each TLS connection gets a
+//! fresh worker process and its bytes are relayed both ways.
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use app::launcher::resolve_worker_bin;
+use app::remote::{MAX_PREAMBLE_BYTES, RemotePreamble};
+use app::telemetry::{init_tracing, tracing_config_from_env};
+use clap::Parser;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio_native_tls::TlsAcceptor;
+
+#[derive(Parser, Debug)]
+#[command(about = "Runs sandbox workers for RLM servers using the remote backend")]
+struct Args {
+    #[arg(long, env = "RLM_DAEMON_LISTEN", default_value = "0.0.0.0:7411")]
+    listen: SocketAddr,
+    /// PEM certificate chain the daemon presents
+    #[arg(long, env = "RLM_DAEMON_TLS_CERT")]
+    tls_cert: PathBuf,
+    /// PEM PKCS#8 private key for the certificate
+    #[arg(long, env = "RLM_DAEMON_TLS_KEY")]
+    tls_key: PathBuf,
+    /// Shared secret servers must send before a worker starts
+    #[arg(long, env = "RLM_DAEMON_TOKEN")]
+    token: String,
+    /// Worker binary; defaults to the `sandbox_worker` next to this one
+    #[arg(long, env = "RLM_DAEMON_WORKER")]
+    worker: Option<PathBuf>,
+    /// Most workers running at once; further connections wait for a slot
+    #[arg(long, env = "RLM_DAEMON_MAX_WORKERS", default_value_t = 64)]
+    max_workers: usize,
+}
+
+struct Daemon {
+    acceptor: TlsAcceptor,
+    token: String,
+    worker: PathBuf,
+    slots: Semaphore,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let (log_level, log_format) = tracing_config_from_env()?;
+    let _tracing = init_tracing("rlm-sandbox-daemon", &log_level, log_format)?;
+    if args.token.is_empty() {
+        return Err("the sandbox daemon needs a non-empty token".into());
+    }
+    if args.max_workers == 0 {
+        return Err("--max-workers must be at least 1".into());
+    }
+    let cert = fs::read(&args.tls_cert)
+        .map_err(|err| format!("failed to read {}: {err}", args.tls_cert.display()))?;
+    let key = fs::read(&args.tls_key)
+        .map_err(|err| format!("failed to read {}: {err}", args.tls_key.display()))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .map_err(|err| format!("invalid TLS certificate or key: {err}"))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|err| format!("failed to set up TLS: {err}"))?;
+    let worker = match args.worker {
+        Some(worker) => worker,
+        None => resolve_worker_bin()?,
+    };
+    let daemon = Arc::new(Daemon {
+        acceptor: acceptor.into(),
+        token: args.token,
+        worker,
+        slots: Semaphore::new(args.max_workers),
+    });
+    let listener = TcpListener::bind(args.listen).await?;
+    tracing::info!(addr = %args.listen, worker = %daemon.worker.display(), "listening");
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to accept connection");
+                continue;
+            }
+        };
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            if let Err(err) = daemon.serve(socket).await {
+                tracing::warn!(peer = %peer, error = %err, "connection closed");
+            }
+        });
+    }
+}
+
+impl Daemon {
+    async fn serve(&self, socket: TcpStream) -> Result<(), String> {
+        let _ = socket.set_nodelay(true);
+        let tls = self
+            .acceptor
+            .accept(socket)
+            .await
+            .map_err(|err| format!("TLS handshake failed: {err}"))?;
+        let (reader, mut writer) = tokio::io::split(tls);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        (&mut reader)
+            .take(MAX_PREAMBLE_BYTES)
+            .read_line(&mut line)
+            .await
+            .map_err(|err| format!("failed to read preamble: {err}"))?;
+        let preamble: RemotePreamble =
+            serde_json::from_str(&line).map_err(|err| format!("invalid preamble: {err}"))?;
+        if preamble.token != self.token {
+            return Err("invalid token".to_owned());
+        }
+        let _slot = self
+            .slots
+            .acquire()
+            .await
+            .map_err(|err| format!("daemon shutting down: {err}"))?;
+        let mut child = Command::new(&self.worker)
+            .env_clear()
+            .envs(preamble.worker_env())
+            .current_dir(env::temp_dir())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| format!("failed to start worker: {err}"))?;
+        let pid = child.id().unwrap_or_default();
+        tracing::info!(pid, "worker started");
+        let mut stdin = child.stdin.take().ok_or("worker stdin unavailable")?;
+        let mut stdout = child.stdout.take().ok_or("worker stdout unavailable")?;
+        // Whichever side finishes first ends the sandbox: the server hanging
+        // up, or the worker exiting.
+        tokio::select! {
+            _ = tokio::io::copy(&mut reader, &mut stdin) => {}
+            _ = tokio::io::copy(&mut stdout, &mut writer) => {}
+        }
+        let _ = child.kill().await;
+        tracing::info!(pid, "worker stopped");
+        Ok(())
+    }
+}
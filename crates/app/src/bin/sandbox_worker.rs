@@ -1,149 +1,1369 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::env;
+use std::fs::File;
+use std::future::Future;
 use std::io::{self, BufRead, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use app::protocol::{SandboxRunRequest, SandboxRunResult, WorkerRequest, WorkerResponse};
+use app::llm_broker::WorkerLlmBroker;
+use app::protocol::{
+    Envelope, Framing, SandboxRunRequest, SandboxRunResult, WorkerError, WorkerErrorCode,
+    WorkerRequest, WorkerResponse, WorkerStats, encode_frame, read_frame,
+};
+use app::telemetry::{
+    init_tracing, monitor_runtime, runtime_metrics_interval, set_trace_parent,
+    tracing_config_from_env,
+};
+use app::{
+    CODE_ONLY_ENV, LLM_BROKER_ENV, MEMORY_LIMIT_ENV, PARENT_TIMEOUT_ENV, WORKER_SOCKET_DIR_ENV,
+};
+use async_trait::async_trait;
+use rlm::llm::{LlmClient, LlmClientFactory, LlmError, Message};
 use rlm::prompts::DEFAULT_QUERY;
 use rlm::rlm::{RlmConfig, RlmRepl};
+use rlm::trace::{CompletionTrace, ProgressEvent};
 use rlm::utils::context_from_value;
+use serde_json::Value;
+use tokio::sync::watch;
 
 #[cfg(feature = "mimalloc")]
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = worker_config_from_env()?;
-    let mut repl = RlmRepl::new(config)?;
-    let runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_io()
-        .enable_time()
-        .build()?;
-
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(line) => line,
-            Err(err) => {
-                let _ = emit(
-                    &mut stdout,
-                    &WorkerResponse::Error {
-                        message: format!("stdin read failed: {err}"),
-                    },
-                );
-                continue;
+/// How long REPLs get to answer what they were sent once the worker is
+/// told to stop.
+const WIND_DOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shorter when out of memory, since the code running keeps allocating.
+const OOM_WIND_DOWN_TIMEOUT: Duration = Duration::from_millis(500);
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+struct WorkerRuntime {
+    runtime: tokio::runtime::Runtime,
+    metrics_interval: Option<Duration>,
+}
+
+impl WorkerRuntime {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(monitor_runtime(
+            "sandbox_worker",
+            self.metrics_interval,
+            future,
+        ))
+    }
+
+    /// Like `block_on`, but gives up once the host cancels the run. REPL
+    /// code already executing still runs to its own time limit.
+    fn run_cancellable<T>(
+        &self,
+        cancelled: &watch::Receiver<bool>,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> Result<T, WorkerError> {
+        let mut cancelled = cancelled.clone();
+        self.block_on(async move {
+            tokio::select! {
+                result = future => result.map_err(worker_error),
+                _ = cancelled.wait_for(|cancelled| *cancelled) => {
+                    Err(WorkerError::new(WorkerErrorCode::Cancelled, "run cancelled"))
+                }
             }
+        })
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (log_level, log_format) = tracing_config_from_env()?;
+    let _tracing = init_tracing("rlm-sandbox-worker", &log_level, log_format)?;
+    let transport = Transport::from_env()?;
+    let output = Arc::new(match &transport {
+        Transport::Stdio => Output::new(Box::new(io::stdout()), false),
+        #[cfg(unix)]
+        Transport::Socket { .. } => Output::new(Box::new(io::sink()), true),
+    });
+    let code_only = env::var(CODE_ONLY_ENV).is_ok_and(|value| value == "1");
+    let brokered = !code_only && env::var(LLM_BROKER_ENV).is_ok_and(|value| value == "1");
+    let watchdog = Arc::new(Watchdog::new());
+    let factory = ReplFactory {
+        config: worker_config_from_env(brokered || code_only)?,
+        brokered,
+        code_only,
+        metrics_interval: runtime_metrics_interval()?,
+        output: output.clone(),
+        controls: Arc::new(Mutex::new(HashMap::new())),
+        watchdog: watchdog.clone(),
+        closing: Arc::new(AtomicBool::new(false)),
+        out_of_memory: Arc::new(AtomicBool::new(false)),
+        totals: Arc::new(RunTotals::default()),
+    };
+    let mut repls = HashMap::new();
+    repls.insert(None, factory.start(None)?);
+
+    if let Some(timeout) = parent_timeout_from_env()? {
+        watchdog.clone().spawn(timeout)?;
+    }
+    let memory_limit = memory_limit_from_env()?;
+    #[cfg(unix)]
+    let socket_path = match &transport {
+        Transport::Socket { path, .. } => Some(path.clone()),
+        Transport::Stdio => None,
+    };
+    let (sender, requests) = mpsc::channel();
+    #[cfg(unix)]
+    spawn_terminate_handler(sender.clone())?;
+    if let Some(limit) = memory_limit {
+        spawn_memory_watchdog(
+            limit,
+            watchdog.clone(),
+            factory.out_of_memory.clone(),
+            sender.clone(),
+        )?;
+    }
+    spawn_request_reader(
+        transport,
+        sender,
+        output.clone(),
+        factory.controls.clone(),
+        watchdog.clone(),
+    )?;
+    for inbound in requests {
+        let Inbound::Request(message) = inbound else {
+            break;
         };
-        if line.trim().is_empty() {
-            continue;
-        }
-        let request = match serde_json::from_str::<WorkerRequest>(&line) {
-            Ok(request) => request,
-            Err(err) => {
-                let _ = emit(
-                    &mut stdout,
-                    &WorkerResponse::Error {
-                        message: format!("invalid request: {err}"),
-                    },
+        let Envelope {
+            id,
+            repl,
+            message: request,
+        } = match message {
+            Ok(envelope) => envelope,
+            Err(message) => {
+                let _busy = watchdog.busy();
+                let _ = output.emit(
+                    None,
+                    None,
+                    &WorkerResponse::Error(WorkerError::new(
+                        WorkerErrorCode::InvalidRequest,
+                        message,
+                    )),
                 );
                 continue;
             }
         };
         match request {
-            WorkerRequest::Ping => emit(&mut stdout, &WorkerResponse::Pong)?,
+            WorkerRequest::Hello { framings } => {
+                let _busy = watchdog.busy();
+                // Every framing is supported, so the host's first choice wins.
+                let framing = framings.first().copied().unwrap_or_default();
+                output.emit(id, repl.as_deref(), &WorkerResponse::Hello { framing })?;
+                output.set_framing(framing);
+            }
             WorkerRequest::Shutdown => {
-                emit(&mut stdout, &WorkerResponse::Ack)?;
+                let _busy = watchdog.busy();
+                output.emit(id, repl.as_deref(), &WorkerResponse::Ack)?;
+                break;
+            }
+            WorkerRequest::Close if repl.is_some() => {
+                let _busy = watchdog.busy();
+                // The REPL's thread finishes what it was sent and exits.
+                repls.remove(&repl);
+                factory
+                    .controls
+                    .lock()
+                    .expect("repl controls lock poisoned")
+                    .remove(&repl);
+                output.emit(id, repl.as_deref(), &WorkerResponse::Ack)?;
+            }
+            request => {
+                let thread = match repls.entry(repl.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match factory.start(repl.clone()) {
+                        Ok(thread) => entry.insert(thread),
+                        Err(err) => {
+                            let _busy = watchdog.busy();
+                            output.emit(
+                                id,
+                                repl.as_deref(),
+                                &WorkerResponse::Error(WorkerError::internal(format!(
+                                    "failed to start repl: {err}"
+                                ))),
+                            )?;
+                            continue;
+                        }
+                    },
+                };
+                let _ = thread.sender.send((id, request));
+            }
+        }
+    }
+    factory.wind_down(repls);
+    #[cfg(unix)]
+    if let Some(path) = socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+    if factory.out_of_memory.load(Ordering::SeqCst) {
+        return Err("sandbox worker exceeded its memory limit".into());
+    }
+    Ok(())
+}
+
+/// Cancel signal and LLM broker of one REPL, which the request reader
+/// answers to directly, even mid-run.
+#[derive(Clone)]
+struct ReplControl {
+    cancel: Arc<watch::Sender<bool>>,
+    broker: Option<WorkerLlmBroker>,
+}
+
+type ReplControls = Mutex<HashMap<Option<String>, ReplControl>>;
+
+/// What the main thread acts on, in arrival order.
+enum Inbound {
+    Request(Result<Envelope<WorkerRequest>, String>),
+    /// The server is gone for good, SIGTERM arrived, or memory ran out.
+    Stop,
+}
+
+/// Feeds a REPL's thread, which exits once this is dropped.
+struct ReplThread {
+    sender: mpsc::Sender<(Option<u64>, WorkerRequest)>,
+    thread: JoinHandle<()>,
+    pending: Arc<Pending>,
+}
+
+/// The id of the request a REPL is handling, until it is answered: by the
+/// REPL, or by a wind-down that stopped waiting for it. Whichever takes it
+/// first replies, so the server never hears twice.
+#[derive(Default)]
+struct Pending(Mutex<Option<Option<u64>>>);
+
+impl Pending {
+    fn start(&self, id: Option<u64>) {
+        *self.0.lock().expect("pending request lock poisoned") = Some(id);
+    }
+
+    fn take(&self) -> Option<Option<u64>> {
+        self.0.lock().expect("pending request lock poisoned").take()
+    }
+}
+
+/// Starts REPLs as the first requests naming them arrive.
+struct ReplFactory {
+    config: RlmConfig,
+    brokered: bool,
+    code_only: bool,
+    metrics_interval: Option<Duration>,
+    output: Arc<Output>,
+    controls: Arc<ReplControls>,
+    watchdog: Arc<Watchdog>,
+    /// Set once the worker stops taking requests.
+    closing: Arc<AtomicBool>,
+    /// Set before `closing` when it stops for lack of memory.
+    out_of_memory: Arc<AtomicBool>,
+    totals: Arc<RunTotals>,
+}
+
+impl ReplFactory {
+    /// Starts the REPL on a thread of its own and returns what feeds it.
+    fn start(&self, name: Option<String>) -> Result<ReplThread, String> {
+        let broker = self.brokered.then(|| {
+            let output = self.output.clone();
+            let name = name.clone();
+            WorkerLlmBroker::new(move |response| output.emit(None, name.as_deref(), response))
+        });
+        let config = self.config.clone();
+        let repl = match &broker {
+            Some(broker) => RlmRepl::new_with_client_factory(config, Arc::new(broker.clone())),
+            None if self.code_only => RlmRepl::new_with_client_factory(config, Arc::new(NoLlm)),
+            None => RlmRepl::new(config),
+        }
+        .map_err(|err| err.to_string())?;
+        let progress_output = self.output.clone();
+        let progress_name = name.clone();
+        repl.set_progress(Some(Arc::new(move |event: &ProgressEvent| {
+            // Progress is advisory; a failed write shows up on the run's reply.
+            let _ = progress_output.emit(
+                None,
+                progress_name.as_deref(),
+                &WorkerResponse::Progress(event.clone()),
+            );
+        })));
+        let runtime = WorkerRuntime {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .enable_time()
+                .build()
+                .map_err(|err| format!("failed to start runtime: {err}"))?,
+            metrics_interval: self.metrics_interval,
+        };
+        let cancel = Arc::new(watch::Sender::new(false));
+        self.controls
+            .lock()
+            .expect("repl controls lock poisoned")
+            .insert(
+                name.clone(),
+                ReplControl {
+                    cancel: cancel.clone(),
+                    broker,
+                },
+            );
+        let mut host = ReplHost {
+            name,
+            repl,
+            runtime,
+            upload: ContextUpload::default(),
+            cancel,
+            output: self.output.clone(),
+            closing: self.closing.clone(),
+            out_of_memory: self.out_of_memory.clone(),
+            totals: self.totals.clone(),
+            controls: self.controls.clone(),
+            pending: Arc::new(Pending::default()),
+            code_only: self.code_only,
+        };
+        let pending = host.pending.clone();
+        let (sender, requests) = mpsc::channel();
+        let watchdog = self.watchdog.clone();
+        let thread_name = match &host.name {
+            Some(name) => format!("repl-{name}"),
+            None => "repl".to_owned(),
+        };
+        let thread = thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || {
+                for (id, request) in requests {
+                    let _busy = watchdog.busy();
+                    host.pending.start(id);
+                    if let Err(err) = host.handle(id, request) {
+                        // Only a lost server connection fails a reply.
+                        tracing::error!(error = %err, "failed to reply; exiting");
+                        std::process::exit(1);
+                    }
+                }
+            })
+            .map_err(|err| format!("failed to spawn repl thread: {err}"))?;
+        Ok(ReplThread {
+            sender,
+            thread,
+            pending,
+        })
+    }
+
+    /// Cancels runs in flight and fails anything still queued, then gives
+    /// the REPLs a moment to send those replies and drop their scratch
+    /// directories. Requests a REPL is still stuck on are answered for it,
+    /// and the output is closed last so nothing is cut off.
+    fn wind_down(&self, repls: HashMap<Option<String>, ReplThread>) {
+        self.closing.store(true, Ordering::SeqCst);
+        cancel_repls(&self.controls);
+        let repls: Vec<_> = repls
+            .into_iter()
+            .map(|(name, repl)| (name, repl.thread, repl.pending))
+            .collect();
+        let timeout = if self.out_of_memory.load(Ordering::SeqCst) {
+            OOM_WIND_DOWN_TIMEOUT
+        } else {
+            WIND_DOWN_TIMEOUT
+        };
+        let deadline = Instant::now() + timeout;
+        while repls.iter().any(|(_, thread, _)| !thread.is_finished()) {
+            if Instant::now() >= deadline {
+                tracing::warn!("repls still busy; exiting anyway");
                 break;
             }
-            WorkerRequest::Run(request) => match run_request(&runtime, &mut repl, request) {
-                Ok(result) => emit(&mut stdout, &WorkerResponse::RunResult(result))?,
-                Err(err) => emit(&mut stdout, &WorkerResponse::Error { message: err })?,
+            thread::sleep(Duration::from_millis(10));
+        }
+        for (name, _, pending) in &repls {
+            if let Some(id) = pending.take() {
+                let error = closing_error(&self.out_of_memory);
+                let _ = self
+                    .output
+                    .emit(id, name.as_deref(), &WorkerResponse::Error(error));
+            }
+        }
+        self.output.close();
+    }
+}
+
+/// One REPL and the state its requests build up.
+struct ReplHost {
+    name: Option<String>,
+    repl: RlmRepl,
+    runtime: WorkerRuntime,
+    upload: ContextUpload,
+    cancel: Arc<watch::Sender<bool>>,
+    output: Arc<Output>,
+    closing: Arc<AtomicBool>,
+    out_of_memory: Arc<AtomicBool>,
+    totals: Arc<RunTotals>,
+    controls: Arc<ReplControls>,
+    pending: Arc<Pending>,
+    code_only: bool,
+}
+
+impl ReplHost {
+    /// Answers the request being handled, unless a wind-down already did.
+    fn reply(&self, id: Option<u64>, response: &WorkerResponse) -> Result<(), String> {
+        if self.pending.take().is_none() {
+            return Ok(());
+        }
+        self.output.emit(id, self.name.as_deref(), response)
+    }
+
+    fn closing_error(&self) -> WorkerError {
+        closing_error(&self.out_of_memory)
+    }
+
+    fn stats(&self) -> WorkerStats {
+        let repls = self
+            .controls
+            .lock()
+            .expect("repl controls lock poisoned")
+            .len();
+        WorkerStats {
+            rss_bytes: status_bytes("VmRSS:"),
+            peak_rss_bytes: status_bytes("VmHWM:"),
+            cpu_ms: cpu_ms(),
+            runs: self.totals.runs.load(Ordering::Relaxed),
+            repl_time_ms: self.totals.repl_time_ms.load(Ordering::Relaxed),
+            repls: repls as u64,
+        }
+    }
+
+    fn handle(&mut self, id: Option<u64>, request: WorkerRequest) -> Result<(), String> {
+        if self.closing.load(Ordering::SeqCst) {
+            return self.reply(id, &WorkerResponse::Error(self.closing_error()));
+        }
+        match request {
+            WorkerRequest::Ping => self.reply(id, &WorkerResponse::Pong)?,
+            // Only named REPLs are closed; the default one is kept.
+            WorkerRequest::Reset | WorkerRequest::Close => {
+                self.repl.reset();
+                self.upload = ContextUpload::default();
+                self.reply(id, &WorkerResponse::Ack)?;
+            }
+            WorkerRequest::BeginContext { total_bytes } => {
+                self.upload.begin(total_bytes);
+                self.reply(id, &WorkerResponse::Ack)?;
+            }
+            WorkerRequest::ContextChunk { data } => match self.upload.append(&data) {
+                Ok(()) => self.reply(id, &WorkerResponse::Ack)?,
+                Err(err) => self.reply(id, &WorkerResponse::Error(err))?,
+            },
+            WorkerRequest::EndContext => match self.upload.finish() {
+                Ok(()) => self.reply(id, &WorkerResponse::Ack)?,
+                Err(err) => self.reply(id, &WorkerResponse::Error(err))?,
             },
+            // Answered by the main thread, or the reader thread even mid-run.
+            WorkerRequest::Hello { .. }
+            | WorkerRequest::Shutdown
+            | WorkerRequest::LlmResult(_)
+            | WorkerRequest::Cancel => {}
+            WorkerRequest::Run(request) if self.code_only && request.code.is_none() => {
+                self.reply(
+                    id,
+                    &WorkerResponse::Error(WorkerError::new(
+                        WorkerErrorCode::InvalidRequest,
+                        "this sandbox worker only executes code; runs must include code",
+                    )),
+                )?;
+            }
+            WorkerRequest::Run(request) => {
+                let _span = request_span(
+                    "run",
+                    request.request_id.as_deref(),
+                    request.trace_context.as_ref(),
+                )
+                .entered();
+                let start = Instant::now();
+                // The reader has seen everything sent before this run, so
+                // only cancels meant for it can follow.
+                self.cancel.send_replace(false);
+                // A wind-down that began since the check above cancelled
+                // before the reset.
+                if self.closing.load(Ordering::SeqCst) {
+                    self.cancel.send_replace(true);
+                }
+                let staged = self.upload.take();
+                let spent_before = self.repl.total_usage();
+                let result = run_request(
+                    &self.runtime,
+                    &mut self.repl,
+                    &self.cancel.subscribe(),
+                    *request,
+                    staged,
+                );
+                // Cancelled by the wind-down rather than the server.
+                let result = result.map_err(|err| match err.code {
+                    WorkerErrorCode::Cancelled if self.closing.load(Ordering::SeqCst) => {
+                        self.closing_error()
+                    }
+                    _ => err,
+                });
+                log_request(&result, start);
+                self.totals.runs.fetch_add(1, Ordering::Relaxed);
+                match result {
+                    Ok(mut result) => {
+                        self.totals
+                            .repl_time_ms
+                            .fetch_add(result.usage.repl_time_ms, Ordering::Relaxed);
+                        let stats = self.stats();
+                        result.rss_bytes = stats.rss_bytes;
+                        result.stats = Some(stats);
+                        self.reply(id, &WorkerResponse::RunResult(result))?;
+                    }
+                    Err(mut err) => {
+                        err.usage = Some(self.repl.total_usage().since(spent_before));
+                        self.reply(id, &WorkerResponse::Error(err))?;
+                    }
+                }
+            }
+            WorkerRequest::GetVariable {
+                name,
+                request_id,
+                trace_context,
+            } => {
+                let _span = request_span(
+                    "get_variable",
+                    request_id.as_deref(),
+                    trace_context.as_ref(),
+                )
+                .entered();
+                let start = Instant::now();
+                let result = self
+                    .runtime
+                    .block_on(self.repl.get_variable(&name))
+                    .map_err(worker_error);
+                log_request(&result, start);
+                match result {
+                    Ok(value) => self.reply(id, &WorkerResponse::Variable { value })?,
+                    Err(err) => self.reply(id, &WorkerResponse::Error(err))?,
+                }
+            }
+            WorkerRequest::Snapshot { request_id } => {
+                let _span = request_span("snapshot", request_id.as_deref(), None).entered();
+                let start = Instant::now();
+                let result = self
+                    .runtime
+                    .block_on(self.repl.snapshot())
+                    .map_err(worker_error);
+                log_request(&result, start);
+                match result {
+                    Ok(snapshot) => self.reply(
+                        id,
+                        &WorkerResponse::Snapshot {
+                            snapshot: Box::new(snapshot),
+                        },
+                    )?,
+                    Err(err) => self.reply(id, &WorkerResponse::Error(err))?,
+                }
+            }
+            WorkerRequest::Restore {
+                snapshot,
+                request_id,
+            } => {
+                let _span = request_span("restore", request_id.as_deref(), None).entered();
+                let start = Instant::now();
+                let result = self
+                    .runtime
+                    .block_on(self.repl.restore(*snapshot))
+                    .map_err(worker_error);
+                log_request(&result, start);
+                match result {
+                    Ok(()) => self.reply(id, &WorkerResponse::Ack)?,
+                    Err(err) => self.reply(id, &WorkerResponse::Error(err))?,
+                }
+            }
         }
+        Ok(())
     }
+}
+
+/// Where requests come from and replies go.
+enum Transport {
+    Stdio,
+    /// A Unix socket the server connects to, one connection at a time. A
+    /// closed connection leaves the worker waiting for the next one.
+    #[cfg(unix)]
+    Socket {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl Transport {
+    fn from_env() -> Result<Self, String> {
+        let Some(dir) = env::var_os(WORKER_SOCKET_DIR_ENV) else {
+            return Ok(Self::Stdio);
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = app::worker_socket_path(Path::new(&dir), std::process::id());
+            // Left behind by an earlier process with the same pid.
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)
+                .map_err(|err| format!("failed to listen on {}: {err}", path.display()))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+                .map_err(|err| format!("failed to set permissions on {}: {err}", path.display()))?;
+            Ok(Self::Socket { listener, path })
+        }
+        #[cfg(not(unix))]
+        Err(format!(
+            "{WORKER_SOCKET_DIR_ENV} needs Unix sockets: {dir:?}"
+        ))
+    }
+}
+
+/// Turns SIGTERM, e.g. from `docker stop`, into the same wind-down as a
+/// `shutdown` request instead of dying halfway through a reply.
+#[cfg(unix)]
+fn spawn_terminate_handler(sender: mpsc::Sender<Inbound>) -> Result<(), String> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .map_err(|err| format!("failed to start signal runtime: {err}"))?;
+    // Registered up front so an early SIGTERM is not fatal.
+    let mut terminate = {
+        let _guard = runtime.enter();
+        signal(SignalKind::terminate()).map_err(|err| format!("failed to handle SIGTERM: {err}"))?
+    };
+    thread::Builder::new()
+        .name("worker-signals".to_owned())
+        .spawn(move || {
+            runtime.block_on(terminate.recv());
+            tracing::info!("received SIGTERM; shutting down");
+            let _ = sender.send(Inbound::Stop);
+        })
+        .map_err(|err| format!("failed to spawn signal handler: {err}"))?;
     Ok(())
 }
 
+/// Watches resident memory while requests are in flight, so one that needs
+/// too much fails with `oom` instead of the OOM killer cutting the worker off
+/// mid-reply. Idle workers are left to the pool's recycling.
+fn spawn_memory_watchdog(
+    limit: u64,
+    watchdog: Arc<Watchdog>,
+    out_of_memory: Arc<AtomicBool>,
+    sender: mpsc::Sender<Inbound>,
+) -> Result<(), String> {
+    thread::Builder::new()
+        .name("worker-memory".to_owned())
+        .spawn(move || {
+            loop {
+                thread::sleep(MEMORY_CHECK_INTERVAL);
+                if watchdog.idle() {
+                    continue;
+                }
+                let Some(rss_bytes) = status_bytes("VmRSS:") else {
+                    tracing::warn!("resident memory unavailable; memory limit not enforced");
+                    return;
+                };
+                if rss_bytes >= limit {
+                    tracing::error!(rss_bytes, limit, "memory limit reached; stopping");
+                    out_of_memory.store(true, Ordering::SeqCst);
+                    let _ = sender.send(Inbound::Stop);
+                    return;
+                }
+            }
+        })
+        .map_err(|err| format!("failed to spawn memory watchdog: {err}"))?;
+    Ok(())
+}
+
+/// Reads requests on a separate thread so LLM results for a brokered run in
+/// flight are delivered while the main thread is busy with it.
+fn spawn_request_reader(
+    transport: Transport,
+    sender: mpsc::Sender<Inbound>,
+    output: Arc<Output>,
+    controls: Arc<ReplControls>,
+    watchdog: Arc<Watchdog>,
+) -> Result<(), String> {
+    let reader = RequestReader {
+        sender,
+        controls,
+        watchdog,
+    };
+    thread::Builder::new()
+        .name("worker-reader".to_owned())
+        .spawn(move || {
+            reader.serve(transport, &output);
+            let _ = reader.sender.send(Inbound::Stop);
+        })
+        .map_err(|err| format!("failed to spawn request reader: {err}"))?;
+    Ok(())
+}
+
+struct RequestReader {
+    sender: mpsc::Sender<Inbound>,
+    controls: Arc<ReplControls>,
+    watchdog: Arc<Watchdog>,
+}
+
+impl RequestReader {
+    /// Reads until no server can send anything more.
+    fn serve(&self, transport: Transport, output: &Output) {
+        match transport {
+            Transport::Stdio => {
+                if let Err(err) = self.read(io::stdin().lock()) {
+                    self.watchdog.queue();
+                    let _ = self
+                        .sender
+                        .send(Inbound::Request(Err(format!("stdin read failed: {err}"))));
+                }
+                self.disconnected();
+            }
+            #[cfg(unix)]
+            Transport::Socket { listener, .. } => {
+                loop {
+                    // Whatever the last server asked for is answered, or
+                    // dropped, before the next one can connect.
+                    self.watchdog.wait_idle();
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            tracing::error!(error = %err, "failed to accept connection");
+                            break;
+                        }
+                    };
+                    match stream.try_clone() {
+                        Ok(writer) => output.connect(Box::new(writer)),
+                        Err(err) => {
+                            tracing::warn!(error = %err, "failed to clone connection");
+                            continue;
+                        }
+                    }
+                    tracing::info!("server connected");
+                    let result = self.read(io::BufReader::new(stream));
+                    output.disconnect();
+                    self.disconnected();
+                    match result {
+                        Ok(true) => tracing::info!("server disconnected"),
+                        Ok(false) => break,
+                        Err(err) => tracing::warn!(error = %err, "server connection failed"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes on requests until the end of `input`, or false once the main
+    /// thread has stopped taking them.
+    fn read(&self, mut input: impl BufRead) -> io::Result<bool> {
+        loop {
+            let request = match read_frame(&mut input)? {
+                None => return Ok(true),
+                Some(frame) => {
+                    self.watchdog.heard();
+                    frame
+                        .decode::<Envelope<WorkerRequest>>()
+                        .map_err(|err| format!("invalid request: {err}"))
+                }
+            };
+            let request = match request {
+                Ok(envelope) => match self.answer(envelope) {
+                    Some(envelope) => Ok(envelope),
+                    None => continue,
+                },
+                Err(err) => Err(err),
+            };
+            self.watchdog.queue();
+            if self.sender.send(Inbound::Request(request)).is_err() {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Handles cancels and brokered LLM results on the spot, for whichever
+    /// REPL they are meant for, and passes on anything else.
+    fn answer(&self, envelope: Envelope<WorkerRequest>) -> Option<Envelope<WorkerRequest>> {
+        let control = || {
+            self.controls
+                .lock()
+                .expect("repl controls lock poisoned")
+                .get(&envelope.repl)
+                .cloned()
+        };
+        match envelope.message {
+            WorkerRequest::Cancel => {
+                if let Some(control) = control() {
+                    control.cancel.send_replace(true);
+                }
+                None
+            }
+            WorkerRequest::LlmResult(result) => {
+                match control().and_then(|control| control.broker) {
+                    Some(broker) => {
+                        broker.resolve(result);
+                        None
+                    }
+                    None => Some(Envelope {
+                        message: WorkerRequest::LlmResult(result),
+                        ..envelope
+                    }),
+                }
+            }
+            message => Some(Envelope {
+                message,
+                ..envelope
+            }),
+        }
+    }
+
+    /// Abandons whatever the server that went away was waiting for.
+    fn disconnected(&self) {
+        cancel_repls(&self.controls);
+    }
+}
+
+/// What requests get once the worker is winding down.
+fn closing_error(out_of_memory: &AtomicBool) -> WorkerError {
+    if out_of_memory.load(Ordering::SeqCst) {
+        WorkerError::new(
+            WorkerErrorCode::Oom,
+            "sandbox worker exceeded its memory limit",
+        )
+    } else {
+        WorkerError::new(
+            WorkerErrorCode::Cancelled,
+            "sandbox worker is shutting down",
+        )
+    }
+}
+
+fn cancel_repls(controls: &ReplControls) {
+    let controls = controls.lock().expect("repl controls lock poisoned");
+    for control in controls.values() {
+        control.cancel.send_replace(true);
+        if let Some(broker) = &control.broker {
+            broker.close();
+        }
+    }
+}
+
+fn request_span(
+    kind: &'static str,
+    request_id: Option<&str>,
+    trace_context: Option<&HashMap<String, String>>,
+) -> tracing::Span {
+    let span = tracing::info_span!("worker", kind, request_id = request_id.unwrap_or("-"));
+    if let Some(trace_context) = trace_context {
+        set_trace_parent(&span, trace_context);
+    }
+    span
+}
+
+fn log_request<T>(result: &Result<T, WorkerError>, start: Instant) {
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(_) => tracing::info!(latency_ms, "worker request finished"),
+        Err(err) => tracing::warn!(latency_ms, error = %err, "worker request failed"),
+    }
+}
+
 fn run_request(
-    runtime: &tokio::runtime::Runtime,
+    runtime: &WorkerRuntime,
     repl: &mut RlmRepl,
+    cancelled: &watch::Receiver<bool>,
     request: SandboxRunRequest,
-) -> Result<SandboxRunResult, String> {
+    staged: Option<Value>,
+) -> Result<SandboxRunResult, WorkerError> {
     let query = if request.query.is_empty() {
         DEFAULT_QUERY.to_owned()
     } else {
         request.query
     };
+    let include_trace = request.include_trace;
+    repl.set_max_completion_tokens(request.overrides.max_completion_tokens);
+    repl.set_max_iterations(request.overrides.max_iterations);
+    repl.set_deadline(
+        request
+            .deadline_ms
+            .map(|ms| Instant::now() + Duration::from_millis(ms)),
+    );
+    repl.set_route(&request.overrides.route)
+        .map_err(|err| WorkerError::new(WorkerErrorCode::InvalidRequest, err.to_string()))?;
 
     if request.initialize {
-        let context = context_from_value(request.context);
+        let context = if request.staged_context {
+            Some(staged.ok_or_else(|| {
+                WorkerError::new(
+                    WorkerErrorCode::InvalidRequest,
+                    "run expects a streamed context, but none was received",
+                )
+            })?)
+        } else if let Some(path) = &request.context_path {
+            Some(read_context_file(path)?)
+        } else {
+            request.context
+        };
+        let context = context_from_value(context);
         if let Some(code) = request.code {
-            runtime
-                .block_on(repl.setup_context(context, Some(&query)))
-                .map_err(|err| err.to_string())?;
-            let result = runtime
-                .block_on(repl.execute_code(&code))
-                .map_err(|err| err.to_string())?;
+            runtime.run_cancellable(cancelled, repl.setup_context(context, Some(&query)))?;
+            let result = runtime.run_cancellable(cancelled, repl.execute_code(&code))?;
+            let trace = include_trace.then(|| CompletionTrace::for_code(&code, &result));
             return Ok(SandboxRunResult {
                 response: None,
                 stdout: Some(result.stdout),
                 stderr: Some(result.stderr),
+                locals: Some(result.locals),
+                trace,
+                finish_reason: None,
+                used_fallback: false,
+                llm_subcalls: 0,
+                rlm_subcalls: 0,
+                rss_bytes: None,
+                stats: None,
+                usage: repl.last_usage(),
             });
         }
         let response = runtime
-            .block_on(repl.completion(context, Some(&query)))
-            .map_err(|err| err.to_string())?;
+            .run_cancellable(cancelled, repl.completion(context, Some(&query)))
+            .map_err(|err| with_partial_trace(err, repl, include_trace))?;
         return Ok(SandboxRunResult {
             response: Some(response),
             stdout: None,
             stderr: None,
+            locals: None,
+            trace: include_trace.then(|| repl.last_trace().clone()),
+            finish_reason: Some(repl.last_finish_reason()),
+            used_fallback: repl.last_used_fallback(),
+            llm_subcalls: repl.last_trace().llm_subcalls,
+            rlm_subcalls: repl.last_trace().rlm_subcalls,
+            rss_bytes: None,
+            stats: None,
+            usage: repl.last_usage(),
         });
     }
 
     if let Some(code) = request.code {
-        let result = runtime
-            .block_on(repl.execute_code(&code))
-            .map_err(|err| err.to_string())?;
+        let result = runtime.run_cancellable(cancelled, repl.execute_code(&code))?;
+        let trace = include_trace.then(|| CompletionTrace::for_code(&code, &result));
         return Ok(SandboxRunResult {
             response: None,
             stdout: Some(result.stdout),
             stderr: Some(result.stderr),
+            locals: Some(result.locals),
+            trace,
+            finish_reason: None,
+            used_fallback: false,
+            llm_subcalls: 0,
+            rlm_subcalls: 0,
+            rss_bytes: None,
+            stats: None,
+            usage: repl.last_usage(),
         });
     }
 
     let response = runtime
-        .block_on(repl.completion_with_existing(Some(&query)))
-        .map_err(|err| err.to_string())?;
+        .run_cancellable(cancelled, repl.completion_with_existing(Some(&query)))
+        .map_err(|err| with_partial_trace(err, repl, include_trace))?;
     Ok(SandboxRunResult {
         response: Some(response),
         stdout: None,
         stderr: None,
+        locals: None,
+        trace: include_trace.then(|| repl.last_trace().clone()),
+        finish_reason: Some(repl.last_finish_reason()),
+        used_fallback: repl.last_used_fallback(),
+        llm_subcalls: repl.last_trace().llm_subcalls,
+        rlm_subcalls: repl.last_trace().rlm_subcalls,
+        rss_bytes: None,
+        stats: None,
+        usage: repl.last_usage(),
     })
 }
 
-fn worker_config_from_env() -> Result<RlmConfig, String> {
-    let api_key = env::var("OPENAI_API_KEY")
-        .map_err(|_| "OPENAI_API_KEY is required for sandbox worker".to_owned())?;
-    Ok(RlmConfig {
-        api_key: Some(api_key),
-        base_url: "https://api.openai.com/v1".to_owned(),
-        model: "gpt-5".to_owned(),
-        recursive_model: "gpt-5-mini".to_owned(),
-        max_iterations: 20,
-        depth: 1,
-        enable_logging: false,
-        disable_recursive: false,
-    })
+/// Keeps the iterations a failed completion got through, for the host's
+/// audit log.
+fn with_partial_trace(mut err: WorkerError, repl: &RlmRepl, include_trace: bool) -> WorkerError {
+    if include_trace {
+        err.trace = Some(Box::new(repl.last_trace().clone()));
+    }
+    err
+}
+
+/// Bounds the buffer reserved up front on the host's say-so.
+const MAX_CONTEXT_RESERVE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A context streamed in ahead of the run that uses it.
+#[derive(Default)]
+struct ContextUpload {
+    text: Option<String>,
+    ready: Option<Value>,
+}
+
+impl ContextUpload {
+    /// Discards anything streamed before.
+    fn begin(&mut self, total_bytes: u64) {
+        let capacity = total_bytes.min(MAX_CONTEXT_RESERVE_BYTES) as usize;
+        self.text = Some(String::with_capacity(capacity));
+        self.ready = None;
+    }
+
+    fn append(&mut self, data: &str) -> Result<(), WorkerError> {
+        self.text
+            .as_mut()
+            .ok_or_else(|| {
+                WorkerError::new(
+                    WorkerErrorCode::InvalidRequest,
+                    "context chunk without begin_context",
+                )
+            })?
+            .push_str(data);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), WorkerError> {
+        let text = self.text.take().ok_or_else(|| {
+            WorkerError::new(
+                WorkerErrorCode::InvalidRequest,
+                "end_context without begin_context",
+            )
+        })?;
+        let value = serde_json::from_str(&text).map_err(|err| {
+            WorkerError::new(
+                WorkerErrorCode::InvalidRequest,
+                format!("invalid streamed context: {err}"),
+            )
+        })?;
+        self.ready = Some(value);
+        Ok(())
+    }
+
+    /// The finished context, which only the next run may use.
+    fn take(&mut self) -> Option<Value> {
+        self.text = None;
+        self.ready.take()
+    }
+}
+
+/// Exits a worker whose server has gone quiet, e.g. one wedged or cut off
+/// without closing the pipe. Requests queued or being handled count as
+/// contact.
+struct Watchdog {
+    start: Instant,
+    /// Milliseconds after `start` of the last message or finished request.
+    last_heard_ms: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+struct BusyGuard<'a>(&'a Watchdog);
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.heard();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Watchdog {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_heard_ms: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a request passed to the main thread until its `busy` guard
+    /// drops.
+    fn queue(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn heard(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_heard_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn busy(&self) -> BusyGuard<'_> {
+        BusyGuard(self)
+    }
+
+    fn idle(&self) -> bool {
+        self.in_flight.load(Ordering::Relaxed) == 0
+    }
+
+    #[cfg(unix)]
+    fn wait_idle(&self) {
+        while !self.idle() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn spawn(self: Arc<Self>, timeout: Duration) -> Result<(), String> {
+        let check_every = (timeout / 4).min(Duration::from_secs(1));
+        thread::Builder::new()
+            .name("worker-watchdog".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(check_every);
+                    if !self.idle() {
+                        continue;
+                    }
+                    let quiet_ms = (self.start.elapsed().as_millis() as u64)
+                        .saturating_sub(self.last_heard_ms.load(Ordering::Relaxed));
+                    if quiet_ms >= timeout.as_millis() as u64 {
+                        tracing::error!(quiet_ms, "no message from the server; exiting");
+                        std::process::exit(1);
+                    }
+                }
+            })
+            .map_err(|err| format!("failed to spawn watchdog: {err}"))?;
+        Ok(())
+    }
+}
+
+fn parent_timeout_from_env() -> Result<Option<Duration>, String> {
+    let Ok(value) = env::var(PARENT_TIMEOUT_ENV) else {
+        return Ok(None);
+    };
+    let secs = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid {PARENT_TIMEOUT_ENV}: {value}"))?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+fn memory_limit_from_env() -> Result<Option<u64>, String> {
+    let Ok(value) = env::var(MEMORY_LIMIT_ENV) else {
+        return Ok(None);
+    };
+    let bytes = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid {MEMORY_LIMIT_ENV}: {value}"))?;
+    Ok((bytes > 0).then_some(bytes))
+}
+
+/// Reads a context the host handed off through its shared directory.
+fn read_context_file(path: &str) -> Result<Value, WorkerError> {
+    let invalid = |err: String| {
+        WorkerError::new(
+            WorkerErrorCode::InvalidRequest,
+            format!("failed to read context file {path}: {err}"),
+        )
+    };
+    let file = File::open(path).map_err(|err| invalid(err.to_string()))?;
+    serde_json::from_reader(io::BufReader::new(file)).map_err(|err| invalid(err.to_string()))
+}
+
+/// Classifies a failed REPL operation for the host.
+fn worker_error(err: anyhow::Error) -> WorkerError {
+    let code = if let Some(err) = err.downcast_ref::<LlmError>() {
+        match err {
+            LlmError::DeadlineExceeded => WorkerErrorCode::Timeout,
+            _ => WorkerErrorCode::LlmError,
+        }
+    } else if err.downcast_ref::<io::Error>().is_some_and(|err| {
+        // Writing the context filled the sandbox's scratch space.
+        matches!(
+            err.kind(),
+            io::ErrorKind::StorageFull | io::ErrorKind::FileTooLarge
+        )
+    }) {
+        WorkerErrorCode::ContextTooLarge
+    } else if err.to_string().starts_with("python ") {
+        WorkerErrorCode::PythonError
+    } else {
+        WorkerErrorCode::Internal
+    };
+    WorkerError::new(code, err.to_string())
+}
+
+/// Runs across all of a worker's REPLs.
+#[derive(Default)]
+struct RunTotals {
+    runs: AtomicU64,
+    repl_time_ms: AtomicU64,
+}
+
+/// A memory figure such as `VmRSS:` from `/proc/self/status`.
+fn status_bytes(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// `utime` plus `stime` from `/proc/self/stat`, which count ticks of the
+/// kernel's fixed 100 Hz user clock.
+fn cpu_ms() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may hold spaces, so fields are counted after it.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let utime = fields.next()?.parse::<u64>().ok()?;
+    let stime = fields.next()?.parse::<u64>().ok()?;
+    Some((utime + stime) * 10)
+}
+
+/// Reads the `RLM_*` settings the launcher forwards from the server, without
+/// an API key for brokered workers, whose host adds it to each call, and
+/// code-only workers, which need none.
+fn worker_config_from_env(without_api_key: bool) -> Result<RlmConfig, String> {
+    let config = if without_api_key {
+        RlmConfig::from_env_without_api_key()
+    } else {
+        RlmConfig::from_env()
+    };
+    let mut config = config.map_err(|err| format!("invalid sandbox worker config: {err}"))?;
+    // REPL logs go to stdout, which carries the protocol.
+    config.enable_logging = false;
+    Ok(config)
+}
+
+/// Every LLM client of a code-only worker: code calling `llm_query` gets an
+/// error instead of a network request.
+struct NoLlm;
+
+impl LlmClientFactory for NoLlm {
+    fn client(
+        &self,
+        _model: &str,
+        _api_key: Option<&str>,
+        _base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError> {
+        Ok(Arc::new(NoLlm))
+    }
 }
 
-fn emit(stdout: &mut impl Write, response: &WorkerResponse) -> Result<(), String> {
-    let payload = serde_json::to_string(response).map_err(|err| err.to_string())?;
-    stdout
-        .write_all(payload.as_bytes())
-        .map_err(|err| format!("stdout write failed: {err}"))?;
-    stdout
-        .write_all(b"\n")
-        .map_err(|err| format!("stdout write failed: {err}"))?;
-    stdout
-        .flush()
-        .map_err(|err| format!("stdout flush failed: {err}"))
+#[async_trait]
+impl LlmClient for NoLlm {
+    async fn completion(
+        &self,
+        _messages: &[Message],
+        _max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        Err(LlmError::Remote {
+            message: "LLM calls are disabled in this code-only sandbox".to_owned(),
+            upstream: false,
+        })
+    }
+}
+
+struct Output {
+    writer: Mutex<Box<dyn Write + Send>>,
+    framing: Mutex<Framing>,
+    /// Whether another server may connect after this one goes away, in
+    /// which case failed writes are dropped instead of ending the worker.
+    reconnects: bool,
+}
+
+impl Output {
+    fn new(writer: Box<dyn Write + Send>, reconnects: bool) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            framing: Mutex::new(Framing::JsonLines),
+            reconnects,
+        }
+    }
+
+    fn set_framing(&self, framing: Framing) {
+        *self.framing.lock().expect("output lock poisoned") = framing;
+    }
+
+    /// Sends replies to a newly connected server, which starts out with
+    /// JSON lines like any other.
+    #[cfg(unix)]
+    fn connect(&self, writer: Box<dyn Write + Send>) {
+        *self.writer.lock().expect("output lock poisoned") = writer;
+        self.set_framing(Framing::JsonLines);
+    }
+
+    #[cfg(unix)]
+    fn disconnect(&self) {
+        *self.writer.lock().expect("output lock poisoned") = Box::new(io::sink());
+    }
+
+    /// Waits out a message being written and drops any after it.
+    fn close(&self) {
+        let mut writer = self.writer.lock().expect("output lock poisoned");
+        let _ = writer.flush();
+        *writer = Box::new(io::sink());
+    }
+
+    /// Holds the writer lock for the whole message, since brokered LLM calls
+    /// write from REPL threads.
+    fn emit(
+        &self,
+        id: Option<u64>,
+        repl: Option<&str>,
+        response: &WorkerResponse,
+    ) -> Result<(), String> {
+        let framing = *self.framing.lock().expect("output lock poisoned");
+        let envelope = Envelope {
+            id,
+            repl: repl.map(str::to_owned),
+            message: response,
+        };
+        let bytes = encode_frame(&envelope, framing)?;
+        let mut writer = self.writer.lock().expect("output lock poisoned");
+        let result = writer.write_all(&bytes).and_then(|()| writer.flush());
+        match result {
+            Ok(()) => Ok(()),
+            Err(_) if self.reconnects => {
+                *writer = Box::new(io::sink());
+                Ok(())
+            }
+            Err(err) => Err(format!("output write failed: {err}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_upload_round_trips() {
+        let mut upload = ContextUpload::default();
+        upload.begin(u64::MAX);
+        upload.append(r#"{"a": [1,"#).unwrap();
+        upload.append(" 2]}").unwrap();
+        upload.finish().unwrap();
+        assert_eq!(upload.take(), Some(serde_json::json!({"a": [1, 2]})));
+        assert_eq!(upload.take(), None);
+    }
+
+    #[test]
+    fn context_upload_rejects_bad_sequences() {
+        let mut upload = ContextUpload::default();
+        let err = upload.append("{}").unwrap_err();
+        assert_eq!(err.code, WorkerErrorCode::InvalidRequest);
+        assert!(upload.finish().is_err());
+
+        upload.begin(2);
+        upload.append("{").unwrap();
+        let err = upload.finish().unwrap_err();
+        assert_eq!(err.code, WorkerErrorCode::InvalidRequest);
+        assert!(err.message.starts_with("invalid streamed context"));
+        // A failed upload is discarded rather than left for the next run.
+        assert!(upload.append("}").is_err());
+        assert_eq!(upload.take(), None);
+    }
+
+    #[test]
+    fn missing_context_files_are_invalid_requests() {
+        let err = read_context_file("/nonexistent/context.json").unwrap_err();
+        assert_eq!(err.code, WorkerErrorCode::InvalidRequest);
+        assert!(err.message.contains("/nonexistent/context.json"));
+    }
 }
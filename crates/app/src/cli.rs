@@ -0,0 +1,436 @@
+use std::path::PathBuf;
+
+use app::ProcessUser;
+use app::pool::SandboxLimitAction;
+use app::protocol::Framing;
+use app::session::EvictionPolicyKind;
+use app::telemetry::{DEFAULT_LOG_LEVEL, LogFormat};
+use clap::{Args, Parser, Subcommand};
+use rlm::rlm::{
+    DEFAULT_BASE_URL, DEFAULT_DEPTH, DEFAULT_MAX_ITERATIONS, DEFAULT_MODEL, DEFAULT_RECURSIVE_MODEL,
+};
+
+const DEFAULT_MAX_SESSIONS: usize = 256;
+const DEFAULT_MAX_INFLIGHT: usize = 128;
+const DEFAULT_INGRESS_CAPACITY: usize = 2048;
+const DEFAULT_MAX_PENDING_PER_SESSION: usize = 32;
+const DEFAULT_SHED_QUEUE_DEPTH: usize = DEFAULT_INGRESS_CAPACITY / 2;
+const DEFAULT_SANDBOX_POOL_SIZE: usize = 8;
+const DEFAULT_SANDBOX_HEALTH_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_POOL_SCALE_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_POOL_LAUNCH_PARALLELISM: usize = 4;
+const DEFAULT_SANDBOX_LAUNCH_TIMEOUT_SECONDS: u64 = 120;
+const DEFAULT_SANDBOX_LAUNCH_RETRIES: u32 = 2;
+const DEFAULT_SANDBOX_WRITE_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_SANDBOX_READ_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_SANDBOX_CONTAINER_CLI: &str = "docker";
+const DEFAULT_SANDBOX_RUNTIME: &str = "runsc";
+const DEFAULT_SANDBOX_IMAGE: &str = "rust:latest";
+const DEFAULT_SANDBOX_SCRATCH_SIZE: &str = "64m";
+const DEFAULT_SANDBOX_CONTEXT_FILE_BYTES: usize = 1024 * 1024;
+const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 1800;
+const DEFAULT_DRAIN_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_INPUT_BYTES: usize = 10_485_760;
+const DEFAULT_MAX_BODY_BYTES: usize = 11 * 1024 * 1024;
+const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_MAX_ITERATIONS_LIMIT: usize = 50;
+const DEFAULT_CONTEXT_STORE_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Parser)]
+#[command(
+    name = "app",
+    about = "OpenAI-compatible RLM server",
+    args_conflicts_with_subcommands = true
+)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+    #[command(flatten)]
+    pub(crate) serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Run the HTTP server (default)
+    Serve(ServeArgs),
+}
+
+#[derive(Args)]
+pub(crate) struct ServeArgs {
+    #[arg(long, env = "RLM_HOST", default_value = "0.0.0.0")]
+    pub(crate) host: String,
+    #[arg(long, env = "RLM_PORT", default_value_t = 3000)]
+    pub(crate) port: u16,
+    /// Model used when requests omit `model`
+    #[arg(long, env = "RLM_MODEL", default_value = DEFAULT_MODEL)]
+    pub(crate) model: String,
+    /// JSON file mapping accepted model names to `base_url`, upstream `model`
+    /// and `recursive_model`
+    #[arg(long, env = "RLM_MODEL_ROUTES")]
+    pub(crate) model_routes: Option<PathBuf>,
+    /// Model that takes over the default route after repeated 429/5xx from
+    /// the primary
+    #[arg(long, env = "RLM_FALLBACK_MODEL")]
+    pub(crate) fallback_model: Option<String>,
+    /// Endpoint for `--fallback-model`; defaults to `--llm-base-url`
+    #[arg(long, env = "RLM_FALLBACK_BASE_URL")]
+    pub(crate) fallback_base_url: Option<String>,
+    /// Sub-LLM model for routes that name none
+    #[arg(long, env = "RLM_RECURSIVE_MODEL", default_value = DEFAULT_RECURSIVE_MODEL)]
+    pub(crate) recursive_model: String,
+    /// REPL iterations per completion when requests omit
+    /// `rlm_max_iterations`
+    #[arg(long, env = "RLM_MAX_ITERATIONS", default_value_t = DEFAULT_MAX_ITERATIONS)]
+    pub(crate) max_iterations: usize,
+    /// Recursion depth available to `rlm_query`
+    #[arg(long, env = "RLM_DEPTH", default_value_t = DEFAULT_DEPTH)]
+    pub(crate) depth: usize,
+    /// Turn `rlm_query` off whatever `--depth` says, and show the model full
+    /// REPL output instead of truncating it at 100k characters
+    #[arg(long, env = "RLM_DISABLE_RECURSIVE")]
+    pub(crate) disable_recursive: bool,
+    /// Completion token cap when requests set none
+    #[arg(long, env = "RLM_MAX_COMPLETION_TOKENS")]
+    pub(crate) max_completion_tokens: Option<u32>,
+    #[arg(long, env = "RLM_MAX_SESSIONS", default_value_t = DEFAULT_MAX_SESSIONS)]
+    pub(crate) max_sessions: usize,
+    #[arg(long, env = "RLM_MAX_INFLIGHT", default_value_t = DEFAULT_MAX_INFLIGHT)]
+    pub(crate) max_inflight: usize,
+    #[arg(long, env = "RLM_INGRESS_CAPACITY", default_value_t = DEFAULT_INGRESS_CAPACITY)]
+    pub(crate) ingress_capacity: usize,
+    /// Requests one session may have queued or running before new ones get
+    /// 429 (0 disables)
+    #[arg(
+        long,
+        env = "RLM_MAX_PENDING_PER_SESSION",
+        default_value_t = DEFAULT_MAX_PENDING_PER_SESSION
+    )]
+    pub(crate) max_pending_per_session: usize,
+    #[arg(long, env = "RLM_SANDBOX_POOL_SIZE", default_value_t = DEFAULT_SANDBOX_POOL_SIZE)]
+    pub(crate) pool_size: usize,
+    /// Sandboxes launched at once while filling the pool at startup
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_POOL_LAUNCH_PARALLELISM",
+        default_value_t = DEFAULT_POOL_LAUNCH_PARALLELISM
+    )]
+    pub(crate) pool_launch_parallelism: usize,
+    /// Lowest idle target when autoscaling
+    #[arg(long, env = "RLM_SANDBOX_POOL_MIN_SIZE", default_value_t = 0)]
+    pub(crate) pool_min_size: usize,
+    /// Scale the idle target with load up to this many sandboxes, starting
+    /// from --pool-size (0 keeps the pool fixed)
+    #[arg(long, env = "RLM_SANDBOX_POOL_MAX_SIZE", default_value_t = 0)]
+    pub(crate) pool_max_size: usize,
+    /// How often the autoscaler re-evaluates the idle target
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_POOL_SCALE_INTERVAL_SECS",
+        default_value_t = DEFAULT_POOL_SCALE_INTERVAL_SECONDS
+    )]
+    pub(crate) pool_scale_interval_secs: u64,
+    /// Most sandboxes alive at once, idle or in use (0 is unlimited)
+    #[arg(long, env = "RLM_MAX_SANDBOXES", default_value_t = 0)]
+    pub(crate) max_sandboxes: usize,
+    /// At the sandbox limit, wait for a sandbox to free up or fail with 429
+    #[arg(long, env = "RLM_SANDBOX_LIMIT_ACTION", default_value_t = SandboxLimitAction::Wait)]
+    pub(crate) sandbox_limit_action: SandboxLimitAction,
+    /// Ping idle sandboxes this often and replace dead ones (0 disables)
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_HEALTH_INTERVAL_SECS",
+        default_value_t = DEFAULT_SANDBOX_HEALTH_INTERVAL_SECONDS
+    )]
+    pub(crate) sandbox_health_interval_secs: u64,
+    /// Reset sandboxes released by sessions and reuse them instead of
+    /// launching fresh ones
+    #[arg(long, env = "RLM_SANDBOX_REUSE")]
+    pub(crate) sandbox_reuse: bool,
+    /// Give up on a sandbox that has not answered a ping this long after
+    /// launch
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_LAUNCH_TIMEOUT_SECS",
+        default_value_t = DEFAULT_SANDBOX_LAUNCH_TIMEOUT_SECONDS
+    )]
+    pub(crate) sandbox_launch_timeout_secs: u64,
+    /// Kill a sandbox worker that takes longer than this to accept a request
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_WRITE_TIMEOUT_SECS",
+        default_value_t = DEFAULT_SANDBOX_WRITE_TIMEOUT_SECONDS
+    )]
+    pub(crate) sandbox_write_timeout_secs: u64,
+    /// Kill a sandbox worker that takes longer than this to answer a
+    /// snapshot, restore, reset or variable read
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_READ_TIMEOUT_SECS",
+        default_value_t = DEFAULT_SANDBOX_READ_TIMEOUT_SECONDS
+    )]
+    pub(crate) sandbox_read_timeout_secs: u64,
+    /// Worker protocol framing: json (lines) or msgpack (length-prefixed,
+    /// faster for large contexts); workers without msgpack keep json
+    #[arg(long, env = "RLM_SANDBOX_FRAMING", default_value_t = Framing::JsonLines)]
+    pub(crate) sandbox_framing: Framing,
+    /// Hand contexts of at least this many bytes to workers as a file in a
+    /// read-only shared directory instead of inline (0 always sends them
+    /// inline, e.g. for a remote container engine)
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_CONTEXT_FILE_BYTES",
+        default_value_t = DEFAULT_SANDBOX_CONTEXT_FILE_BYTES
+    )]
+    pub(crate) sandbox_context_file_bytes: usize,
+    /// Retries after a transient launch failure, with exponential backoff
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_LAUNCH_RETRIES",
+        default_value_t = DEFAULT_SANDBOX_LAUNCH_RETRIES
+    )]
+    pub(crate) sandbox_launch_retries: u32,
+    /// How sandbox workers are started: docker (gVisor containers), podman
+    /// (the same through podman), bwrap (bubblewrap namespaces; fast, weaker
+    /// isolation), process (plain child processes, no isolation; for
+    /// development) or remote (sandbox_daemon on other machines)
+    #[arg(long, env = "RLM_SANDBOX_BACKEND", default_value = "docker")]
+    pub(crate) sandbox_backend: String,
+    /// Sessions each sandbox worker serves at once, each in a REPL of its
+    /// own; above 1, light sessions share warm workers
+    #[arg(long, env = "RLM_SANDBOX_REPLS_PER_WORKER", default_value_t = 1)]
+    pub(crate) sandbox_repls_per_worker: usize,
+    /// With the process backend, run workers as uid[:gid] (Unix only)
+    #[arg(long, env = "RLM_SANDBOX_PROCESS_USER")]
+    pub(crate) sandbox_process_user: Option<ProcessUser>,
+    /// With the process backend, talk to workers over Unix sockets in this
+    /// directory; idle workers outlive the server and are reconnected to by
+    /// the next one started with the same directory (Unix only)
+    #[arg(long, env = "RLM_SANDBOX_SOCKET_DIR")]
+    pub(crate) sandbox_socket_dir: Option<PathBuf>,
+    /// With the remote backend, comma-separated host:port of sandbox_daemon
+    /// instances
+    #[arg(long, env = "RLM_SANDBOX_REMOTE_ADDRS", value_delimiter = ',')]
+    pub(crate) sandbox_remote_addrs: Vec<String>,
+    /// Shared secret the remote sandbox daemons require
+    #[arg(long, env = "RLM_SANDBOX_REMOTE_TOKEN", default_value = "")]
+    pub(crate) sandbox_remote_token: String,
+    /// PEM CA certificate to trust for the remote sandbox daemons
+    #[arg(long, env = "RLM_SANDBOX_REMOTE_CA")]
+    pub(crate) sandbox_remote_ca: Option<PathBuf>,
+    /// Make worker LLM calls from the server instead of the sandbox, so
+    /// sandboxes get no network access and never see the API key
+    #[arg(long, env = "RLM_SANDBOX_LLM_BROKER")]
+    pub(crate) sandbox_llm_broker: bool,
+    /// Docker-compatible CLI the docker backend drives, e.g. podman or
+    /// nerdctl
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_CONTAINER_CLI",
+        default_value = DEFAULT_SANDBOX_CONTAINER_CLI
+    )]
+    pub(crate) sandbox_container_cli: String,
+    /// OCI runtime for sandbox containers, e.g. runc for development; empty
+    /// uses the engine's default
+    #[arg(long, env = "RLM_SANDBOX_RUNTIME", default_value = DEFAULT_SANDBOX_RUNTIME)]
+    pub(crate) sandbox_runtime: String,
+    /// Container image sandboxes run in
+    #[arg(long, env = "RLM_SANDBOX_IMAGE", default_value = DEFAULT_SANDBOX_IMAGE)]
+    pub(crate) sandbox_image: String,
+    /// Path of a sandbox_worker baked into --sandbox-image (see
+    /// docker/sandbox-worker.Dockerfile); the server's own worker binary is
+    /// then not mounted
+    #[arg(long, env = "RLM_SANDBOX_IMAGE_WORKER")]
+    pub(crate) sandbox_image_worker: Option<String>,
+    /// Memory limit per sandbox, e.g. 512m (unset is unlimited)
+    #[arg(long, env = "RLM_SANDBOX_MEMORY")]
+    pub(crate) sandbox_memory: Option<String>,
+    /// CPUs per sandbox, e.g. 1.5 (unset is unlimited)
+    #[arg(long, env = "RLM_SANDBOX_CPUS")]
+    pub(crate) sandbox_cpus: Option<f64>,
+    /// Most processes and threads per sandbox (unset is unlimited)
+    #[arg(long, env = "RLM_SANDBOX_PIDS_LIMIT")]
+    pub(crate) sandbox_pids_limit: Option<u64>,
+    /// Seccomp profile (JSON) applied to sandbox containers
+    #[arg(long, env = "RLM_SANDBOX_SECCOMP_PROFILE")]
+    pub(crate) sandbox_seccomp_profile: Option<PathBuf>,
+    /// Drop every Linux capability in sandbox containers
+    #[arg(long, env = "RLM_SANDBOX_CAP_DROP_ALL")]
+    pub(crate) sandbox_cap_drop_all: bool,
+    /// Stop sandbox processes from gaining privileges through setuid binaries
+    #[arg(long, env = "RLM_SANDBOX_NO_NEW_PRIVILEGES")]
+    pub(crate) sandbox_no_new_privileges: bool,
+    /// Run the worker inside containers as user[:group], e.g. 65534:65534
+    #[arg(long, env = "RLM_SANDBOX_USER")]
+    pub(crate) sandbox_user: Option<String>,
+    /// Mount sandbox images read-only with a tmpfs /tmp as the only
+    /// writable path
+    #[arg(long, env = "RLM_SANDBOX_READ_ONLY")]
+    pub(crate) sandbox_read_only: bool,
+    /// Size of the tmpfs /tmp in read-only sandboxes, e.g. 64m
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_SCRATCH_SIZE",
+        default_value = DEFAULT_SANDBOX_SCRATCH_SIZE
+    )]
+    pub(crate) sandbox_scratch_size: String,
+    /// Comma-separated ulimits per sandbox, e.g. nofile=1024:1024
+    #[arg(long, env = "RLM_SANDBOX_ULIMITS", value_delimiter = ',')]
+    pub(crate) sandbox_ulimits: Vec<String>,
+    /// Replace a sandbox after this many runs (0 disables)
+    #[arg(long, env = "RLM_SANDBOX_MAX_RUNS", default_value_t = 0)]
+    pub(crate) sandbox_max_runs: u64,
+    /// Replace a sandbox after this many bytes of run requests (0 disables)
+    #[arg(long, env = "RLM_SANDBOX_MAX_CONTEXT_BYTES", default_value_t = 0)]
+    pub(crate) sandbox_max_context_bytes: u64,
+    /// Replace a sandbox once its worker's resident memory reaches this many
+    /// bytes (0 disables)
+    #[arg(long, env = "RLM_SANDBOX_MAX_RSS_BYTES", default_value_t = 0)]
+    pub(crate) sandbox_max_rss_bytes: u64,
+    /// Workers fail the request in flight with an `oom` error and exit once
+    /// their resident memory reaches this many bytes, ahead of the OOM
+    /// killer (0 disables)
+    #[arg(
+        long,
+        env = "RLM_SANDBOX_WORKER_MEMORY_LIMIT_BYTES",
+        default_value_t = 0
+    )]
+    pub(crate) sandbox_worker_memory_limit_bytes: u64,
+    #[arg(
+        long,
+        env = "RLM_REQUEST_TIMEOUT_SECS",
+        default_value_t = DEFAULT_REQUEST_TIMEOUT_SECONDS
+    )]
+    pub(crate) request_timeout_secs: u64,
+    #[arg(
+        long,
+        env = "RLM_DRAIN_TIMEOUT_SECS",
+        default_value_t = DEFAULT_DRAIN_TIMEOUT_SECONDS
+    )]
+    pub(crate) drain_timeout_secs: u64,
+    /// Retire a session's sandbox after this many idle seconds (0 disables)
+    #[arg(long, env = "RLM_SESSION_IDLE_TTL_SECS", default_value_t = 0)]
+    pub(crate) session_idle_ttl_secs: u64,
+    /// Which idle session to evict at capacity: lru, lfu, ttl-weighted or
+    /// cost-weighted
+    #[arg(long, env = "RLM_EVICTION_POLICY", default_value_t = EvictionPolicyKind::Lru)]
+    pub(crate) eviction_policy: EvictionPolicyKind,
+    /// At capacity with no idle session to evict, wait up to this many
+    /// milliseconds for one to free up before rejecting (0 rejects at once)
+    #[arg(long, env = "RLM_SESSION_ADMISSION_WAIT_MS", default_value_t = 0)]
+    pub(crate) session_admission_wait_ms: u64,
+    /// Requests per minute allowed per client API key (0 disables)
+    #[arg(long, env = "RLM_RATE_LIMIT_RPM", default_value_t = 0)]
+    pub(crate) rate_limit_rpm: u32,
+    /// Concurrent requests allowed per client API key (0 disables)
+    #[arg(long, env = "RLM_RATE_LIMIT_CONCURRENCY", default_value_t = 0)]
+    pub(crate) rate_limit_concurrency: usize,
+    /// Reject chat requests with 429 once this many are in flight; defaults to
+    /// `--max-inflight` (0 disables)
+    #[arg(long, env = "RLM_SHED_INFLIGHT")]
+    pub(crate) shed_inflight: Option<usize>,
+    /// Reject chat requests with 429 once this many commands wait for the
+    /// session manager (0 disables)
+    #[arg(long, env = "RLM_SHED_QUEUE_DEPTH", default_value_t = DEFAULT_SHED_QUEUE_DEPTH)]
+    pub(crate) shed_queue_depth: usize,
+    /// Retry-After sent with shed requests
+    #[arg(long, env = "RLM_SHED_RETRY_AFTER_SECS", default_value_t = 1)]
+    pub(crate) shed_retry_after_secs: u64,
+    /// Daily token budget per client API key (0 disables)
+    #[arg(long, env = "RLM_DAILY_TOKEN_BUDGET", default_value_t = 0)]
+    pub(crate) daily_token_budget: u64,
+    /// Daily estimated cost budget in USD per client API key (0 disables)
+    #[arg(long, env = "RLM_DAILY_COST_BUDGET_USD", default_value_t = 0.0)]
+    pub(crate) daily_cost_budget_usd: f64,
+    /// USD per million prompt tokens used for cost estimates
+    #[arg(long, env = "RLM_PROMPT_PRICE_PER_MILLION", default_value_t = 1.25)]
+    pub(crate) prompt_price_per_million: f64,
+    /// USD per million completion tokens used for cost estimates
+    #[arg(long, env = "RLM_COMPLETION_PRICE_PER_MILLION", default_value_t = 10.0)]
+    pub(crate) completion_price_per_million: f64,
+    /// Upper bound for per-request `rlm_max_iterations` overrides
+    #[arg(
+        long,
+        env = "RLM_MAX_ITERATIONS_LIMIT",
+        default_value_t = DEFAULT_MAX_ITERATIONS_LIMIT
+    )]
+    pub(crate) max_iterations_limit: usize,
+    /// Comma-separated sub-LLM models callers may pick with
+    /// `rlm_recursive_model`
+    #[arg(long, env = "RLM_RECURSIVE_MODEL_ALLOWLIST", value_delimiter = ',')]
+    pub(crate) recursive_model_allowlist: Vec<String>,
+    /// Maximum bytes for a single message content or code string
+    #[arg(long, env = "RLM_MAX_INPUT_BYTES", default_value_t = DEFAULT_MAX_INPUT_BYTES)]
+    pub(crate) max_input_bytes: usize,
+    /// Maximum request body bytes on the v1 POST routes
+    #[arg(long, env = "RLM_MAX_BODY_BYTES", default_value_t = DEFAULT_MAX_BODY_BYTES)]
+    pub(crate) max_body_bytes: usize,
+    /// Maximum decoded body bytes for gzip/zstd requests to
+    /// /v1/chat/completions
+    #[arg(
+        long,
+        env = "RLM_MAX_DECOMPRESSED_BYTES",
+        default_value_t = DEFAULT_MAX_DECOMPRESSED_BYTES
+    )]
+    pub(crate) max_decompressed_bytes: usize,
+    /// Total bytes of uploaded contexts kept by /v1/contexts before evicting
+    /// the oldest
+    #[arg(
+        long,
+        env = "RLM_CONTEXT_STORE_MAX_BYTES",
+        default_value_t = DEFAULT_CONTEXT_STORE_MAX_BYTES
+    )]
+    pub(crate) context_store_max_bytes: usize,
+    /// Total bytes of session snapshots kept so evicted or crashed sessions
+    /// are restored on their next run (0 disables)
+    #[arg(long, env = "RLM_SESSION_SNAPSHOT_MAX_BYTES", default_value_t = 0)]
+    pub(crate) session_snapshot_max_bytes: usize,
+    /// Upstream LLM base URL probed by /readyz
+    #[arg(long, env = "RLM_LLM_BASE_URL", default_value = DEFAULT_BASE_URL)]
+    pub(crate) llm_base_url: String,
+    /// JSON file of tenant profiles; when set, v1 requests must present one of
+    /// their keys
+    #[arg(long, env = "RLM_TENANTS")]
+    pub(crate) tenants: Option<PathBuf>,
+    /// JSON file of `patterns` (label + regex) and `deny_list` terms masked
+    /// on the server before text reaches a sandbox or the upstream LLM
+    #[arg(long, env = "RLM_REDACTION_CONFIG")]
+    pub(crate) redaction_config: Option<PathBuf>,
+    /// Directory for the append-only JSONL audit log of executed sandbox code;
+    /// auditing is off when unset
+    #[arg(long, env = "RLM_AUDIT_LOG_DIR")]
+    pub(crate) audit_log_dir: Option<PathBuf>,
+    /// Days of audit files to keep (0 keeps them all)
+    #[arg(long, env = "RLM_AUDIT_RETENTION_DAYS", default_value_t = 30)]
+    pub(crate) audit_retention_days: u64,
+    /// Redis URL of a session registry shared by replicas (needs the `redis`
+    /// feature)
+    #[arg(long, env = "RLM_SESSION_REGISTRY_URL")]
+    pub(crate) session_registry_url: Option<String>,
+    /// Name this replica records in the session registry; random when unset
+    #[arg(long, env = "RLM_REPLICA_ID")]
+    pub(crate) replica_id: Option<String>,
+    /// Base URL other replicas forward this replica's sessions to; they
+    /// reject those requests with 421 when unset
+    #[arg(long, env = "RLM_REPLICA_URL")]
+    pub(crate) replica_url: Option<String>,
+    /// Seconds a session stays assigned to its replica after its last request
+    #[arg(long, env = "RLM_SESSION_REGISTRY_TTL_SECS", default_value_t = 3600)]
+    pub(crate) session_registry_ttl_secs: u64,
+    /// SQLite file recording session metadata across restarts (needs the
+    /// `sqlite` feature)
+    #[arg(long, env = "RLM_SESSION_STORE")]
+    pub(crate) session_store: Option<PathBuf>,
+    /// Days of inactivity before stored session metadata is dropped (0 keeps
+    /// it)
+    #[arg(long, env = "RLM_SESSION_STORE_RETENTION_DAYS", default_value_t = 30)]
+    pub(crate) session_store_retention_days: u64,
+    /// Bearer token for /admin endpoints; they are disabled when unset
+    #[arg(long, env = "RLM_ADMIN_TOKEN")]
+    pub(crate) admin_token: Option<String>,
+    /// Tracing filter directive, e.g. `info` or `app=debug,tower_http=warn`
+    #[arg(long, env = "RLM_LOG", default_value = DEFAULT_LOG_LEVEL)]
+    pub(crate) log_level: String,
+    /// Log output format: text or json
+    #[arg(long, env = "RLM_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    pub(crate) log_format: LogFormat,
+}
@@ -1,17 +1,197 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::process::{Child, ChildStdin, ChildStdout};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::SandboxHandle;
-use crate::protocol::{SandboxRunRequest, SandboxRunResult, WorkerRequest, WorkerResponse};
+use rlm::redact::Redaction;
+use rlm::repl::ReplSnapshot;
+use rlm::trace::ProgressEvent;
+use tracing::Level;
+use uuid::Uuid;
 
+use crate::llm_broker::{BrokerRoute, HostLlmBroker};
+use crate::protocol::{
+    Envelope, Frame, Framing, LlmOutcome, LlmRequest, LlmResult, SandboxRunRequest,
+    SandboxRunResult, WorkerError, WorkerErrorCode, WorkerRequest, WorkerResponse, encode_frame,
+    read_frame,
+};
+use crate::telemetry::current_trace_context;
+use crate::{SandboxError, SandboxHandle, SandboxIoTimeouts, SandboxUsage};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a cancelled run has to answer before its worker is killed.
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+/// How long a worker that closed its stdout has to exit before it is
+/// treated as merely unresponsive.
+const EXIT_WAIT: Duration = Duration::from_millis(500);
+/// Contexts whose JSON is longer than this are streamed to the worker in
+/// pieces of this size rather than inside the run request.
+const CONTEXT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+const SIGKILL: i32 = 9;
+/// What the container CLI exits with when its container was SIGKILLed.
+const CONTAINER_KILLED_EXIT: i32 = 128 + SIGKILL;
+
+/// An encoded message for the worker, and where to report once it has been
+/// written.
+type WriteJob = (Vec<u8>, Option<SyncSender<Result<(), String>>>);
+
+/// Where the reader thread sends each REPL's replies.
+type Routes = Mutex<HashMap<Option<String>, Sender<Result<Frame, String>>>>;
+
+/// One handle on a worker, which talks to one of its REPLs. Further handles
+/// on the same worker come from [`SandboxHandle::share`].
 pub struct SandboxClient {
-    child: Child,
-    stdin: BufWriter<ChildStdin>,
-    stdout: BufReader<ChildStdout>,
+    link: Arc<Link>,
+    /// The REPL this handle talks to; `None` for the worker's default one.
+    repl: Option<String>,
+    next_id: u64,
+    /// Feeds the thread writing the worker's stdin, so a worker that stops
+    /// reading cannot block the caller. Shared with brokered LLM calls, which
+    /// answer from other threads.
+    stdin: Sender<WriteJob>,
+    responses: Receiver<Result<Frame, String>>,
+    io_timeouts: SandboxIoTimeouts,
+    /// What requests are written in; responses are read in either.
+    framing: Framing,
+    usage: SandboxUsage,
+    llm_broker: Option<Arc<HostLlmBroker>>,
+    /// What brokered calls of the run in flight may use.
+    llm_route: Option<BrokerRoute>,
+    /// Placeholder map brokered calls are redacted with.
+    redaction: Option<Arc<Redaction>>,
+    context_dir: Option<Arc<ContextDir>>,
+    /// Cleared once the worker turns out to predate chunked contexts.
+    chunked_context: bool,
+    /// Session the worker's forwarded stderr is tagged with.
+    session: Arc<Mutex<Option<String>>>,
+    /// Set once the worker has been left running for another server.
+    detached: bool,
+}
+
+/// The worker every handle on it shares.
+struct Link {
+    peer: Mutex<Peer>,
+    /// CLI and name of the container the worker runs in.
+    container: Mutex<Option<(String, String)>>,
+    routes: Arc<Routes>,
+}
+
+/// What the client talks to.
+enum Peer {
+    Process(Child),
+    /// A worker behind a daemon connection, which ends with the socket.
+    Remote {
+        socket: TcpStream,
+        name: String,
+    },
+    /// A worker listening on a Unix socket, which keeps running when the
+    /// connection closes. `child` is unset for workers another server
+    /// started.
+    #[cfg(unix)]
+    Socket {
+        child: Option<Child>,
+        pid: u32,
+        socket: UnixStream,
+        path: PathBuf,
+    },
+}
+
+/// Host directory the worker sees read-only, where large contexts are
+/// written instead of being sent inline. Removed with the client.
+pub struct ContextDir {
+    host: PathBuf,
+    /// Where the worker finds `host`.
+    sandbox: PathBuf,
+    min_bytes: usize,
+}
+
+impl ContextDir {
+    /// Creates a fresh directory under the host's temp dir.
+    pub fn create(min_bytes: usize) -> Result<Self, String> {
+        let host = std::env::temp_dir().join(format!("rlm-context-{}", Uuid::new_v4().simple()));
+        fs::create_dir(&host)
+            .map_err(|err| format!("failed to create {}: {err}", host.display()))?;
+        // Workers may run as another user; the random file names keep
+        // contexts from being found by listing.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&host, fs::Permissions::from_mode(0o711))
+                .map_err(|err| format!("failed to set permissions on {}: {err}", host.display()))?;
+        }
+        Ok(Self {
+            sandbox: host.clone(),
+            host,
+            min_bytes,
+        })
+    }
+
+    /// Where the worker sees the directory, if it is mounted elsewhere.
+    pub fn mounted_at(mut self, sandbox: impl Into<PathBuf>) -> Self {
+        self.sandbox = sandbox.into();
+        self
+    }
+
+    pub fn host_path(&self) -> &Path {
+        &self.host
+    }
+
+    /// Moves a large context out of `request` into a file the worker reads,
+    /// and returns the file's size and a guard that removes it.
+    fn hand_off(
+        &self,
+        request: &mut SandboxRunRequest,
+    ) -> Result<Option<(u64, ContextFile)>, SandboxError> {
+        let Some(context) = &request.context else {
+            return Ok(None);
+        };
+        let bytes = serde_json::to_vec(context).map_err(|err| {
+            SandboxError::Worker(WorkerError::internal(format!(
+                "failed to encode context: {err}"
+            )))
+        })?;
+        if bytes.len() < self.min_bytes {
+            return Ok(None);
+        }
+        let name = format!("{}.json", Uuid::new_v4().simple());
+        let file = ContextFile(self.host.join(&name));
+        fs::write(&file.0, &bytes).map_err(|err| {
+            SandboxError::Worker(WorkerError::internal(format!(
+                "failed to write context file: {err}"
+            )))
+        })?;
+        request.context = None;
+        request.context_path = Some(self.sandbox.join(name).to_string_lossy().into_owned());
+        Ok(Some((bytes.len() as u64, file)))
+    }
+}
+
+impl Drop for ContextDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.host);
+    }
+}
+
+/// A context handed off for one run, removed once the run is over.
+struct ContextFile(PathBuf);
+
+impl Drop for ContextFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
 }
 
 impl SandboxClient {
-    pub fn new(mut child: Child) -> Result<Self, String> {
+    pub fn new(mut child: Child, io_timeouts: SandboxIoTimeouts) -> Result<Self, String> {
         let stdin = child
             .stdin
             .take()
@@ -20,67 +200,762 @@ impl SandboxClient {
             .stdout
             .take()
             .ok_or_else(|| "sandbox worker missing stdout".to_owned())?;
-        Ok(Self {
+        let session = Arc::new(Mutex::new(None));
+        if let Some(stderr) = child.stderr.take() {
+            spawn_stderr_forwarder(stderr, format!("pid:{}", child.id()), session.clone())?;
+        }
+        Self::with_transport(Peer::Process(child), stdout, stdin, session, io_timeouts)
+    }
+
+    /// A worker reached through a daemon connection; `socket` is the
+    /// connection under `reader` and `writer`, and `name` identifies it.
+    pub fn remote(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        socket: TcpStream,
+        name: String,
+        io_timeouts: SandboxIoTimeouts,
+    ) -> Result<Self, String> {
+        Self::with_transport(
+            Peer::Remote { socket, name },
+            reader,
+            writer,
+            Arc::new(Mutex::new(None)),
+            io_timeouts,
+        )
+    }
+
+    /// A worker listening on `socket`'s Unix socket at `path`. Its stderr
+    /// is forwarded if this server started it as `child`.
+    #[cfg(unix)]
+    pub fn socket(
+        mut child: Option<Child>,
+        pid: u32,
+        socket: UnixStream,
+        path: PathBuf,
+        io_timeouts: SandboxIoTimeouts,
+    ) -> Result<Self, String> {
+        let clone = || {
+            socket
+                .try_clone()
+                .map_err(|err| format!("failed to clone worker socket: {err}"))
+        };
+        let (reader, writer) = (clone()?, clone()?);
+        let session = Arc::new(Mutex::new(None));
+        if let Some(stderr) = child.as_mut().and_then(|child| child.stderr.take()) {
+            spawn_stderr_forwarder(stderr, format!("pid:{pid}"), session.clone())?;
+        }
+        let peer = Peer::Socket {
             child,
-            stdin: BufWriter::new(stdin),
-            stdout: BufReader::new(stdout),
+            pid,
+            socket,
+            path,
+        };
+        Self::with_transport(peer, reader, writer, session, io_timeouts)
+    }
+
+    fn with_transport(
+        peer: Peer,
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        session: Arc<Mutex<Option<String>>>,
+        io_timeouts: SandboxIoTimeouts,
+    ) -> Result<Self, String> {
+        let (route, responses) = mpsc::channel();
+        let routes = Arc::new(Mutex::new(HashMap::from([(None, route)])));
+        spawn_response_reader(reader, routes.clone())?;
+        Ok(Self {
+            link: Arc::new(Link {
+                peer: Mutex::new(peer),
+                container: Mutex::new(None),
+                routes,
+            }),
+            repl: None,
+            next_id: 0,
+            stdin: spawn_request_writer(writer)?,
+            responses,
+            io_timeouts,
+            framing: Framing::JsonLines,
+            usage: SandboxUsage::default(),
+            llm_broker: None,
+            llm_route: None,
+            redaction: None,
+            context_dir: None,
+            chunked_context: true,
+            session,
+            detached: false,
         })
     }
 
-    pub fn ping(&mut self) -> Result<(), String> {
-        match self.send_request(&WorkerRequest::Ping)? {
-            WorkerResponse::Pong => Ok(()),
-            WorkerResponse::Error { message } => Err(message),
-            other => Err(format!("unexpected ping response: {other:?}")),
+    /// `cli` is the container CLI that started `container`.
+    pub fn with_container(self, cli: String, container: String) -> Self {
+        *self
+            .link
+            .container
+            .lock()
+            .expect("sandbox link lock poisoned") = Some((cli, container));
+        self
+    }
+
+    /// Switches to `framing` if the worker supports it; workers that predate
+    /// the handshake keep JSON lines.
+    pub fn negotiate_framing(
+        &mut self,
+        framing: Framing,
+        deadline: Option<Instant>,
+    ) -> Result<(), SandboxError> {
+        if framing == self.framing {
+            return Ok(());
+        }
+        let request = WorkerRequest::Hello {
+            framings: vec![framing, Framing::JsonLines],
+        };
+        match self.send_request(&request, deadline, &|| false, &|_| {})? {
+            WorkerResponse::Hello { framing } => {
+                self.framing = framing;
+                Ok(())
+            }
+            WorkerResponse::Error(_) => Ok(()),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected hello response: {other:?}"
+            )))),
         }
     }
 
-    fn send_request(&mut self, request: &WorkerRequest) -> Result<WorkerResponse, String> {
-        let line = serde_json::to_string(request).map_err(|err| err.to_string())?;
-        self.stdin
-            .write_all(line.as_bytes())
-            .map_err(|err| format!("sandbox worker write failed: {err}"))?;
-        self.stdin
-            .write_all(b"\n")
-            .map_err(|err| format!("sandbox worker write failed: {err}"))?;
-        self.stdin
-            .flush()
-            .map_err(|err| format!("sandbox worker flush failed: {err}"))?;
+    /// Hands contexts of at least `dir`'s size over through it.
+    pub fn with_context_dir(mut self, dir: Option<ContextDir>) -> Self {
+        self.context_dir = dir.map(Arc::new);
+        self
+    }
 
-        let mut response_line = String::new();
-        let read = self
-            .stdout
-            .read_line(&mut response_line)
-            .map_err(|err| format!("sandbox worker read failed: {err}"))?;
-        if read == 0 {
-            return Err("sandbox worker closed stdout".to_owned());
+    /// Serves the worker's `llm_request` messages through `broker`.
+    pub fn with_llm_broker(mut self, broker: Option<Arc<HostLlmBroker>>) -> Self {
+        self.llm_broker = broker;
+        self
+    }
+
+    fn send_request(
+        &mut self,
+        request: &WorkerRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<WorkerResponse, SandboxError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let bytes =
+            encode_frame(&self.envelope(Some(id), request), self.framing).map_err(|err| {
+                SandboxError::Worker(WorkerError::internal(format!(
+                    "failed to encode request: {err}"
+                )))
+            })?;
+        match request {
+            WorkerRequest::Run(_) => {
+                self.usage.runs += 1;
+                self.usage.context_bytes += bytes.len() as u64;
+            }
+            WorkerRequest::ContextChunk { .. } => self.usage.context_bytes += bytes.len() as u64,
+            _ => {}
+        }
+        self.write(bytes, deadline)?;
+
+        // Runs may legitimately go quiet for minutes, and pings carry
+        // deadlines sized for their purpose.
+        let read_deadline = match request {
+            WorkerRequest::Run(_) | WorkerRequest::Ping => None,
+            _ => Some(Instant::now() + self.io_timeouts.read),
+        };
+        loop {
+            let wait = [deadline, read_deadline]
+                .into_iter()
+                .flatten()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .fold(CANCEL_POLL_INTERVAL, Duration::min);
+            match self.responses.recv_timeout(wait) {
+                Ok(frame) => {
+                    let frame = frame.map_err(|message| self.gone(message))?;
+                    let reply: Envelope<WorkerResponse> = frame.decode().map_err(|err| {
+                        SandboxError::Worker(WorkerError::internal(format!(
+                            "sandbox worker invalid response: {err}"
+                        )))
+                    })?;
+                    // Replies left over from a request given up on earlier
+                    // are skipped.
+                    if reply.id.is_none_or(|reply| reply == id) {
+                        match reply.message {
+                            WorkerResponse::LlmRequest(request) => self.broker_llm_request(request),
+                            WorkerResponse::Progress(event) => progress(event),
+                            response => return Ok(response),
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(self.gone("sandbox worker closed stdout".to_owned()));
+                }
+            }
+            // Checked after every frame too, so a chatty worker cannot
+            // outrun its deadline or a cancellation.
+            let run = matches!(request, WorkerRequest::Run(_));
+            if passed(deadline) {
+                tracing::warn!(sandbox = %self.identifier(), "sandbox worker timed out");
+                return Err(SandboxError::TimedOut(self.abandon(run)));
+            }
+            if passed(read_deadline) {
+                tracing::warn!(sandbox = %self.identifier(), "sandbox worker stopped answering; killing it");
+                self.kill();
+                return Err(SandboxError::Unresponsive(format!(
+                    "sandbox worker did not answer within {}s",
+                    self.io_timeouts.read.as_secs()
+                )));
+            }
+            if cancelled() {
+                tracing::info!(sandbox = %self.identifier(), "caller went away; cancelling sandbox run");
+                return Err(SandboxError::Cancelled(self.abandon(run)));
+            }
+        }
+    }
+
+    /// Streams a large context ahead of its run in acknowledged pieces, so
+    /// no single write has to carry all of it and each gets its own write
+    /// timeout. Workers that predate chunked contexts take it inline.
+    fn stream_context(
+        &mut self,
+        request: &mut SandboxRunRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), SandboxError> {
+        if !self.chunked_context || !request.initialize {
+            return Ok(());
+        }
+        let Some(context) = &request.context else {
+            return Ok(());
+        };
+        let text = serde_json::to_string(context).map_err(|err| {
+            SandboxError::Worker(WorkerError::internal(format!(
+                "failed to encode context: {err}"
+            )))
+        })?;
+        if text.len() <= CONTEXT_CHUNK_BYTES {
+            return Ok(());
+        }
+        let begin = WorkerRequest::BeginContext {
+            total_bytes: text.len() as u64,
+        };
+        match self.send_request(&begin, deadline, cancelled, &|_| {})? {
+            WorkerResponse::Ack => {}
+            WorkerResponse::Error(_) => {
+                self.chunked_context = false;
+                return Ok(());
+            }
+            other => {
+                return Err(SandboxError::Worker(WorkerError::internal(format!(
+                    "unexpected begin_context response: {other:?}"
+                ))));
+            }
+        }
+        let mut rest = text.as_str();
+        while !rest.is_empty() {
+            let mut end = CONTEXT_CHUNK_BYTES.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (chunk, tail) = rest.split_at(end);
+            let chunk = WorkerRequest::ContextChunk {
+                data: chunk.to_owned(),
+            };
+            self.expect_ack(&chunk, deadline, cancelled)?;
+            rest = tail;
+        }
+        self.expect_ack(&WorkerRequest::EndContext, deadline, cancelled)?;
+        request.context = None;
+        request.staged_context = true;
+        Ok(())
+    }
+
+    fn expect_ack(
+        &mut self,
+        request: &WorkerRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), SandboxError> {
+        match self.send_request(request, deadline, cancelled, &|_| {})? {
+            WorkerResponse::Ack => Ok(()),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected context transfer response: {other:?}"
+            )))),
+        }
+    }
+
+    /// Gives up on the request in flight: a run is cancelled, and a worker
+    /// that is not running or does not stop is killed. Returns the worker's
+    /// account of the cancelled run.
+    fn abandon(&mut self, run: bool) -> Option<WorkerError> {
+        match run.then(|| self.cancel_run()).flatten() {
+            Some(WorkerResponse::Error(err)) => Some(err),
+            Some(_) => None,
+            None => {
+                self.kill();
+                None
+            }
         }
-        serde_json::from_str(response_line.trim_end())
-            .map_err(|err| format!("sandbox worker invalid response: {err}"))
     }
 
-    fn shutdown_graceful(&mut self) {
-        let _ = self.send_request(&WorkerRequest::Shutdown);
+    /// Asks the worker to abandon its run and waits briefly for the answer;
+    /// `None` if it never came, in which case the worker should be killed.
+    fn cancel_run(&mut self) -> Option<WorkerResponse> {
+        let Ok(bytes) = encode_frame(&self.envelope(None, &WorkerRequest::Cancel), self.framing)
+        else {
+            return None;
+        };
+        if self.stdin.send((bytes, None)).is_err() {
+            return None;
+        }
+        let grace = Instant::now() + CANCEL_GRACE;
+        loop {
+            let frame = match self
+                .responses
+                .recv_timeout(grace.saturating_duration_since(Instant::now()))
+            {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(_)) | Err(_) => return None,
+            };
+            match frame
+                .decode::<Envelope<WorkerResponse>>()
+                .map(|reply| reply.message)
+            {
+                // The abandoned run no longer waits for its LLM calls.
+                Ok(WorkerResponse::LlmRequest(_) | WorkerResponse::Progress(_)) => {}
+                Ok(response) => return Some(response),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: Vec<u8>, deadline: Option<Instant>) -> Result<(), SandboxError> {
+        let (done, written) = mpsc::sync_channel(1);
+        if self.stdin.send((bytes, Some(done))).is_err() {
+            return Err(SandboxError::Unresponsive(
+                "sandbox worker stdin closed".to_owned(),
+            ));
+        }
+        let write_deadline = Instant::now() + self.io_timeouts.write;
+        let until = deadline.map_or(write_deadline, |deadline| deadline.min(write_deadline));
+        match written.recv_timeout(until.saturating_duration_since(Instant::now())) {
+            Ok(result) => result.map_err(SandboxError::Unresponsive),
+            Err(RecvTimeoutError::Timeout) if passed(deadline) => {
+                tracing::warn!(sandbox = %self.identifier(), "sandbox worker timed out");
+                self.kill();
+                Err(SandboxError::TimedOut(None))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::warn!(sandbox = %self.identifier(), "sandbox worker stopped reading; killing it");
+                self.kill();
+                Err(SandboxError::Unresponsive(format!(
+                    "sandbox worker did not read a request within {}s",
+                    self.io_timeouts.write.as_secs()
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(SandboxError::Unresponsive(
+                "sandbox worker stdin closed".to_owned(),
+            )),
+        }
+    }
+
+    fn broker_llm_request(&self, request: LlmRequest) {
+        let stdin = self.stdin.clone();
+        let framing = self.framing;
+        let repl = self.repl.clone();
+        let reply = move |result: LlmResult| {
+            let envelope = Envelope {
+                id: None,
+                repl,
+                message: WorkerRequest::LlmResult(result),
+            };
+            // A failed write means the worker is gone, which the pending
+            // request will notice on its own.
+            if let Ok(bytes) = encode_frame(&envelope, framing) {
+                let _ = stdin.send((bytes, None));
+            }
+        };
+        match &self.llm_broker {
+            Some(broker) => {
+                let route = self
+                    .llm_route
+                    .clone()
+                    .unwrap_or_else(|| broker.default_route());
+                broker.handle(request, &route, reply);
+            }
+            None => reply(LlmResult {
+                id: request.id,
+                outcome: LlmOutcome::Failed {
+                    message: "llm brokering is not enabled for this sandbox".to_owned(),
+                    upstream: false,
+                },
+            }),
+        }
+    }
+
+    fn envelope<'a>(
+        &self,
+        id: Option<u64>,
+        request: &'a WorkerRequest,
+    ) -> Envelope<&'a WorkerRequest> {
+        Envelope {
+            id,
+            repl: self.repl.clone(),
+            message: request,
+        }
+    }
+
+    fn shutdown_graceful(&mut self) -> Result<(), SandboxError> {
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        self.send_request(&WorkerRequest::Shutdown, Some(deadline), &|| false, &|_| {})
+            .map(|_| ())
+    }
+
+    /// Explains a worker that went away on its own. A SIGKILL nobody here
+    /// sent is almost always the OOM killer enforcing the memory limit.
+    fn gone(&mut self, message: String) -> SandboxError {
+        let status = {
+            let mut peer = self.link.peer.lock().expect("sandbox link lock poisoned");
+            let child = match &mut *peer {
+                Peer::Process(child) => child,
+                #[cfg(unix)]
+                Peer::Socket {
+                    child: Some(child), ..
+                } => child,
+                _ => return SandboxError::Unresponsive(message),
+            };
+            let wait_until = Instant::now() + EXIT_WAIT;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => break Some(status),
+                    Ok(None) if Instant::now() < wait_until => {
+                        thread::sleep(Duration::from_millis(10))
+                    }
+                    _ => break None,
+                }
+            }
+        };
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.and_then(|status| status.signal())
+        };
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+        let killed = signal == Some(SIGKILL)
+            || status.and_then(|status| status.code()) == Some(CONTAINER_KILLED_EXIT);
+        if killed {
+            tracing::warn!(sandbox = %self.identifier(), "sandbox worker was killed, likely out of memory");
+            return SandboxError::Worker(WorkerError::new(
+                WorkerErrorCode::Oom,
+                "sandbox worker was killed, most likely for exceeding its memory limit",
+            ));
+        }
+        SandboxError::Unresponsive(message)
+    }
+
+    fn kill(&mut self) {
+        // Killing the container CLI alone leaves the container running.
+        let container = self
+            .link
+            .container
+            .lock()
+            .expect("sandbox link lock poisoned")
+            .take();
+        if let Some((cli, container)) = container {
+            let _ = Command::new(cli)
+                .arg("kill")
+                .arg(&container)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+        match &mut *self.link.peer.lock().expect("sandbox link lock poisoned") {
+            Peer::Process(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Peer::Remote { socket, .. } => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            #[cfg(unix)]
+            Peer::Socket {
+                child,
+                pid,
+                socket,
+                path,
+            } => {
+                let _ = socket.shutdown(Shutdown::Both);
+                match child {
+                    Some(child) => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    None => {
+                        let _ = Command::new("kill")
+                            .arg("-KILL")
+                            .arg(pid.to_string())
+                            .stdout(Stdio::null())
+                            .stderr(Stdio::null())
+                            .status();
+                    }
+                }
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn running(&mut self) -> bool {
+        match &mut *self.link.peer.lock().expect("sandbox link lock poisoned") {
+            Peer::Process(child) => matches!(child.try_wait(), Ok(None)),
+            #[cfg(unix)]
+            Peer::Socket {
+                child: Some(child), ..
+            } => matches!(child.try_wait(), Ok(None)),
+            // A closed connection shows up on the next request.
+            _ => true,
+        }
     }
 }
 
 impl SandboxHandle for SandboxClient {
-    fn run(&mut self, request: SandboxRunRequest) -> Result<SandboxRunResult, String> {
-        match self.send_request(&WorkerRequest::Run(request))? {
-            WorkerResponse::RunResult(result) => Ok(result),
-            WorkerResponse::Error { message } => Err(message),
-            other => Err(format!("unexpected run response: {other:?}")),
+    fn run(
+        &mut self,
+        mut request: SandboxRunRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<SandboxRunResult, SandboxError> {
+        let _span = tracing::info_span!("sandbox_run", sandbox = %self.identifier()).entered();
+        request.trace_context = current_trace_context();
+        let context_file = match &self.context_dir {
+            Some(dir) => dir.hand_off(&mut request)?,
+            None => None,
+        };
+        match &context_file {
+            Some((bytes, _)) => self.usage.context_bytes += bytes,
+            None => self.stream_context(&mut request, deadline, cancelled)?,
+        }
+        self.llm_route = self
+            .llm_broker
+            .as_ref()
+            .map(|broker| broker.route_for(&mut request, self.redaction.clone()));
+        let start = Instant::now();
+        let result = self.send_request(
+            &WorkerRequest::Run(Box::new(request)),
+            deadline,
+            cancelled,
+            progress,
+        );
+        self.llm_route = None;
+        drop(context_file);
+        tracing::debug!(
+            ok = result.is_ok(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "sandbox run finished"
+        );
+        match result? {
+            WorkerResponse::RunResult(result) => {
+                if let Some(stats) = result.stats {
+                    self.usage.worker = stats;
+                } else if result.rss_bytes.is_some() {
+                    self.usage.worker.rss_bytes = result.rss_bytes;
+                }
+                Ok(result)
+            }
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected run response: {other:?}"
+            )))),
+        }
+    }
+
+    fn get_variable(
+        &mut self,
+        name: &str,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>, SandboxError> {
+        let _span =
+            tracing::info_span!("sandbox_get_variable", sandbox = %self.identifier()).entered();
+        let request = WorkerRequest::GetVariable {
+            name: name.to_owned(),
+            request_id,
+            trace_context: current_trace_context(),
+        };
+        match self.send_request(&request, deadline, &|| false, &|_| {})? {
+            WorkerResponse::Variable { value } => Ok(value),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected get_variable response: {other:?}"
+            )))),
+        }
+    }
+
+    fn snapshot(
+        &mut self,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<ReplSnapshot, SandboxError> {
+        let _span = tracing::info_span!("sandbox_snapshot", sandbox = %self.identifier()).entered();
+        match self.send_request(
+            &WorkerRequest::Snapshot { request_id },
+            deadline,
+            &|| false,
+            &|_| {},
+        )? {
+            WorkerResponse::Snapshot { snapshot } => Ok(*snapshot),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected snapshot response: {other:?}"
+            )))),
+        }
+    }
+
+    fn restore(
+        &mut self,
+        snapshot: ReplSnapshot,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<(), SandboxError> {
+        let _span = tracing::info_span!("sandbox_restore", sandbox = %self.identifier()).entered();
+        let request = WorkerRequest::Restore {
+            snapshot: Box::new(snapshot),
+            request_id,
+        };
+        match self.send_request(&request, deadline, &|| false, &|_| {})? {
+            WorkerResponse::Ack => Ok(()),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected restore response: {other:?}"
+            )))),
+        }
+    }
+
+    fn ping(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError> {
+        match self.send_request(&WorkerRequest::Ping, deadline, &|| false, &|_| {})? {
+            WorkerResponse::Pong => Ok(()),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected ping response: {other:?}"
+            )))),
+        }
+    }
+
+    fn reset(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError> {
+        match self.send_request(&WorkerRequest::Reset, deadline, &|| false, &|_| {})? {
+            WorkerResponse::Ack => Ok(()),
+            WorkerResponse::Error(err) => Err(SandboxError::Worker(err)),
+            other => Err(SandboxError::Worker(WorkerError::internal(format!(
+                "unexpected reset response: {other:?}"
+            )))),
         }
     }
 
+    fn usage(&self) -> SandboxUsage {
+        self.usage
+    }
+
+    fn set_session(&mut self, session: Option<&str>) {
+        *self.session.lock().expect("sandbox session lock poisoned") = session.map(str::to_owned);
+    }
+
+    fn set_redaction(&mut self, redaction: Option<Arc<Redaction>>) {
+        self.redaction = redaction;
+    }
+
+    fn exited(&mut self) -> bool {
+        !self.running()
+    }
+
     fn terminate(&mut self) {
-        self.shutdown_graceful();
-        let _ = self.child.kill();
-        let _ = self.child.wait();
+        if self.detached {
+            return;
+        }
+        // Other handles still use the worker, so only this REPL goes.
+        if Arc::strong_count(&self.link) > 1 {
+            if self.repl.is_some() {
+                let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+                let _ =
+                    self.send_request(&WorkerRequest::Close, Some(deadline), &|| false, &|_| {});
+            }
+            self.link
+                .routes
+                .lock()
+                .expect("sandbox routes lock poisoned")
+                .remove(&self.repl);
+            self.detached = true;
+            return;
+        }
+        if self.running() && self.shutdown_graceful().is_ok() {
+            *self
+                .link
+                .container
+                .lock()
+                .expect("sandbox link lock poisoned") = None;
+        }
+        self.kill();
+    }
+
+    fn detach(&mut self) {
+        #[cfg(unix)]
+        if let Peer::Socket { socket, .. } =
+            &*self.link.peer.lock().expect("sandbox link lock poisoned")
+        {
+            // The worker goes back to waiting for a connection.
+            let _ = socket.shutdown(Shutdown::Both);
+            self.detached = true;
+            return;
+        }
+        self.terminate();
+    }
+
+    /// Starts a named REPL on the same worker. Workers that predate named
+    /// REPLs never answer its first ping.
+    fn share(&mut self) -> Result<Box<dyn SandboxHandle>, SandboxError> {
+        let name = Uuid::new_v4().simple().to_string();
+        let (route, responses) = mpsc::channel();
+        self.link
+            .routes
+            .lock()
+            .expect("sandbox routes lock poisoned")
+            .insert(Some(name.clone()), route);
+        let mut handle = Self {
+            link: self.link.clone(),
+            repl: Some(name),
+            next_id: 0,
+            stdin: self.stdin.clone(),
+            responses,
+            io_timeouts: self.io_timeouts,
+            framing: self.framing,
+            usage: SandboxUsage::default(),
+            llm_broker: self.llm_broker.clone(),
+            llm_route: None,
+            redaction: None,
+            context_dir: self.context_dir.clone(),
+            chunked_context: self.chunked_context,
+            session: self.session.clone(),
+            detached: false,
+        };
+        handle.ping(Some(Instant::now() + self.io_timeouts.read))?;
+        Ok(Box::new(handle))
     }
 
     fn identifier(&self) -> String {
-        format!("pid:{}", self.child.id())
+        let worker = match &*self.link.peer.lock().expect("sandbox link lock poisoned") {
+            Peer::Process(child) => format!("pid:{}", child.id()),
+            Peer::Remote { name, .. } => name.clone(),
+            #[cfg(unix)]
+            Peer::Socket { pid, .. } => format!("pid:{pid}"),
+        };
+        match &self.repl {
+            Some(repl) => format!("{worker}/{repl}"),
+            None => worker,
+        }
     }
 }
 
@@ -89,3 +964,160 @@ impl Drop for SandboxClient {
         self.terminate();
     }
 }
+
+fn passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+fn spawn_request_writer(stdin: impl Write + Send + 'static) -> Result<Sender<WriteJob>, String> {
+    let (sender, jobs) = mpsc::channel::<WriteJob>();
+    thread::Builder::new()
+        .name("sandbox-client-writer".to_owned())
+        .spawn(move || {
+            let mut stdin = BufWriter::new(stdin);
+            for (bytes, done) in jobs {
+                let result = write_frame(&mut stdin, &bytes);
+                if let Some(done) = done {
+                    let _ = done.send(result);
+                }
+            }
+        })
+        .map_err(|err| format!("failed to spawn sandbox writer: {err}"))?;
+    Ok(sender)
+}
+
+fn write_frame(stdin: &mut impl Write, bytes: &[u8]) -> Result<(), String> {
+    stdin
+        .write_all(bytes)
+        .map_err(|err| format!("sandbox worker write failed: {err}"))?;
+    stdin
+        .flush()
+        .map_err(|err| format!("sandbox worker flush failed: {err}"))
+}
+
+/// Logs each line the worker writes to stderr, which is mostly its own
+/// tracing output, tagged with the sandbox and its current session.
+fn spawn_stderr_forwarder(
+    stderr: ChildStderr,
+    sandbox: String,
+    session: Arc<Mutex<Option<String>>>,
+) -> Result<(), String> {
+    thread::Builder::new()
+        .name("sandbox-client-stderr".to_owned())
+        .spawn(move || {
+            let mut stderr = BufReader::new(stderr);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match stderr.read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end();
+                if line.is_empty() {
+                    continue;
+                }
+                let session = session
+                    .lock()
+                    .expect("sandbox session lock poisoned")
+                    .clone()
+                    .unwrap_or_else(|| "-".to_owned());
+                let (level, line) = parse_worker_line(line);
+                macro_rules! forward {
+                    ($level:expr) => {
+                        tracing::event!($level, sandbox = %sandbox, session = %session, "{line}")
+                    };
+                }
+                match level {
+                    Level::ERROR => forward!(Level::ERROR),
+                    Level::WARN => forward!(Level::WARN),
+                    Level::INFO => forward!(Level::INFO),
+                    _ => forward!(Level::DEBUG),
+                }
+            }
+        })
+        .map_err(|err| format!("failed to spawn sandbox stderr reader: {err}"))?;
+    Ok(())
+}
+
+/// Level of a worker log line in either log format, and the line without
+/// the timestamp and level of the text format. Anything else, such as a
+/// panic message, counts as a warning.
+fn parse_worker_line(line: &str) -> (Level, &str) {
+    if line.starts_with('{') {
+        let level = line
+            .split("\"level\":\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .and_then(|level| level.parse().ok());
+        return (level.unwrap_or(Level::WARN), line);
+    }
+    let parsed = line
+        .split_once(char::is_whitespace)
+        .and_then(|(_timestamp, rest)| rest.trim_start().split_once(char::is_whitespace))
+        .and_then(|(level, rest)| Some((level.parse().ok()?, rest.trim_start())));
+    parsed.unwrap_or((Level::WARN, line))
+}
+
+/// Passes each reply to the handle on its REPL; with a single handle there
+/// is nothing to look up.
+fn spawn_response_reader(
+    stdout: impl Read + Send + 'static,
+    routes: Arc<Routes>,
+) -> Result<(), String> {
+    thread::Builder::new()
+        .name("sandbox-client-reader".to_owned())
+        .spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            loop {
+                let frame = read_frame(&mut stdout);
+                let routes = routes.lock().expect("sandbox routes lock poisoned");
+                match frame {
+                    Ok(None) => break,
+                    Ok(Some(frame)) => {
+                        let route = if routes.len() == 1 {
+                            routes.values().next()
+                        } else {
+                            routes.get(&frame.repl())
+                        };
+                        if let Some(route) = route {
+                            let _ = route.send(Ok(frame));
+                        }
+                    }
+                    Err(err) => {
+                        for route in routes.values() {
+                            let _ = route.send(Err(format!("sandbox worker read failed: {err}")));
+                        }
+                        break;
+                    }
+                }
+            }
+            // Every handle sees the worker close its output.
+            routes.lock().expect("sandbox routes lock poisoned").clear();
+        })
+        .map_err(|err| format!("failed to spawn sandbox reader: {err}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_lines_keep_their_level() {
+        assert_eq!(
+            parse_worker_line("2026-01-01T00:00:00.000000Z  INFO sandbox_worker: ready"),
+            (Level::INFO, "sandbox_worker: ready")
+        );
+        let json = r#"{"timestamp":"t","level":"ERROR","fields":{"message":"boom"}}"#;
+        assert_eq!(parse_worker_line(json), (Level::ERROR, json));
+    }
+
+    #[test]
+    fn unparsed_worker_lines_are_warnings() {
+        let panic = "thread 'main' panicked at src/bin/sandbox_worker.rs:1:1:";
+        assert_eq!(parse_worker_line(panic), (Level::WARN, panic));
+        assert_eq!(parse_worker_line("{not json"), (Level::WARN, "{not json"));
+    }
+}
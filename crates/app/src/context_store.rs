@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContextInfo {
+    pub id: String,
+    pub object: &'static str,
+    pub bytes: usize,
+    pub created: u64,
+}
+
+pub struct ContextStore {
+    max_bytes: usize,
+    state: Mutex<ContextState>,
+}
+
+#[derive(Default)]
+struct ContextState {
+    total_bytes: usize,
+    entries: HashMap<String, StoredContext>,
+}
+
+struct StoredContext {
+    owner: String,
+    value: Arc<Value>,
+    info: ContextInfo,
+    last_used: Instant,
+}
+
+impl ContextStore {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::new(ContextState::default()),
+        }
+    }
+
+    /// Stores `value` for `owner`, evicting the least recently used contexts to
+    /// make room.
+    pub fn insert(&self, owner: &str, value: Value, bytes: usize) -> Result<ContextInfo, String> {
+        if bytes > self.max_bytes {
+            return Err(format!(
+                "context too large for store; max {} bytes",
+                self.max_bytes
+            ));
+        }
+        let info = ContextInfo {
+            id: format!("ctx_{}", Uuid::new_v4().simple()),
+            object: "context",
+            bytes,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+        };
+        let mut state = self.state.lock().expect("context store lock poisoned");
+        while state.total_bytes + bytes > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        state.total_bytes += bytes;
+        state.entries.insert(
+            info.id.clone(),
+            StoredContext {
+                owner: owner.to_owned(),
+                value: Arc::new(value),
+                info: info.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(info)
+    }
+
+    /// Contexts are only visible to the client key that uploaded them.
+    pub fn get(&self, owner: &str, id: &str) -> Option<Arc<Value>> {
+        let mut state = self.state.lock().expect("context store lock poisoned");
+        let entry = state
+            .entries
+            .get_mut(id)
+            .filter(|entry| entry.owner == owner)?;
+        entry.last_used = Instant::now();
+        Some(Arc::clone(&entry.value))
+    }
+
+    pub fn remove(&self, owner: &str, id: &str) -> Option<ContextInfo> {
+        let mut state = self.state.lock().expect("context store lock poisoned");
+        if state.entries.get(id)?.owner != owner {
+            return None;
+        }
+        state.remove(id)
+    }
+}
+
+impl ContextState {
+    fn remove(&mut self, id: &str) -> Option<ContextInfo> {
+        let entry = self.entries.remove(id)?;
+        self.total_bytes -= entry.info.bytes;
+        Some(entry.info)
+    }
+}
+
+pub fn validate_context_id(value: &str) -> Option<&str> {
+    let value = value.trim();
+    let hex = value.strip_prefix("ctx_")?;
+    (hex.len() == 32 && hex.bytes().all(|byte| byte.is_ascii_hexdigit())).then_some(value)
+}
@@ -0,0 +1,1288 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use app::audit::RunAudit;
+use app::context_store::validate_context_id;
+use app::protocol::RunOverrides;
+use app::registry::Ownership;
+use app::session::{
+    Priority, SessionError, SessionErrorKind, SessionRequest, SessionResponse, SessionStats,
+    TenantScope, UsageSink, VariableRequest, tenant_session_key, validate_session_id,
+};
+use app::session_store::context_hash;
+use app::tenants::Tenant;
+use app::usage::{TokenUsage, estimate_tokens};
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Extension, Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use rlm::llm::FinishReason;
+use rlm::repl::LocalValue;
+use rlm::rlm::ModelRoute;
+use rlm::trace::CompletionUsage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::middleware::{CurrentTenant, RequestId, client_key_from_headers};
+use crate::openai::{
+    OpenAiAssistantMessage, OpenAiChatChoice, OpenAiChatCompletionsRequest,
+    OpenAiChatCompletionsResponse, OpenAiUsage, json_rejection_response,
+    openai_context_from_messages, openai_error_response, openai_error_response_with_code,
+    openai_message_text, openai_query_from_messages, openai_tool_calls_from_trace,
+    validate_openai_input, with_retry_after,
+};
+use crate::{AppState, READINESS_TIMEOUT};
+
+const CONTEXT_ID_HEADER: &str = "x-rlm-context-id";
+const SERVED_BY_HEADER: &str = "x-rlm-served-by";
+const SESSION_RESTORED_HEADER: &str = "x-rlm-session-restored";
+const DEADLINE_HEADER: &str = "x-rlm-deadline-ms";
+const PRIORITY_HEADER: &str = "x-rlm-priority";
+const SESSION_RUNS_HEADER: &str = "x-rlm-session-runs";
+const SESSION_REPL_MS_HEADER: &str = "x-rlm-session-repl-ms";
+const SESSION_SUBCALLS_HEADER: &str = "x-rlm-session-subcalls";
+const ADMIN_SESSIONS_LIMIT: usize = 1000;
+const MAX_VARIABLE_NAME_LEN: usize = 256;
+/// nginx's status for a request the client gave up on.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateContextRequest {
+    context: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct DeletedContextResponse {
+    id: String,
+    object: &'static str,
+    deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExecuteCodeRequest {
+    code: String,
+    context: Option<Value>,
+    reset: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExecuteCodeResponse {
+    session_id: String,
+    stdout: String,
+    stderr: String,
+    locals: Vec<LocalValue>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ForkSessionRequest {
+    session_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ForkSessionResponse {
+    session_id: String,
+    forked_from: String,
+}
+
+#[derive(Debug, Serialize)]
+struct VariableResponse {
+    session_id: String,
+    name: String,
+    value: String,
+}
+
+pub(crate) async fn healthcheck() -> Response {
+    let mut response = StatusCode::OK.into_response();
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    sandbox: ReadinessCheck,
+    llm: ReadinessCheck,
+}
+
+#[derive(Serialize)]
+struct ReadinessCheck {
+    ok: bool,
+    error: Option<String>,
+}
+
+impl From<Result<(), String>> for ReadinessCheck {
+    fn from(result: Result<(), String>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+pub(crate) async fn readiness_handler(State(state): State<AppState>) -> Response {
+    let deadline = Instant::now() + READINESS_TIMEOUT;
+    let sandbox = async {
+        tokio::time::timeout(READINESS_TIMEOUT, state.sessions.check_ready(deadline))
+            .await
+            .unwrap_or_else(|_| Err("sandbox readiness check timed out".to_owned()))
+    };
+    let llm = async {
+        // Any HTTP response means the upstream is reachable; auth is checked
+        // per request.
+        state
+            .http
+            .get(&state.config.llm_base_url)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("LLM base URL unreachable: {err}"))
+    };
+    let (sandbox, llm) = tokio::join!(sandbox, llm);
+    let ready = sandbox.is_ok() && llm.is_ok();
+    if let Err(err) = sandbox.as_ref().and(llm.as_ref()) {
+        tracing::warn!(error = %err, "readiness check failed");
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let mut response = (
+        status,
+        Json(ReadinessReport {
+            ready,
+            sandbox: sandbox.into(),
+            llm: llm.into(),
+        }),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+pub(crate) async fn admin_usage_handler(State(state): State<AppState>) -> Response {
+    Json(state.usage.report()).into_response()
+}
+
+pub(crate) async fn admin_sessions_handler(State(state): State<AppState>) -> Response {
+    let live_sessions = match state.sessions.list_sessions().await {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            tracing::error!(error = %err, "failed to list live sessions");
+            return openai_error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "session manager unavailable",
+                "server_error",
+            );
+        }
+    };
+    let Some(store) = &state.session_store else {
+        return Json(serde_json::json!({ "live_sessions": live_sessions })).into_response();
+    };
+    match store.list(ADMIN_SESSIONS_LIMIT) {
+        Ok(sessions) => Json(serde_json::json!({
+            "sessions": sessions,
+            "live_sessions": live_sessions,
+        }))
+        .into_response(),
+        Err(err) => {
+            tracing::error!(error = %err, "failed to list session metadata");
+            openai_error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to read session metadata",
+                "server_error",
+            )
+        }
+    }
+}
+
+pub(crate) async fn admin_session_stats_handler(State(state): State<AppState>) -> Response {
+    Json(state.sessions.stats()).into_response()
+}
+
+pub(crate) async fn admin_pool_handler(State(state): State<AppState>) -> Response {
+    Json(state.sessions.pool_stats()).into_response()
+}
+
+pub(crate) async fn openai_chat_completions_handler(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(CurrentTenant(tenant)): Extension<CurrentTenant>,
+    headers: HeaderMap,
+    payload: Result<Json<OpenAiChatCompletionsRequest>, JsonRejection>,
+) -> Response {
+    let Json(payload) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return json_rejection_response(rejection),
+    };
+    let OpenAiChatCompletionsRequest {
+        messages,
+        model,
+        stream,
+        reset,
+        context_id,
+        include_trace,
+        include_tool_calls,
+        max_tokens,
+        max_completion_tokens,
+        rlm_max_iterations,
+        rlm_recursive_model,
+    } = payload;
+    if stream.unwrap_or(false) {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "stream=true unsupported; use stream=false",
+            "invalid_request_error",
+        );
+    }
+    if messages.is_empty() {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "messages required",
+            "invalid_request_error",
+        );
+    }
+    let max_completion_tokens = max_completion_tokens.or(max_tokens);
+    if max_completion_tokens == Some(0) {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "max_completion_tokens must be greater than zero",
+            "invalid_request_error",
+        );
+    }
+    if let Err((status, message)) = validate_openai_input(&messages, state.config.max_input_bytes) {
+        return openai_error_response(status, &message, "invalid_request_error");
+    }
+    let client_key = client_key_from_headers(&headers);
+    if let Err(exceeded) = state.usage.check_budget(&client_key) {
+        return with_retry_after(
+            openai_error_response_with_code(
+                StatusCode::TOO_MANY_REQUESTS,
+                &exceeded.message,
+                "insufficient_quota",
+                "insufficient_quota",
+            ),
+            exceeded.retry_after,
+        );
+    }
+    let prompt_tokens = messages
+        .iter()
+        .map(|message| estimate_tokens(&openai_message_text(message)))
+        .sum();
+
+    let model = model.unwrap_or_else(|| state.config.models.default_model().to_owned());
+    let allowed = |name: &str| {
+        tenant
+            .as_ref()
+            .is_none_or(|tenant| tenant.allows_model(name))
+    };
+    let Some(route) = state.config.models.get(&model).filter(|_| allowed(&model)) else {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "model {model} not found; expected one of {}",
+                state
+                    .config
+                    .models
+                    .names()
+                    .filter(|name| allowed(name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            "invalid_request_error",
+        );
+    };
+    let session_id = match session_id_from_transport(&headers) {
+        Ok(Some(session_id)) => session_id,
+        Ok(None) => Uuid::new_v4().to_string(),
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let reset = match header_bool(&headers, "x-rlm-reset") {
+        Ok(header_reset) => reset.unwrap_or(false) || header_reset,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let include_trace = match header_bool(&headers, "x-rlm-include-trace") {
+        Ok(header_trace) => include_trace.unwrap_or(false) || header_trace,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let include_tool_calls = match header_bool(&headers, "x-rlm-include-tool-calls") {
+        Ok(header_tool_calls) => include_tool_calls.unwrap_or(false) || header_tool_calls,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let max_iterations = match max_iterations_from_request(
+        rlm_max_iterations,
+        &headers,
+        state.config.max_iterations_limit,
+    ) {
+        Ok(max_iterations) => max_iterations,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let timeout = match request_timeout(&state, &headers) {
+        Ok(timeout) => timeout,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let priority = match request_priority(&headers) {
+        Ok(priority) => priority,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    if let Some(recursive_model) = &rlm_recursive_model
+        && !state
+            .config
+            .recursive_model_allowlist
+            .contains(recursive_model)
+    {
+        let message = if state.config.recursive_model_allowlist.is_empty() {
+            "rlm_recursive_model overrides are disabled on this server".to_owned()
+        } else {
+            format!(
+                "rlm_recursive_model {recursive_model} not allowed; expected one of {}",
+                state.config.recursive_model_allowlist.join(", ")
+            )
+        };
+        return openai_error_response(StatusCode::BAD_REQUEST, &message, "invalid_request_error");
+    }
+    let context_id = match context_id_from_request(context_id, &headers) {
+        Ok(context_id) => context_id,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let query = openai_query_from_messages(&messages);
+    let context = match &context_id {
+        Some(context_id) => match state.contexts.get(&client_key, context_id) {
+            Some(context) => Value::clone(&context),
+            None => {
+                return openai_error_response(
+                    StatusCode::NOT_FOUND,
+                    &format!("context {context_id} not found"),
+                    "invalid_request_error",
+                );
+            }
+        },
+        None => openai_context_from_messages(messages),
+    };
+    if let Err((status, message)) = enforce_tenant_context_limit(tenant.as_deref(), &context) {
+        return openai_error_response(status, &message, "invalid_request_error");
+    }
+    let context_hash = state
+        .session_store
+        .is_some()
+        .then(|| context_hash(&context));
+
+    let response = match dispatch_session_request(
+        &state,
+        session_id.clone(),
+        reset,
+        SessionInput {
+            query,
+            context: Some(context),
+            context_id,
+            code: None,
+            include_trace: include_trace || include_tool_calls || state.audit.is_some(),
+            overrides: RunOverrides {
+                max_completion_tokens,
+                max_iterations,
+                route: ModelRoute {
+                    api_key: tenant_api_key(tenant.as_deref()).or_else(|| route.api_key.clone()),
+                    recursive_model: rlm_recursive_model.or_else(|| route.recursive_model.clone()),
+                    ..route.clone()
+                },
+            },
+            tenant: tenant.as_ref().map(|tenant| tenant.scope()),
+            timeout,
+            priority,
+            // Failed runs still spent tokens, and are charged by the session.
+            usage_sink: Some({
+                let usage = state.usage.clone();
+                let client_key = client_key.clone();
+                UsageSink::new(move |reported| {
+                    // Runs that failed before calling the model cost nothing.
+                    let calls = reported.prompt_tokens
+                        + reported.completion_tokens
+                        + reported.unreported_calls;
+                    if calls > 0 {
+                        usage.record(&client_key, charged_tokens(reported, prompt_tokens, ""));
+                    }
+                })
+            }),
+            audit: run_audit(&state, &session_id, &request_id, tenant.as_deref()),
+        },
+        request_id.clone(),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => return session_error_response(err),
+    };
+    let restored = record_session_run(
+        &state,
+        tenant.as_deref(),
+        &session_id,
+        context_hash.as_deref(),
+    );
+    let content = match response.response {
+        Some(content) => content,
+        None => {
+            return openai_error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "missing assistant response",
+                "server_error",
+            );
+        }
+    };
+    let tokens = charged_tokens(response.usage, prompt_tokens, &content);
+    state.usage.record(&client_key, tokens);
+
+    let used_fallback = response.used_fallback;
+    let session_stats = response.stats;
+    let (tool_calls, tool_messages) = match &response.trace {
+        Some(trace) if include_tool_calls => openai_tool_calls_from_trace(trace),
+        _ => (Vec::new(), Vec::new()),
+    };
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let body = OpenAiChatCompletionsResponse {
+        id: format!("chatcmpl-{}", Uuid::new_v4().simple()),
+        object: "chat.completion".to_owned(),
+        created,
+        model,
+        choices: vec![OpenAiChatChoice {
+            index: 0,
+            message: OpenAiAssistantMessage {
+                role: "assistant".to_owned(),
+                content,
+                tool_calls,
+            },
+            finish_reason: match response.finish_reason {
+                Some(FinishReason::Length) => "length",
+                _ => "stop",
+            }
+            .to_owned(),
+            tool_messages,
+        }],
+        usage: OpenAiUsage {
+            prompt_tokens: tokens.prompt_tokens,
+            completion_tokens: tokens.completion_tokens,
+            total_tokens: tokens.prompt_tokens + tokens.completion_tokens,
+        },
+        trace: response.trace.filter(|_| include_trace),
+    };
+
+    let mut response = Json(body).into_response();
+    if let Err((status, message)) = set_session_response_headers(&mut response, &session_id) {
+        return openai_error_response(status, &message, "server_error");
+    }
+    set_session_stats_headers(&mut response, &session_stats);
+    response.headers_mut().insert(
+        SERVED_BY_HEADER,
+        HeaderValue::from_static(if used_fallback { "fallback" } else { "primary" }),
+    );
+    if restored {
+        response
+            .headers_mut()
+            .insert(SESSION_RESTORED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+pub(crate) async fn execute_code_handler(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(CurrentTenant(tenant)): Extension<CurrentTenant>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<ExecuteCodeRequest>, JsonRejection>,
+) -> Response {
+    let Json(payload) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return json_rejection_response(rejection),
+    };
+    let Some(session_id) = validate_session_id(&session_id) else {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid session id",
+            "invalid_request_error",
+        );
+    };
+    let ExecuteCodeRequest {
+        code,
+        context,
+        reset,
+    } = payload;
+    if code.trim().is_empty() {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "code required",
+            "invalid_request_error",
+        );
+    }
+    let max_input_bytes = state.config.max_input_bytes;
+    if code.len() > max_input_bytes {
+        return openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("code too large; max {max_input_bytes} bytes"),
+            "invalid_request_error",
+        );
+    }
+    if let Some(context) = &context
+        && let Err((status, message)) = enforce_tenant_context_limit(tenant.as_deref(), context)
+    {
+        return openai_error_response(status, &message, "invalid_request_error");
+    }
+    let timeout = match request_timeout(&state, &headers) {
+        Ok(timeout) => timeout,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let priority = match request_priority(&headers) {
+        Ok(priority) => priority,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+
+    let context_hash = context
+        .as_ref()
+        .filter(|_| state.session_store.is_some())
+        .map(context_hash);
+    let response = match dispatch_session_request(
+        &state,
+        session_id.clone(),
+        reset.unwrap_or(false),
+        SessionInput {
+            query: String::new(),
+            context,
+            context_id: None,
+            code: Some(code),
+            include_trace: false,
+            overrides: RunOverrides {
+                route: ModelRoute {
+                    api_key: tenant_api_key(tenant.as_deref()),
+                    ..ModelRoute::default()
+                },
+                ..RunOverrides::default()
+            },
+            tenant: tenant.as_ref().map(|tenant| tenant.scope()),
+            timeout,
+            priority,
+            usage_sink: None,
+            audit: run_audit(&state, &session_id, &request_id, tenant.as_deref()),
+        },
+        request_id.clone(),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => return session_error_response(err),
+    };
+    let restored = record_session_run(
+        &state,
+        tenant.as_deref(),
+        &session_id,
+        context_hash.as_deref(),
+    );
+    let session_stats = response.stats;
+    let body = ExecuteCodeResponse {
+        session_id: session_id.clone(),
+        stdout: response.stdout.unwrap_or_default(),
+        stderr: response.stderr.unwrap_or_default(),
+        locals: response.locals.unwrap_or_default(),
+    };
+
+    let mut response = Json(body).into_response();
+    if let Err((status, message)) = set_session_response_headers(&mut response, &session_id) {
+        return openai_error_response(status, &message, "server_error");
+    }
+    set_session_stats_headers(&mut response, &session_stats);
+    if restored {
+        response
+            .headers_mut()
+            .insert(SESSION_RESTORED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+pub(crate) async fn create_context_handler(
+    State(state): State<AppState>,
+    Extension(CurrentTenant(tenant)): Extension<CurrentTenant>,
+    headers: HeaderMap,
+    payload: Result<Json<CreateContextRequest>, JsonRejection>,
+) -> Response {
+    let Json(CreateContextRequest { context }) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return json_rejection_response(rejection),
+    };
+    let bytes = match serde_json::to_vec(&context) {
+        Ok(encoded) => encoded.len(),
+        Err(err) => {
+            return openai_error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid context: {err}"),
+                "invalid_request_error",
+            );
+        }
+    };
+    let max_input_bytes = state.config.max_input_bytes;
+    if bytes > max_input_bytes {
+        return openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("context too large; max {max_input_bytes} bytes"),
+            "invalid_request_error",
+        );
+    }
+    if let Err((status, message)) = enforce_tenant_context_limit(tenant.as_deref(), &context) {
+        return openai_error_response(status, &message, "invalid_request_error");
+    }
+    let client_key = client_key_from_headers(&headers);
+    match state.contexts.insert(&client_key, context, bytes) {
+        Ok(info) => Json(info).into_response(),
+        Err(message) => openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &message,
+            "invalid_request_error",
+        ),
+    }
+}
+
+pub(crate) async fn delete_context_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(context_id): Path<String>,
+) -> Response {
+    let client_key = client_key_from_headers(&headers);
+    match state.contexts.remove(&client_key, &context_id) {
+        Some(info) => Json(DeletedContextResponse {
+            id: info.id,
+            object: info.object,
+            deleted: true,
+        })
+        .into_response(),
+        None => openai_error_response(
+            StatusCode::NOT_FOUND,
+            &format!("context {context_id} not found"),
+            "invalid_request_error",
+        ),
+    }
+}
+
+pub(crate) async fn get_variable_handler(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(CurrentTenant(tenant)): Extension<CurrentTenant>,
+    Path((session_id, name)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(session_id) = validate_session_id(&session_id) else {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid session id",
+            "invalid_request_error",
+        );
+    };
+    if !is_python_identifier(&name) {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "variable name must be a Python identifier",
+            "invalid_request_error",
+        );
+    }
+
+    let timeout = match request_timeout(&state, &headers) {
+        Ok(timeout) => timeout,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let priority = match request_priority(&headers) {
+        Ok(priority) => priority,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let deadline = Instant::now() + timeout;
+    let (respond_to, response_rx) = oneshot::channel();
+    let request = VariableRequest {
+        session_id: session_id.clone(),
+        tenant: tenant.as_ref().map(|tenant| tenant.scope()),
+        name: name.clone(),
+        request_id: Some(request_id),
+        span: tracing::Span::current(),
+        deadline: Some(deadline),
+        priority,
+        respond_to,
+    };
+    if let Err(err) = state.sessions.try_get_variable(request) {
+        return session_error_response(err);
+    }
+    let value = match await_session_response(deadline, timeout, response_rx).await {
+        Ok(value) => value,
+        Err(err) => return session_error_response(err),
+    };
+
+    let mut response = Json(VariableResponse {
+        session_id: session_id.clone(),
+        name,
+        value,
+    })
+    .into_response();
+    if let Err((status, message)) = set_session_response_headers(&mut response, &session_id) {
+        return openai_error_response(status, &message, "server_error");
+    }
+    response
+}
+
+/// Copies a session's context and locals into a new session id, so
+/// explorations can branch without reloading the context.
+pub(crate) async fn fork_session_handler(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(CurrentTenant(tenant)): Extension<CurrentTenant>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    let Some(source_id) = validate_session_id(&session_id) else {
+        return openai_error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid session id",
+            "invalid_request_error",
+        );
+    };
+    let payload = if body.is_empty() {
+        ForkSessionRequest::default()
+    } else {
+        match serde_json::from_slice::<ForkSessionRequest>(&body) {
+            Ok(payload) => payload,
+            Err(err) => {
+                return openai_error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("invalid request body: {err}"),
+                    "invalid_request_error",
+                );
+            }
+        }
+    };
+    let fork_id = match payload.session_id {
+        Some(fork_id) => match validate_session_id(&fork_id) {
+            Some(fork_id) => fork_id,
+            None => {
+                return openai_error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid session_id",
+                    "invalid_request_error",
+                );
+            }
+        },
+        None => Uuid::new_v4().to_string(),
+    };
+    let timeout = match request_timeout(&state, &headers) {
+        Ok(timeout) => timeout,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let priority = match request_priority(&headers) {
+        Ok(priority) => priority,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let scope = tenant.as_ref().map(|tenant| tenant.scope());
+    // The fork lives on this replica, so it has to own the new id too.
+    if let Some(registry) = &state.registry {
+        match registry
+            .claim(&tenant_session_key(scope.as_ref(), fork_id.clone()))
+            .await
+        {
+            Ok(Ownership::Local) => {}
+            Ok(Ownership::Remote(_)) => {
+                return openai_error_response(
+                    StatusCode::CONFLICT,
+                    "session already exists",
+                    "invalid_request_error",
+                );
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "session registry lookup failed");
+                return openai_error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "session registry unavailable",
+                    "server_error",
+                );
+            }
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let (respond_to, response_rx) = oneshot::channel();
+    let request = SessionRequest {
+        session_id: fork_id.clone(),
+        tenant: scope,
+        reset: false,
+        query: String::new(),
+        context: None,
+        context_id: None,
+        code: None,
+        include_trace: false,
+        overrides: RunOverrides::default(),
+        request_id: Some(request_id),
+        span: tracing::Span::current(),
+        deadline: Some(deadline),
+        priority,
+        fork_from: Some(source_id.clone()),
+        usage_sink: None,
+        audit: None,
+        respond_to,
+    };
+    if let Err(err) = state.sessions.try_dispatch(request) {
+        return session_error_response(err);
+    }
+    if let Err(err) = await_session_response(deadline, timeout, response_rx).await {
+        return session_error_response(err);
+    }
+
+    let mut response = Json(ForkSessionResponse {
+        session_id: fork_id.clone(),
+        forked_from: source_id,
+    })
+    .into_response();
+    if let Err((status, message)) = set_session_response_headers(&mut response, &fork_id) {
+        return openai_error_response(status, &message, "server_error");
+    }
+    response
+}
+
+struct SessionInput {
+    query: String,
+    context: Option<Value>,
+    context_id: Option<String>,
+    code: Option<String>,
+    include_trace: bool,
+    overrides: RunOverrides,
+    tenant: Option<TenantScope>,
+    timeout: Duration,
+    priority: Priority,
+    usage_sink: Option<UsageSink>,
+    audit: Option<RunAudit>,
+}
+
+async fn dispatch_session_request(
+    state: &AppState,
+    session_id: String,
+    reset: bool,
+    input: SessionInput,
+    request_id: String,
+) -> Result<SessionResponse, SessionError> {
+    let deadline = Instant::now() + input.timeout;
+    let (respond_to, response_rx) = oneshot::channel();
+    state.sessions.try_dispatch(SessionRequest {
+        session_id,
+        tenant: input.tenant,
+        reset,
+        query: input.query,
+        context: input.context,
+        context_id: input.context_id,
+        code: input.code,
+        include_trace: input.include_trace,
+        overrides: input.overrides,
+        request_id: Some(request_id),
+        span: tracing::Span::current(),
+        deadline: Some(deadline),
+        priority: input.priority,
+        fork_from: None,
+        usage_sink: input.usage_sink,
+        audit: input.audit,
+        respond_to,
+    })?;
+    await_session_response(deadline, input.timeout, response_rx).await
+}
+
+async fn await_session_response<T>(
+    deadline: Instant,
+    timeout: Duration,
+    response_rx: oneshot::Receiver<Result<T, SessionError>>,
+) -> Result<T, SessionError> {
+    match tokio::time::timeout_at(deadline.into(), response_rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err(SessionError::internal("session response channel closed")),
+        Err(_) => Err(SessionError::timeout(format!(
+            "request timed out after {timeout:?}"
+        ))),
+    }
+}
+
+/// The server's request timeout, shortened by an `x-rlm-deadline-ms` budget.
+pub(crate) fn request_timeout(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<Duration, (StatusCode, String)> {
+    let Some(value) = headers.get(DEADLINE_HEADER) else {
+        return Ok(state.config.request_timeout);
+    };
+    let budget = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|ms| *ms > 0)
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("invalid {DEADLINE_HEADER} header"),
+            )
+        })?;
+    Ok(Duration::from_millis(budget).min(state.config.request_timeout))
+}
+
+/// Scheduling priority from `x-rlm-priority`; `normal` when absent.
+fn request_priority(headers: &HeaderMap) -> Result<Priority, (StatusCode, String)> {
+    let Some(value) = headers.get(PRIORITY_HEADER) else {
+        return Ok(Priority::default());
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(Priority::parse)
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("invalid {PRIORITY_HEADER} header; expected low, normal or high"),
+            )
+        })
+}
+
+fn is_python_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= MAX_VARIABLE_NAME_LEN
+        && chars
+            .next()
+            .is_some_and(|first| first == '_' || first.is_ascii_alphabetic())
+        && chars.all(|ch| ch == '_' || ch.is_ascii_alphanumeric())
+}
+
+/// Returns whether the session predates this process, i.e. its sandbox state
+/// was lost in a restart.
+fn record_session_run(
+    state: &AppState,
+    tenant: Option<&Tenant>,
+    session_id: &str,
+    context_hash: Option<&str>,
+) -> bool {
+    let Some(store) = &state.session_store else {
+        return false;
+    };
+    let tenant = tenant.map(|tenant| tenant.name.as_str());
+    match store.record_run(tenant, session_id, context_hash) {
+        Ok(record) => record.restored,
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to record session metadata");
+            false
+        }
+    }
+}
+
+/// Tokens to bill for a run. Estimates only stand in when some call's backend
+/// did not report usage.
+fn charged_tokens(reported: CompletionUsage, prompt_tokens: u64, content: &str) -> TokenUsage {
+    if reported.unreported_calls == 0 && reported.prompt_tokens + reported.completion_tokens > 0 {
+        TokenUsage {
+            prompt_tokens: reported.prompt_tokens,
+            completion_tokens: reported.completion_tokens,
+        }
+    } else {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens: estimate_tokens(content),
+        }
+    }
+}
+
+/// Audits the run when the audit log is on; the session writes the entries.
+fn run_audit(
+    state: &AppState,
+    session_id: &str,
+    request_id: &str,
+    tenant: Option<&Tenant>,
+) -> Option<RunAudit> {
+    state.audit.clone().map(|log| RunAudit {
+        log,
+        session_id: session_id.to_owned(),
+        request_id: request_id.to_owned(),
+        tenant: tenant.map(|tenant| tenant.name.clone()),
+    })
+}
+
+fn enforce_tenant_context_limit(
+    tenant: Option<&Tenant>,
+    context: &Value,
+) -> Result<(), (StatusCode, String)> {
+    let Some((name, max_bytes)) =
+        tenant.and_then(|tenant| Some((&tenant.name, tenant.max_context_bytes?)))
+    else {
+        return Ok(());
+    };
+    let bytes = serde_json::to_vec(context).map_err(internal_error)?.len();
+    if bytes > max_bytes {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("context too large for tenant {name}; max {max_bytes} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+fn tenant_api_key(tenant: Option<&Tenant>) -> Option<String> {
+    tenant.and_then(|tenant| tenant.upstream_api_key.clone())
+}
+
+fn internal_error<E: std::fmt::Display>(err: E) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+fn session_error_response(err: SessionError) -> Response {
+    match err.kind {
+        SessionErrorKind::Overloaded => openai_error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &err.message,
+            "rate_limit_error",
+        ),
+        SessionErrorKind::NotFound => {
+            openai_error_response(StatusCode::NOT_FOUND, &err.message, "invalid_request_error")
+        }
+        SessionErrorKind::Conflict => {
+            openai_error_response(StatusCode::CONFLICT, &err.message, "invalid_request_error")
+        }
+        SessionErrorKind::Timeout => {
+            openai_error_response(StatusCode::GATEWAY_TIMEOUT, &err.message, "timeout_error")
+        }
+        SessionErrorKind::TooLarge => openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &err.message,
+            "invalid_request_error",
+        ),
+        SessionErrorKind::Upstream => {
+            openai_error_response(StatusCode::BAD_GATEWAY, &err.message, "server_error")
+        }
+        // Nobody is left to read this; the status is for logs and metrics.
+        SessionErrorKind::Cancelled => openai_error_response_with_code(
+            StatusCode::from_u16(CLIENT_CLOSED_REQUEST).expect("valid status code"),
+            &err.message,
+            "invalid_request_error",
+            "cancelled",
+        ),
+        SessionErrorKind::Internal => openai_error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &err.message,
+            "server_error",
+        ),
+    }
+}
+
+fn extract_cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    for header_value in headers.get_all(header::COOKIE).iter() {
+        let cookie_str = match header_value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for pair in cookie_str.split(';') {
+            let mut parts = pair.trim().splitn(2, '=');
+            let key = parts.next()?.trim();
+            let value = parts.next().unwrap_or("").trim();
+            if key == name && !value.is_empty() {
+                return Some(value.to_owned());
+            }
+        }
+    }
+    None
+}
+
+fn session_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    let value = extract_cookie_value(headers, "rlm_session")?;
+    validate_session_id(&value)
+}
+
+pub(crate) fn session_id_from_transport(
+    headers: &HeaderMap,
+) -> Result<Option<String>, (StatusCode, String)> {
+    if let Some(value) = headers.get("x-rlm-session-id") {
+        let raw = header_str(value, "x-rlm-session-id")?;
+        if let Some(validated) = validate_session_id(raw) {
+            return Ok(Some(validated));
+        }
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "invalid x-rlm-session-id header".to_owned(),
+        ));
+    }
+    Ok(session_id_from_headers(headers))
+}
+
+fn context_id_from_request(
+    body_value: Option<String>,
+    headers: &HeaderMap,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let raw = match body_value {
+        Some(value) => value,
+        None => match headers.get(CONTEXT_ID_HEADER) {
+            Some(value) => header_str(value, CONTEXT_ID_HEADER)?.to_owned(),
+            None => return Ok(None),
+        },
+    };
+    validate_context_id(&raw)
+        .map(|context_id| Some(context_id.to_owned()))
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "invalid context_id".to_owned()))
+}
+
+fn max_iterations_from_request(
+    body_value: Option<usize>,
+    headers: &HeaderMap,
+    limit: usize,
+) -> Result<Option<usize>, (StatusCode, String)> {
+    let max_iterations = match body_value {
+        Some(value) => value,
+        None => match headers.get("x-rlm-max-iterations") {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        "invalid x-rlm-max-iterations header".to_owned(),
+                    )
+                })?,
+            None => return Ok(None),
+        },
+    };
+    if max_iterations == 0 || max_iterations > limit {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("rlm_max_iterations must be between 1 and {limit}"),
+        ));
+    }
+    Ok(Some(max_iterations))
+}
+
+fn set_session_response_headers(
+    response: &mut Response,
+    session_id: &str,
+) -> Result<(), (StatusCode, String)> {
+    let session_header = HeaderValue::from_str(session_id).map_err(internal_error)?;
+    response
+        .headers_mut()
+        .insert("x-rlm-session-id", session_header);
+    let cookie_value = format!("rlm_session={session_id}; Path=/; HttpOnly; SameSite=Lax");
+    let header_value = HeaderValue::from_str(&cookie_value).map_err(internal_error)?;
+    response
+        .headers_mut()
+        .insert(header::SET_COOKIE, header_value);
+    Ok(())
+}
+
+fn set_session_stats_headers(response: &mut Response, stats: &SessionStats) {
+    let headers = response.headers_mut();
+    headers.insert(SESSION_RUNS_HEADER, HeaderValue::from(stats.runs));
+    headers.insert(
+        SESSION_REPL_MS_HEADER,
+        HeaderValue::from(stats.repl_time_ms),
+    );
+    headers.insert(
+        SESSION_SUBCALLS_HEADER,
+        HeaderValue::from(stats.llm_subcalls + stats.rlm_subcalls),
+    );
+}
+
+/// A header's value as text. Anything but visible ASCII is the client's
+/// mistake, not ours.
+fn header_str<'a>(value: &'a HeaderValue, name: &str) -> Result<&'a str, (StatusCode, String)> {
+    value.to_str().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("{name} header must be visible ASCII"),
+        )
+    })
+}
+
+fn header_bool(headers: &HeaderMap, name: &str) -> Result<bool, (StatusCode, String)> {
+    let Some(value) = headers.get(name) else {
+        return Ok(false);
+    };
+    let value = header_str(value, name)?.trim();
+    if value.eq_ignore_ascii_case("1")
+        || value.eq_ignore_ascii_case("true")
+        || value.eq_ignore_ascii_case("yes")
+        || value.eq_ignore_ascii_case("on")
+    {
+        return Ok(true);
+    }
+    if value.eq_ignore_ascii_case("0")
+        || value.eq_ignore_ascii_case("false")
+        || value.eq_ignore_ascii_case("no")
+        || value.eq_ignore_ascii_case("off")
+    {
+        return Ok(false);
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        format!("invalid boolean header {name}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_runs_are_client_closed_requests() {
+        let response = session_error_response(SessionError::cancelled("sandbox run cancelled"));
+        assert_eq!(response.status().as_u16(), CLIENT_CLOSED_REQUEST);
+        let response = session_error_response(SessionError::internal("worker crashed"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn assert_bad_header<T: std::fmt::Debug>(result: Result<T, (StatusCode, String)>, name: &str) {
+        let (status, message) = result.expect_err("rejected");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, format!("{name} header must be visible ASCII"));
+    }
+
+    #[test]
+    fn non_ascii_headers_are_bad_requests() {
+        let value = HeaderValue::from_bytes("café".as_bytes()).expect("opaque header value");
+        let mut headers = HeaderMap::new();
+        for name in ["x-rlm-session-id", CONTEXT_ID_HEADER, "x-rlm-reset"] {
+            headers.insert(name, value.clone());
+        }
+        assert_bad_header(session_id_from_transport(&headers), "x-rlm-session-id");
+        assert_bad_header(context_id_from_request(None, &headers), CONTEXT_ID_HEADER);
+        assert_bad_header(header_bool(&headers, "x-rlm-reset"), "x-rlm-reset");
+
+        // A context_id body field takes precedence over the header.
+        let context_id = format!("ctx_{}", "a".repeat(32));
+        assert_eq!(
+            context_id_from_request(Some(context_id.clone()), &headers),
+            Ok(Some(context_id))
+        );
+    }
+}
@@ -1,46 +1,495 @@
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::client::SandboxClient;
-use crate::{SandboxHandle, SandboxLaunchConfig, SandboxLauncher};
+use rlm::redact::Redaction;
+use rlm::repl::ReplSnapshot;
+use rlm::trace::ProgressEvent;
+use uuid::Uuid;
 
-pub fn build_launcher(config: SandboxLaunchConfig) -> Box<dyn SandboxLauncher> {
-    Box::new(DockerRunscLauncher { config })
+use crate::client::{ContextDir, SandboxClient};
+use crate::protocol::{SandboxRunRequest, SandboxRunResult};
+use crate::remote::RemoteLauncher;
+use crate::telemetry::{
+    LOG_FORMAT_ENV, LOG_LEVEL_ENV, OTLP_ENDPOINT_ENV, RUNTIME_METRICS_INTERVAL_ENV,
+};
+use crate::{
+    LLM_BROKER_ENV, LaunchError, MEMORY_LIMIT_ENV, PARENT_TIMEOUT_ENV, SandboxError, SandboxHandle,
+    SandboxLaunchConfig, SandboxLauncher, SandboxResources, SandboxSecurity, SandboxUsage,
+    WORKER_SOCKET_DIR_ENV, worker_socket_path,
+};
+
+const INSTANCE_LABEL: &str = "rlm.instance";
+/// How often a launch checks whether a socket worker is listening yet.
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Where containerized workers see their context directory.
+const SANDBOX_CONTEXT_DIR: &str = "/rlm-context";
+/// Host paths the worker needs inside a bubblewrap sandbox: shared libraries,
+/// DNS and TLS roots. Missing ones are skipped.
+const BWRAP_READONLY_PATHS: [&str; 11] = [
+    "/usr",
+    "/bin",
+    "/lib",
+    "/lib64",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/ssl",
+    "/etc/ca-certificates",
+    "/etc/pki",
+    "/etc/localtime",
+];
+
+/// Builds a launcher for the server's launch config.
+pub type LauncherFactory =
+    dyn Fn(SandboxLaunchConfig) -> Result<Box<dyn SandboxLauncher>, String> + Send + Sync;
+
+/// Sandbox backends by the name `--sandbox-backend` selects them with.
+/// Downstream crates can `register` their own `SandboxLauncher`s next to the
+/// built-in ones.
+pub struct LauncherRegistry {
+    factories: BTreeMap<String, Box<LauncherFactory>>,
+}
+
+impl LauncherRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// `docker`: a container per worker through the configured container CLI
+    /// and runtime (gVisor by default).
+    /// `podman`: the same, driven through podman.
+    /// `bwrap`: a bubblewrap namespace jail per worker; starts far faster
+    /// than a container, but shares the host kernel without gVisor in
+    /// between.
+    /// `process`: the worker binary as a plain child process, for
+    /// development hosts without Docker; no isolation beyond `process_user`.
+    /// `remote`: workers started by `sandbox_daemon` on other machines,
+    /// reached over TLS.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register("docker", |config| Ok(ContainerLauncher::boxed(config)))
+            .register("podman", |mut config| {
+                config.container_cli = "podman".to_owned();
+                Ok(ContainerLauncher::boxed(config))
+            })
+            .register("bwrap", |config| Ok(Box::new(BwrapLauncher { config })))
+            .register("process", |config| Ok(Box::new(ProcessLauncher { config })))
+            .register("remote", |config| {
+                Ok(Box::new(RemoteLauncher::new(config)?))
+            });
+        registry
+    }
+
+    /// Replaces any backend already registered under `name`.
+    pub fn register(
+        &mut self,
+        name: &str,
+        factory: impl Fn(SandboxLaunchConfig) -> Result<Box<dyn SandboxLauncher>, String>
+        + Send
+        + Sync
+        + 'static,
+    ) -> &mut Self {
+        self.factories
+            .insert(name.trim().to_ascii_lowercase(), Box::new(factory));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        config: SandboxLaunchConfig,
+    ) -> Result<Box<dyn SandboxLauncher>, String> {
+        match self.factories.get(&name.trim().to_ascii_lowercase()) {
+            Some(factory) => factory(config),
+            None => Err(format!(
+                "unknown sandbox backend {name}; expected one of {}",
+                self.names().collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
 }
 
-struct DockerRunscLauncher {
+impl Default for LauncherRegistry {
+    fn default() -> Self {
+        Self::with_builtins()
+    }
+}
+
+struct ContainerLauncher {
     config: SandboxLaunchConfig,
+    instance: String,
+}
+
+impl ContainerLauncher {
+    fn boxed(config: SandboxLaunchConfig) -> Box<dyn SandboxLauncher> {
+        Box::new(Self {
+            config,
+            instance: Uuid::new_v4().simple().to_string(),
+        })
+    }
 }
 
-impl SandboxLauncher for DockerRunscLauncher {
-    fn launch(&self) -> Result<Box<dyn SandboxHandle>, String> {
-        let worker_bin = resolve_worker_bin()?;
-        let worker_mount = format!("{}:/sandbox_worker:ro", worker_bin.display());
-        let mut command = Command::new("docker");
+impl SandboxLauncher for ContainerLauncher {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        let deadline = Instant::now() + self.config.launch_timeout;
+        let container = format!("rlm-sandbox-{}", Uuid::new_v4().simple());
+        let mut command = Command::new(&self.config.container_cli);
         command
             .arg("run")
             .arg("--rm")
             .arg("-i")
-            .arg("--runtime=runsc")
-            .arg("-v")
-            .arg(worker_mount);
-        apply_worker_env_args(&mut command, &self.config);
+            .arg("--name")
+            .arg(&container)
+            .arg("--label")
+            .arg(format!("{INSTANCE_LABEL}={}", self.instance));
+        let worker = match &self.config.image_worker {
+            Some(worker) => worker.as_str(),
+            None => {
+                let worker_bin = resolve_worker_bin().map_err(LaunchError::Permanent)?;
+                command
+                    .arg("-v")
+                    .arg(format!("{}:/sandbox_worker:ro", worker_bin.display()));
+                "/sandbox_worker"
+            }
+        };
+        let context_dir = context_dir(&self.config)?.map(|dir| dir.mounted_at(SANDBOX_CONTEXT_DIR));
+        if let Some(dir) = &context_dir {
+            command.arg("-v").arg(format!(
+                "{}:{SANDBOX_CONTEXT_DIR}:ro",
+                dir.host_path().display()
+            ));
+        }
+        if let Some(runtime) = &self.config.container_runtime {
+            command.arg(format!("--runtime={runtime}"));
+        }
+        if self.config.llm_broker.is_some() {
+            command.arg("--network=none");
+        }
+        apply_resource_args(&mut command, &self.config.resources);
+        apply_security_args(&mut command, &self.config.security);
+        if self.config.security.read_only_rootfs {
+            apply_read_only_args(&mut command, &self.config.resources);
+        }
+        for (name, value) in worker_env(&self.config) {
+            command.arg("-e").arg(format!("{name}={value}"));
+        }
         command
-            .arg("rust:latest")
-            .arg("/sandbox_worker")
+            .arg(&self.config.image)
+            .arg(worker)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit());
+            .stderr(Stdio::piped());
         let child = command
             .spawn()
-            .map_err(|err| format!("failed to spawn sandbox docker container: {err}"))?;
-        let mut client = SandboxClient::new(child)?;
-        client.ping()?;
+            .map_err(|err| spawn_error("sandbox container", err))?;
+        let mut client = SandboxClient::new(child, self.config.io_timeouts)
+            .map_err(LaunchError::Transient)?
+            .with_container(self.config.container_cli.clone(), container)
+            .with_llm_broker(self.config.llm_broker.clone())
+            .with_context_dir(context_dir);
+        client
+            .ping(Some(deadline))
+            .and_then(|()| client.negotiate_framing(self.config.framing, Some(deadline)))
+            .map_err(|err| LaunchError::Transient(format!("sandbox did not start: {err}")))?;
         Ok(Box::new(client))
     }
+
+    fn shutdown(&self) {
+        let output = match Command::new(&self.config.container_cli)
+            .arg("ps")
+            .arg("-q")
+            .arg("--filter")
+            .arg(format!("label={INSTANCE_LABEL}={}", self.instance))
+            .stderr(Stdio::null())
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to list sandbox containers");
+                return;
+            }
+        };
+        let ids = String::from_utf8_lossy(&output.stdout);
+        let ids: Vec<&str> = ids.split_whitespace().collect();
+        if ids.is_empty() {
+            return;
+        }
+        let _ = Command::new(&self.config.container_cli)
+            .arg("kill")
+            .args(&ids)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+struct ProcessLauncher {
+    config: SandboxLaunchConfig,
+}
+
+impl SandboxLauncher for ProcessLauncher {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        let deadline = Instant::now() + self.config.launch_timeout;
+        let worker_bin = resolve_worker_bin().map_err(LaunchError::Permanent)?;
+        let context_dir = context_dir(&self.config)?;
+        let mut command = Command::new(worker_bin);
+        command
+            .env_clear()
+            .envs(worker_env(&self.config))
+            .current_dir(env::temp_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            // Keeps terminal signals aimed at the server away from workers.
+            command.process_group(0);
+            if let Some(user) = self.config.process_user {
+                command.gid(user.gid).uid(user.uid);
+            }
+            if let Some(dir) = &self.config.socket_dir {
+                command.env(WORKER_SOCKET_DIR_ENV, dir);
+                return start_socket_worker(command, &self.config, dir, context_dir, deadline);
+            }
+        }
+        start_worker(
+            command,
+            &self.config,
+            context_dir,
+            "sandbox worker process",
+            deadline,
+        )
+    }
+
+    /// Connects to each worker socket left in the socket directory, wiping
+    /// the worker's state. Sockets nobody answers on are removed.
+    #[cfg(unix)]
+    fn reconnect(&self) -> Vec<Box<dyn SandboxHandle>> {
+        use std::os::unix::net::UnixStream;
+
+        let Some(dir) = &self.config.socket_dir else {
+            return Vec::new();
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut handles = Vec::new();
+        for entry in entries.flatten() {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("worker-")?.strip_suffix(".sock"))
+                .and_then(|pid| pid.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let path = entry.path();
+            let socket = match UnixStream::connect(&path) {
+                Ok(socket) => socket,
+                Err(_) => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            let deadline = Instant::now() + self.config.launch_timeout;
+            let adopted = SandboxClient::socket(None, pid, socket, path, self.config.io_timeouts)
+                .and_then(|client| {
+                    let mut client = client
+                        .with_llm_broker(self.config.llm_broker.clone())
+                        .with_context_dir(
+                            context_dir(&self.config).map_err(|err| err.to_string())?,
+                        );
+                    client
+                        .ping(Some(deadline))
+                        .and_then(|()| {
+                            client.negotiate_framing(self.config.framing, Some(deadline))
+                        })
+                        .and_then(|()| client.reset(Some(deadline)))
+                        .map_err(|err| err.to_string())?;
+                    Ok(client)
+                });
+            match adopted {
+                Ok(client) => handles.push(Box::new(client) as Box<dyn SandboxHandle>),
+                Err(err) => {
+                    tracing::warn!(pid, error = %err, "failed to reconnect to sandbox worker");
+                }
+            }
+        }
+        handles
+    }
+}
+
+/// Starts a worker that listens on a socket under `dir`, and connects to it
+/// once it does.
+#[cfg(unix)]
+fn start_socket_worker(
+    mut command: Command,
+    config: &SandboxLaunchConfig,
+    dir: &Path,
+    context_dir: Option<ContextDir>,
+    deadline: Instant,
+) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+    use std::os::unix::net::UnixStream;
+
+    fs::create_dir_all(dir).map_err(|err| {
+        LaunchError::Permanent(format!(
+            "failed to create socket directory {}: {err}",
+            dir.display()
+        ))
+    })?;
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| spawn_error("sandbox worker process", err))?;
+    let pid = child.id();
+    let path = worker_socket_path(dir, pid);
+    let socket = loop {
+        if let Ok(socket) = UnixStream::connect(&path) {
+            break socket;
+        }
+        let exited = matches!(child.try_wait(), Ok(Some(_)));
+        if exited || Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = fs::remove_file(&path);
+            return Err(LaunchError::Transient(format!(
+                "sandbox worker did not listen on {}",
+                path.display()
+            )));
+        }
+        thread::sleep(SOCKET_POLL_INTERVAL);
+    };
+    let mut client = SandboxClient::socket(Some(child), pid, socket, path, config.io_timeouts)
+        .map_err(LaunchError::Transient)?
+        .with_llm_broker(config.llm_broker.clone())
+        .with_context_dir(context_dir);
+    client
+        .ping(Some(deadline))
+        .and_then(|()| client.negotiate_framing(config.framing, Some(deadline)))
+        .map_err(|err| LaunchError::Transient(format!("sandbox did not start: {err}")))?;
+    Ok(Box::new(client))
+}
+
+/// Runs the worker in fresh user, PID, IPC, UTS and cgroup namespaces with
+/// a read-only view of the host's system directories and a private `/tmp`.
+/// The network namespace is shared so the worker can reach the LLM API,
+/// unless LLM calls are brokered through the host.
+struct BwrapLauncher {
+    config: SandboxLaunchConfig,
+}
+
+impl SandboxLauncher for BwrapLauncher {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        let deadline = Instant::now() + self.config.launch_timeout;
+        let worker_bin = resolve_worker_bin().map_err(LaunchError::Permanent)?;
+        let mut command = Command::new("bwrap");
+        command
+            .arg("--die-with-parent")
+            .arg("--new-session")
+            .arg("--unshare-all")
+            .arg("--hostname")
+            .arg("rlm-sandbox");
+        if self.config.llm_broker.is_none() {
+            command.arg("--share-net");
+        }
+        for path in BWRAP_READONLY_PATHS {
+            command.arg("--ro-bind-try").arg(path).arg(path);
+        }
+        let context_dir = context_dir(&self.config)?.map(|dir| dir.mounted_at(SANDBOX_CONTEXT_DIR));
+        if let Some(dir) = &context_dir {
+            command
+                .arg("--ro-bind")
+                .arg(dir.host_path())
+                .arg(SANDBOX_CONTEXT_DIR);
+        }
+        command
+            .arg("--ro-bind")
+            .arg(worker_bin)
+            .arg("/sandbox_worker")
+            .arg("--dev")
+            .arg("/dev")
+            .arg("--proc")
+            .arg("/proc")
+            .arg("--tmpfs")
+            .arg("/tmp")
+            .arg("--chdir")
+            .arg("/tmp")
+            .arg("/sandbox_worker")
+            .env_clear()
+            .envs(worker_env(&self.config));
+        // Only for finding `bwrap` itself; the worker gets it too, harmlessly.
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        start_worker(
+            command,
+            &self.config,
+            context_dir,
+            "bubblewrap sandbox",
+            deadline,
+        )
+    }
 }
 
-fn resolve_worker_bin() -> Result<std::path::PathBuf, String> {
+fn start_worker(
+    mut command: Command,
+    config: &SandboxLaunchConfig,
+    context_dir: Option<ContextDir>,
+    what: &str,
+    deadline: Instant,
+) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+    let child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| spawn_error(what, err))?;
+    let mut client = SandboxClient::new(child, config.io_timeouts)
+        .map_err(LaunchError::Transient)?
+        .with_llm_broker(config.llm_broker.clone())
+        .with_context_dir(context_dir);
+    client
+        .ping(Some(deadline))
+        .and_then(|()| client.negotiate_framing(config.framing, Some(deadline)))
+        .map_err(|err| LaunchError::Transient(format!("sandbox did not start: {err}")))?;
+    Ok(Box::new(client))
+}
+
+fn context_dir(config: &SandboxLaunchConfig) -> Result<Option<ContextDir>, LaunchError> {
+    config
+        .context_file_min_bytes
+        .map(ContextDir::create)
+        .transpose()
+        .map_err(LaunchError::Transient)
+}
+
+/// Missing binaries and denied permissions fail the same way on every try.
+fn spawn_error(what: &str, err: io::Error) -> LaunchError {
+    let message = format!("failed to spawn {what}: {err}");
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+            LaunchError::Permanent(message)
+        }
+        _ => LaunchError::Transient(message),
+    }
+}
+
+/// The `sandbox_worker` binary next to the running executable.
+pub fn resolve_worker_bin() -> Result<PathBuf, String> {
     let current =
         env::current_exe().map_err(|err| format!("failed to resolve current executable: {err}"))?;
     let mut worker = current
@@ -61,8 +510,278 @@ fn resolve_worker_bin() -> Result<std::path::PathBuf, String> {
     Ok(worker)
 }
 
-fn apply_worker_env_args(command: &mut Command, config: &SandboxLaunchConfig) {
+fn apply_resource_args(command: &mut Command, resources: &SandboxResources) {
+    if let Some(memory) = &resources.memory {
+        command.arg("--memory").arg(memory);
+    }
+    if let Some(cpus) = resources.cpus {
+        command.arg("--cpus").arg(cpus.to_string());
+    }
+    if let Some(pids_limit) = resources.pids_limit {
+        command.arg("--pids-limit").arg(pids_limit.to_string());
+    }
+    for ulimit in &resources.ulimits {
+        command.arg("--ulimit").arg(ulimit);
+    }
+}
+
+fn apply_security_args(command: &mut Command, security: &SandboxSecurity) {
+    if let Some(profile) = &security.seccomp_profile {
+        command
+            .arg("--security-opt")
+            .arg(format!("seccomp={}", profile.display()));
+    }
+    if security.drop_all_capabilities {
+        command.arg("--cap-drop=ALL");
+    }
+    if security.no_new_privileges {
+        command.arg("--security-opt").arg("no-new-privileges");
+    }
+    if let Some(user) = &security.user {
+        command.arg("--user").arg(user);
+    }
+}
+
+fn apply_read_only_args(command: &mut Command, resources: &SandboxResources) {
+    let mut scratch = "/tmp:rw,nosuid,nodev".to_owned();
+    if let Some(size) = &resources.scratch_size {
+        scratch.push_str(",size=");
+        scratch.push_str(size);
+    }
     command
+        .arg("--read-only")
+        .arg("--tmpfs")
+        .arg(scratch)
+        .arg("--workdir")
+        .arg("/tmp")
         .arg("-e")
-        .arg(format!("OPENAI_API_KEY={}", config.worker.api_key));
+        .arg("TMPDIR=/tmp");
+}
+
+pub(crate) fn worker_env(config: &SandboxLaunchConfig) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        (LOG_LEVEL_ENV, config.worker.log_level.clone()),
+        (LOG_FORMAT_ENV, config.worker.log_format.to_string()),
+    ];
+    if config.llm_broker.is_some() {
+        vars.push((LLM_BROKER_ENV, "1".to_owned()));
+    } else {
+        vars.push(("OPENAI_API_KEY", config.worker.api_key.clone()));
+    }
+    if let Some(timeout) = config.parent_timeout {
+        vars.push((PARENT_TIMEOUT_ENV, timeout.as_secs().max(1).to_string()));
+    }
+    if let Some(limit) = config.memory_limit_bytes {
+        vars.push((MEMORY_LIMIT_ENV, limit.to_string()));
+    }
+    let worker = &config.worker;
+    vars.extend([
+        ("RLM_BASE_URL", worker.base_url.clone()),
+        ("RLM_MODEL", worker.model.clone()),
+        ("RLM_RECURSIVE_MODEL", worker.recursive_model.clone()),
+        ("RLM_MAX_ITERATIONS", worker.max_iterations.to_string()),
+        ("RLM_DEPTH", worker.depth.to_string()),
+        (
+            "RLM_DISABLE_RECURSIVE",
+            worker.disable_recursive.to_string(),
+        ),
+    ]);
+    if let Some(max_completion_tokens) = worker.max_completion_tokens {
+        vars.push((
+            "RLM_MAX_COMPLETION_TOKENS",
+            max_completion_tokens.to_string(),
+        ));
+    }
+    if let Some(model) = &worker.fallback_model {
+        vars.push(("RLM_FALLBACK_MODEL", model.clone()));
+    }
+    if let Some(base_url) = &worker.fallback_base_url {
+        vars.push(("RLM_FALLBACK_BASE_URL", base_url.clone()));
+    }
+    for name in [RUNTIME_METRICS_INTERVAL_ENV, OTLP_ENDPOINT_ENV] {
+        if let Ok(value) = env::var(name) {
+            vars.push((name, value));
+        }
+    }
+    vars
+}
+
+/// Serves several sessions from each worker `inner` launches, up to
+/// `repls_per_sandbox`, by opening a REPL per sandbox it hands out instead
+/// of a worker.
+pub struct SharedLauncher {
+    inner: Box<dyn SandboxLauncher>,
+    repls_per_sandbox: usize,
+    sandboxes: Mutex<Vec<SharedSandbox>>,
+}
+
+/// A worker's default REPL, which is kept for opening the others.
+struct SharedSandbox {
+    handle: Box<dyn SandboxHandle>,
+    /// Cloned into each REPL handed out, and dropped with it.
+    leases: Arc<()>,
+}
+
+impl SharedSandbox {
+    fn repls(&self) -> usize {
+        Arc::strong_count(&self.leases) - 1
+    }
+
+    fn open(&mut self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        let handle = self
+            .handle
+            .share()
+            .map_err(|err| LaunchError::Transient(format!("failed to open a repl: {err}")))?;
+        Ok(Box::new(LeasedRepl {
+            handle,
+            _lease: self.leases.clone(),
+        }))
+    }
+}
+
+impl SharedLauncher {
+    pub fn new(inner: Box<dyn SandboxLauncher>, repls_per_sandbox: usize) -> Self {
+        Self {
+            inner,
+            repls_per_sandbox,
+            sandboxes: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl SandboxLauncher for SharedLauncher {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        // Held while starting a worker too, so concurrent launches fill it
+        // rather than each starting their own.
+        let mut sandboxes = self
+            .sandboxes
+            .lock()
+            .expect("shared sandboxes lock poisoned");
+        sandboxes.retain_mut(|sandbox| !sandbox.handle.exited());
+        // Keeps one worker whose sessions all ended warm for the next.
+        let mut spare = false;
+        sandboxes.retain_mut(|sandbox| {
+            if sandbox.repls() > 0 || !std::mem::replace(&mut spare, true) {
+                return true;
+            }
+            sandbox.handle.terminate();
+            false
+        });
+        if let Some(index) = sandboxes
+            .iter()
+            .position(|sandbox| sandbox.repls() < self.repls_per_sandbox)
+        {
+            match sandboxes[index].open() {
+                Ok(repl) => return Ok(repl),
+                Err(err) => {
+                    tracing::warn!(
+                        sandbox = %sandboxes[index].handle.identifier(),
+                        error = %err,
+                        "shared sandbox failed; replacing"
+                    );
+                    sandboxes.swap_remove(index).handle.terminate();
+                }
+            }
+        }
+        let mut sandbox = SharedSandbox {
+            handle: self.inner.launch()?,
+            leases: Arc::new(()),
+        };
+        let repl = sandbox.open();
+        sandboxes.push(sandbox);
+        repl
+    }
+
+    fn shutdown(&self) {
+        let sandboxes = std::mem::take(
+            &mut *self
+                .sandboxes
+                .lock()
+                .expect("shared sandboxes lock poisoned"),
+        );
+        for mut sandbox in sandboxes {
+            sandbox.handle.terminate();
+        }
+        self.inner.shutdown();
+    }
+}
+
+/// A REPL on a shared worker, counted against it while alive.
+struct LeasedRepl {
+    handle: Box<dyn SandboxHandle>,
+    _lease: Arc<()>,
+}
+
+impl SandboxHandle for LeasedRepl {
+    fn run(
+        &mut self,
+        request: SandboxRunRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<SandboxRunResult, SandboxError> {
+        self.handle.run(request, deadline, cancelled, progress)
+    }
+
+    fn get_variable(
+        &mut self,
+        name: &str,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>, SandboxError> {
+        self.handle.get_variable(name, request_id, deadline)
+    }
+
+    fn snapshot(
+        &mut self,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<ReplSnapshot, SandboxError> {
+        self.handle.snapshot(request_id, deadline)
+    }
+
+    fn restore(
+        &mut self,
+        snapshot: ReplSnapshot,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<(), SandboxError> {
+        self.handle.restore(snapshot, request_id, deadline)
+    }
+
+    fn ping(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError> {
+        self.handle.ping(deadline)
+    }
+
+    fn reset(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError> {
+        self.handle.reset(deadline)
+    }
+
+    fn usage(&self) -> SandboxUsage {
+        self.handle.usage()
+    }
+
+    fn set_session(&mut self, session: Option<&str>) {
+        self.handle.set_session(session);
+    }
+
+    fn set_redaction(&mut self, redaction: Option<Arc<Redaction>>) {
+        self.handle.set_redaction(redaction);
+    }
+
+    fn exited(&mut self) -> bool {
+        self.handle.exited()
+    }
+
+    fn terminate(&mut self) {
+        self.handle.terminate();
+    }
+
+    fn detach(&mut self) {
+        self.handle.detach();
+    }
+
+    fn identifier(&self) -> String {
+        self.handle.identifier()
+    }
 }
@@ -1,27 +1,348 @@
+pub mod audit;
 pub mod client;
+pub mod context_store;
 pub mod launcher;
+pub mod llm_broker;
+pub mod model_routes;
 pub mod pool;
 pub mod protocol;
+pub mod rate_limit;
+pub mod registry;
+pub mod remote;
 pub mod session;
+pub mod session_store;
+pub mod snapshot_store;
+pub mod telemetry;
+pub mod tenants;
+pub mod usage;
 
-use protocol::{SandboxRunRequest, SandboxRunResult};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use llm_broker::HostLlmBroker;
+use protocol::{Framing, SandboxRunRequest, SandboxRunResult, WorkerError, WorkerStats};
+use remote::RemoteWorkers;
+use rlm::redact::Redaction;
+use rlm::repl::ReplSnapshot;
+use rlm::trace::{CompletionUsage, ProgressEvent};
+use telemetry::LogFormat;
+
+/// Set for workers that must send LLM calls through the host.
+pub const LLM_BROKER_ENV: &str = "RLM_LLM_BROKER";
+/// Set for workers that only execute submitted code and never call an LLM,
+/// which makes them a plain sandboxed Python service.
+pub const CODE_ONLY_ENV: &str = "RLM_WORKER_CODE_ONLY";
+/// Seconds an idle worker waits to hear from the server before exiting.
+pub const PARENT_TIMEOUT_ENV: &str = "RLM_WORKER_PARENT_TIMEOUT_SECS";
+/// Resident bytes past which a busy worker gives up; see
+/// [`SandboxLaunchConfig::memory_limit_bytes`].
+pub const MEMORY_LIMIT_ENV: &str = "RLM_WORKER_MEMORY_LIMIT_BYTES";
+/// Directory a worker listens in instead of talking over stdio; see
+/// [`worker_socket_path`].
+pub const WORKER_SOCKET_DIR_ENV: &str = "RLM_WORKER_SOCKET_DIR";
+
+/// The Unix socket the worker with `pid` listens on under `dir`.
+pub fn worker_socket_path(dir: &Path, pid: u32) -> PathBuf {
+    dir.join(format!("worker-{pid}.sock"))
+}
 
 #[derive(Debug, Clone)]
 pub struct SandboxWorkerConfig {
     pub api_key: String,
+    /// Defaults for runs whose requests do not override them.
+    pub base_url: String,
+    pub model: String,
+    pub recursive_model: String,
+    pub max_iterations: usize,
+    pub depth: usize,
+    pub disable_recursive: bool,
+    pub max_completion_tokens: Option<u32>,
+    pub fallback_model: Option<String>,
+    pub fallback_base_url: Option<String>,
+    pub log_level: String,
+    pub log_format: LogFormat,
 }
 
 #[derive(Debug, Clone)]
 pub struct SandboxLaunchConfig {
     pub worker: SandboxWorkerConfig,
+    /// How long a launch may take to answer its first ping.
+    pub launch_timeout: Duration,
+    pub io_timeouts: SandboxIoTimeouts,
+    /// Framing asked of workers once they answer their first ping.
+    pub framing: Framing,
+    /// Contexts whose JSON is at least this many bytes are written to a
+    /// read-only directory shared with the worker instead of being sent
+    /// inline; `None` always sends them inline.
+    pub context_file_min_bytes: Option<usize>,
+    /// Resident memory at which workers fail the request in flight with an
+    /// `oom` error and exit; `None` leaves it to the OOM killer.
+    pub memory_limit_bytes: Option<u64>,
+    /// Idle workers that hear nothing from the server for this long assume
+    /// it is gone and exit; `None` keeps them waiting.
+    pub parent_timeout: Option<Duration>,
+    /// Docker backend only: the Docker-compatible CLI that manages
+    /// containers, e.g. `docker`, `podman` or `nerdctl`.
+    pub container_cli: String,
+    /// Docker backend only: OCI runtime for containers; `None` uses the
+    /// engine's default.
+    pub container_runtime: Option<String>,
+    /// Docker backend only.
+    pub image: String,
+    /// Docker backend only: path of a worker binary baked into `image`,
+    /// which is run instead of mounting the server's own.
+    pub image_worker: Option<String>,
+    /// Docker backend only.
+    pub resources: SandboxResources,
+    /// Docker backend only.
+    pub security: SandboxSecurity,
+    /// Process backend only: run workers as this user instead of the
+    /// server's. Ignored off Unix.
+    pub process_user: Option<ProcessUser>,
+    /// Process backend only: workers listen on Unix sockets in this
+    /// directory instead of using stdio, and idle ones outlive the server so
+    /// the next one started with the same directory takes them over.
+    /// Ignored off Unix.
+    pub socket_dir: Option<PathBuf>,
+    /// Remote backend only.
+    pub remote: RemoteWorkers,
+    /// Route worker LLM calls through the host, which keeps the API key out
+    /// of sandboxes and lets them run without network access.
+    pub llm_broker: Option<Arc<HostLlmBroker>>,
+}
+
+/// Restrictions on what the worker process may do inside its container.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxSecurity {
+    /// JSON seccomp profile; `None` keeps the runtime's default profile.
+    pub seccomp_profile: Option<PathBuf>,
+    pub drop_all_capabilities: bool,
+    pub no_new_privileges: bool,
+    /// `user[:group]` to run the worker as instead of the image's default.
+    pub user: Option<String>,
+    /// Mount the image read-only, leaving a tmpfs `/tmp` as the only
+    /// writable path, so nothing outlives the container.
+    pub read_only_rootfs: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessUser {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FromStr for ProcessUser {
+    type Err = String;
+
+    /// `uid[:gid]`; the gid defaults to the uid.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid process user {value}; expected uid[:gid]");
+        let (uid, gid) = value.trim().split_once(':').unwrap_or((value.trim(), ""));
+        let uid = uid.parse().map_err(|_| invalid())?;
+        let gid = if gid.is_empty() {
+            uid
+        } else {
+            gid.parse().map_err(|_| invalid())?
+        };
+        Ok(Self { uid, gid })
+    }
+}
+
+/// Per-container limits; `None` leaves the runtime's default.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxResources {
+    /// Memory limit in Docker's notation, e.g. `512m`.
+    pub memory: Option<String>,
+    pub cpus: Option<f64>,
+    pub pids_limit: Option<u64>,
+    /// `name=soft[:hard]` entries, e.g. `nofile=1024:1024`.
+    pub ulimits: Vec<String>,
+    /// Size of the `/tmp` tmpfs given to read-only containers, e.g. `64m`.
+    pub scratch_size: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LaunchError {
+    /// May succeed if tried again, e.g. a slow or crashed container.
+    Transient(String),
+    /// Will fail the same way every time, e.g. a missing binary.
+    Permanent(String),
+}
+
+impl LaunchError {
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Permanent(_))
+    }
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transient(message) | Self::Permanent(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Bounds on worker I/O past which a worker counts as wedged and is killed.
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxIoTimeouts {
+    /// Longest a request may take to write, e.g. while the worker is not
+    /// reading its input.
+    pub write: Duration,
+    /// Longest a worker may take to answer a request other than a run or a
+    /// ping, e.g. a snapshot. Runs and pings are bounded by their deadlines.
+    pub read: Duration,
+}
+
+/// Why a request to a sandbox failed.
+#[derive(Debug, Clone)]
+pub enum SandboxError {
+    /// The worker answered with an error, or with something that was not a
+    /// valid answer; the sandbox is still usable unless the code is `Oom`.
+    Worker(WorkerError),
+    /// The worker stopped reading or answering, or went away; the sandbox
+    /// was killed and the request may be retried on another one.
+    Unresponsive(String),
+    /// The request's deadline passed; the run was cancelled, or the sandbox
+    /// killed if it did not stop. Carries the worker's answer to the
+    /// cancellation, if any.
+    TimedOut(Option<WorkerError>),
+    /// The caller went away; handled like `TimedOut`.
+    Cancelled(Option<WorkerError>),
+}
+
+impl SandboxError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Unresponsive(_))
+    }
+
+    /// The worker's own account of the failed request, when it gave one.
+    pub fn report(&self) -> Option<&WorkerError> {
+        match self {
+            Self::Worker(err) => Some(err),
+            Self::TimedOut(report) | Self::Cancelled(report) => report.as_ref(),
+            Self::Unresponsive(_) => None,
+        }
+    }
+
+    /// What the failed run spent, when the worker got to say.
+    pub fn usage(&self) -> Option<CompletionUsage> {
+        self.report().and_then(|report| report.usage)
+    }
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Worker(err) => err.fmt(f),
+            Self::Unresponsive(message) => f.write_str(message),
+            Self::TimedOut(_) => f.write_str("sandbox worker timed out"),
+            Self::Cancelled(_) => f.write_str("sandbox run cancelled"),
+        }
+    }
+}
+
+/// Wear on one sandbox since it was launched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SandboxUsage {
+    pub runs: u64,
+    /// Bytes of run requests sent, which is mostly context.
+    pub context_bytes: u64,
+    /// What the worker reported about itself after its last run.
+    pub worker: WorkerStats,
 }
 
 pub trait SandboxHandle: Send {
-    fn run(&mut self, request: SandboxRunRequest) -> Result<SandboxRunResult, String>;
+    /// `cancelled` is polled while the run is in flight; once it returns true
+    /// the run is abandoned and the sandbox is killed. `progress` sees the
+    /// worker's progress events as they arrive.
+    fn run(
+        &mut self,
+        request: SandboxRunRequest,
+        deadline: Option<Instant>,
+        cancelled: &dyn Fn() -> bool,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<SandboxRunResult, SandboxError>;
+    fn get_variable(
+        &mut self,
+        name: &str,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>, SandboxError>;
+    fn snapshot(
+        &mut self,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<ReplSnapshot, SandboxError>;
+    /// Replaces the sandbox's REPL state with `snapshot`.
+    fn restore(
+        &mut self,
+        snapshot: ReplSnapshot,
+        request_id: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<(), SandboxError>;
+    fn ping(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError>;
+    /// Wipes REPL state so the sandbox can be handed to another session.
+    fn reset(&mut self, deadline: Option<Instant>) -> Result<(), SandboxError>;
+    fn usage(&self) -> SandboxUsage {
+        SandboxUsage::default()
+    }
+    /// Tags the worker output forwarded to the server's logs with the
+    /// session the sandbox now serves. Output that arrives after a run keeps
+    /// the run's session until the next one.
+    fn set_session(&mut self, _session: Option<&str>) {}
+    /// Placeholder map of the session the sandbox now serves; brokered LLM
+    /// calls are redacted with it before they leave the host.
+    fn set_redaction(&mut self, _redaction: Option<Arc<Redaction>>) {}
+    /// Whether the sandbox process is known to have exited.
+    fn exited(&mut self) -> bool {
+        false
+    }
     fn terminate(&mut self);
+    /// Lets go of a sandbox that can outlive the server, leaving it for the
+    /// next one to reconnect to; others are terminated.
+    fn detach(&mut self) {
+        self.terminate();
+    }
+    /// Opens another handle on the same sandbox with a REPL of its own,
+    /// which runs alongside this one's; terminating it drops only that REPL.
+    fn share(&mut self) -> Result<Box<dyn SandboxHandle>, SandboxError> {
+        Err(SandboxError::Worker(WorkerError::internal(
+            "this sandbox hosts a single REPL",
+        )))
+    }
     fn identifier(&self) -> String;
 }
 
-pub trait SandboxLauncher: Send {
-    fn launch(&self) -> Result<Box<dyn SandboxHandle>, String>;
+pub trait SandboxLauncher: Send + Sync {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError>;
+
+    /// Sandboxes a previous server detached that this launcher can take
+    /// over; the pool counts them towards its idle target.
+    fn reconnect(&self) -> Vec<Box<dyn SandboxHandle>> {
+        Vec::new()
+    }
+
+    fn shutdown(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_user_parses() {
+        let user: ProcessUser = " 1000 ".parse().expect("uid");
+        assert_eq!((user.uid, user.gid), (1000, 1000));
+        let user: ProcessUser = "1000:2000".parse().expect("uid:gid");
+        assert_eq!((user.uid, user.gid), (1000, 2000));
+        let user: ProcessUser = "1000:".parse().expect("empty gid");
+        assert_eq!((user.uid, user.gid), (1000, 1000));
+        for invalid in ["", "root", "1000:wheel", "-1", "1:2:3"] {
+            assert!(invalid.parse::<ProcessUser>().is_err(), "{invalid}");
+        }
+    }
 }
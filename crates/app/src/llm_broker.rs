@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rlm::llm::{Completion, HttpLlmClientFactory, LlmClient, LlmClientFactory, LlmError, Message};
+use rlm::redact::Redaction;
+use tokio::sync::oneshot;
+use tracing::Instrument;
+
+use crate::protocol::{LlmOutcome, LlmRequest, LlmResult, SandboxRunRequest, WorkerResponse};
+
+const BROKER_THREADS: usize = 2;
+
+/// API key, base URL and model.
+type ClientKey = (String, String, String);
+
+type SendResponse = dyn Fn(&WorkerResponse) -> Result<(), String> + Send + Sync;
+
+/// Worker side of LLM brokering: every client it builds forwards its calls
+/// to the host as `llm_request` messages and waits for the matching
+/// `llm_result`.
+#[derive(Clone)]
+pub struct WorkerLlmBroker {
+    state: Arc<WorkerBrokerState>,
+}
+
+struct WorkerBrokerState {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<LlmOutcome>>>,
+    send: Box<SendResponse>,
+}
+
+impl WorkerLlmBroker {
+    /// `send` writes one message to the host.
+    pub fn new(
+        send: impl Fn(&WorkerResponse) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            state: Arc::new(WorkerBrokerState {
+                next_id: AtomicU64::new(0),
+                pending: Mutex::new(HashMap::new()),
+                send: Box::new(send),
+            }),
+        }
+    }
+
+    /// Hands a result to the call waiting for it; results for calls that
+    /// already gave up are dropped.
+    pub fn resolve(&self, result: LlmResult) {
+        let sender = self
+            .state
+            .pending
+            .lock()
+            .expect("llm broker lock poisoned")
+            .remove(&result.id);
+        if let Some(sender) = sender {
+            let _ = sender.send(result.outcome);
+        }
+    }
+
+    /// Fails every waiting call, e.g. once the host has gone away.
+    pub fn close(&self) {
+        self.state
+            .pending
+            .lock()
+            .expect("llm broker lock poisoned")
+            .clear();
+    }
+}
+
+impl LlmClientFactory for WorkerLlmBroker {
+    fn client(
+        &self,
+        model: &str,
+        _api_key: Option<&str>,
+        base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError> {
+        Ok(Arc::new(BrokeredLlmClient {
+            state: self.state.clone(),
+            model: model.to_owned(),
+            base_url: base_url.to_owned(),
+        }))
+    }
+}
+
+struct BrokeredLlmClient {
+    state: Arc<WorkerBrokerState>,
+    model: String,
+    base_url: String,
+}
+
+/// Forgets a call whose future was dropped, e.g. at the run deadline.
+struct PendingCall<'a> {
+    state: &'a WorkerBrokerState,
+    id: u64,
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        self.state
+            .pending
+            .lock()
+            .expect("llm broker lock poisoned")
+            .remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl LlmClient for BrokeredLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.state
+            .pending
+            .lock()
+            .expect("llm broker lock poisoned")
+            .insert(id, sender);
+        let _pending = PendingCall {
+            state: &self.state,
+            id,
+        };
+        let request = WorkerResponse::LlmRequest(LlmRequest {
+            id,
+            model: self.model.clone(),
+            base_url: self.base_url.clone(),
+            messages: messages.to_vec(),
+            max_completion_tokens,
+        });
+        (self.state.send)(&request).map_err(|message| LlmError::Remote {
+            message,
+            upstream: false,
+        })?;
+        match receiver.await {
+            Ok(LlmOutcome::Completed {
+                content,
+                finish_reason,
+                usage,
+            }) => Ok(Completion {
+                content,
+                finish_reason,
+                usage,
+            }),
+            Ok(LlmOutcome::Failed { message, upstream }) => {
+                Err(LlmError::Remote { message, upstream })
+            }
+            Err(_) => Err(LlmError::Remote {
+                message: "llm broker closed".to_owned(),
+                upstream: false,
+            }),
+        }
+    }
+}
+
+/// Host side of LLM brokering: makes the calls brokered workers ask for with
+/// the host's credentials, and only to the endpoints their run was routed to,
+/// so a compromised worker cannot send a key anywhere else.
+pub struct HostLlmBroker {
+    /// Kept apart from the server's runtime, which starts after the pool.
+    runtime: Option<tokio::runtime::Runtime>,
+    handle: tokio::runtime::Handle,
+    api_key: String,
+    base_url: String,
+    clients: Mutex<HashMap<ClientKey, Arc<dyn LlmClient>>>,
+}
+
+impl fmt::Debug for HostLlmBroker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostLlmBroker")
+            .field("base_url", &self.base_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for HostLlmBroker {
+    fn drop(&mut self) {
+        // A plain drop blocks, which panics if the last reference goes away
+        // inside the server's runtime.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Credentials and endpoints a run may use through the broker.
+#[derive(Debug, Clone)]
+pub struct BrokerRoute {
+    api_key: Option<String>,
+    base_urls: Vec<String>,
+    /// The session's placeholder map, applied to every message before it
+    /// goes upstream. Replies are not restored: they go back into the
+    /// sandbox.
+    redaction: Option<Arc<Redaction>>,
+}
+
+impl HostLlmBroker {
+    /// `api_key` and `base_url` apply to runs without a route of their own.
+    pub fn new(api_key: String, base_url: String) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(BROKER_THREADS)
+            .thread_name("llm-broker")
+            .enable_all()
+            .build()
+            .map_err(|err| format!("failed to start llm broker runtime: {err}"))?;
+        Ok(Self {
+            handle: runtime.handle().clone(),
+            runtime: Some(runtime),
+            api_key,
+            base_url,
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes the route's API key out of `request` so it never reaches the
+    /// worker, and returns what the run may use.
+    pub fn route_for(
+        &self,
+        request: &mut SandboxRunRequest,
+        redaction: Option<Arc<Redaction>>,
+    ) -> BrokerRoute {
+        let route = &mut request.overrides.route;
+        let mut base_urls = vec![self.base_url.clone()];
+        base_urls.extend(route.base_url.clone());
+        base_urls.extend(route.fallback_base_url.clone());
+        BrokerRoute {
+            api_key: route.api_key.take(),
+            base_urls,
+            redaction,
+        }
+    }
+
+    pub fn default_route(&self) -> BrokerRoute {
+        BrokerRoute {
+            api_key: None,
+            base_urls: vec![self.base_url.clone()],
+            redaction: None,
+        }
+    }
+
+    /// Makes the call in the background and passes the result to `reply`.
+    pub fn handle(
+        &self,
+        mut request: LlmRequest,
+        route: &BrokerRoute,
+        reply: impl FnOnce(LlmResult) + Send + 'static,
+    ) {
+        if let Some(redaction) = &route.redaction {
+            for message in &mut request.messages {
+                message.content = redaction.redact_text(&message.content);
+            }
+        }
+        let client = self.client(&request, route);
+        let span = tracing::info_span!("brokered_llm_call", model = %request.model);
+        self.handle.spawn(
+            async move {
+                let outcome = match client {
+                    Ok(client) => match client
+                        .completion_with_finish_reason(
+                            &request.messages,
+                            request.max_completion_tokens,
+                        )
+                        .await
+                    {
+                        Ok(completion) => LlmOutcome::Completed {
+                            content: completion.content,
+                            finish_reason: completion.finish_reason,
+                            usage: completion.usage,
+                        },
+                        Err(err) => LlmOutcome::Failed {
+                            upstream: err.is_upstream_failure(),
+                            message: err.to_string(),
+                        },
+                    },
+                    Err(message) => {
+                        tracing::warn!(error = %message, "rejected brokered llm call");
+                        LlmOutcome::Failed {
+                            message,
+                            upstream: false,
+                        }
+                    }
+                };
+                reply(LlmResult {
+                    id: request.id,
+                    outcome,
+                });
+            }
+            .instrument(span),
+        );
+    }
+
+    fn client(
+        &self,
+        request: &LlmRequest,
+        route: &BrokerRoute,
+    ) -> Result<Arc<dyn LlmClient>, String> {
+        if !route.base_urls.contains(&request.base_url) {
+            return Err(format!(
+                "llm endpoint {} is not allowed for this run",
+                request.base_url
+            ));
+        }
+        let api_key = route.api_key.as_deref().unwrap_or(&self.api_key);
+        let key = (
+            api_key.to_owned(),
+            request.base_url.clone(),
+            request.model.clone(),
+        );
+        let mut clients = self.clients.lock().expect("llm broker lock poisoned");
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = HttpLlmClientFactory
+            .client(&request.model, Some(api_key), &request.base_url)
+            .map_err(|err| err.to_string())?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
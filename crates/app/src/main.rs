@@ -1,30 +1,69 @@
-use std::borrow::Cow;
-use std::env;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+mod cli;
+mod handlers;
+mod middleware;
+mod openai;
 
-use app::launcher::build_launcher;
+use std::env;
+use std::future::IntoFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+
+use app::audit::AuditLog;
+use app::context_store::ContextStore;
+use app::launcher::{LauncherRegistry, SharedLauncher};
+use app::llm_broker::HostLlmBroker;
+use app::model_routes::ModelRoutes;
+use app::pool::{PoolAutoscale, PoolConfig, RecyclePolicy};
+use app::protocol::Framing;
+use app::rate_limit::{KeyedRateLimiter, RateLimitConfig};
+use app::registry::{SessionOwner, SessionRegistry};
+use app::remote::RemoteWorkers;
 use app::session::{
-    SessionConfig, SessionError, SessionErrorKind, SessionManagerHandle, SessionRequest,
-    spawn_session_manager,
+    EvictionPolicyKind, SessionConfig, SessionManagerHandle, spawn_session_manager,
+};
+use app::session_store::SessionStore;
+use app::snapshot_store::SnapshotStore;
+use app::telemetry::{LogFormat, init_tracing, monitor_runtime, runtime_metrics_interval};
+use app::tenants::Tenants;
+use app::usage::{UsageConfig, UsageTracker};
+use app::{
+    ProcessUser, SandboxIoTimeouts, SandboxLaunchConfig, SandboxResources, SandboxSecurity,
+    SandboxWorkerConfig,
 };
-use app::{SandboxLaunchConfig, SandboxWorkerConfig};
-use axum::Json;
 use axum::Router;
-use axum::extract::{DefaultBodyLimit, Request, State};
-use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
-use axum::middleware::{self, Next};
-use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
-use rlm::prompts::DEFAULT_QUERY;
-use serde::{Deserialize, Serialize};
+use axum::extract::DefaultBodyLimit;
+use axum::middleware::{from_fn, from_fn_with_state};
+use axum::routing::{delete, get, post};
+use clap::Parser;
+use rlm::redact::{PatternRedactor, RedactionConfig, Redactor};
+use rlm::rlm::{ModelRoute, RlmConfig};
 use serde_json::Value;
-use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tower::ServiceBuilder;
 use tower::limit::ConcurrencyLimitLayer;
 use tower_http::compression::CompressionLayer;
-use tower_http::timeout::TimeoutLayer;
+use tower_http::decompression::RequestDecompressionLayer;
 use uuid::Uuid;
 
+use crate::cli::{Cli, Command};
+use crate::handlers::{
+    admin_pool_handler, admin_session_stats_handler, admin_sessions_handler, admin_usage_handler,
+    create_context_handler, delete_context_handler, execute_code_handler, fork_session_handler,
+    get_variable_handler, healthcheck, openai_chat_completions_handler, readiness_handler,
+};
+use crate::middleware::{
+    authenticate_tenant, enforce_rate_limit, log_request_response, reject_oversized_body,
+    reject_unsupported_encoding, require_admin, route_to_session_owner, shed_load,
+};
+use crate::openai::{method_not_allowed, route_not_found};
+
+/// Health checks a worker may miss before it gives up on the server.
+const SANDBOX_HEARTBEAT_MISSES: u32 = 3;
+const SANDBOX_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[cfg(feature = "mimalloc")]
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
@@ -32,33 +71,104 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 #[derive(Clone)]
 struct AppConfig {
     api_key: String,
-    model: String,
+    redaction: Option<Arc<dyn Redactor>>,
+    host: String,
+    port: u16,
+    models: ModelRoutes,
     max_sessions: usize,
     max_inflight: usize,
     ingress_capacity: usize,
-    sandbox_pool_size: usize,
+    max_pending_per_session: usize,
+    shed_inflight: usize,
+    shed_queue_depth: usize,
+    shed_retry_after: Duration,
+    pool: PoolConfig,
+    sandbox_launch_timeout: Duration,
+    sandbox_io_timeouts: SandboxIoTimeouts,
+    sandbox_framing: Framing,
+    sandbox_context_file_min_bytes: Option<usize>,
+    sandbox_worker_memory_limit_bytes: Option<u64>,
+    sandbox_backend: String,
+    sandbox_repls_per_worker: usize,
+    sandbox_container_cli: String,
+    sandbox_container_runtime: Option<String>,
+    sandbox_image: String,
+    sandbox_image_worker: Option<String>,
+    sandbox_resources: SandboxResources,
+    sandbox_security: SandboxSecurity,
+    sandbox_process_user: Option<ProcessUser>,
+    sandbox_socket_dir: Option<PathBuf>,
+    sandbox_remote: RemoteWorkers,
+    sandbox_llm_broker: Option<Arc<HostLlmBroker>>,
+    request_timeout: Duration,
+    drain_timeout: Duration,
+    session_idle_ttl: Option<Duration>,
+    eviction_policy: EvictionPolicyKind,
+    session_admission_wait: Duration,
+    log_level: String,
+    log_format: LogFormat,
+    rate_limit: RateLimitConfig,
+    usage: UsageConfig,
+    admin_token: Option<String>,
+    llm_base_url: String,
+    max_input_bytes: usize,
+    max_body_bytes: usize,
+    max_decompressed_bytes: usize,
+    context_store_max_bytes: usize,
+    session_snapshot_max_bytes: usize,
+    max_iterations_limit: usize,
+    recursive_model_allowlist: Vec<String>,
+    recursive_model: String,
+    max_iterations: usize,
+    depth: usize,
+    disable_recursive: bool,
+    max_completion_tokens: Option<u32>,
 }
 
-const DEFAULT_MAX_SESSIONS: usize = 256;
-const DEFAULT_MAX_INFLIGHT: usize = 128;
-const DEFAULT_INGRESS_CAPACITY: usize = 2048;
-const DEFAULT_SANDBOX_POOL_SIZE: usize = 8;
-const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 1800;
-
-const MAX_SESSION_ID_LEN: usize = 64;
-const OPENAI_MAX_INPUT_STRING_BYTES: usize = 10_485_760;
-const MAX_LLM_BODY_LIMIT_BYTES: usize = 11 * 1024 * 1024;
-
 impl AppConfig {
     fn to_worker_config(&self) -> SandboxWorkerConfig {
+        let route = self.models.default_route();
         SandboxWorkerConfig {
             api_key: self.api_key.clone(),
+            base_url: route.base_url.clone().unwrap_or_default(),
+            model: route.model.clone().unwrap_or_default(),
+            recursive_model: route
+                .recursive_model
+                .clone()
+                .unwrap_or_else(|| self.recursive_model.clone()),
+            max_iterations: self.max_iterations,
+            depth: self.depth,
+            disable_recursive: self.disable_recursive,
+            max_completion_tokens: self.max_completion_tokens,
+            fallback_model: route.fallback_model.clone(),
+            fallback_base_url: route.fallback_base_url.clone(),
+            log_level: self.log_level.clone(),
+            log_format: self.log_format,
         }
     }
 
     fn to_launch_config(&self) -> SandboxLaunchConfig {
         SandboxLaunchConfig {
             worker: self.to_worker_config(),
+            launch_timeout: self.sandbox_launch_timeout,
+            io_timeouts: self.sandbox_io_timeouts,
+            framing: self.sandbox_framing,
+            context_file_min_bytes: self.sandbox_context_file_min_bytes,
+            memory_limit_bytes: self.sandbox_worker_memory_limit_bytes,
+            parent_timeout: self
+                .pool
+                .health_interval
+                .map(|interval| interval * SANDBOX_HEARTBEAT_MISSES),
+            container_cli: self.sandbox_container_cli.clone(),
+            container_runtime: self.sandbox_container_runtime.clone(),
+            image: self.sandbox_image.clone(),
+            image_worker: self.sandbox_image_worker.clone(),
+            resources: self.sandbox_resources.clone(),
+            security: self.sandbox_security.clone(),
+            process_user: self.sandbox_process_user,
+            socket_dir: self.sandbox_socket_dir.clone(),
+            remote: self.sandbox_remote.clone(),
+            llm_broker: self.sandbox_llm_broker.clone(),
         }
     }
 }
@@ -66,449 +176,475 @@ impl AppConfig {
 #[derive(Clone)]
 struct AppState {
     sessions: SessionManagerHandle,
+    rate_limiter: Option<Arc<KeyedRateLimiter>>,
+    usage: Arc<UsageTracker>,
+    contexts: Arc<ContextStore>,
+    tenants: Option<Arc<Tenants>>,
+    audit: Option<Arc<AuditLog>>,
+    inflight_chats: Arc<AtomicUsize>,
+    registry: Option<Arc<SessionRegistry>>,
+    session_store: Option<Arc<SessionStore>>,
+    http: reqwest::Client,
     config: AppConfig,
 }
 
-#[derive(Debug, Deserialize)]
-struct OpenAiChatCompletionsRequest {
-    #[serde(default)]
-    messages: Vec<OpenAiChatMessage>,
-    model: Option<String>,
-    stream: Option<bool>,
-    reset: Option<bool>,
-}
-
-#[derive(Debug, Deserialize)]
-struct OpenAiChatMessage {
-    role: String,
-    content: Value,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiChatCompletionsResponse {
-    id: String,
-    object: String,
-    created: u64,
-    model: String,
-    choices: Vec<OpenAiChatChoice>,
-    usage: OpenAiUsage,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiChatChoice {
-    index: usize,
-    message: OpenAiAssistantMessage,
-    finish_reason: String,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiAssistantMessage {
-    role: String,
-    content: String,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiUsage {
-    prompt_tokens: usize,
-    completion_tokens: usize,
-    total_tokens: usize,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiErrorEnvelope {
-    error: OpenAiErrorBody,
-}
-
-#[derive(Debug, Serialize)]
-struct OpenAiErrorBody {
-    message: String,
-    #[serde(rename = "type")]
-    error_type: String,
-    param: Option<String>,
-}
-
-async fn healthcheck() -> Response {
-    let mut response = StatusCode::OK.into_response();
-    response
-        .headers_mut()
-        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
-    response
-}
-
-async fn log_request_response(request: Request, next: Next) -> Response {
-    let method = request.method().clone();
-    let uri = request.uri().clone();
-    let start = Instant::now();
-    println!("request: {method} {uri}");
-    let response = next.run(request).await;
-    println!(
-        "response: {method} {uri} status={} latency_ms={}",
-        response.status(),
-        start.elapsed().as_millis()
-    );
-    response
-}
-
-async fn openai_chat_completions_handler(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(payload): Json<OpenAiChatCompletionsRequest>,
-) -> Response {
-    let OpenAiChatCompletionsRequest {
-        messages,
-        model,
-        stream,
-        reset,
-    } = payload;
-    if stream.unwrap_or(false) {
-        return openai_error_response(
-            StatusCode::BAD_REQUEST,
-            "stream=true unsupported; use stream=false",
-            "invalid_request_error",
+/// Logs session manager and sandbox pool stats alongside the runtime metrics.
+async fn report_session_metrics(sessions: SessionManagerHandle, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let stats = sessions.stats();
+        tracing::info!(
+            active_sessions = stats.active_sessions,
+            idle_sessions = stats.idle_sessions,
+            queue_depth = stats.queue_depth,
+            evictions = stats.evictions,
+            reaped = stats.reaped,
+            rejected = stats.rejected,
+            dispatch_failures = stats.dispatch_failures,
+            "session metrics"
         );
-    }
-    if messages.is_empty() {
-        return openai_error_response(
-            StatusCode::BAD_REQUEST,
-            "messages required",
-            "invalid_request_error",
+        let pool = sessions.pool_stats();
+        tracing::info!(
+            idle = pool.idle,
+            in_use = pool.in_use,
+            launching = pool.launching,
+            resetting = pool.resetting,
+            checking = pool.checking,
+            waiting = pool.waiting,
+            target_idle = pool.target_idle,
+            launched = pool.launched,
+            transient_launch_failures = pool.transient_launch_failures,
+            permanent_launch_failures = pool.permanent_launch_failures,
+            avg_launch_ms = pool.avg_launch_ms,
+            avg_acquire_wait_ms = pool.avg_acquire_wait_ms,
+            "pool metrics"
         );
     }
-    if let Err((status, message)) = validate_openai_input(&messages) {
-        return openai_error_response(status, &message, "invalid_request_error");
-    }
-
-    let model = model.unwrap_or_else(|| state.config.model.clone());
-    if model != state.config.model {
-        return openai_error_response(
-            StatusCode::BAD_REQUEST,
-            &format!(
-                "model override unsupported; expected {}",
-                state.config.model
-            ),
-            "invalid_request_error",
-        );
-    }
-    let session_id = match session_id_from_transport(&headers) {
-        Ok(Some(session_id)) => session_id,
-        Ok(None) => Uuid::new_v4().to_string(),
-        Err((status, message)) => {
-            return openai_error_response(status, &message, "invalid_request_error");
-        }
-    };
-    let reset = match header_bool(&headers, "x-rlm-reset") {
-        Ok(header_reset) => reset.unwrap_or(false) || header_reset,
-        Err((status, message)) => {
-            return openai_error_response(status, &message, "invalid_request_error");
-        }
-    };
-    let (query, context) = (
-        openai_query_from_messages(&messages),
-        Some(openai_context_from_messages(messages)),
-    );
-
-    let (respond_to, response_rx) = oneshot::channel();
-    if let Err(err) = state.sessions.try_dispatch(SessionRequest {
-        session_id: session_id.clone(),
-        reset,
-        query,
-        context,
-        code: None,
-        respond_to,
-    }) {
-        return session_error_response(err);
-    }
-    let response = match response_rx.await {
-        Ok(Ok(response)) => response,
-        Ok(Err(err)) => return session_error_response(err),
-        Err(_) => {
-            return openai_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "session response channel closed",
-                "server_error",
-            );
-        }
-    };
-    let content = match response.response {
-        Some(content) => content,
-        None => {
-            return openai_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "missing assistant response",
-                "server_error",
-            );
-        }
-    };
-
-    let created = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_or(0, |duration| duration.as_secs());
-    let body = OpenAiChatCompletionsResponse {
-        id: format!("chatcmpl-{}", Uuid::new_v4().simple()),
-        object: "chat.completion".to_owned(),
-        created,
-        model,
-        choices: vec![OpenAiChatChoice {
-            index: 0,
-            message: OpenAiAssistantMessage {
-                role: "assistant".to_owned(),
-                content,
-            },
-            finish_reason: "stop".to_owned(),
-        }],
-        usage: OpenAiUsage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        },
-    };
-
-    let mut response = Json(body).into_response();
-    if let Err((status, message)) = set_session_response_headers(&mut response, &session_id) {
-        return openai_error_response(status, &message, "server_error");
-    }
-    response
 }
 
-fn internal_error<E: std::fmt::Display>(err: E) -> (StatusCode, String) {
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+/// The redactor sessions apply on the host; workers never see the config.
+fn load_redaction_config(path: &std::path::Path) -> Result<Arc<dyn Redactor>, String> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let config: RedactionConfig = serde_json::from_str(&raw)
+        .map_err(|err| format!("invalid redaction config in {}: {err}", path.display()))?;
+    let redactor = PatternRedactor::new(&config)
+        .map_err(|err| format!("invalid redaction pattern in {}: {err}", path.display()))?;
+    Ok(Arc::new(redactor))
 }
 
-fn session_error_response(err: SessionError) -> Response {
-    match err.kind {
-        SessionErrorKind::Overloaded => openai_error_response(
-            StatusCode::SERVICE_UNAVAILABLE,
-            &err.message,
-            "server_error",
-        ),
-        SessionErrorKind::Internal => openai_error_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &err.message,
-            "server_error",
-        ),
+/// Catches malformed limits at startup; otherwise every launch would fail the
+/// same way and be retried as transient.
+fn validate_sandbox_resources(resources: &SandboxResources) -> Result<(), String> {
+    if let Some(cpus) = resources.cpus
+        && !(cpus.is_finite() && cpus > 0.0)
+    {
+        return Err(format!("sandbox cpus must be positive, got {cpus}"));
     }
-}
-
-fn openai_error_response(status: StatusCode, message: &str, error_type: &str) -> Response {
-    let mut response = Json(OpenAiErrorEnvelope {
-        error: OpenAiErrorBody {
-            message: message.to_owned(),
-            error_type: error_type.to_owned(),
-            param: None,
-        },
-    })
-    .into_response();
-    *response.status_mut() = status;
-    response
-}
-
-fn validate_openai_input(messages: &[OpenAiChatMessage]) -> Result<(), (StatusCode, String)> {
-    for (idx, message) in messages.iter().enumerate() {
-        if message.role.trim().is_empty() {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("messages[{idx}].role required"),
-            ));
-        }
-        let content_len = openai_message_text(message).len();
-        if content_len > OPENAI_MAX_INPUT_STRING_BYTES {
-            return Err((
-                StatusCode::PAYLOAD_TOO_LARGE,
-                format!(
-                    "messages[{idx}].content too large; max {} bytes",
-                    OPENAI_MAX_INPUT_STRING_BYTES
-                ),
-            ));
-        }
+    if resources.pids_limit == Some(0) {
+        return Err("sandbox pids limit must be at least 1".to_owned());
     }
-    Ok(())
-}
-
-fn extract_cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
-    for header_value in headers.get_all(header::COOKIE).iter() {
-        let cookie_str = match header_value.to_str() {
-            Ok(value) => value,
-            Err(_) => continue,
-        };
-        for pair in cookie_str.split(';') {
-            let mut parts = pair.trim().splitn(2, '=');
-            let key = parts.next()?.trim();
-            let value = parts.next().unwrap_or("").trim();
-            if key == name && !value.is_empty() {
-                return Some(value.to_owned());
-            }
+    if let Some(size) = &resources.scratch_size {
+        let amount = size
+            .strip_suffix(|unit: char| "bkmgBKMG".contains(unit))
+            .unwrap_or(size);
+        if !amount.parse::<u64>().is_ok_and(|amount| amount > 0) {
+            return Err(format!(
+                "invalid sandbox scratch size {size}; expected a positive size like 64m"
+            ));
         }
     }
-    None
-}
-
-fn validate_session_id(value: &str) -> Option<String> {
-    let mut value = value.trim();
-    value = value.trim_matches('"');
-    value = value.trim_matches('\'');
-    if value.is_empty() || value.len() > MAX_SESSION_ID_LEN || !value.is_ascii() {
-        return None;
-    }
-    Uuid::parse_str(value).ok()?;
-    Some(value.to_owned())
-}
-
-fn session_id_from_headers(headers: &HeaderMap) -> Option<String> {
-    let value = extract_cookie_value(headers, "rlm_session")?;
-    validate_session_id(&value)
-}
-
-fn session_id_from_transport(headers: &HeaderMap) -> Result<Option<String>, (StatusCode, String)> {
-    if let Some(value) = headers.get("x-rlm-session-id") {
-        let raw = value.to_str().map_err(internal_error)?;
-        if let Some(validated) = validate_session_id(raw) {
-            return Ok(Some(validated));
+    for ulimit in &resources.ulimits {
+        let valid = ulimit.split_once('=').is_some_and(|(name, limits)| {
+            !name.is_empty()
+                && limits
+                    .split(':')
+                    .all(|limit| limit == "-1" || limit.parse::<u64>().is_ok())
+                && limits.split(':').count() <= 2
+        });
+        if !valid {
+            return Err(format!(
+                "invalid sandbox ulimit {ulimit}; expected name=soft[:hard]"
+            ));
         }
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "invalid x-rlm-session-id header".to_owned(),
-        ));
     }
-    Ok(session_id_from_headers(headers))
-}
-
-fn set_session_response_headers(
-    response: &mut Response,
-    session_id: &str,
-) -> Result<(), (StatusCode, String)> {
-    let session_header = HeaderValue::from_str(session_id).map_err(internal_error)?;
-    response
-        .headers_mut()
-        .insert("x-rlm-session-id", session_header);
-    let cookie_value = format!("rlm_session={session_id}; Path=/; HttpOnly; SameSite=Lax");
-    let header_value = HeaderValue::from_str(&cookie_value).map_err(internal_error)?;
-    response
-        .headers_mut()
-        .insert(header::SET_COOKIE, header_value);
     Ok(())
 }
 
-fn header_bool(headers: &HeaderMap, name: &str) -> Result<bool, (StatusCode, String)> {
-    let Some(value) = headers.get(name) else {
-        return Ok(false);
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenvy::dotenv().ok();
+    let cli = Cli::parse();
+    let args = match cli.command {
+        Some(Command::Serve(args)) => args,
+        None => cli.serve,
     };
-    let value = value.to_str().map_err(internal_error)?.trim();
-    if value.eq_ignore_ascii_case("1")
-        || value.eq_ignore_ascii_case("true")
-        || value.eq_ignore_ascii_case("yes")
-        || value.eq_ignore_ascii_case("on")
-    {
-        return Ok(true);
+    let _tracing = init_tracing("rlm-server", &args.log_level, args.log_format)?;
+    let metrics_interval = runtime_metrics_interval()?;
+    let api_key =
+        env::var("OPENAI_API_KEY").map_err(|_| "OPENAI_API_KEY is required for the RLM server")?;
+    let models = ModelRoutes::load(
+        args.model_routes.as_deref(),
+        &args.model,
+        ModelRoute {
+            base_url: Some(args.llm_base_url.clone()),
+            fallback_model: args.fallback_model,
+            fallback_base_url: args.fallback_base_url,
+            ..ModelRoute::default()
+        },
+    )?;
+    RlmConfig {
+        recursive_model: args.recursive_model.clone(),
+        max_iterations: args.max_iterations,
+        depth: args.depth,
+        disable_recursive: args.disable_recursive,
+        max_completion_tokens: args.max_completion_tokens,
+        ..RlmConfig::default()
     }
-    if value.eq_ignore_ascii_case("0")
-        || value.eq_ignore_ascii_case("false")
-        || value.eq_ignore_ascii_case("no")
-        || value.eq_ignore_ascii_case("off")
-    {
-        return Ok(false);
+    .validate()
+    .map_err(|err| format!("invalid RLM settings: {err}"))?;
+    let redaction = args
+        .redaction_config
+        .as_deref()
+        .map(load_redaction_config)
+        .transpose()?;
+    let sandbox_resources = SandboxResources {
+        memory: args.sandbox_memory.filter(|memory| !memory.is_empty()),
+        cpus: args.sandbox_cpus,
+        pids_limit: args.sandbox_pids_limit,
+        ulimits: args.sandbox_ulimits,
+        scratch_size: Some(args.sandbox_scratch_size).filter(|size| !size.is_empty()),
+    };
+    validate_sandbox_resources(&sandbox_resources)?;
+    if args.sandbox_repls_per_worker == 0 {
+        return Err("sandbox repls per worker must be at least 1".into());
     }
-    Err((
-        StatusCode::BAD_REQUEST,
-        format!("invalid boolean header {name}"),
-    ))
-}
-
-fn openai_message_text(message: &OpenAiChatMessage) -> Cow<'_, str> {
-    match &message.content {
-        Value::String(text) => Cow::Borrowed(text),
-        Value::Null => Cow::Borrowed(""),
-        other => Cow::Owned(other.to_string()),
+    if args.sandbox_container_cli.trim().is_empty() {
+        return Err("sandbox container CLI must not be empty".into());
     }
-}
-
-fn openai_query_from_messages(messages: &[OpenAiChatMessage]) -> String {
-    for message in messages.iter().rev() {
-        if message.role == "user" {
-            let content = openai_message_text(message);
-            if !content.is_empty() {
-                return content.into_owned();
-            }
-        }
+    let sandbox_security = SandboxSecurity {
+        seccomp_profile: args.sandbox_seccomp_profile,
+        drop_all_capabilities: args.sandbox_cap_drop_all,
+        no_new_privileges: args.sandbox_no_new_privileges,
+        user: args.sandbox_user.filter(|user| !user.is_empty()),
+        read_only_rootfs: args.sandbox_read_only,
+    };
+    if let Some(profile) = &sandbox_security.seccomp_profile {
+        let raw = std::fs::read_to_string(profile)
+            .map_err(|err| format!("failed to read {}: {err}", profile.display()))?;
+        serde_json::from_str::<Value>(&raw)
+            .map_err(|err| format!("invalid seccomp profile in {}: {err}", profile.display()))?;
     }
-    messages
-        .last()
-        .map(openai_message_text)
-        .filter(|text| !text.is_empty())
-        .map(Cow::into_owned)
-        .unwrap_or_else(|| DEFAULT_QUERY.to_owned())
-}
-
-fn openai_context_from_messages(messages: Vec<OpenAiChatMessage>) -> Value {
-    Value::Array(
-        messages
-            .into_iter()
-            .map(|message| {
-                let mut object = serde_json::Map::new();
-                object.insert("role".to_owned(), Value::String(message.role));
-                object.insert("content".to_owned(), message.content);
-                Value::Object(object)
-            })
-            .collect(),
-    )
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenvy::dotenv().ok();
-    let api_key =
-        env::var("OPENAI_API_KEY").map_err(|_| "OPENAI_API_KEY is required for the RLM server")?;
+    let sandbox_llm_broker = if args.sandbox_llm_broker {
+        Some(Arc::new(HostLlmBroker::new(
+            api_key.clone(),
+            args.llm_base_url.clone(),
+        )?))
+    } else {
+        None
+    };
     let config = AppConfig {
         api_key,
-        model: "gpt-5".to_owned(),
-        max_sessions: DEFAULT_MAX_SESSIONS,
-        max_inflight: DEFAULT_MAX_INFLIGHT,
-        ingress_capacity: DEFAULT_INGRESS_CAPACITY,
-        sandbox_pool_size: DEFAULT_SANDBOX_POOL_SIZE,
+        redaction,
+        host: args.host,
+        port: args.port,
+        models,
+        max_sessions: args.max_sessions,
+        max_inflight: args.max_inflight,
+        ingress_capacity: args.ingress_capacity,
+        max_pending_per_session: args.max_pending_per_session,
+        shed_inflight: args.shed_inflight.unwrap_or(args.max_inflight),
+        shed_queue_depth: args.shed_queue_depth,
+        shed_retry_after: Duration::from_secs(args.shed_retry_after_secs),
+        pool: PoolConfig {
+            target_idle: args.pool_size,
+            launch_parallelism: args.pool_launch_parallelism,
+            launch_retries: args.sandbox_launch_retries,
+            max_total: args.max_sandboxes,
+            at_limit: args.sandbox_limit_action,
+            health_interval: (args.sandbox_health_interval_secs > 0)
+                .then(|| Duration::from_secs(args.sandbox_health_interval_secs)),
+            reuse: args.sandbox_reuse,
+            recycle: RecyclePolicy {
+                max_runs: args.sandbox_max_runs,
+                max_context_bytes: args.sandbox_max_context_bytes,
+                max_rss_bytes: args.sandbox_max_rss_bytes,
+            },
+            autoscale: (args.pool_max_size > 0).then(|| PoolAutoscale {
+                min_idle: args.pool_min_size,
+                max_idle: args.pool_max_size,
+                interval: Duration::from_secs(args.pool_scale_interval_secs.max(1)),
+            }),
+        },
+        sandbox_launch_timeout: Duration::from_secs(args.sandbox_launch_timeout_secs.max(1)),
+        sandbox_framing: args.sandbox_framing,
+        sandbox_context_file_min_bytes: (args.sandbox_context_file_bytes > 0)
+            .then_some(args.sandbox_context_file_bytes),
+        sandbox_worker_memory_limit_bytes: (args.sandbox_worker_memory_limit_bytes > 0)
+            .then_some(args.sandbox_worker_memory_limit_bytes),
+        sandbox_io_timeouts: SandboxIoTimeouts {
+            write: Duration::from_secs(args.sandbox_write_timeout_secs.max(1)),
+            read: Duration::from_secs(args.sandbox_read_timeout_secs.max(1)),
+        },
+        sandbox_backend: args.sandbox_backend,
+        sandbox_repls_per_worker: args.sandbox_repls_per_worker,
+        sandbox_container_cli: args.sandbox_container_cli,
+        sandbox_container_runtime: Some(args.sandbox_runtime.trim().to_owned())
+            .filter(|runtime| !runtime.is_empty()),
+        sandbox_image: args.sandbox_image,
+        sandbox_image_worker: args
+            .sandbox_image_worker
+            .filter(|worker| !worker.is_empty()),
+        sandbox_resources,
+        sandbox_security,
+        sandbox_process_user: args.sandbox_process_user,
+        sandbox_socket_dir: args.sandbox_socket_dir,
+        sandbox_remote: RemoteWorkers {
+            addrs: args
+                .sandbox_remote_addrs
+                .into_iter()
+                .map(|addr| addr.trim().to_owned())
+                .filter(|addr| !addr.is_empty())
+                .collect(),
+            token: args.sandbox_remote_token,
+            ca_cert: args.sandbox_remote_ca,
+        },
+        sandbox_llm_broker,
+        request_timeout: Duration::from_secs(args.request_timeout_secs),
+        drain_timeout: Duration::from_secs(args.drain_timeout_secs),
+        session_idle_ttl: (args.session_idle_ttl_secs > 0)
+            .then(|| Duration::from_secs(args.session_idle_ttl_secs)),
+        eviction_policy: args.eviction_policy,
+        session_admission_wait: Duration::from_millis(args.session_admission_wait_ms),
+        log_level: args.log_level,
+        log_format: args.log_format,
+        rate_limit: RateLimitConfig {
+            requests_per_minute: args.rate_limit_rpm,
+            max_concurrent: args.rate_limit_concurrency,
+        },
+        usage: UsageConfig {
+            daily_token_budget: args.daily_token_budget,
+            daily_cost_budget_usd: args.daily_cost_budget_usd,
+            prompt_price_per_million: args.prompt_price_per_million,
+            completion_price_per_million: args.completion_price_per_million,
+        },
+        admin_token: args.admin_token.filter(|token| !token.is_empty()),
+        llm_base_url: args.llm_base_url,
+        max_input_bytes: args.max_input_bytes,
+        max_body_bytes: args.max_body_bytes,
+        max_decompressed_bytes: args.max_decompressed_bytes,
+        context_store_max_bytes: args.context_store_max_bytes,
+        session_snapshot_max_bytes: args.session_snapshot_max_bytes,
+        max_iterations_limit: args.max_iterations_limit,
+        recursive_model_allowlist: args
+            .recursive_model_allowlist
+            .into_iter()
+            .map(|model| model.trim().to_owned())
+            .filter(|model| !model.is_empty())
+            .collect(),
+        recursive_model: args.recursive_model,
+        max_iterations: args.max_iterations,
+        depth: args.depth,
+        disable_recursive: args.disable_recursive,
+        max_completion_tokens: args.max_completion_tokens,
     };
 
-    let launcher = build_launcher(config.to_launch_config());
+    let mut launcher = LauncherRegistry::with_builtins()
+        .build(&config.sandbox_backend, config.to_launch_config())?;
+    if config.sandbox_repls_per_worker > 1 {
+        launcher = Box::new(SharedLauncher::new(
+            launcher,
+            config.sandbox_repls_per_worker,
+        ));
+    }
     let sessions = spawn_session_manager(
         SessionConfig {
             max_sessions: config.max_sessions,
             ingress_capacity: config.ingress_capacity,
-            sandbox_pool_size: config.sandbox_pool_size,
+            max_pending_per_session: config.max_pending_per_session,
+            pool: config.pool,
+            idle_ttl: config.session_idle_ttl,
+            eviction: config.eviction_policy,
+            admission_wait: config.session_admission_wait,
         },
         launcher,
+        (config.session_snapshot_max_bytes > 0)
+            .then(|| Arc::new(SnapshotStore::new(config.session_snapshot_max_bytes))),
+        config.redaction.clone(),
     )
     .map_err(|err| format!("failed to initialize session manager: {err}"))?;
-    let state = AppState { sessions, config };
-
-    let host = "0.0.0.0";
-    let port = 3000;
-    let addr = format!("{host}:{port}");
+    let addr = format!("{}:{}", config.host, config.port);
+    let drain_timeout = config.drain_timeout;
+    let rate_limiter = config
+        .rate_limit
+        .is_enabled()
+        .then(|| KeyedRateLimiter::new(config.rate_limit));
+    let usage = Arc::new(UsageTracker::new(config.usage));
+    let contexts = Arc::new(ContextStore::new(config.context_store_max_bytes));
+    let tenants = args
+        .tenants
+        .as_deref()
+        .map(Tenants::load)
+        .transpose()?
+        .map(Arc::new);
+    let audit = args
+        .audit_log_dir
+        .map(|dir| AuditLog::open(dir, args.audit_retention_days))
+        .transpose()?
+        .map(Arc::new);
+    let registry = args
+        .session_registry_url
+        .as_deref()
+        .map(|url| {
+            let owner = SessionOwner {
+                replica: args
+                    .replica_id
+                    .unwrap_or_else(|| Uuid::new_v4().to_string()),
+                url: args.replica_url,
+            };
+            SessionRegistry::open(
+                url,
+                owner,
+                Duration::from_secs(args.session_registry_ttl_secs),
+            )
+        })
+        .transpose()?
+        .map(Arc::new);
+    let session_store = args
+        .session_store
+        .as_deref()
+        .map(|path| SessionStore::open(path, args.session_store_retention_days))
+        .transpose()?
+        .map(Arc::new);
+    if let Some(store) = &session_store {
+        tracing::info!(
+            sessions = store.previous_count(),
+            "loaded session metadata from previous runs"
+        );
+    }
+    let http = reqwest::Client::builder()
+        .timeout(READINESS_TIMEOUT)
+        .build()
+        .map_err(|err| format!("failed to build HTTP client: {err}"))?;
+    let state = AppState {
+        sessions: sessions.clone(),
+        rate_limiter,
+        usage,
+        contexts,
+        tenants,
+        audit,
+        inflight_chats: Arc::new(AtomicUsize::new(0)),
+        registry,
+        session_store,
+        http,
+        config,
+    };
 
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_io()
         .enable_time()
         .build()?;
-    rt.block_on(async move {
-        let chat_timeout = Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS);
+    rt.block_on(monitor_runtime("app", metrics_interval, async move {
+        if let Some(interval) = metrics_interval {
+            tokio::spawn(report_session_metrics(state.sessions.clone(), interval));
+        }
+        let max_body_bytes = state.config.max_body_bytes;
+        let max_decompressed_bytes = state.config.max_decompressed_bytes;
+        let admin = Router::new()
+            .route("/admin/usage", get(admin_usage_handler))
+            .route("/admin/sessions", get(admin_sessions_handler))
+            .route("/admin/sessions/stats", get(admin_session_stats_handler))
+            .route("/admin/pool", get(admin_pool_handler))
+            .route_layer(from_fn_with_state(state.clone(), require_admin));
+        let decompression = ServiceBuilder::new()
+            .layer(DefaultBodyLimit::max(max_decompressed_bytes))
+            .layer(from_fn(reject_unsupported_encoding))
+            .layer(RequestDecompressionLayer::new());
         let app = Router::new()
-            .route("/healthz", get(healthcheck))
             .route(
                 "/v1/chat/completions",
-                post(openai_chat_completions_handler).layer(
-                    ServiceBuilder::new()
-                        .layer(DefaultBodyLimit::max(MAX_LLM_BODY_LIMIT_BYTES))
-                        .layer(TimeoutLayer::with_status_code(
-                            StatusCode::REQUEST_TIMEOUT,
-                            chat_timeout,
-                        )),
-                ),
+                post(openai_chat_completions_handler).layer(decompression.clone()),
+            )
+            .route(
+                "/v1/contexts",
+                post(create_context_handler).layer(decompression),
+            )
+            .route("/v1/contexts/{id}", delete(delete_context_handler))
+            .route(
+                "/v1/sessions/{id}/execute",
+                post(execute_code_handler)
+                    .layer(ServiceBuilder::new().layer(DefaultBodyLimit::max(max_body_bytes))),
             )
+            .route(
+                "/v1/sessions/{id}/variables/{name}",
+                get(get_variable_handler),
+            )
+            .route("/v1/sessions/{id}/fork", post(fork_session_handler))
+            .route_layer(from_fn_with_state(state.clone(), enforce_rate_limit))
+            .route_layer(from_fn_with_state(state.clone(), route_to_session_owner))
+            .route_layer(from_fn_with_state(state.clone(), authenticate_tenant))
+            .route_layer(from_fn_with_state(state.clone(), reject_oversized_body))
+            .merge(admin)
+            .route("/healthz", get(healthcheck))
+            .route("/readyz", get(readiness_handler))
+            .fallback(route_not_found)
+            .method_not_allowed_fallback(method_not_allowed)
             .layer(CompressionLayer::new())
             .layer(ConcurrencyLimitLayer::new(state.config.max_inflight))
-            .layer(middleware::from_fn(log_request_response))
+            .layer(from_fn_with_state(state.clone(), shed_load))
+            .layer(from_fn(log_request_response))
             .with_state(state);
 
         let listener = tokio::net::TcpListener::bind(&addr).await?;
-        println!("listening on {addr}");
-        axum::serve(listener, app).await?;
+        tracing::info!(%addr, "listening");
+        let (draining_tx, draining_rx) = watch::channel(false);
+        let server = axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                let _ = draining_tx.send(true);
+            })
+            .into_future();
+        tokio::select! {
+            result = server => result?,
+            _ = drain_deadline(draining_rx, drain_timeout) => {
+                tracing::warn!("drain deadline exceeded; dropping in-flight requests");
+            }
+        }
         Ok::<(), Box<dyn std::error::Error>>(())
-    })?;
+    }))?;
+
+    tracing::info!("terminating sandboxes");
+    sessions.shutdown(SANDBOX_SHUTDOWN_TIMEOUT)?;
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("shutdown signal received; draining in-flight requests");
+}
+
+async fn drain_deadline(mut draining: watch::Receiver<bool>, timeout: Duration) {
+    if draining.wait_for(|draining| *draining).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+    tokio::time::sleep(timeout).await;
+}
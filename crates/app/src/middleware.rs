@@ -0,0 +1,396 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use app::registry::{Ownership, SessionOwner, SessionRegistry};
+use app::session::{tenant_session_key, validate_session_id};
+use app::tenants::Tenant;
+use app::usage::ANONYMOUS_CLIENT_KEY;
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::handlers::session_id_from_transport;
+use crate::openai::{
+    openai_error_response, openai_error_response_with_code, route_not_found, with_retry_after,
+};
+
+const FORWARDED_BY_HEADER: &str = "x-rlm-forwarded-by";
+const SUPPORTED_CONTENT_ENCODINGS: [&str; 3] = ["identity", "gzip", "zstd"];
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+/// Tenant resolved from the bearer key; `None` when tenants are not configured.
+#[derive(Clone)]
+pub(crate) struct CurrentTenant(pub(crate) Option<Arc<Tenant>>);
+
+pub(crate) async fn log_request_response(mut request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(validate_request_id)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+    let span = tracing::info_span!(
+        "request",
+        method = %method,
+        uri = %uri,
+        request_id = %request_id
+    );
+    let start = Instant::now();
+    let mut response = async {
+        tracing::info!("request started");
+        let response = next.run(request).await;
+        tracing::info!(
+            status = response.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "request finished"
+        );
+        response
+    }
+    .instrument(span)
+    .await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+pub(crate) async fn reject_oversized_body(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let max_body_bytes = state.config.max_body_bytes;
+    if content_length.is_some_and(|len| len > max_body_bytes as u64) {
+        return openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("request body too large; max {max_body_bytes} bytes"),
+            "invalid_request_error",
+        );
+    }
+    next.run(request).await
+}
+
+pub(crate) async fn reject_unsupported_encoding(request: Request, next: Next) -> Response {
+    let encoding = request
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|value| {
+            value
+                .to_str()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+    if let Some(encoding) = encoding
+        && !SUPPORTED_CONTENT_ENCODINGS.contains(&encoding.as_str())
+    {
+        return openai_error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            &format!("content-encoding {encoding:?} unsupported; use gzip or zstd"),
+            "invalid_request_error",
+        );
+    }
+    next.run(request).await
+}
+
+pub(crate) async fn authenticate_tenant(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let tenant = match &state.tenants {
+        Some(tenants) => match tenants.authenticate(&client_key_from_headers(request.headers())) {
+            Some(tenant) => Some(tenant),
+            None => {
+                return openai_error_response_with_code(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid api key",
+                    "invalid_request_error",
+                    "invalid_api_key",
+                );
+            }
+        },
+        None => None,
+    };
+    request.extensions_mut().insert(CurrentTenant(tenant));
+    next.run(request).await
+}
+
+pub(crate) async fn enforce_rate_limit(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    let key = client_key_from_headers(request.headers());
+    let _permit = match limiter.try_acquire(&key) {
+        Ok(permit) => permit,
+        Err(limited) => {
+            tracing::warn!(
+                retry_after_secs = limited.retry_after.as_secs(),
+                "rate limited"
+            );
+            return with_retry_after(
+                openai_error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &limited.message,
+                    "rate_limit_error",
+                ),
+                limited.retry_after,
+            );
+        }
+    };
+    next.run(request).await
+}
+
+/// Rejects chat requests before they queue behind the concurrency limit or the
+/// session manager's ingress channel.
+pub(crate) async fn shed_load(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.uri().path() != "/v1/chat/completions" {
+        return next.run(request).await;
+    }
+    let config = &state.config;
+    let inflight = state.inflight_chats.fetch_add(1, Ordering::Relaxed);
+    let _inflight = InflightGuard(&state.inflight_chats);
+    let queue_depth = state.sessions.queue_depth();
+    let reason = if config.shed_inflight > 0 && inflight >= config.shed_inflight {
+        Some("too many chat requests in flight")
+    } else if config.shed_queue_depth > 0 && queue_depth >= config.shed_queue_depth {
+        Some("session queue is backed up")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        tracing::warn!(inflight, queue_depth, reason, "shedding chat request");
+        return with_retry_after(
+            openai_error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                &format!("server overloaded: {reason}; retry later"),
+                "rate_limit_error",
+            ),
+            config.shed_retry_after,
+        );
+    }
+    next.run(request).await
+}
+
+struct InflightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Forwards requests for sessions another replica owns, per the registry.
+pub(crate) async fn route_to_session_owner(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(registry) = state.registry.clone() else {
+        return next.run(request).await;
+    };
+    let session_id = match routed_session_id(&request) {
+        Ok(Some(session_id)) => session_id,
+        Ok(None) => return next.run(request).await,
+        Err((status, message)) => {
+            return openai_error_response(status, &message, "invalid_request_error");
+        }
+    };
+    let tenant = request
+        .extensions()
+        .get::<CurrentTenant>()
+        .and_then(|tenant| tenant.0.as_ref())
+        .map(|tenant| tenant.scope());
+    match registry
+        .claim(&tenant_session_key(tenant.as_ref(), session_id))
+        .await
+    {
+        Ok(Ownership::Local) => next.run(request).await,
+        Ok(Ownership::Remote(owner)) => forward_to_owner(&state, &registry, owner, request).await,
+        Err(err) => {
+            tracing::error!(error = %err, "session registry lookup failed");
+            openai_error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "session registry unavailable",
+                "server_error",
+            )
+        }
+    }
+}
+
+fn routed_session_id(request: &Request) -> Result<Option<String>, (StatusCode, String)> {
+    let path = request.uri().path();
+    if path == "/v1/chat/completions" {
+        return session_id_from_transport(request.headers());
+    }
+    Ok(path
+        .strip_prefix("/v1/sessions/")
+        .and_then(|rest| rest.split('/').next())
+        .and_then(validate_session_id))
+}
+
+async fn forward_to_owner(
+    state: &AppState,
+    registry: &SessionRegistry,
+    owner: SessionOwner,
+    request: Request,
+) -> Response {
+    // A forwarded request that lands on a non-owner means the registry moved
+    // underneath it; reject rather than bounce between replicas.
+    let Some(url) = owner
+        .url
+        .filter(|_| !request.headers().contains_key(FORWARDED_BY_HEADER))
+    else {
+        return openai_error_response(
+            StatusCode::MISDIRECTED_REQUEST,
+            &format!("session is served by replica {}", owner.replica),
+            "invalid_request_error",
+        );
+    };
+    let (parts, body) = request.into_parts();
+    let max_body_bytes = state.config.max_body_bytes;
+    let Ok(body) = axum::body::to_bytes(body, max_body_bytes).await else {
+        return openai_error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("request body too large; max {max_body_bytes} bytes"),
+            "invalid_request_error",
+        );
+    };
+    let target = format!(
+        "{}{}",
+        url.trim_end_matches('/'),
+        parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str())
+    );
+    let method = reqwest::Method::from_bytes(parts.method.as_str().as_bytes())
+        .unwrap_or(reqwest::Method::POST);
+    let mut forward = state
+        .http
+        .request(method, target)
+        .timeout(state.config.request_timeout)
+        .header(FORWARDED_BY_HEADER, registry.replica())
+        .body(body);
+    for (name, value) in &parts.headers {
+        if !matches!(
+            *name,
+            header::HOST | header::CONTENT_LENGTH | header::ACCEPT_ENCODING | header::CONNECTION
+        ) {
+            forward = forward.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let failed = |err: reqwest::Error| {
+        tracing::warn!(replica = %owner.replica, error = %err, "session forwarding failed");
+        openai_error_response(
+            StatusCode::BAD_GATEWAY,
+            &format!("failed to reach replica {}", owner.replica),
+            "server_error",
+        )
+    };
+    let upstream = match forward.send().await {
+        Ok(upstream) => upstream,
+        Err(err) => return failed(err),
+    };
+    let status =
+        StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let headers: Vec<_> = upstream
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                header::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?,
+                HeaderValue::from_bytes(value.as_bytes()).ok()?,
+            ))
+        })
+        .filter(|(name, _)| {
+            !matches!(
+                *name,
+                header::CONTENT_LENGTH | header::TRANSFER_ENCODING | header::CONNECTION
+            )
+        })
+        .collect();
+    let body = match upstream.bytes().await {
+        Ok(body) => body,
+        Err(err) => return failed(err),
+    };
+    let mut response = Response::new(axum::body::Body::from(body));
+    *response.status_mut() = status;
+    for (name, value) in headers {
+        response.headers_mut().append(name, value);
+    }
+    response
+}
+
+pub(crate) async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = &state.config.admin_token else {
+        return route_not_found().await;
+    };
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token.trim() == admin_token);
+    if !authorized {
+        return openai_error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid admin token",
+            "invalid_request_error",
+        );
+    }
+    next.run(request).await
+}
+
+pub(crate) fn client_key_from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .unwrap_or(ANONYMOUS_CLIENT_KEY)
+        .to_owned()
+}
+
+fn validate_request_id(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty()
+        || value.len() > MAX_REQUEST_ID_LEN
+        || !value.bytes().all(|byte| byte.is_ascii_graphic())
+    {
+        return None;
+    }
+    Some(value.to_owned())
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use reqwest::Url;
+use rlm::rlm::ModelRoute;
+
+/// Maps model names accepted from clients to upstream routes.
+#[derive(Debug, Clone)]
+pub struct ModelRoutes {
+    default_model: String,
+    routes: BTreeMap<String, ModelRoute>,
+}
+
+impl ModelRoutes {
+    /// Builds the table from an optional JSON file of
+    /// `{"name": {"base_url", "model", "recursive_model", "fallback_model",
+    /// "fallback_base_url"}}` entries. `default_model` always routes, to
+    /// `default_route` unless the file overrides it, and entries without a
+    /// `base_url` inherit the default route's.
+    pub fn load(
+        path: Option<&Path>,
+        default_model: &str,
+        default_route: ModelRoute,
+    ) -> Result<Self, String> {
+        let mut routes = match path {
+            Some(path) => {
+                let raw = fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+                serde_json::from_str::<BTreeMap<String, ModelRoute>>(&raw)
+                    .map_err(|err| format!("invalid model routes in {}: {err}", path.display()))?
+            }
+            None => BTreeMap::new(),
+        };
+        let default_base_url = default_route.base_url.clone().unwrap_or_default();
+        routes
+            .entry(default_model.to_owned())
+            .or_insert(default_route);
+        for (name, route) in &mut routes {
+            if name.trim().is_empty() {
+                return Err("model route names must not be empty".to_owned());
+            }
+            let base_url = route
+                .base_url
+                .get_or_insert_with(|| default_base_url.clone());
+            if !valid_base_url(base_url) {
+                return Err(format!("model route {name}: invalid base_url {base_url}"));
+            }
+            let model = route.model.get_or_insert_with(|| name.clone());
+            if model.trim().is_empty() {
+                return Err(format!("model route {name}: model must not be empty"));
+            }
+            if route
+                .recursive_model
+                .as_ref()
+                .is_some_and(|model| model.trim().is_empty())
+            {
+                return Err(format!(
+                    "model route {name}: recursive_model must not be empty"
+                ));
+            }
+            if route
+                .fallback_model
+                .as_ref()
+                .is_some_and(|model| model.trim().is_empty())
+            {
+                return Err(format!(
+                    "model route {name}: fallback_model must not be empty"
+                ));
+            }
+            if let Some(base_url) = &route.fallback_base_url {
+                if route.fallback_model.is_none() {
+                    return Err(format!(
+                        "model route {name}: fallback_base_url requires fallback_model"
+                    ));
+                }
+                if !valid_base_url(base_url) {
+                    return Err(format!(
+                        "model route {name}: invalid fallback_base_url {base_url}"
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            default_model: default_model.to_owned(),
+            routes,
+        })
+    }
+
+    pub fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    /// Route of `default_model`, with `base_url` and `model` always set.
+    pub fn default_route(&self) -> &ModelRoute {
+        &self.routes[&self.default_model]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelRoute> {
+        self.routes.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+}
+
+fn valid_base_url(base_url: &str) -> bool {
+    Url::parse(base_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
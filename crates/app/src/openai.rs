@@ -0,0 +1,417 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use rlm::prompts::DEFAULT_QUERY;
+use rlm::trace::CompletionTrace;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OpenAiChatCompletionsRequest {
+    #[serde(default)]
+    pub(crate) messages: Vec<OpenAiChatMessage>,
+    pub(crate) model: Option<String>,
+    pub(crate) stream: Option<bool>,
+    pub(crate) reset: Option<bool>,
+    pub(crate) context_id: Option<String>,
+    pub(crate) include_trace: Option<bool>,
+    pub(crate) include_tool_calls: Option<bool>,
+    pub(crate) max_tokens: Option<u32>,
+    pub(crate) max_completion_tokens: Option<u32>,
+    pub(crate) rlm_max_iterations: Option<usize>,
+    pub(crate) rlm_recursive_model: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OpenAiChatMessage {
+    pub(crate) role: String,
+    pub(crate) content: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiChatCompletionsResponse {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    pub(crate) created: u64,
+    pub(crate) model: String,
+    pub(crate) choices: Vec<OpenAiChatChoice>,
+    pub(crate) usage: OpenAiUsage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) trace: Option<CompletionTrace>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiChatChoice {
+    pub(crate) index: usize,
+    pub(crate) message: OpenAiAssistantMessage,
+    pub(crate) finish_reason: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tool_messages: Vec<OpenAiToolMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiAssistantMessage {
+    pub(crate) role: String,
+    pub(crate) content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tool_calls: Vec<OpenAiToolCall>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiToolCall {
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) call_type: &'static str,
+    pub(crate) function: OpenAiFunctionCall,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiFunctionCall {
+    pub(crate) name: &'static str,
+    pub(crate) arguments: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiToolMessage {
+    pub(crate) role: &'static str,
+    pub(crate) tool_call_id: String,
+    pub(crate) content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiUsage {
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) total_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiErrorEnvelope {
+    pub(crate) error: OpenAiErrorBody,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAiErrorBody {
+    pub(crate) message: String,
+    #[serde(rename = "type")]
+    pub(crate) error_type: String,
+    pub(crate) param: Option<String>,
+    pub(crate) code: Option<String>,
+}
+
+pub(crate) fn openai_error_response(
+    status: StatusCode,
+    message: &str,
+    error_type: &str,
+) -> Response {
+    openai_error_response_with_code(status, message, error_type, openai_error_code(status))
+}
+
+pub(crate) fn openai_error_response_with_code(
+    status: StatusCode,
+    message: &str,
+    error_type: &str,
+    code: &str,
+) -> Response {
+    let mut response = Json(OpenAiErrorEnvelope {
+        error: OpenAiErrorBody {
+            message: message.to_owned(),
+            error_type: error_type.to_owned(),
+            param: None,
+            code: Some(code.to_owned()),
+        },
+    })
+    .into_response();
+    *response.status_mut() = status;
+    response
+}
+
+pub(crate) fn with_retry_after(mut response: Response, retry_after: Duration) -> Response {
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(retry_after.as_secs()),
+    );
+    response
+}
+
+fn openai_error_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_exceeded",
+        StatusCode::GATEWAY_TIMEOUT => "timeout",
+        status if status.is_client_error() => "invalid_request",
+        _ => "server_error",
+    }
+}
+
+pub(crate) fn json_rejection_response(rejection: JsonRejection) -> Response {
+    openai_error_response(
+        rejection.status(),
+        &rejection.body_text(),
+        "invalid_request_error",
+    )
+}
+
+pub(crate) async fn route_not_found() -> Response {
+    openai_error_response(
+        StatusCode::NOT_FOUND,
+        "unknown route",
+        "invalid_request_error",
+    )
+}
+
+pub(crate) async fn method_not_allowed() -> Response {
+    openai_error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method not allowed",
+        "invalid_request_error",
+    )
+}
+
+pub(crate) fn validate_openai_input(
+    messages: &[OpenAiChatMessage],
+    max_input_bytes: usize,
+) -> Result<(), (StatusCode, String)> {
+    for (idx, message) in messages.iter().enumerate() {
+        if message.role.trim().is_empty() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("messages[{idx}].role required"),
+            ));
+        }
+        if let Value::Array(parts) = &message.content {
+            validate_openai_content_parts(idx, parts)?;
+        }
+        let content_len = openai_message_text(message).len();
+        if content_len > max_input_bytes {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("messages[{idx}].content too large; max {max_input_bytes} bytes"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_openai_content_parts(idx: usize, parts: &[Value]) -> Result<(), (StatusCode, String)> {
+    for (part_idx, part) in parts.iter().enumerate() {
+        let part_type = part.get("type").and_then(Value::as_str);
+        match part_type {
+            Some("text") if part.get("text").is_some_and(Value::is_string) => {}
+            Some("text") => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("messages[{idx}].content[{part_idx}].text must be a string"),
+                ));
+            }
+            Some(other) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "messages[{idx}].content[{part_idx}].type {other} unsupported; only text \
+                         parts are supported"
+                    ),
+                ));
+            }
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("messages[{idx}].content[{part_idx}].type required"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn openai_message_text(message: &OpenAiChatMessage) -> Cow<'_, str> {
+    match &message.content {
+        Value::String(text) => Cow::Borrowed(text),
+        Value::Null => Cow::Borrowed(""),
+        Value::Array(parts) => Cow::Owned(
+            parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        other => Cow::Owned(other.to_string()),
+    }
+}
+
+pub(crate) fn openai_query_from_messages(messages: &[OpenAiChatMessage]) -> String {
+    for message in messages.iter().rev() {
+        if message.role == "user" {
+            let content = openai_message_text(message);
+            if !content.is_empty() {
+                return content.into_owned();
+            }
+        }
+    }
+    messages
+        .last()
+        .map(openai_message_text)
+        .filter(|text| !text.is_empty())
+        .map(Cow::into_owned)
+        .unwrap_or_else(|| DEFAULT_QUERY.to_owned())
+}
+
+pub(crate) fn openai_tool_calls_from_trace(
+    trace: &CompletionTrace,
+) -> (Vec<OpenAiToolCall>, Vec<OpenAiToolMessage>) {
+    let mut tool_calls = Vec::new();
+    let mut tool_messages = Vec::new();
+    for iteration in &trace.iterations {
+        for (block_idx, block) in iteration.code_blocks.iter().enumerate() {
+            let id = format!("call_{}_{block_idx}", iteration.iteration);
+            tool_calls.push(OpenAiToolCall {
+                id: id.clone(),
+                call_type: "function",
+                function: OpenAiFunctionCall {
+                    name: "repl",
+                    arguments: serde_json::json!({ "code": block.code }).to_string(),
+                },
+            });
+            tool_messages.push(OpenAiToolMessage {
+                role: "tool",
+                tool_call_id: id,
+                content: block.output_excerpt.clone(),
+            });
+        }
+    }
+    (tool_calls, tool_messages)
+}
+
+pub(crate) fn openai_context_from_messages(messages: Vec<OpenAiChatMessage>) -> Value {
+    Value::Array(
+        messages
+            .into_iter()
+            .map(|message| {
+                let content = match &message.content {
+                    Value::Array(_) => Value::String(openai_message_text(&message).into_owned()),
+                    _ => message.content,
+                };
+                let mut object = serde_json::Map::new();
+                object.insert("role".to_owned(), Value::String(message.role));
+                object.insert("content".to_owned(), content);
+                Value::Object(object)
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn messages(value: Value) -> Vec<OpenAiChatMessage> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn text_parts_are_joined() {
+        let messages = messages(json!([{
+            "role": "user",
+            "content": [{"type": "text", "text": "a"}, {"type": "text", "text": "b"}],
+        }]));
+        assert!(validate_openai_input(&messages, 3).is_ok());
+        assert_eq!(
+            validate_openai_input(&messages, 2),
+            Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "messages[0].content too large; max 2 bytes".to_owned()
+            ))
+        );
+        assert_eq!(openai_query_from_messages(&messages), "a\nb");
+        assert_eq!(
+            openai_context_from_messages(messages),
+            json!([{"role": "user", "content": "a\nb"}])
+        );
+    }
+
+    #[test]
+    fn unsupported_parts_are_rejected() {
+        for (part, message) in [
+            (
+                json!({"type": "image_url", "image_url": {"url": "x"}}),
+                "messages[0].content[1].type image_url unsupported; only text parts are supported",
+            ),
+            (
+                json!({"type": "text", "text": 3}),
+                "messages[0].content[1].text must be a string",
+            ),
+            (json!({"text": "b"}), "messages[0].content[1].type required"),
+        ] {
+            let messages = messages(json!([{
+                "role": "user",
+                "content": [{"type": "text", "text": "a"}, part],
+            }]));
+            let (status, error) = validate_openai_input(&messages, 1024).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(error, message);
+        }
+    }
+
+    #[test]
+    fn query_falls_back_to_the_last_message() {
+        let messages = messages(json!([
+            {"role": "user", "content": ""},
+            {"role": "assistant", "content": "done"},
+        ]));
+        assert_eq!(openai_query_from_messages(&messages), "done");
+        assert_eq!(openai_query_from_messages(&[]), DEFAULT_QUERY);
+    }
+
+    async fn error_body(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_carry_a_code_for_their_status() {
+        for (status, code) in [
+            (StatusCode::BAD_REQUEST, "invalid_request"),
+            (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            (StatusCode::TOO_MANY_REQUESTS, "rate_limit_exceeded"),
+            (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+            (StatusCode::BAD_GATEWAY, "server_error"),
+        ] {
+            let response = openai_error_response(status, "boom", "error");
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                error_body(response).await,
+                json!({"error": {
+                    "message": "boom",
+                    "type": "error",
+                    "param": null,
+                    "code": code,
+                }})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_get_an_envelope() {
+        let response = route_not_found().await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_body(response).await["error"]["code"], "not_found");
+        let response = method_not_allowed().await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            error_body(response).await["error"]["code"],
+            "method_not_allowed"
+        );
+    }
+}
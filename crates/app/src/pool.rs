@@ -1,56 +1,1128 @@
 use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::{SandboxHandle, SandboxLauncher};
+use serde::Serialize;
 
+use crate::{SandboxError, SandboxHandle, SandboxLauncher, SandboxUsage};
+
+const RESET_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const LAUNCH_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const LAUNCH_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+pub type LaunchResult = Result<Box<dyn SandboxHandle>, String>;
+pub type AcquireResult = Result<Box<dyn SandboxHandle>, AcquireError>;
+
+/// Work the pool's helper threads finished; the owner passes each back to
+/// [`SandboxPool::receive`].
+pub enum PoolEvent {
+    /// A refill launch.
+    Launched(LaunchResult),
+    /// A retired sandbox wiped for reuse, or `None` if the reset failed and
+    /// it was terminated instead.
+    Reset(Option<Box<dyn SandboxHandle>>),
+    /// An idle sandbox that answered its ping, or `None` if it failed and
+    /// was terminated instead.
+    Checked(Option<Box<dyn SandboxHandle>>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    pub target_idle: usize,
+    /// Concurrent launches while filling the pool at startup.
+    pub launch_parallelism: usize,
+    /// Extra attempts after a transient launch failure, with exponential
+    /// backoff. Permanent failures are never retried.
+    pub launch_retries: u32,
+    /// Most sandboxes alive at once, idle or in use; 0 is unlimited.
+    pub max_total: usize,
+    pub at_limit: SandboxLimitAction,
+    /// How often idle sandboxes are pinged; `None` disables health checks.
+    pub health_interval: Option<Duration>,
+    /// Reset retired sandboxes and return them to the pool instead of
+    /// terminating them.
+    pub reuse: bool,
+    pub recycle: RecyclePolicy,
+    /// Moves the idle target with load instead of holding it at
+    /// `target_idle`, which is then only the starting point.
+    pub autoscale: Option<PoolAutoscale>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolAutoscale {
+    pub min_idle: usize,
+    pub max_idle: usize,
+    /// How often the idle target is re-evaluated.
+    pub interval: Duration,
+}
+
+/// Limits after which a sandbox is replaced rather than kept; 0 disables a
+/// limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecyclePolicy {
+    pub max_runs: u64,
+    pub max_context_bytes: u64,
+    pub max_rss_bytes: u64,
+}
+
+impl RecyclePolicy {
+    pub fn worn_out(&self, usage: &SandboxUsage) -> bool {
+        (self.max_runs > 0 && usage.runs >= self.max_runs)
+            || (self.max_context_bytes > 0 && usage.context_bytes >= self.max_context_bytes)
+            || (self.max_rss_bytes > 0
+                && usage
+                    .worker
+                    .rss_bytes
+                    .is_some_and(|rss| rss >= self.max_rss_bytes))
+    }
+}
+
+/// What `acquire` does when `max_total` sandboxes already exist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SandboxLimitAction {
+    /// Queue until a sandbox is retired.
+    #[default]
+    Wait,
+    Fail,
+}
+
+impl FromStr for SandboxLimitAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "wait" => Ok(Self::Wait),
+            "fail" => Ok(Self::Fail),
+            other => Err(format!(
+                "invalid sandbox limit action {other}; expected wait or fail"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SandboxLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Wait => "wait",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AcquireError {
+    Launch(String),
+    AtLimit,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Launch(err) => f.write_str(err),
+            Self::AtLimit => f.write_str("sandbox limit reached"),
+        }
+    }
+}
+
+/// Point-in-time view of the pool. Gauges reflect the pool after its last
+/// command; counters are totals since startup.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SandboxPoolStats {
+    pub idle: usize,
+    pub in_use: usize,
+    pub launching: usize,
+    /// Retired sandboxes being wiped for reuse.
+    pub resetting: usize,
+    /// Idle sandboxes out for a health check or readiness ping.
+    pub checking: usize,
+    pub waiting: usize,
+    pub target_idle: usize,
+    pub launched: u64,
+    pub transient_launch_failures: u64,
+    pub permanent_launch_failures: u64,
+    pub launch_retries: u64,
+    /// Mean time for a successful launch, retries included.
+    pub avg_launch_ms: u64,
+    pub acquires: u64,
+    /// Acquires that found no idle sandbox.
+    pub waited_acquires: u64,
+    /// Mean wait across all acquires.
+    pub avg_acquire_wait_ms: u64,
+}
+
+/// Reads a pool's stats from any thread.
+#[derive(Clone)]
+pub struct SandboxPoolMonitor(Arc<PoolCounters>);
+
+impl SandboxPoolMonitor {
+    pub fn stats(&self) -> SandboxPoolStats {
+        let counters = &self.0;
+        let launched = counters.launched.load(Ordering::Relaxed);
+        let acquires = counters.acquires.load(Ordering::Relaxed);
+        SandboxPoolStats {
+            idle: counters.idle.load(Ordering::Relaxed),
+            in_use: counters.in_use.load(Ordering::Relaxed),
+            launching: counters.launching.load(Ordering::Relaxed),
+            resetting: counters.resetting.load(Ordering::Relaxed),
+            checking: counters.checking.load(Ordering::Relaxed),
+            waiting: counters.waiting.load(Ordering::Relaxed),
+            target_idle: counters.target_idle.load(Ordering::Relaxed),
+            launched,
+            transient_launch_failures: counters.transient_failures.load(Ordering::Relaxed),
+            permanent_launch_failures: counters.permanent_failures.load(Ordering::Relaxed),
+            launch_retries: counters.retries.load(Ordering::Relaxed),
+            avg_launch_ms: counters.launch_ms.load(Ordering::Relaxed) / launched.max(1),
+            acquires,
+            waited_acquires: counters.waited_acquires.load(Ordering::Relaxed),
+            avg_acquire_wait_ms: counters.acquire_wait_ms.load(Ordering::Relaxed) / acquires.max(1),
+        }
+    }
+}
+
+/// Shared with the launching threads and monitors.
+#[derive(Default)]
+struct PoolCounters {
+    idle: AtomicUsize,
+    in_use: AtomicUsize,
+    launching: AtomicUsize,
+    resetting: AtomicUsize,
+    checking: AtomicUsize,
+    waiting: AtomicUsize,
+    target_idle: AtomicUsize,
+    launched: AtomicU64,
+    transient_failures: AtomicU64,
+    permanent_failures: AtomicU64,
+    retries: AtomicU64,
+    launch_ms: AtomicU64,
+    acquires: AtomicU64,
+    waited_acquires: AtomicU64,
+    acquire_wait_ms: AtomicU64,
+}
+
+/// Warm sandboxes plus a background thread that launches replacements, so
+/// callers never wait on `docker run` from the pool's own thread. Resets, pings
+/// and terminations run on threads of their own for the same reason.
 pub struct SandboxPool {
-    launcher: Box<dyn SandboxLauncher>,
+    launcher: Arc<dyn SandboxLauncher>,
     idle: VecDeque<Box<dyn SandboxHandle>>,
+    config: PoolConfig,
+    /// Current idle target; fixed unless autoscaling.
     target_idle: usize,
+    /// Acquirers waiting for a background launch, oldest first.
+    waiters: VecDeque<Waiter>,
+    /// Launches requested from the refill thread but not yet reported back.
+    launching: usize,
+    /// Handed out and not yet retired.
+    in_use: usize,
+    /// Retired sandboxes whose reset has not been reported back yet.
+    resetting: usize,
+    /// Idle sandboxes whose ping has not been reported back yet.
+    checking: usize,
+    refill: Option<Sender<()>>,
+    events: Arc<dyn Fn(PoolEvent) + Send + Sync>,
+    load: LoadWindow,
+    next_health_check: Option<Instant>,
+    next_rescale: Option<Instant>,
+    counters: Arc<PoolCounters>,
+}
+
+struct Waiter {
+    respond_to: Sender<AcquireResult>,
+    since: Instant,
+}
+
+/// Acquire load since the idle target was last re-evaluated.
+#[derive(Default)]
+struct LoadWindow {
+    acquires: usize,
+    /// Acquires that found no idle sandbox and had to wait for a launch.
+    waited: usize,
+    wait_time: Duration,
 }
 
 impl SandboxPool {
-    pub fn new(launcher: Box<dyn SandboxLauncher>, target_idle: usize) -> Result<Self, String> {
-        let mut pool = Self {
+    /// Fills the pool before returning. Later launches, resets and pings happen
+    /// on helper threads that report each result through `on_event`; the
+    /// owner passes it back to [`SandboxPool::receive`].
+    pub fn new(
+        launcher: Box<dyn SandboxLauncher>,
+        config: PoolConfig,
+        on_event: impl Fn(PoolEvent) + Send + Sync + 'static,
+    ) -> Result<Self, String> {
+        if config.max_total > 0 && config.target_idle > config.max_total {
+            return Err(format!(
+                "sandbox pool size {} exceeds the sandbox limit {}",
+                config.target_idle, config.max_total
+            ));
+        }
+        if let Some(autoscale) = config.autoscale
+            && !(autoscale.min_idle..=autoscale.max_idle).contains(&config.target_idle)
+        {
+            return Err(format!(
+                "sandbox pool size {} is outside the autoscaling range {}..={}",
+                config.target_idle, autoscale.min_idle, autoscale.max_idle
+            ));
+        }
+        let launcher: Arc<dyn SandboxLauncher> = Arc::from(launcher);
+        let counters = Arc::new(PoolCounters::default());
+        let mut idle: VecDeque<_> = launcher.reconnect().into();
+        if !idle.is_empty() {
+            tracing::info!(count = idle.len(), "reconnected to sandboxes");
+        }
+        for mut extra in idle.split_off(idle.len().min(config.target_idle)) {
+            extra.terminate();
+        }
+        let missing = config.target_idle - idle.len();
+        idle.extend(launch_all(launcher.as_ref(), missing, &config, &counters)?);
+        let events: Arc<dyn Fn(PoolEvent) + Send + Sync> = Arc::new(on_event);
+        let (refill, requests) = mpsc::channel::<()>();
+        let refill_launcher = launcher.clone();
+        let refill_counters = counters.clone();
+        let refill_events = events.clone();
+        thread::Builder::new()
+            .name("pool-refill".to_owned())
+            .spawn(move || {
+                while requests.recv().is_ok() {
+                    refill_events(PoolEvent::Launched(launch_with_retry(
+                        refill_launcher.as_ref(),
+                        config.launch_retries,
+                        &refill_counters,
+                    )));
+                }
+            })
+            .map_err(|err| format!("failed to spawn pool refill thread: {err}"))?;
+        let now = Instant::now();
+        let pool = Self {
             launcher,
-            idle: VecDeque::new(),
-            target_idle,
+            idle,
+            config,
+            target_idle: config.target_idle,
+            waiters: VecDeque::new(),
+            launching: 0,
+            in_use: 0,
+            resetting: 0,
+            checking: 0,
+            refill: Some(refill),
+            events,
+            load: LoadWindow::default(),
+            next_health_check: config.health_interval.map(|interval| now + interval),
+            next_rescale: config.autoscale.map(|autoscale| now + autoscale.interval),
+            counters,
         };
-        pool.refill_strict()?;
+        pool.publish_stats();
         Ok(pool)
     }
 
-    pub fn acquire(&mut self) -> Result<Box<dyn SandboxHandle>, String> {
-        let handle = if let Some(handle) = self.idle.pop_front() {
-            handle
+    /// Hands `respond_to` a warm sandbox, or queues it for the next one the
+    /// refill thread launches. At the sandbox limit it either waits for a
+    /// retire or fails, per [`PoolConfig::at_limit`].
+    pub fn acquire(&mut self, respond_to: Sender<AcquireResult>) {
+        self.idle.retain_mut(|handle| !handle.exited());
+        self.load.acquires += 1;
+        self.counters.acquires.fetch_add(1, Ordering::Relaxed);
+        if let Some(handle) = self.idle.pop_front() {
+            match respond_to.send(Ok(handle)) {
+                Ok(()) => self.in_use += 1,
+                // The acquirer gave up waiting; the sandbox goes to the next
+                // one instead of leaking its slot.
+                Err(mpsc::SendError(result)) => {
+                    if let Ok(handle) = result {
+                        self.give(handle);
+                    }
+                }
+            }
+        } else if self.at_limit() && self.config.at_limit == SandboxLimitAction::Fail {
+            let _ = respond_to.send(Err(AcquireError::AtLimit));
         } else {
-            self.launcher.launch()?
-        };
-        self.refill_best_effort();
-        Ok(handle)
+            self.waiters.push_back(Waiter {
+                respond_to,
+                since: Instant::now(),
+            });
+        }
+        self.refill();
     }
 
+    /// Takes a result reported by one of the helper threads.
+    pub fn receive(&mut self, event: PoolEvent) {
+        match event {
+            PoolEvent::Launched(result) => self.launched(result),
+            PoolEvent::Reset(handle) => {
+                self.resetting = self.resetting.saturating_sub(1);
+                self.take_back(handle);
+            }
+            PoolEvent::Checked(handle) => {
+                self.checking = self.checking.saturating_sub(1);
+                self.take_back(handle);
+            }
+        }
+    }
+
+    /// Takes a result reported by the refill thread. A failed launch fails
+    /// the oldest waiter rather than retrying, and the others get fresh
+    /// launches if the limit held them back.
+    pub fn launched(&mut self, result: LaunchResult) {
+        self.launching = self.launching.saturating_sub(1);
+        match result {
+            Ok(handle) => {
+                if self.refill.is_none() {
+                    terminate_in_background(handle);
+                    return;
+                }
+                self.give(handle);
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to launch sandbox");
+                if let Some(waiter) = self.waiters.pop_front() {
+                    let _ = waiter.respond_to.send(Err(AcquireError::Launch(err)));
+                }
+                self.refill();
+            }
+        }
+    }
+
+    /// With reuse enabled, a live sandbox the pool still has room for is
+    /// reset on a helper thread and kept once that reports back; anything
+    /// else is terminated.
     pub fn retire(&mut self, mut handle: Box<dyn SandboxHandle>) {
-        handle.terminate();
-        self.refill_best_effort();
+        self.in_use = self.in_use.saturating_sub(1);
+        let wanted =
+            !self.waiters.is_empty() || self.idle.len() + self.resetting < self.target_idle;
+        if self.config.reuse
+            && wanted
+            && self.refill.is_some()
+            && !self.config.recycle.worn_out(&handle.usage())
+            && !handle.exited()
+        {
+            let events = self.events.clone();
+            let spawned = offload("pool-reset", move || {
+                events(PoolEvent::Reset(
+                    match handle.reset(Some(Instant::now() + RESET_TIMEOUT)) {
+                        Ok(()) => Some(handle),
+                        Err(err) => {
+                            tracing::warn!(
+                                sandbox = %handle.identifier(),
+                                error = %err,
+                                "failed to reset sandbox; terminating"
+                            );
+                            handle.terminate();
+                            None
+                        }
+                    },
+                ));
+            });
+            if spawned {
+                self.resetting += 1;
+                return;
+            }
+        } else {
+            terminate_in_background(handle);
+        }
+        self.refill();
+    }
+
+    /// Takes a sandbox back from a helper thread, or replaces it if the
+    /// helper terminated it.
+    fn take_back(&mut self, handle: Option<Box<dyn SandboxHandle>>) {
+        match handle {
+            Some(handle) if self.refill.is_some() => self.give(handle),
+            Some(handle) => terminate_in_background(handle),
+            None => self.refill(),
+        }
+    }
+
+    /// Pings the oldest idle sandbox on a helper thread, which answers
+    /// `respond`. A sandbox that fails is replaced, and the next probe pings
+    /// another.
+    pub fn check_ready(
+        &mut self,
+        deadline: Instant,
+        respond: impl FnOnce(Result<(), String>) + Send + 'static,
+    ) {
+        let Some(mut handle) = self.idle.pop_front() else {
+            // Keep one sandbox warm even with an empty target so the next
+            // probe can pass.
+            if self.launching == 0 && !self.at_limit() {
+                self.request_launch();
+            }
+            self.refill();
+            respond(Err("no idle sandbox yet; one is launching".to_owned()));
+            return;
+        };
+        let events = self.events.clone();
+        let spawned = offload("pool-ready", move || {
+            let handle = match handle.ping(Some(deadline)) {
+                Ok(()) => {
+                    respond(Ok(()));
+                    Some(handle)
+                }
+                Err(err) => {
+                    handle.terminate();
+                    respond(Err(format!("no healthy idle sandbox: {err}")));
+                    None
+                }
+            };
+            events(PoolEvent::Checked(handle));
+        });
+        if spawned {
+            self.checking += 1;
+        } else {
+            self.refill();
+        }
+    }
+
+    /// When [`SandboxPool::maintain`] next has work to do, if ever.
+    pub fn next_maintenance(&self) -> Option<Instant> {
+        [self.next_health_check, self.next_rescale]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Runs the periodic health check and autoscaling once they are due.
+    pub fn maintain(&mut self) {
+        let now = Instant::now();
+        if self.next_health_check.is_some_and(|at| now >= at) {
+            self.check_idle_health();
+            self.next_health_check = self.config.health_interval.map(|interval| now + interval);
+        }
+        if let Some(autoscale) = self.config.autoscale
+            && self.next_rescale.is_some_and(|at| now >= at)
+        {
+            self.rescale(autoscale);
+            self.next_rescale = Some(now + autoscale.interval);
+        }
+    }
+
+    /// Grows the idle target by the number of acquires that had to wait for
+    /// a launch, or shrinks it by one when fewer acquires arrived than it
+    /// holds.
+    fn rescale(&mut self, autoscale: PoolAutoscale) {
+        let load = std::mem::take(&mut self.load);
+        let target = if load.waited > 0 {
+            self.target_idle + load.waited
+        } else if load.acquires < self.target_idle {
+            self.target_idle - 1
+        } else {
+            self.target_idle
+        }
+        .clamp(autoscale.min_idle, autoscale.max_idle);
+        if target != self.target_idle {
+            tracing::info!(
+                from = self.target_idle,
+                to = target,
+                acquires = load.acquires,
+                waited = load.waited,
+                avg_wait_ms = load.wait_time.as_millis() as u64 / load.waited.max(1) as u64,
+                "rescaled sandbox pool"
+            );
+        }
+        self.target_idle = target;
+        while self.idle.len() > self.target_idle {
+            if let Some(handle) = self.idle.pop_back() {
+                terminate_in_background(handle);
+            }
+        }
+        self.refill();
+    }
+
+    /// Pings every idle sandbox at once, each on a helper thread. Healthy
+    /// ones come back as they answer; the ones that fail are replaced.
+    fn check_idle_health(&mut self) {
+        let deadline = Instant::now() + HEALTH_CHECK_TIMEOUT;
+        for handle in std::mem::take(&mut self.idle) {
+            let events = self.events.clone();
+            if offload("pool-health", move || {
+                events(PoolEvent::Checked(check_health(handle, deadline)))
+            }) {
+                self.checking += 1;
+            }
+        }
+        self.refill();
+    }
+
+    /// Detaches the idle sandboxes all at once and waits for them, so a
+    /// slow one holds shutdown up for its own timeout only. Sandboxes still
+    /// resetting or being pinged are terminated when they report back to
+    /// nobody.
+    pub fn shutdown(&mut self) {
+        self.refill = None;
+        self.waiters.clear();
+        thread::scope(|scope| {
+            for mut handle in self.idle.drain(..) {
+                scope.spawn(move || handle.detach());
+            }
+        });
+        self.launcher.shutdown();
+    }
+
+    pub fn stats(&self) -> SandboxPoolStats {
+        self.monitor().stats()
+    }
+
+    pub fn monitor(&self) -> SandboxPoolMonitor {
+        SandboxPoolMonitor(self.counters.clone())
+    }
+
+    /// Stores the current gauges for monitors; the owner calls this after
+    /// each command.
+    pub fn publish_stats(&self) {
+        let counters = &self.counters;
+        counters.idle.store(self.idle.len(), Ordering::Relaxed);
+        counters.in_use.store(self.in_use, Ordering::Relaxed);
+        counters.launching.store(self.launching, Ordering::Relaxed);
+        counters.resetting.store(self.resetting, Ordering::Relaxed);
+        counters.checking.store(self.checking, Ordering::Relaxed);
+        counters
+            .waiting
+            .store(self.waiters.len(), Ordering::Relaxed);
+        counters
+            .target_idle
+            .store(self.target_idle, Ordering::Relaxed);
     }
 
     pub fn idle_len(&self) -> usize {
         self.idle.len()
     }
 
-    fn refill_strict(&mut self) -> Result<(), String> {
-        while self.idle.len() < self.target_idle {
-            self.idle.push_back(self.launcher.launch()?);
+    /// Sandboxes alive or launching.
+    pub fn total(&self) -> usize {
+        self.idle.len() + self.in_use + self.launching + self.resetting + self.checking
+    }
+
+    fn at_limit(&self) -> bool {
+        self.config.max_total > 0 && self.total() >= self.config.max_total
+    }
+
+    fn give(&mut self, handle: Box<dyn SandboxHandle>) {
+        let mut handle = Some(handle);
+        while let Some(waiter) = self.waiters.pop_front() {
+            match waiter
+                .respond_to
+                .send(Ok(handle.take().expect("handle present")))
+            {
+                Ok(()) => {
+                    let waited = waiter.since.elapsed();
+                    self.in_use += 1;
+                    self.load.waited += 1;
+                    self.load.wait_time += waited;
+                    self.counters
+                        .waited_acquires
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.counters
+                        .acquire_wait_ms
+                        .fetch_add(waited.as_millis() as u64, Ordering::Relaxed);
+                    return;
+                }
+                Err(mpsc::SendError(result)) => handle = result.ok(),
+            }
+        }
+        if let Some(handle) = handle {
+            self.idle.push_back(handle);
         }
-        Ok(())
     }
 
-    fn refill_best_effort(&mut self) {
-        while self.idle.len() < self.target_idle {
-            match self.launcher.launch() {
-                Ok(handle) => self.idle.push_back(handle),
-                Err(_) => break,
+    /// Asks for enough launches to cover waiters and the idle target, up to
+    /// the sandbox limit.
+    fn refill(&mut self) {
+        while self.idle.len() + self.launching + self.resetting + self.checking
+            < self.target_idle + self.waiters.len()
+            && !self.at_limit()
+        {
+            if !self.request_launch() {
+                break;
             }
         }
     }
+
+    fn request_launch(&mut self) -> bool {
+        let Some(refill) = &self.refill else {
+            return false;
+        };
+        if refill.send(()).is_err() {
+            return false;
+        }
+        self.launching += 1;
+        true
+    }
+}
+
+/// Runs blocking sandbox I/O on a thread of its own so the pool's owner
+/// keeps serving acquires meanwhile. If no thread can be spawned, `job` is
+/// dropped unrun and this returns false.
+fn offload(name: &str, job: impl FnOnce() + Send + 'static) -> bool {
+    match thread::Builder::new().name(name.to_owned()).spawn(job) {
+        Ok(_) => true,
+        Err(err) => {
+            tracing::error!(error = %err, "failed to spawn {name} thread");
+            false
+        }
+    }
+}
+
+/// `handle` if it answers a ping by `deadline`; otherwise it is terminated.
+fn check_health(
+    mut handle: Box<dyn SandboxHandle>,
+    deadline: Instant,
+) -> Option<Box<dyn SandboxHandle>> {
+    let result = if handle.exited() {
+        Err(SandboxError::Unresponsive(
+            "sandbox process exited".to_owned(),
+        ))
+    } else {
+        handle.ping(Some(deadline))
+    };
+    match result {
+        Ok(()) => Some(handle),
+        Err(err) => {
+            tracing::warn!(
+                sandbox = %handle.identifier(),
+                error = %err,
+                "idle sandbox failed health check; replacing"
+            );
+            handle.terminate();
+            None
+        }
+    }
+}
+
+fn terminate_in_background(mut handle: Box<dyn SandboxHandle>) {
+    // A handle dropped unterminated because no thread could be spawned is
+    // the launcher's to clean up, as after a crash.
+    offload("pool-terminate", move || handle.terminate());
+}
+
+/// Launches `count` sandboxes at most `parallelism` at a time. If any launch
+/// fails, the ones that succeeded are terminated and every distinct error is
+/// reported.
+fn launch_all(
+    launcher: &dyn SandboxLauncher,
+    count: usize,
+    config: &PoolConfig,
+    counters: &PoolCounters,
+) -> Result<VecDeque<Box<dyn SandboxHandle>>, String> {
+    let parallelism = config.launch_parallelism;
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(count));
+    thread::scope(|scope| {
+        for _ in 0..parallelism.clamp(1, count.max(1)) {
+            scope.spawn(|| {
+                while next.fetch_add(1, Ordering::Relaxed) < count {
+                    let result = launch_with_retry(launcher, config.launch_retries, counters);
+                    results
+                        .lock()
+                        .expect("pool init lock poisoned")
+                        .push(result);
+                }
+            });
+        }
+    });
+    let mut handles = VecDeque::with_capacity(count);
+    let mut errors = Vec::new();
+    for result in results.into_inner().expect("pool init lock poisoned") {
+        match result {
+            Ok(handle) => handles.push_back(handle),
+            Err(err) => errors.push(err),
+        }
+    }
+    if errors.is_empty() {
+        return Ok(handles);
+    }
+    for mut handle in handles {
+        handle.terminate();
+    }
+    let failed = errors.len();
+    errors.sort();
+    errors.dedup();
+    Err(format!(
+        "failed to launch {failed} of {count} sandboxes: {}",
+        errors.join("; ")
+    ))
+}
+
+fn launch_with_retry(
+    launcher: &dyn SandboxLauncher,
+    retries: u32,
+    counters: &PoolCounters,
+) -> LaunchResult {
+    let start = Instant::now();
+    let mut backoff = LAUNCH_RETRY_INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let err = match launcher.launch() {
+            Ok(handle) => {
+                counters.launched.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .launch_ms
+                    .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+                return Ok(handle);
+            }
+            Err(err) => err,
+        };
+        if err.is_permanent() {
+            counters.permanent_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(err.to_string());
+        }
+        counters.transient_failures.fetch_add(1, Ordering::Relaxed);
+        if attempt >= retries {
+            return Err(err.to_string());
+        }
+        attempt += 1;
+        counters.retries.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(error = %err, attempt, "sandbox launch failed; retrying");
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(LAUNCH_RETRY_MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rlm::repl::ReplSnapshot;
+    use rlm::trace::ProgressEvent;
+
+    use super::*;
+    use crate::LaunchError;
+    use crate::protocol::{SandboxRunRequest, SandboxRunResult};
+
+    /// A broken handle fails its pings and resets.
+    #[derive(Default)]
+    struct FakeHandle {
+        broken: bool,
+    }
+
+    impl FakeHandle {
+        fn check(&self) -> Result<(), SandboxError> {
+            if self.broken {
+                return Err(SandboxError::Unresponsive("broken".to_owned()));
+            }
+            Ok(())
+        }
+    }
+
+    impl SandboxHandle for FakeHandle {
+        fn run(
+            &mut self,
+            _request: SandboxRunRequest,
+            _deadline: Option<Instant>,
+            _cancelled: &dyn Fn() -> bool,
+            _progress: &dyn Fn(ProgressEvent),
+        ) -> Result<SandboxRunResult, SandboxError> {
+            Err(SandboxError::TimedOut(None))
+        }
+
+        fn get_variable(
+            &mut self,
+            _name: &str,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<Option<String>, SandboxError> {
+            Ok(None)
+        }
+
+        fn snapshot(
+            &mut self,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<ReplSnapshot, SandboxError> {
+            Err(SandboxError::TimedOut(None))
+        }
+
+        fn restore(
+            &mut self,
+            _snapshot: ReplSnapshot,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<(), SandboxError> {
+            Ok(())
+        }
+
+        fn ping(&mut self, _deadline: Option<Instant>) -> Result<(), SandboxError> {
+            self.check()
+        }
+
+        fn reset(&mut self, _deadline: Option<Instant>) -> Result<(), SandboxError> {
+            self.check()
+        }
+
+        fn terminate(&mut self) {}
+
+        fn identifier(&self) -> String {
+            "fake".to_owned()
+        }
+    }
+
+    struct FakeLauncher;
+
+    impl SandboxLauncher for FakeLauncher {
+        fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+            Ok(Box::new(FakeHandle::default()))
+        }
+    }
+
+    fn config(target_idle: usize, max_total: usize) -> PoolConfig {
+        PoolConfig {
+            target_idle,
+            launch_parallelism: 1,
+            launch_retries: 0,
+            max_total,
+            at_limit: SandboxLimitAction::Wait,
+            health_interval: None,
+            reuse: false,
+            recycle: RecyclePolicy::default(),
+            autoscale: None,
+        }
+    }
+
+    /// Refill launches are reported nowhere, so tests feed `launched` by hand.
+    fn pool(config: PoolConfig) -> SandboxPool {
+        SandboxPool::new(Box::new(FakeLauncher), config, |_| {}).expect("pool starts")
+    }
+
+    /// A pool whose helper threads report to the returned receiver, for
+    /// tests that feed the events back by hand.
+    fn pool_with_events(config: PoolConfig) -> (SandboxPool, mpsc::Receiver<PoolEvent>) {
+        let (events, received) = mpsc::channel();
+        let pool = SandboxPool::new(Box::new(FakeLauncher), config, move |event| {
+            let _ = events.send(event);
+        })
+        .expect("pool starts");
+        (pool, received)
+    }
+
+    /// The next event other than a refill launch.
+    fn next_returned(events: &mpsc::Receiver<PoolEvent>) -> PoolEvent {
+        loop {
+            let event = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("pool event");
+            if !matches!(event, PoolEvent::Launched(_)) {
+                return event;
+            }
+        }
+    }
+
+    #[test]
+    fn acquire_hands_out_idle_sandbox() {
+        let mut pool = pool(config(1, 0));
+        let (respond_to, response) = mpsc::channel();
+        pool.acquire(respond_to);
+        assert!(response.try_recv().expect("answered").is_ok());
+        pool.publish_stats();
+        assert_eq!(pool.stats().in_use, 1);
+    }
+
+    #[test]
+    fn abandoned_acquire_keeps_its_slot() {
+        let mut pool = pool(config(1, 1));
+        let (respond_to, response) = mpsc::channel();
+        drop(response);
+        pool.acquire(respond_to);
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.in_use, 0);
+        assert_eq!(stats.idle, 1);
+
+        let (respond_to, response) = mpsc::channel();
+        pool.acquire(respond_to);
+        assert!(response.try_recv().expect("answered").is_ok());
+    }
+
+    #[test]
+    fn at_limit_fails_when_configured() {
+        let mut config = config(1, 1);
+        config.at_limit = SandboxLimitAction::Fail;
+        let mut pool = pool(config);
+        let (first, _held) = mpsc::channel();
+        pool.acquire(first);
+        let (second, response) = mpsc::channel();
+        pool.acquire(second);
+        assert!(matches!(
+            response.try_recv().expect("answered"),
+            Err(AcquireError::AtLimit)
+        ));
+    }
+
+    #[test]
+    fn retire_frees_a_slot_for_waiters() {
+        let mut pool = pool(config(1, 1));
+        let (first, held) = mpsc::channel();
+        pool.acquire(first);
+        let handle = held.try_recv().expect("answered").expect("sandbox");
+        let (second, waiting) = mpsc::channel();
+        pool.acquire(second);
+        assert!(waiting.try_recv().is_err());
+        pool.publish_stats();
+        assert_eq!(pool.stats().launching, 0);
+
+        pool.retire(handle);
+        pool.publish_stats();
+        assert_eq!(pool.stats().launching, 1);
+        pool.launched(Ok(Box::new(FakeHandle::default())));
+        assert!(waiting.try_recv().expect("answered").is_ok());
+    }
+
+    #[test]
+    fn reset_happens_off_the_owners_thread() {
+        let mut config = config(1, 1);
+        config.reuse = true;
+        let (mut pool, events) = pool_with_events(config);
+        let (first, held) = mpsc::channel();
+        pool.acquire(first);
+        let handle = held.try_recv().expect("answered").expect("sandbox");
+        let (second, waiting) = mpsc::channel();
+        pool.acquire(second);
+
+        pool.retire(handle);
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.resetting, 1);
+        assert_eq!(stats.launching, 0);
+        assert!(waiting.try_recv().is_err());
+
+        pool.receive(next_returned(&events));
+        assert!(waiting.try_recv().expect("answered").is_ok());
+        pool.publish_stats();
+        assert_eq!(pool.stats().resetting, 0);
+    }
+
+    #[test]
+    fn failed_reset_is_replaced() {
+        let mut config = config(1, 1);
+        config.reuse = true;
+        let (mut pool, events) = pool_with_events(config);
+        let (first, held) = mpsc::channel();
+        pool.acquire(first);
+        drop(held.try_recv().expect("answered").expect("sandbox"));
+
+        pool.retire(Box::new(FakeHandle { broken: true }));
+        let event = next_returned(&events);
+        assert!(matches!(event, PoolEvent::Reset(None)));
+        pool.receive(event);
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.resetting, 0);
+        assert_eq!(stats.launching, 1);
+    }
+
+    #[test]
+    fn health_check_pings_off_the_owners_thread() {
+        let mut config = config(1, 0);
+        config.health_interval = Some(Duration::ZERO);
+        let (mut pool, events) = pool_with_events(config);
+        pool.launched(Ok(Box::new(FakeHandle { broken: true })));
+        pool.maintain();
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.idle, 0);
+        assert_eq!(stats.checking, 2);
+
+        let mut healthy = 0;
+        for _ in 0..2 {
+            let event = next_returned(&events);
+            if matches!(event, PoolEvent::Checked(Some(_))) {
+                healthy += 1;
+            }
+            pool.receive(event);
+        }
+        assert_eq!(healthy, 1);
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.idle, 1);
+        assert_eq!(stats.checking, 0);
+    }
+
+    #[test]
+    fn readiness_pings_one_idle_sandbox() {
+        let (mut pool, events) = pool_with_events(config(1, 0));
+        let (respond_to, response) = mpsc::channel();
+        pool.check_ready(Instant::now() + HEALTH_CHECK_TIMEOUT, move |result| {
+            let _ = respond_to.send(result);
+        });
+        pool.publish_stats();
+        assert_eq!(pool.stats().checking, 1);
+        pool.receive(next_returned(&events));
+        assert_eq!(response.recv_timeout(Duration::from_secs(5)), Ok(Ok(())));
+        assert_eq!(pool.idle_len(), 1);
+    }
+
+    #[test]
+    fn readiness_replaces_a_broken_sandbox() {
+        let (mut pool, events) = pool_with_events(config(0, 0));
+        pool.launched(Ok(Box::new(FakeHandle { broken: true })));
+        let (respond_to, response) = mpsc::channel();
+        pool.check_ready(Instant::now() + HEALTH_CHECK_TIMEOUT, move |result| {
+            let _ = respond_to.send(result);
+        });
+        let event = next_returned(&events);
+        assert!(matches!(event, PoolEvent::Checked(None)));
+        pool.receive(event);
+        let err = response
+            .recv_timeout(Duration::from_secs(5))
+            .expect("answered")
+            .expect_err("broken sandbox");
+        assert!(err.starts_with("no healthy idle sandbox"), "{err}");
+        assert_eq!(pool.idle_len(), 0);
+
+        let (respond_to, response) = mpsc::channel();
+        pool.check_ready(Instant::now() + HEALTH_CHECK_TIMEOUT, move |result| {
+            let _ = respond_to.send(result);
+        });
+        assert!(response.try_recv().expect("answered").is_err());
+        pool.publish_stats();
+        assert_eq!(pool.stats().launching, 1);
+    }
+
+    #[test]
+    fn failed_launch_relaunches_for_remaining_waiters() {
+        let mut pool = pool(config(0, 1));
+        let (first, first_response) = mpsc::channel();
+        pool.acquire(first);
+        let (second, second_response) = mpsc::channel();
+        pool.acquire(second);
+        pool.publish_stats();
+        assert_eq!(pool.stats().launching, 1);
+
+        pool.launched(Err("boom".to_owned()));
+        assert!(matches!(
+            first_response.try_recv().expect("answered"),
+            Err(AcquireError::Launch(_))
+        ));
+        pool.publish_stats();
+        let stats = pool.stats();
+        assert_eq!(stats.launching, 1);
+        assert_eq!(stats.waiting, 1);
+
+        pool.launched(Ok(Box::new(FakeHandle::default())));
+        assert!(second_response.try_recv().expect("answered").is_ok());
+    }
+
+    #[test]
+    fn recycle_policy_limits() {
+        let policy = RecyclePolicy {
+            max_runs: 2,
+            ..RecyclePolicy::default()
+        };
+        let mut usage = SandboxUsage {
+            runs: 1,
+            ..SandboxUsage::default()
+        };
+        assert!(!policy.worn_out(&usage));
+        usage.runs = 2;
+        assert!(policy.worn_out(&usage));
+        assert!(!RecyclePolicy::default().worn_out(&usage));
+    }
+
+    #[test]
+    fn limit_action_parses() {
+        assert_eq!(
+            " Fail ".parse::<SandboxLimitAction>(),
+            Ok(SandboxLimitAction::Fail)
+        );
+        assert!("later".parse::<SandboxLimitAction>().is_err());
+    }
 }
@@ -1,12 +1,57 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+use rlm::llm::{FinishReason, Message, TokenUsage};
+use rlm::repl::{LocalValue, ReplSnapshot};
+use rlm::rlm::ModelRoute;
+use rlm::trace::{CompletionTrace, CompletionUsage, ProgressEvent};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Starts a MessagePack frame; never the first byte of a JSON line.
+const FRAME_MAGIC: u8 = 0xc1;
+pub const MAX_FRAME_BYTES: usize = 1 << 30;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SandboxRunRequest {
     pub initialize: bool,
     pub query: String,
     pub context: Option<Value>,
+    /// JSON file the worker reads the context from instead of `context`, in
+    /// a directory the host shares with it.
+    #[serde(default)]
+    pub context_path: Option<String>,
+    /// The context was streamed in beforehand with
+    /// [`WorkerRequest::BeginContext`] and is used instead of `context`.
+    #[serde(default)]
+    pub staged_context: bool,
     pub code: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub trace_context: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub include_trace: bool,
+    /// Milliseconds left before the caller gives up, measured when sent.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+    #[serde(flatten)]
+    pub overrides: RunOverrides,
+}
+
+/// Per-request settings layered over the worker's `RlmConfig`; `None` keeps
+/// the configured value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunOverrides {
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    pub max_iterations: Option<usize>,
+    #[serde(flatten)]
+    pub route: ModelRoute,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,21 +59,433 @@ pub struct SandboxRunResult {
     pub response: Option<String>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    #[serde(default)]
+    pub locals: Option<Vec<LocalValue>>,
+    #[serde(default)]
+    pub trace: Option<CompletionTrace>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// Set when the route's fallback model served part of the completion.
+    #[serde(default)]
+    pub used_fallback: bool,
+    /// Sub-calls made during the completion, whether or not a trace was
+    /// requested.
+    #[serde(default)]
+    pub llm_subcalls: u64,
+    #[serde(default)]
+    pub rlm_subcalls: u64,
+    /// Worker resident memory after the run, when the platform reports it.
+    #[serde(default)]
+    pub rss_bytes: Option<u64>,
+    /// Tokens and REPL time the run spent; zero from workers that predate
+    /// it.
+    #[serde(default)]
+    pub usage: CompletionUsage,
+    /// The worker's own view of its process after the run; unset from
+    /// workers that predate it.
+    #[serde(default)]
+    pub stats: Option<WorkerStats>,
+}
+
+/// Totals for a whole worker process, across all of its REPLs. Memory and
+/// CPU are unset where the platform does not report them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerStats {
+    #[serde(default)]
+    pub rss_bytes: Option<u64>,
+    /// Most resident memory the worker has used at once.
+    #[serde(default)]
+    pub peak_rss_bytes: Option<u64>,
+    /// User and system CPU time across all of the worker's threads.
+    #[serde(default)]
+    pub cpu_ms: Option<u64>,
+    /// Runs the worker has finished, failed ones included.
+    #[serde(default)]
+    pub runs: u64,
+    /// Time those runs spent executing code blocks.
+    #[serde(default)]
+    pub repl_time_ms: u64,
+    /// REPLs the worker currently hosts.
+    #[serde(default)]
+    pub repls: u64,
+}
+
+/// A message to or from one of a worker's REPLs. Workers host a default REPL
+/// and start a named one for each `repl` they have not seen; each handles
+/// its requests in order, and different REPLs run at the same time. Replies
+/// carry the `id` and `repl` of their request; workers that predate
+/// envelopes ignore both and leave them unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repl: Option<String>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    /// For the default REPL, outside any request.
+    pub fn unaddressed(message: T) -> Self {
+        Self {
+            id: None,
+            repl: None,
+            message,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum WorkerRequest {
     Ping,
-    Run(SandboxRunRequest),
+    Run(Box<SandboxRunRequest>),
+    GetVariable {
+        name: String,
+        #[serde(default)]
+        request_id: Option<String>,
+        #[serde(default)]
+        trace_context: Option<HashMap<String, String>>,
+    },
+    Snapshot {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    Restore {
+        snapshot: Box<ReplSnapshot>,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Drops all REPL state so the sandbox can serve another session.
+    Reset,
+    /// Drops a named REPL altogether; the default one is only reset.
+    Close,
+    /// Starts streaming a context's JSON text in pieces, for the next run
+    /// to use; each piece is acknowledged.
+    BeginContext {
+        total_bytes: u64,
+    },
+    ContextChunk {
+        data: String,
+    },
+    /// Parses the streamed text; an error discards it.
+    EndContext,
+    /// Offers framings in order of preference; the worker answers with
+    /// [`WorkerResponse::Hello`] and both sides write the chosen one from
+    /// then on.
+    Hello {
+        framings: Vec<Framing>,
+    },
+    /// Answers an [`WorkerResponse::LlmRequest`]; may arrive while a run is
+    /// in flight.
+    LlmResult(LlmResult),
+    /// Abandons the run in flight, which then answers with an error. Also
+    /// read mid-run, and ignored when nothing is running.
+    Cancel,
     Shutdown,
 }
 
+/// An LLM call a brokered worker asks the host to make on its behalf.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlmRequest {
+    pub id: u64,
+    pub model: String,
+    pub base_url: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlmResult {
+    pub id: u64,
+    pub outcome: LlmOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LlmOutcome {
+    Completed {
+        content: String,
+        finish_reason: FinishReason,
+        #[serde(default)]
+        usage: Option<TokenUsage>,
+    },
+    /// `upstream` marks failures a fallback model may not share.
+    Failed { message: String, upstream: bool },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum WorkerResponse {
     Pong,
     Ack,
     RunResult(SandboxRunResult),
-    Error { message: String },
+    Variable {
+        value: Option<String>,
+    },
+    Snapshot {
+        snapshot: Box<ReplSnapshot>,
+    },
+    Error(WorkerError),
+    /// Sent in the framing in use before the handshake.
+    Hello {
+        framing: Framing,
+    },
+    /// Sent mid-run by a brokered worker; not a reply to the pending request.
+    LlmRequest(LlmRequest),
+    /// Sent mid-run as the completion advances; not a reply either.
+    Progress(ProgressEvent),
+}
+
+/// A failure reported by the worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerError {
+    /// Missing from workers that predate error codes.
+    #[serde(default)]
+    pub code: WorkerErrorCode,
+    pub message: String,
+    /// What a failed run spent before it stopped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+    /// Iterations a failed completion got through, when its run asked for
+    /// a trace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Box<CompletionTrace>>,
+}
+
+impl WorkerError {
+    pub fn new(code: WorkerErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            usage: None,
+            trace: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(WorkerErrorCode::Internal, message)
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// What kind of failure a [`WorkerError`] is, so the server can choose a
+/// status and whether to retry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerErrorCode {
+    /// An LLM call failed after any fallback.
+    LlmError,
+    /// The run's deadline passed.
+    Timeout,
+    Cancelled,
+    /// The context did not fit in the sandbox's scratch space.
+    ContextTooLarge,
+    /// The interpreter itself failed, as opposed to generated code raising.
+    PythonError,
+    /// The worker was killed, most likely for exceeding its memory limit.
+    Oom,
+    InvalidRequest,
+    #[default]
+    #[serde(other)]
+    Internal,
+}
+
+/// How messages are delimited on the worker's stdin and stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Framing {
+    /// One JSON document per line; what every worker speaks at startup.
+    #[default]
+    #[serde(rename = "json")]
+    JsonLines,
+    /// Length-prefixed MessagePack, which is faster and smaller for large
+    /// contexts and has no trouble with raw newlines.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::JsonLines),
+            "msgpack" => Ok(Self::MessagePack),
+            other => Err(format!(
+                "invalid sandbox framing {other}; expected json or msgpack"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::JsonLines => "json",
+            Self::MessagePack => "msgpack",
+        })
+    }
+}
+
+/// One undecoded message.
+#[derive(Debug)]
+pub enum Frame {
+    Json(String),
+    MessagePack(Vec<u8>),
+}
+
+impl Frame {
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match self {
+            Self::Json(line) => {
+                serde_json::from_str(line.trim_end()).map_err(|err| err.to_string())
+            }
+            Self::MessagePack(bytes) => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// The REPL an [`Envelope`] is addressed to, without decoding the rest.
+    pub fn repl(&self) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Address {
+            #[serde(default)]
+            repl: Option<String>,
+        }
+        self.decode::<Address>()
+            .ok()
+            .and_then(|address| address.repl)
+    }
+}
+
+/// Encodes `message` with its delimiter: a trailing newline for JSON, a
+/// magic byte and big-endian `u32` length for MessagePack.
+pub fn encode_frame<T: Serialize>(message: &T, framing: Framing) -> Result<Vec<u8>, String> {
+    match framing {
+        Framing::JsonLines => {
+            let mut bytes = serde_json::to_vec(message).map_err(|err| err.to_string())?;
+            bytes.push(b'\n');
+            Ok(bytes)
+        }
+        Framing::MessagePack => {
+            // Named fields, since tagged enums and flattened structs need
+            // maps to decode.
+            let body = rmp_serde::to_vec_named(message).map_err(|err| err.to_string())?;
+            if body.len() > MAX_FRAME_BYTES {
+                return Err(format!(
+                    "message of {} bytes exceeds the {MAX_FRAME_BYTES} byte frame limit",
+                    body.len()
+                ));
+            }
+            let mut bytes = Vec::with_capacity(body.len() + 5);
+            bytes.push(FRAME_MAGIC);
+            bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&body);
+            Ok(bytes)
+        }
+    }
+}
+
+/// Reads the next message in whichever framing it was written, skipping
+/// blank lines; `None` at end of input.
+pub fn read_frame(input: &mut impl BufRead) -> io::Result<Option<Frame>> {
+    loop {
+        let first = match input.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+        if first == FRAME_MAGIC {
+            input.consume(1);
+            let mut len = [0; 4];
+            input.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_FRAME_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame of {len} bytes exceeds the {MAX_FRAME_BYTES} byte limit"),
+                ));
+            }
+            let mut body = vec![0; len];
+            input.read_exact(&mut body)?;
+            return Ok(Some(Frame::MessagePack(body)));
+        }
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.trim().is_empty() {
+            return Ok(Some(Frame::Json(line)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing_parses_and_displays() {
+        for framing in [Framing::JsonLines, Framing::MessagePack] {
+            assert_eq!(framing.to_string().parse(), Ok(framing));
+        }
+        assert_eq!(" MsgPack ".parse(), Ok(Framing::MessagePack));
+        assert!("cbor".parse::<Framing>().is_err());
+    }
+
+    #[test]
+    fn frames_round_trip_in_either_framing() {
+        let mut stream = Vec::new();
+        stream.extend(encode_frame(&WorkerResponse::Pong, Framing::JsonLines).unwrap());
+        stream.extend(b"\n  \n");
+        let error = WorkerResponse::Error(WorkerError::internal("line\nbreak"));
+        stream.extend(encode_frame(&error, Framing::MessagePack).unwrap());
+        let mut input = stream.as_slice();
+        let frame = read_frame(&mut input).unwrap().expect("json frame");
+        assert!(matches!(frame, Frame::Json(_)));
+        assert!(matches!(frame.decode(), Ok(WorkerResponse::Pong)));
+        let frame = read_frame(&mut input).unwrap().expect("msgpack frame");
+        assert!(matches!(frame, Frame::MessagePack(_)));
+        assert!(matches!(
+            frame.decode(),
+            Ok(WorkerResponse::Error(err)) if err.message == "line\nbreak"
+        ));
+        assert!(read_frame(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_frames_are_errors() {
+        let mut oversized = vec![FRAME_MAGIC];
+        oversized.extend_from_slice(&(MAX_FRAME_BYTES as u32 + 1).to_be_bytes());
+        let err = read_frame(&mut oversized.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut truncated = encode_frame(&WorkerResponse::Pong, Framing::MessagePack).unwrap();
+        truncated.pop();
+        let err = read_frame(&mut truncated.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let frame = Frame::Json("{\"kind\":\"nope\"}\n".to_owned());
+        assert!(frame.decode::<WorkerResponse>().is_err());
+    }
+
+    #[test]
+    fn error_codes_default_to_internal() {
+        let err: WorkerError =
+            serde_json::from_str(r#"{"code":"context_too_large","message":"full"}"#).unwrap();
+        assert_eq!(err.code, WorkerErrorCode::ContextTooLarge);
+        assert_eq!(err.to_string(), "full");
+        for old in [
+            r#"{"message":"m"}"#,
+            r#"{"code":"from_the_future","message":"m"}"#,
+        ] {
+            let err: WorkerError = serde_json::from_str(old).unwrap();
+            assert_eq!(err.code, WorkerErrorCode::Internal);
+        }
+    }
 }
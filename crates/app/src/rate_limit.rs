@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub requests_per_minute: u32,
+    pub max_concurrent: usize,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.requests_per_minute > 0 || self.max_concurrent > 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+    pub message: String,
+}
+
+pub struct KeyedRateLimiter {
+    config: RateLimitConfig,
+    keys: Mutex<HashMap<String, KeyState>>,
+}
+
+struct KeyState {
+    tokens: f64,
+    refilled_at: Instant,
+    inflight: usize,
+}
+
+pub struct RateLimitPermit {
+    limiter: Arc<KeyedRateLimiter>,
+    key: String,
+}
+
+impl KeyedRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, key: &str) -> Result<RateLimitPermit, RateLimited> {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().expect("rate limiter lock poisoned");
+        if keys.len() >= PRUNE_THRESHOLD {
+            keys.retain(|_, state| !self.is_idle(state, now));
+        }
+        let capacity = f64::from(self.config.requests_per_minute);
+        let state = keys.entry(key.to_owned()).or_insert_with(|| KeyState {
+            tokens: capacity,
+            refilled_at: now,
+            inflight: 0,
+        });
+
+        if self.config.max_concurrent > 0 && state.inflight >= self.config.max_concurrent {
+            return Err(RateLimited {
+                retry_after: Duration::from_secs(1),
+                message: format!(
+                    "too many concurrent requests for this key; max {}",
+                    self.config.max_concurrent
+                ),
+            });
+        }
+        if self.config.requests_per_minute > 0 {
+            self.refill(state, now);
+            if state.tokens < 1.0 {
+                let wait = (1.0 - state.tokens) / self.refill_per_second();
+                return Err(RateLimited {
+                    retry_after: Duration::from_secs(wait.ceil().max(1.0) as u64),
+                    message: format!(
+                        "rate limit exceeded for this key; max {} requests per minute",
+                        self.config.requests_per_minute
+                    ),
+                });
+            }
+            state.tokens -= 1.0;
+        }
+        state.inflight += 1;
+
+        Ok(RateLimitPermit {
+            limiter: Arc::clone(self),
+            key: key.to_owned(),
+        })
+    }
+
+    fn refill(&self, state: &mut KeyState, now: Instant) {
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        let capacity = f64::from(self.config.requests_per_minute);
+        state.tokens = (state.tokens + elapsed * self.refill_per_second()).min(capacity);
+        state.refilled_at = now;
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.config.requests_per_minute) / 60.0
+    }
+
+    fn is_idle(&self, state: &KeyState, now: Instant) -> bool {
+        if state.inflight > 0 {
+            return false;
+        }
+        if self.config.requests_per_minute == 0 {
+            return true;
+        }
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens + elapsed * self.refill_per_second()
+            >= f64::from(self.config.requests_per_minute)
+    }
+
+    fn release(&self, key: &str) {
+        let mut keys = self.keys.lock().expect("rate limiter lock poisoned");
+        if let Some(state) = keys.get_mut(key) {
+            state.inflight = state.inflight.saturating_sub(1);
+        }
+    }
+}
+
+impl Drop for RateLimitPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: u32, max_concurrent: usize) -> Arc<KeyedRateLimiter> {
+        KeyedRateLimiter::new(RateLimitConfig {
+            requests_per_minute,
+            max_concurrent,
+        })
+    }
+
+    #[test]
+    fn requests_per_minute_run_out() {
+        let limiter = limiter(2, 0);
+        drop(limiter.try_acquire("a").expect("first"));
+        drop(limiter.try_acquire("a").expect("second"));
+        let limited = limiter.try_acquire("a").err().expect("limited");
+        assert!(limited.retry_after >= Duration::from_secs(1));
+        assert!(limiter.try_acquire("b").is_ok());
+    }
+
+    #[test]
+    fn concurrency_frees_on_drop() {
+        let limiter = limiter(0, 1);
+        let permit = limiter.try_acquire("a").expect("first");
+        assert!(limiter.try_acquire("a").is_err());
+        assert!(limiter.try_acquire("b").is_ok());
+        drop(permit);
+        assert!(limiter.try_acquire("a").is_ok());
+    }
+
+    #[test]
+    fn idle_keys_are_pruned() {
+        let limiter = limiter(0, 1);
+        let held = limiter.try_acquire("held").expect("held");
+        for key in 0..PRUNE_THRESHOLD {
+            drop(limiter.try_acquire(&key.to_string()).expect("key"));
+        }
+        limiter.try_acquire("new").expect("new");
+        let keys = limiter.keys.lock().unwrap();
+        assert!(keys.len() < PRUNE_THRESHOLD);
+        assert!(keys.contains_key("held"));
+        drop(keys);
+        drop(held);
+    }
+
+    #[test]
+    fn disabled_without_limits() {
+        assert!(
+            !RateLimitConfig {
+                requests_per_minute: 0,
+                max_concurrent: 0
+            }
+            .is_enabled()
+        );
+    }
+}
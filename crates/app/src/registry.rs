@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+const KEY_PREFIX: &str = "rlm:session:";
+#[cfg(feature = "redis")]
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(feature = "redis")]
+const CONNECT_RETRIES: usize = 2;
+#[cfg(feature = "redis")]
+const CONNECT_RETRY_MAX_DELAY_MS: u64 = 500;
+
+// Claims an unowned key or refreshes our own claim; returns the current owner.
+#[cfg(feature = "redis")]
+const CLAIM_SCRIPT: &str = r"
+local owner = redis.call('GET', KEYS[1])
+if not owner or owner == ARGV[1] then
+  redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+  return ARGV[1]
+end
+return owner
+";
+
+/// Replica that serves a session, as stored in the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionOwner {
+    pub replica: String,
+    /// Base URL other replicas forward to; `None` makes them reject instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+pub enum Ownership {
+    Local,
+    Remote(SessionOwner),
+}
+
+/// Shared record of which replica owns each session, so sticky sessions work
+/// behind a load balancer without session affinity.
+pub struct SessionRegistry {
+    owner: SessionOwner,
+    encoded_owner: String,
+    #[cfg(feature = "redis")]
+    ttl: Duration,
+    #[cfg(feature = "redis")]
+    client: redis::Client,
+    #[cfg(feature = "redis")]
+    connection: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+}
+
+impl SessionRegistry {
+    /// Claims last for `ttl` after a session's most recent request.
+    pub fn open(url: &str, owner: SessionOwner, ttl: Duration) -> Result<Self, String> {
+        if ttl.as_secs() == 0 {
+            return Err("session registry ttl must be at least one second".to_owned());
+        }
+        let encoded_owner = serde_json::to_string(&owner).map_err(|err| err.to_string())?;
+        #[cfg(feature = "redis")]
+        {
+            let client = redis::Client::open(url)
+                .map_err(|err| format!("invalid session registry url: {err}"))?;
+            Ok(Self {
+                owner,
+                encoded_owner,
+                ttl,
+                client,
+                connection: tokio::sync::OnceCell::new(),
+            })
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            let _ = (url, owner, encoded_owner, ttl);
+            Err("session registry requires building with the `redis` feature".to_owned())
+        }
+    }
+
+    pub fn replica(&self) -> &str {
+        &self.owner.replica
+    }
+
+    /// Records this replica as the owner of `session_key` unless another live
+    /// replica already holds it.
+    pub async fn claim(&self, session_key: &str) -> Result<Ownership, String> {
+        let key = format!("{KEY_PREFIX}{session_key}");
+        let mut current = self.claim_raw(&key).await?;
+        let mut owner = decode_owner(&current)?;
+        if current != self.encoded_owner && owner.replica == self.owner.replica {
+            // This replica restarted with a different URL; take the claim back.
+            self.release(&key).await?;
+            current = self.claim_raw(&key).await?;
+            owner = decode_owner(&current)?;
+        }
+        if current == self.encoded_owner {
+            Ok(Ownership::Local)
+        } else {
+            Ok(Ownership::Remote(owner))
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    async fn claim_raw(&self, key: &str) -> Result<String, String> {
+        let mut connection = self.connection().await?;
+        redis::Script::new(CLAIM_SCRIPT)
+            .key(key)
+            .arg(&self.encoded_owner)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut connection)
+            .await
+            .map_err(|err| format!("session registry claim failed: {err}"))
+    }
+
+    #[cfg(not(feature = "redis"))]
+    async fn claim_raw(&self, _key: &str) -> Result<String, String> {
+        Ok(self.encoded_owner.clone())
+    }
+
+    #[cfg(feature = "redis")]
+    async fn release(&self, key: &str) -> Result<(), String> {
+        let mut connection = self.connection().await?;
+        redis::cmd("DEL")
+            .arg(key)
+            .query_async(&mut connection)
+            .await
+            .map_err(|err| format!("session registry release failed: {err}"))
+    }
+
+    #[cfg(not(feature = "redis"))]
+    async fn release(&self, _key: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    #[cfg(feature = "redis")]
+    async fn connection(&self) -> Result<redis::aio::ConnectionManager, String> {
+        self.connection
+            .get_or_try_init(|| {
+                let config = redis::aio::ConnectionManagerConfig::new()
+                    .set_connection_timeout(REGISTRY_TIMEOUT)
+                    .set_response_timeout(REGISTRY_TIMEOUT)
+                    .set_number_of_retries(CONNECT_RETRIES)
+                    .set_max_delay(CONNECT_RETRY_MAX_DELAY_MS);
+                redis::aio::ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await
+            .cloned()
+            .map_err(|err| format!("session registry unavailable: {err}"))
+    }
+}
+
+fn decode_owner(owner: &str) -> Result<SessionOwner, String> {
+    serde_json::from_str(owner).map_err(|err| format!("invalid session registry entry: {err}"))
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_native_tls::TlsStream;
+
+use crate::client::SandboxClient;
+use crate::launcher::worker_env;
+use crate::{LaunchError, SandboxHandle, SandboxLaunchConfig, SandboxLauncher};
+
+/// Longest preamble a daemon reads before giving up on a connection.
+pub const MAX_PREAMBLE_BYTES: u64 = 64 * 1024;
+const REMOTE_IO_THREADS: usize = 2;
+
+type RemoteStream = TlsStream<tokio::net::TcpStream>;
+
+/// Worker daemons the remote backend starts sandboxes on.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteWorkers {
+    /// `host:port` of each daemon. Launches take turns, moving on to the
+    /// next daemon when one cannot be reached.
+    pub addrs: Vec<String>,
+    /// Shared secret daemons require before starting a worker.
+    pub token: String,
+    /// PEM CA certificate to trust for daemons on top of the system roots.
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// The first line a server sends a daemon, as JSON; the worker protocol
+/// follows on the same connection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemotePreamble {
+    pub token: String,
+    /// Worker settings, as local backends pass them in its environment.
+    pub env: BTreeMap<String, String>,
+}
+
+impl RemotePreamble {
+    /// Only settings meant for the worker; anything else a server sends is
+    /// dropped rather than let into the daemon's process environment.
+    pub fn worker_env(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.env
+            .iter()
+            .filter(|(name, _)| name.starts_with("RLM_") || name.as_str() == "OPENAI_API_KEY")
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Starts each sandbox as a connection to a worker daemon on another
+/// machine, which runs a fresh worker for it and relays the protocol over
+/// TLS.
+pub(crate) struct RemoteLauncher {
+    config: SandboxLaunchConfig,
+    connector: tokio_native_tls::TlsConnector,
+    runtime: Arc<RemoteRuntime>,
+    next: AtomicUsize,
+}
+
+/// Drives TLS for every connection. Kept apart from the server's runtime,
+/// which starts after the pool.
+struct RemoteRuntime {
+    runtime: Option<tokio::runtime::Runtime>,
+    handle: tokio::runtime::Handle,
+}
+
+impl Drop for RemoteRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl RemoteLauncher {
+    pub(crate) fn new(config: SandboxLaunchConfig) -> Result<Self, String> {
+        let remote = &config.remote;
+        if remote.addrs.is_empty() {
+            return Err("the remote sandbox backend needs at least one worker daemon".to_owned());
+        }
+        if remote.token.is_empty() {
+            return Err("the remote sandbox backend needs a worker daemon token".to_owned());
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(path) = &remote.ca_cert {
+            let pem = fs::read(path)
+                .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+            let cert = native_tls::Certificate::from_pem(&pem)
+                .map_err(|err| format!("invalid CA certificate in {}: {err}", path.display()))?;
+            builder.add_root_certificate(cert);
+        }
+        let connector = builder
+            .build()
+            .map_err(|err| format!("failed to set up TLS: {err}"))?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(REMOTE_IO_THREADS)
+            .thread_name("remote-sandbox-io")
+            .enable_all()
+            .build()
+            .map_err(|err| format!("failed to start remote sandbox runtime: {err}"))?;
+        Ok(Self {
+            config,
+            connector: connector.into(),
+            runtime: Arc::new(RemoteRuntime {
+                handle: runtime.handle().clone(),
+                runtime: Some(runtime),
+            }),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn connect(&self, addr: &str, deadline: Instant) -> Result<SandboxClient, String> {
+        let socket_addr = addr
+            .to_socket_addrs()
+            .map_err(|err| format!("failed to resolve: {err}"))?
+            .next()
+            .ok_or_else(|| "address did not resolve".to_owned())?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let socket = TcpStream::connect_timeout(&socket_addr, timeout)
+            .map_err(|err| format!("failed to connect: {err}"))?;
+        let _ = socket.set_nodelay(true);
+        let control = socket
+            .try_clone()
+            .map_err(|err| format!("failed to clone socket: {err}"))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|err| format!("failed to configure socket: {err}"))?;
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let tls = self.runtime.handle.block_on(async {
+            let socket = tokio::net::TcpStream::from_std(socket)
+                .map_err(|err| format!("failed to register socket: {err}"))?;
+            match tokio::time::timeout(timeout, self.connector.connect(host, socket)).await {
+                Ok(Ok(tls)) => Ok(tls),
+                Ok(Err(err)) => Err(format!("TLS handshake failed: {err}")),
+                Err(_) => Err("TLS handshake timed out".to_owned()),
+            }
+        })?;
+        let (reader, writer) = tokio::io::split(tls);
+        let mut writer = BlockingWriter {
+            half: writer,
+            runtime: self.runtime.clone(),
+        };
+        let preamble = RemotePreamble {
+            token: self.config.remote.token.clone(),
+            env: worker_env(&self.config)
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+        };
+        let mut line = serde_json::to_vec(&preamble)
+            .map_err(|err| format!("failed to encode preamble: {err}"))?;
+        line.push(b'\n');
+        writer
+            .write_all(&line)
+            .and_then(|()| writer.flush())
+            .map_err(|err| format!("failed to send preamble: {err}"))?;
+        let name = match control.local_addr() {
+            Ok(local) => format!("tcp:{addr}/{}", local.port()),
+            Err(_) => format!("tcp:{addr}"),
+        };
+        let reader = BlockingReader {
+            half: reader,
+            runtime: self.runtime.clone(),
+        };
+        SandboxClient::remote(reader, writer, control, name, self.config.io_timeouts)
+    }
+}
+
+impl SandboxLauncher for RemoteLauncher {
+    fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+        let deadline = Instant::now() + self.config.launch_timeout;
+        let addrs = &self.config.remote.addrs;
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut errors = Vec::new();
+        for offset in 0..addrs.len() {
+            let addr = &addrs[(first + offset) % addrs.len()];
+            let started = self.connect(addr, deadline).and_then(|client| {
+                let mut client = client.with_llm_broker(self.config.llm_broker.clone());
+                client
+                    .ping(Some(deadline))
+                    .and_then(|()| client.negotiate_framing(self.config.framing, Some(deadline)))
+                    .map_err(|err| format!("sandbox did not start: {err}"))?;
+                Ok(client)
+            });
+            match started {
+                Ok(client) => return Ok(Box::new(client)),
+                Err(err) => {
+                    tracing::warn!(daemon = %addr, error = %err, "worker daemon launch failed");
+                    errors.push(format!("{addr}: {err}"));
+                }
+            }
+        }
+        Err(LaunchError::Transient(format!(
+            "no worker daemon started a sandbox: {}",
+            errors.join("; ")
+        )))
+    }
+}
+
+/// The read half of a connection for the client's blocking reader thread.
+struct BlockingReader {
+    half: ReadHalf<RemoteStream>,
+    runtime: Arc<RemoteRuntime>,
+}
+
+impl Read for BlockingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.handle.block_on(self.half.read(buf))
+    }
+}
+
+struct BlockingWriter {
+    half: WriteHalf<RemoteStream>,
+    runtime: Arc<RemoteRuntime>,
+}
+
+impl Write for BlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.handle.block_on(self.half.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.handle.block_on(self.half.flush())
+    }
+}
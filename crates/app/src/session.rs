@@ -1,21 +1,70 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use rlm::llm::FinishReason;
+use rlm::redact::{Redaction, Redactor};
+use rlm::repl::LocalValue;
+use rlm::trace::{CompletionTrace, CompletionUsage, ProgressEvent};
+use serde::Serialize;
 use serde_json::Value;
 use tokio::sync::oneshot;
+use uuid::Uuid;
 
-use crate::pool::SandboxPool;
-use crate::protocol::SandboxRunRequest;
-use crate::{SandboxHandle, SandboxLauncher};
+use crate::audit::RunAudit;
+use crate::pool::{
+    AcquireError, AcquireResult, HEALTH_CHECK_TIMEOUT, PoolConfig, PoolEvent, SandboxPool,
+    SandboxPoolMonitor, SandboxPoolStats,
+};
+use crate::protocol::{
+    RunOverrides, SandboxRunRequest, SandboxRunResult, WorkerErrorCode, WorkerStats,
+};
+use crate::snapshot_store::{SessionSnapshot, SnapshotStore};
+use crate::{SandboxError, SandboxHandle, SandboxLauncher};
+
+pub const MAX_SESSION_ID_LEN: usize = 64;
+
+const MIN_REAP_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REAP_INTERVAL: Duration = Duration::from_secs(60);
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_EVICTION_HALF_LIFE: Duration = Duration::from_secs(600);
+const ADMISSION_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SessionErrorKind {
     Overloaded,
+    NotFound,
+    Conflict,
+    Timeout,
+    /// The request's context was too large for the sandbox.
+    TooLarge,
+    /// The upstream LLM failed.
+    Upstream,
+    /// The client went away and the run was abandoned; not a failure.
+    Cancelled,
     Internal,
 }
 
+impl SessionErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Overloaded => "overloaded",
+            Self::NotFound => "not_found",
+            Self::Conflict => "conflict",
+            Self::Timeout => "timeout",
+            Self::TooLarge => "too_large",
+            Self::Upstream => "upstream",
+            Self::Cancelled => "cancelled",
+            Self::Internal => "internal",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SessionError {
     pub kind: SessionErrorKind,
@@ -30,6 +79,48 @@ impl SessionError {
         }
     }
 
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::NotFound,
+            message: message.into(),
+        }
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::Conflict,
+            message: message.into(),
+        }
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::Timeout,
+            message: message.into(),
+        }
+    }
+
+    pub fn too_large(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::TooLarge,
+            message: message.into(),
+        }
+    }
+
+    pub fn upstream(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::Upstream,
+            message: message.into(),
+        }
+    }
+
+    pub fn cancelled(message: impl Into<String>) -> Self {
+        Self {
+            kind: SessionErrorKind::Cancelled,
+            message: message.into(),
+        }
+    }
+
     pub fn internal(message: impl Into<String>) -> Self {
         Self {
             kind: SessionErrorKind::Internal,
@@ -38,24 +129,139 @@ impl SessionError {
     }
 }
 
+/// Scheduling class for a request. It only matters when the session manager
+/// falls behind: queued requests are then dispatched highest priority first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "low" | "batch" => Some(Self::Low),
+            "normal" => Some(Self::Normal),
+            "high" | "interactive" => Some(Self::High),
+            _ => None,
+        }
+    }
+}
+
+/// Charges what a run spent to whoever sent it, for runs whose sender
+/// cannot: the run failed, or nobody was left to take its response.
+#[derive(Clone)]
+pub struct UsageSink(Arc<dyn Fn(CompletionUsage) + Send + Sync>);
+
+impl UsageSink {
+    pub fn new(charge: impl Fn(CompletionUsage) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(charge))
+    }
+
+    fn charge(&self, usage: CompletionUsage) {
+        (self.0)(usage);
+    }
+}
+
+impl fmt::Debug for UsageSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UsageSink")
+    }
+}
+
+/// Tenant a session belongs to; session ids only resolve within their tenant.
+#[derive(Debug, Clone)]
+pub struct TenantScope {
+    pub name: String,
+    pub max_sessions: Option<usize>,
+}
+
 #[derive(Debug)]
 pub struct SessionRequest {
     pub session_id: String,
+    pub tenant: Option<TenantScope>,
     pub reset: bool,
     pub query: String,
     pub context: Option<Value>,
+    pub context_id: Option<String>,
     pub code: Option<String>,
+    pub include_trace: bool,
+    pub overrides: RunOverrides,
+    pub request_id: Option<String>,
+    pub span: tracing::Span,
+    pub deadline: Option<Instant>,
+    pub priority: Priority,
+    /// Start this new session as a copy of another session of the same
+    /// tenant instead of running anything.
+    pub fork_from: Option<String>,
+    pub usage_sink: Option<UsageSink>,
+    pub audit: Option<RunAudit>,
     pub respond_to: oneshot::Sender<Result<SessionResponse, SessionError>>,
 }
 
+#[derive(Debug)]
+pub struct VariableRequest {
+    pub session_id: String,
+    pub tenant: Option<TenantScope>,
+    pub name: String,
+    pub request_id: Option<String>,
+    pub span: tracing::Span,
+    pub deadline: Option<Instant>,
+    pub priority: Priority,
+    pub respond_to: oneshot::Sender<Result<String, SessionError>>,
+}
+
 #[derive(Debug)]
 pub struct SessionResponse {
     pub response: Option<String>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    pub locals: Option<Vec<LocalValue>>,
+    pub trace: Option<CompletionTrace>,
+    pub finish_reason: Option<FinishReason>,
+    pub used_fallback: bool,
+    pub usage: CompletionUsage,
+    /// The session's stats including this run.
+    pub stats: SessionStats,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Running totals for one session, kept by its actor for its lifetime.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStats {
+    pub runs: u64,
+    pub failed_runs: u64,
+    /// Time spent waiting on the sandbox across all runs.
+    pub repl_time_ms: u64,
+    pub llm_subcalls: u64,
+    pub rlm_subcalls: u64,
+    /// Tokens the sandbox's LLM calls reported.
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// What the session's sandbox worker reported after its last run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<WorkerStats>,
+}
+
+/// A live session as seen by the session manager.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub state: SessionActorState,
+    pub pending: usize,
+    pub idle_ms: u64,
+    pub stats: SessionStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SessionActorState {
     Idle,
     Busy,
@@ -66,17 +272,201 @@ pub enum SessionActorState {
 pub struct SessionConfig {
     pub max_sessions: usize,
     pub ingress_capacity: usize,
-    pub sandbox_pool_size: usize,
+    /// Requests a single session may have outstanding; 0 is unlimited.
+    pub max_pending_per_session: usize,
+    pub pool: PoolConfig,
+    pub idle_ttl: Option<Duration>,
+    pub eviction: EvictionPolicyKind,
+    /// How long a new session may wait for room at capacity before it is
+    /// rejected; zero rejects immediately.
+    pub admission_wait: Duration,
+}
+
+/// What an eviction policy knows about an idle session.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionUsage {
+    pub idle_for: Duration,
+    /// Runs dispatched to the session since it was created.
+    pub runs: u64,
+    /// Approximate size of the context the session was initialized with.
+    pub context_bytes: usize,
+}
+
+/// Ranks idle sessions when one must be evicted to admit another.
+pub trait EvictionPolicy: Send {
+    /// How much keeping the session is worth; the idle session with the
+    /// lowest value is evicted first.
+    fn retention(&self, usage: &SessionUsage) -> f64;
+}
+
+/// Evicts the least recently used session.
+pub struct LruEviction;
+
+impl EvictionPolicy for LruEviction {
+    fn retention(&self, usage: &SessionUsage) -> f64 {
+        -usage.idle_for.as_secs_f64()
+    }
+}
+
+/// Evicts the session with the fewest runs.
+pub struct LfuEviction;
+
+impl EvictionPolicy for LfuEviction {
+    fn retention(&self, usage: &SessionUsage) -> f64 {
+        usage.runs as f64
+    }
+}
+
+/// Counts runs but halves their weight every `half_life` of idleness, so busy
+/// sessions that went quiet eventually lose out to newer ones.
+pub struct TtlWeightedEviction {
+    pub half_life: Duration,
+}
+
+impl EvictionPolicy for TtlWeightedEviction {
+    fn retention(&self, usage: &SessionUsage) -> f64 {
+        let half_lives = usage.idle_for.as_secs_f64() / self.half_life.as_secs_f64().max(1.0);
+        usage.runs as f64 * 0.5f64.powf(half_lives)
+    }
+}
+
+/// Prefers to keep sessions whose context is expensive to load again,
+/// discounted by how long they have been idle.
+pub struct CostWeightedEviction;
+
+impl EvictionPolicy for CostWeightedEviction {
+    fn retention(&self, usage: &SessionUsage) -> f64 {
+        usage.context_bytes as f64 / (1.0 + usage.idle_for.as_secs_f64())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicyKind {
+    #[default]
+    Lru,
+    Lfu,
+    TtlWeighted,
+    CostWeighted,
+}
+
+impl EvictionPolicyKind {
+    /// The TTL-weighted policy uses `idle_ttl` as its half-life.
+    pub fn build(self, idle_ttl: Option<Duration>) -> Box<dyn EvictionPolicy> {
+        match self {
+            Self::Lru => Box::new(LruEviction),
+            Self::Lfu => Box::new(LfuEviction),
+            Self::TtlWeighted => Box::new(TtlWeightedEviction {
+                half_life: idle_ttl.unwrap_or(DEFAULT_EVICTION_HALF_LIFE),
+            }),
+            Self::CostWeighted => Box::new(CostWeightedEviction),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicyKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "ttl-weighted" => Ok(Self::TtlWeighted),
+            "cost-weighted" => Ok(Self::CostWeighted),
+            other => Err(format!(
+                "invalid eviction policy {other}; expected lru, lfu, ttl-weighted or cost-weighted"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Lru => "lru",
+            Self::Lfu => "lfu",
+            Self::TtlWeighted => "ttl-weighted",
+            Self::CostWeighted => "cost-weighted",
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct SessionManagerHandle {
-    sender: SyncSender<SessionRequest>,
+    sender: SyncSender<ManagerCommand>,
+    stats: Arc<ManagerStats>,
+    pool: SandboxPoolMonitor,
+}
+
+/// Point-in-time view of the session manager. Counters are totals since
+/// startup.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionManagerStats {
+    pub active_sessions: usize,
+    pub idle_sessions: usize,
+    pub queue_depth: usize,
+    pub evictions: u64,
+    pub reaped: u64,
+    pub rejected: u64,
+    pub dispatch_failures: u64,
+}
+
+/// Updated by the manager loop; gauges reflect the state after its last
+/// command.
+#[derive(Default)]
+struct ManagerStats {
+    /// Commands accepted but not yet picked up.
+    queued: AtomicUsize,
+    active_sessions: AtomicUsize,
+    idle_sessions: AtomicUsize,
+    evictions: AtomicU64,
+    reaped: AtomicU64,
+    rejected: AtomicU64,
+    dispatch_failures: AtomicU64,
+}
+
+impl ManagerStats {
+    fn count(counter: &AtomicU64, amount: usize) {
+        counter.fetch_add(amount as u64, Ordering::Relaxed);
+    }
 }
 
 impl SessionManagerHandle {
     pub fn try_dispatch(&self, request: SessionRequest) -> Result<(), SessionError> {
-        match self.sender.try_send(request) {
+        self.try_send(ManagerCommand::Dispatch(Box::new(request), None))
+    }
+
+    pub fn try_get_variable(&self, request: VariableRequest) -> Result<(), SessionError> {
+        self.try_send(ManagerCommand::GetVariable(request))
+    }
+
+    /// Commands accepted but not yet picked up by the session manager.
+    pub fn queue_depth(&self) -> usize {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> SessionManagerStats {
+        SessionManagerStats {
+            active_sessions: self.stats.active_sessions.load(Ordering::Relaxed),
+            idle_sessions: self.stats.idle_sessions.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth(),
+            evictions: self.stats.evictions.load(Ordering::Relaxed),
+            reaped: self.stats.reaped.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+            dispatch_failures: self.stats.dispatch_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn pool_stats(&self) -> SandboxPoolStats {
+        self.pool.stats()
+    }
+
+    fn try_send(&self, command: ManagerCommand) -> Result<(), SessionError> {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let result = self.sender.try_send(command);
+        if result.is_err() {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        match result {
             Ok(()) => Ok(()),
             Err(TrySendError::Full(_)) => Err(SessionError::overloaded(
                 "request queue is full; retry later",
@@ -86,48 +476,223 @@ impl SessionManagerHandle {
             }
         }
     }
+
+    /// Live sessions with their per-session stats.
+    pub async fn list_sessions(&self) -> Result<Vec<SessionSummary>, String> {
+        let (respond_to, response) = oneshot::channel();
+        self.try_send(ManagerCommand::ListSessions { respond_to })
+            .map_err(|err| err.message)?;
+        response
+            .await
+            .map_err(|_| "session manager dropped session listing".to_owned())
+    }
+
+    pub async fn check_ready(&self, deadline: Instant) -> Result<(), String> {
+        let (respond_to, response) = oneshot::channel();
+        self.try_send(ManagerCommand::CheckReady {
+            deadline,
+            respond_to,
+        })
+        .map_err(|err| err.message)?;
+        response
+            .await
+            .map_err(|_| "session manager dropped readiness check".to_owned())?
+    }
+
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), String> {
+        let (respond_to, response) = mpsc::channel();
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender
+            .send(ManagerCommand::Shutdown { respond_to })
+            .map_err(|_| "session manager unavailable".to_owned())?;
+        response
+            .recv_timeout(timeout)
+            .map_err(|_| "session manager shutdown timed out".to_owned())
+    }
+}
+
+enum ManagerCommand {
+    /// The second field is when a request waiting for room gives up.
+    Dispatch(Box<SessionRequest>, Option<Instant>),
+    GetVariable(VariableRequest),
+    ListSessions {
+        respond_to: oneshot::Sender<Vec<SessionSummary>>,
+    },
+    CheckReady {
+        deadline: Instant,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    Shutdown {
+        respond_to: Sender<()>,
+    },
+}
+
+impl ManagerCommand {
+    fn priority(&self) -> Priority {
+        match self {
+            Self::Dispatch(request, _) => request.priority,
+            Self::GetVariable(request) => request.priority,
+            Self::ListSessions { .. } | Self::CheckReady { .. } | Self::Shutdown { .. } => {
+                Priority::High
+            }
+        }
+    }
+}
+
+/// Commands pulled off the ingress channel but not yet handled, one FIFO per
+/// priority.
+#[derive(Default)]
+struct Backlog {
+    queues: [VecDeque<ManagerCommand>; Priority::COUNT],
+    len: usize,
+}
+
+impl Backlog {
+    fn push(&mut self, command: ManagerCommand) {
+        self.queues[command.priority() as usize].push_back(command);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<ManagerCommand> {
+        let command = self
+            .queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())?;
+        self.len -= 1;
+        Some(command)
+    }
 }
 
 struct ActorEntry {
     sender: Sender<ActorMessage>,
+    tenant: Option<String>,
     pending: usize,
     state: SessionActorState,
+    last_active: Instant,
+    runs: u64,
+    context_bytes: usize,
+    stats: Arc<Mutex<SessionStats>>,
+}
+
+impl ActorEntry {
+    fn usage(&self, now: Instant) -> SessionUsage {
+        SessionUsage {
+            idle_for: now.saturating_duration_since(self.last_active),
+            runs: self.runs,
+            context_bytes: self.context_bytes,
+        }
+    }
+
+    fn summary(&self, session_key: &str, now: Instant) -> SessionSummary {
+        let session_id = match &self.tenant {
+            Some(tenant) => session_key
+                .strip_prefix(tenant.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+                .unwrap_or(session_key),
+            None => session_key,
+        };
+        SessionSummary {
+            session_id: session_id.to_owned(),
+            tenant: self.tenant.clone(),
+            state: self.state,
+            pending: self.pending,
+            idle_ms: if self.pending == 0 {
+                now.saturating_duration_since(self.last_active).as_millis() as u64
+            } else {
+                0
+            },
+            stats: self
+                .stats
+                .lock()
+                .expect("session stats lock poisoned")
+                .clone(),
+        }
+    }
 }
 
 enum ActorMessage {
-    Run(ActorRequest),
+    Run(Box<ActorRequest>),
+    GetVariable(VariableRequest),
+    Snapshot(ActorSnapshotRequest),
 }
 
 struct ActorRequest {
     reset: bool,
     query: String,
     context: Option<Value>,
+    context_id: Option<String>,
     code: Option<String>,
+    include_trace: bool,
+    overrides: RunOverrides,
+    request_id: Option<String>,
+    span: tracing::Span,
+    deadline: Option<Instant>,
+    fork_from: Option<Sender<ActorMessage>>,
+    usage_sink: Option<UsageSink>,
+    audit: Option<RunAudit>,
     respond_to: oneshot::Sender<Result<SessionResponse, SessionError>>,
 }
 
+struct ActorSnapshotRequest {
+    request_id: Option<String>,
+    span: tracing::Span,
+    deadline: Option<Instant>,
+    respond_to: Sender<Result<ForkSource, SessionError>>,
+}
+
+/// What a forked session takes over from its source.
+struct ForkSource {
+    saved: SessionSnapshot,
+    /// The source's placeholder map, which its REPL state refers to.
+    redaction: Option<Arc<Redaction>>,
+}
+
+struct ActorSession {
+    handle: Box<dyn SandboxHandle>,
+    initialized: bool,
+    // Stored context the REPL was initialized from, if any.
+    context_id: Option<String>,
+}
+
 struct ActorFinished {
     session_id: String,
 }
 
 enum PoolCommand {
     Acquire {
-        respond_to: Sender<Result<Box<dyn SandboxHandle>, String>>,
+        respond_to: Sender<AcquireResult>,
     },
+    Event(PoolEvent),
     Retire {
         handle: Box<dyn SandboxHandle>,
     },
+    CheckReady {
+        deadline: Instant,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    Shutdown {
+        respond_to: Sender<()>,
+    },
 }
 
+/// With `snapshots`, each session's REPL state is saved after every run and
+/// restored when the session next runs in a fresh sandbox. With `redactor`,
+/// queries, contexts and code are redacted before they reach a sandbox, and
+/// results are restored on the way out; the placeholder map stays in this
+/// process.
 pub fn spawn_session_manager(
     config: SessionConfig,
     launcher: Box<dyn SandboxLauncher>,
+    snapshots: Option<Arc<SnapshotStore>>,
+    redactor: Option<Arc<dyn Redactor>>,
 ) -> Result<SessionManagerHandle, String> {
-    let pool = SandboxPool::new(launcher, config.sandbox_pool_size)?;
-    let pool_sender = spawn_pool_broker(pool)?;
+    let (pool_sender, pool) = spawn_pool_broker(launcher, config.pool)?;
     let (request_sender, request_receiver) =
-        mpsc::sync_channel::<SessionRequest>(config.ingress_capacity.max(1));
+        mpsc::sync_channel::<ManagerCommand>(config.ingress_capacity.max(1));
     let (finished_sender, finished_receiver) = mpsc::channel::<ActorFinished>();
+    let stats = Arc::new(ManagerStats::default());
+    let manager_stats = stats.clone();
 
     thread::Builder::new()
         .name("session-manager".to_owned())
@@ -135,72 +700,244 @@ pub fn spawn_session_manager(
             run_session_manager_loop(
                 config,
                 request_receiver,
+                manager_stats,
                 finished_receiver,
                 finished_sender,
                 pool_sender,
+                snapshots,
+                redactor,
             );
         })
         .map_err(|err| format!("failed to spawn session manager: {err}"))?;
 
     Ok(SessionManagerHandle {
         sender: request_sender,
+        stats,
+        pool,
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_session_manager_loop(
     config: SessionConfig,
-    request_receiver: Receiver<SessionRequest>,
+    request_receiver: Receiver<ManagerCommand>,
+    stats: Arc<ManagerStats>,
     finished_receiver: Receiver<ActorFinished>,
     finished_sender: Sender<ActorFinished>,
     pool_sender: Sender<PoolCommand>,
+    snapshots: Option<Arc<SnapshotStore>>,
+    redactor: Option<Arc<dyn Redactor>>,
 ) {
     let session_capacity = config.max_sessions.max(1);
     let mut actors: HashMap<String, ActorEntry> = HashMap::with_capacity(session_capacity);
-    let mut idle_lru: VecDeque<String> = VecDeque::with_capacity(session_capacity);
     let mut idle_index: HashSet<String> = HashSet::with_capacity(session_capacity);
+    let reap_interval = config
+        .idle_ttl
+        .map(|ttl| (ttl / 2).clamp(MIN_REAP_INTERVAL, MAX_REAP_INTERVAL));
+    let mut last_reap = Instant::now();
+    let mut backlog = Backlog::default();
+    let backlog_capacity = config.ingress_capacity.max(1);
+    let eviction = config.eviction.build(config.idle_ttl);
+    // New sessions parked until an actor frees up or their wait runs out.
+    // They count against the backlog capacity, so once it is full the
+    // ingress queue backs up and new requests are turned away.
+    let mut waiting: VecDeque<ManagerCommand> = VecDeque::new();
+    let mut last_admission_retry = Instant::now();
 
     loop {
-        let request = match request_receiver.recv() {
-            Ok(request) => request,
-            Err(_) => break,
+        let freed = drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 4096);
+        stats.active_sessions.store(actors.len(), Ordering::Relaxed);
+        stats
+            .idle_sessions
+            .store(idle_index.len(), Ordering::Relaxed);
+        if !waiting.is_empty()
+            && (freed > 0 || last_admission_retry.elapsed() >= ADMISSION_RETRY_INTERVAL)
+        {
+            for command in waiting.drain(..) {
+                stats.queued.fetch_add(1, Ordering::Relaxed);
+                backlog.push(command);
+            }
+            last_admission_retry = Instant::now();
+        }
+        if backlog.len == 0 {
+            let poll_interval = if waiting.is_empty() {
+                reap_interval
+            } else {
+                Some(reap_interval.map_or(ADMISSION_RETRY_INTERVAL, |interval| {
+                    interval.min(ADMISSION_RETRY_INTERVAL)
+                }))
+            };
+            match poll_interval {
+                Some(interval) => match request_receiver.recv_timeout(interval) {
+                    Ok(command) => backlog.push(command),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match request_receiver.recv() {
+                    Ok(command) => backlog.push(command),
+                    Err(_) => break,
+                },
+            }
+        }
+        // Pull in whatever else is waiting so priorities apply across it.
+        while backlog.len + waiting.len() < backlog_capacity
+            && let Ok(command) = request_receiver.try_recv()
+        {
+            backlog.push(command);
+        }
+        let command = backlog.pop();
+        if command.is_some() {
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let (Some(ttl), Some(interval)) = (config.idle_ttl, reap_interval)
+            && last_reap.elapsed() >= interval
+        {
+            drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 4096);
+            let reaped = reap_idle_actors(&mut actors, &mut idle_index, ttl);
+            ManagerStats::count(&stats.reaped, reaped);
+            if reaped > 0 {
+                tracing::info!(reaped, "reaped idle sessions");
+            }
+            last_reap = Instant::now();
+        }
+        let Some(command) = command else {
+            continue;
+        };
+        let (request, admit_by) = match command {
+            ManagerCommand::Dispatch(request, admit_by) => (request, admit_by),
+            ManagerCommand::GetVariable(request) => {
+                drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 4096);
+                dispatch_variable_request(
+                    &mut actors,
+                    &mut idle_index,
+                    &stats,
+                    request,
+                    config.max_pending_per_session,
+                );
+                continue;
+            }
+            ManagerCommand::ListSessions { respond_to } => {
+                drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 4096);
+                let now = Instant::now();
+                let _ = respond_to.send(
+                    actors
+                        .iter()
+                        .map(|(session_key, entry)| entry.summary(session_key, now))
+                        .collect(),
+                );
+                continue;
+            }
+            ManagerCommand::CheckReady {
+                deadline,
+                respond_to,
+            } => {
+                if let Err(err) = pool_sender.send(PoolCommand::CheckReady {
+                    deadline,
+                    respond_to,
+                }) && let PoolCommand::CheckReady { respond_to, .. } = err.0
+                {
+                    let _ = respond_to.send(Err("sandbox pool unavailable".to_owned()));
+                }
+                continue;
+            }
+            ManagerCommand::Shutdown { respond_to } => {
+                actors.clear();
+                let (pool_done, pool_response) = mpsc::channel();
+                if pool_sender
+                    .send(PoolCommand::Shutdown {
+                        respond_to: pool_done,
+                    })
+                    .is_ok()
+                {
+                    let _ = pool_response.recv();
+                }
+                let _ = respond_to.send(());
+                return;
+            }
+        };
+        drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 4096);
+
+        let session_id = tenant_session_key(request.tenant.as_ref(), request.session_id.clone());
+        let fork_source = match &request.fork_from {
+            None => None,
+            Some(source) => {
+                let source = tenant_session_key(request.tenant.as_ref(), source.clone());
+                if actors.contains_key(&session_id) {
+                    let _ = request
+                        .respond_to
+                        .send(Err(SessionError::conflict("session already exists")));
+                    continue;
+                }
+                let Some(source_entry) = actors.get(&source) else {
+                    let _ = request
+                        .respond_to
+                        .send(Err(SessionError::not_found("session not found")));
+                    continue;
+                };
+                Some((source, source_entry.sender.clone()))
+            }
         };
-        drain_finished_events(
-            &finished_receiver,
-            &mut actors,
-            &mut idle_lru,
-            &mut idle_index,
-            4096,
-        );
-        let SessionRequest {
-            session_id,
-            reset,
-            query,
-            context,
-            code,
-            respond_to,
-        } = request;
 
         if !actors.contains_key(&session_id) {
-            if !evict_until_capacity(
+            let rejection = if let Some(tenant) = &request.tenant
+                && let Some(max_sessions) = tenant.max_sessions
+                && !evict_tenant_until_quota(
+                    &mut actors,
+                    &mut idle_index,
+                    &stats,
+                    eviction.as_ref(),
+                    &tenant.name,
+                    max_sessions.max(1),
+                ) {
+                Some(format!(
+                    "tenant {} reached its session quota; no idle session available",
+                    tenant.name
+                ))
+            } else if !evict_until_capacity(
                 &mut actors,
-                &mut idle_lru,
                 &mut idle_index,
+                &stats,
+                eviction.as_ref(),
                 config.max_sessions.max(1),
             ) {
-                let _ = respond_to.send(Err(SessionError::overloaded(
-                    "max sessions reached; no idle session available",
-                )));
+                Some("max sessions reached; no idle session available".to_owned())
+            } else {
+                None
+            };
+            if let Some(message) = rejection {
+                let now = Instant::now();
+                let admit_by = admit_by.unwrap_or(now + config.admission_wait);
+                let admit_by = request
+                    .deadline
+                    .map_or(admit_by, |deadline| deadline.min(admit_by));
+                if now < admit_by
+                    && !request.respond_to.is_closed()
+                    && backlog.len + waiting.len() < backlog_capacity
+                {
+                    waiting.push_back(ManagerCommand::Dispatch(request, Some(admit_by)));
+                    continue;
+                }
+                ManagerStats::count(&stats.rejected, 1);
+                let _ = request
+                    .respond_to
+                    .send(Err(SessionError::overloaded(message)));
                 continue;
             }
 
+            let session_stats = Arc::new(Mutex::new(SessionStats::default()));
             let actor_sender = match spawn_session_actor(
                 session_id.clone(),
                 finished_sender.clone(),
                 pool_sender.clone(),
+                snapshots.clone(),
+                redactor.clone(),
+                config.pool,
+                session_stats.clone(),
             ) {
                 Ok(sender) => sender,
                 Err(err) => {
-                    let _ = respond_to.send(Err(SessionError::internal(err)));
+                    ManagerStats::count(&stats.dispatch_failures, 1);
+                    let _ = request.respond_to.send(Err(SessionError::internal(err)));
                     continue;
                 }
             };
@@ -208,233 +945,1695 @@ fn run_session_manager_loop(
                 session_id.clone(),
                 ActorEntry {
                     sender: actor_sender,
+                    tenant: request.tenant.as_ref().map(|tenant| tenant.name.clone()),
                     pending: 0,
                     state: SessionActorState::Idle,
+                    last_active: Instant::now(),
+                    runs: 0,
+                    context_bytes: request.context.as_ref().map_or(0, approx_json_bytes),
+                    stats: session_stats,
                 },
             );
         }
+        let SessionRequest {
+            session_id: _,
+            tenant: _,
+            reset,
+            query,
+            context,
+            context_id,
+            code,
+            include_trace,
+            overrides,
+            request_id,
+            span,
+            deadline,
+            priority: _,
+            fork_from: _,
+            usage_sink,
+            audit,
+            respond_to,
+        } = *request;
 
+        // Admitting the fork may have evicted its source; the sender clone
+        // keeps that actor alive long enough to answer the snapshot.
+        if let Some((source, _)) = &fork_source
+            && let Some(source_entry) = actors.get_mut(source)
+        {
+            remove_from_idle_set(&mut idle_index, source);
+            source_entry.pending += 1;
+            source_entry.state = SessionActorState::Busy;
+        }
         let entry = actors
             .get_mut(&session_id)
             .expect("session actor inserted before dispatch");
+        if at_pending_limit(entry, config.max_pending_per_session) {
+            ManagerStats::count(&stats.rejected, 1);
+            let _ = respond_to.send(Err(SessionError::overloaded(
+                "too many pending requests for this session; retry later",
+            )));
+            continue;
+        }
 
-        remove_from_idle_lru(&mut idle_index, &session_id);
+        remove_from_idle_set(&mut idle_index, &session_id);
         entry.pending += 1;
+        entry.runs += 1;
+        entry.last_active = Instant::now();
+        if reset {
+            entry.context_bytes = context.as_ref().map_or(0, approx_json_bytes);
+        }
         entry.state = if reset {
             SessionActorState::ResetPending
         } else {
             SessionActorState::Busy
         };
 
-        if let Err(err) = entry.sender.send(ActorMessage::Run(ActorRequest {
+        if let Err(err) = entry.sender.send(ActorMessage::Run(Box::new(ActorRequest {
             reset,
             query,
             context,
+            context_id,
             code,
+            include_trace,
+            overrides,
+            request_id,
+            span,
+            deadline,
+            fork_from: fork_source.map(|(_, sender)| sender),
+            usage_sink,
+            audit,
             respond_to,
-        })) {
-            let ActorMessage::Run(actor_request) = err.0;
-            let _ = actor_request
-                .respond_to
-                .send(Err(SessionError::internal("failed to dispatch to actor")));
+        }))) {
+            ManagerStats::count(&stats.dispatch_failures, 1);
+            if let ActorMessage::Run(actor_request) = err.0 {
+                let _ = actor_request
+                    .respond_to
+                    .send(Err(SessionError::internal("failed to dispatch to actor")));
+            }
             actors.remove(&session_id);
-            remove_from_idle_lru(&mut idle_index, &session_id);
-        }
-        drain_finished_events(
-            &finished_receiver,
-            &mut actors,
-            &mut idle_lru,
-            &mut idle_index,
-            512,
-        );
+            remove_from_idle_set(&mut idle_index, &session_id);
+        }
+        drain_finished_events(&finished_receiver, &mut actors, &mut idle_index, 512);
     }
 
     actors.clear();
 }
 
+fn dispatch_variable_request(
+    actors: &mut HashMap<String, ActorEntry>,
+    idle_index: &mut HashSet<String>,
+    stats: &ManagerStats,
+    request: VariableRequest,
+    max_pending: usize,
+) {
+    let session_id = tenant_session_key(request.tenant.as_ref(), request.session_id.clone());
+    let Some(entry) = actors.get_mut(&session_id) else {
+        let _ = request
+            .respond_to
+            .send(Err(SessionError::not_found("session not found")));
+        return;
+    };
+    if at_pending_limit(entry, max_pending) {
+        ManagerStats::count(&stats.rejected, 1);
+        let _ = request.respond_to.send(Err(SessionError::overloaded(
+            "too many pending requests for this session; retry later",
+        )));
+        return;
+    }
+
+    remove_from_idle_set(idle_index, &session_id);
+    entry.pending += 1;
+    entry.last_active = Instant::now();
+    if entry.state == SessionActorState::Idle {
+        entry.state = SessionActorState::Busy;
+    }
+
+    if let Err(err) = entry.sender.send(ActorMessage::GetVariable(request)) {
+        ManagerStats::count(&stats.dispatch_failures, 1);
+        if let ActorMessage::GetVariable(request) = err.0 {
+            let _ = request
+                .respond_to
+                .send(Err(SessionError::internal("failed to dispatch to actor")));
+        }
+        actors.remove(&session_id);
+        remove_from_idle_set(idle_index, &session_id);
+    }
+}
+
+fn at_pending_limit(entry: &ActorEntry, max_pending: usize) -> bool {
+    max_pending > 0 && entry.pending >= max_pending
+}
+
 fn evict_until_capacity(
     actors: &mut HashMap<String, ActorEntry>,
-    idle_lru: &mut VecDeque<String>,
     idle_index: &mut HashSet<String>,
+    stats: &ManagerStats,
+    eviction: &dyn EvictionPolicy,
     max_sessions: usize,
 ) -> bool {
     while actors.len() >= max_sessions {
-        if !evict_oldest_idle_actor(actors, idle_lru, idle_index) {
+        if !evict_idle_actor(actors, idle_index, eviction, |_| true) {
             return false;
         }
+        ManagerStats::count(&stats.evictions, 1);
     }
     true
 }
 
-fn drain_finished_events(
-    finished_receiver: &Receiver<ActorFinished>,
+fn evict_tenant_until_quota(
     actors: &mut HashMap<String, ActorEntry>,
-    idle_lru: &mut VecDeque<String>,
     idle_index: &mut HashSet<String>,
-    max_batch: usize,
-) {
-    let mut drained = 0usize;
-    while drained < max_batch {
-        let finished = match finished_receiver.try_recv() {
-            Ok(finished) => finished,
-            Err(_) => break,
-        };
-        drained += 1;
-        let Some(entry) = actors.get_mut(&finished.session_id) else {
-            continue;
-        };
-        entry.pending = entry.pending.saturating_sub(1);
-        if entry.pending == 0 {
-            entry.state = SessionActorState::Idle;
-            if idle_index.insert(finished.session_id.clone()) {
-                idle_lru.push_back(finished.session_id);
-            }
-        } else {
-            entry.state = SessionActorState::Busy;
+    stats: &ManagerStats,
+    eviction: &dyn EvictionPolicy,
+    tenant: &str,
+    max_sessions: usize,
+) -> bool {
+    let owned_by_tenant = |entry: &ActorEntry| entry.tenant.as_deref() == Some(tenant);
+    while actors
+        .values()
+        .filter(|entry| owned_by_tenant(entry))
+        .count()
+        >= max_sessions
+    {
+        if !evict_idle_actor(actors, idle_index, eviction, owned_by_tenant) {
+            return false;
         }
+        ManagerStats::count(&stats.evictions, 1);
     }
+    true
 }
 
-fn evict_oldest_idle_actor(
+/// Evicts the idle session the policy values least, breaking ties by least
+/// recent use.
+fn evict_idle_actor(
     actors: &mut HashMap<String, ActorEntry>,
-    idle_lru: &mut VecDeque<String>,
     idle_index: &mut HashSet<String>,
+    eviction: &dyn EvictionPolicy,
+    eligible: impl Fn(&ActorEntry) -> bool,
 ) -> bool {
-    while let Some(session_id) = idle_lru.pop_front() {
-        if !idle_index.remove(&session_id) {
+    let now = Instant::now();
+    let victim = actors
+        .iter()
+        .filter(|(session_id, entry)| {
+            entry.pending == 0 && idle_index.contains(*session_id) && eligible(entry)
+        })
+        .map(|(session_id, entry)| {
+            (
+                eviction.retention(&entry.usage(now)),
+                entry.last_active,
+                session_id,
+            )
+        })
+        .min_by(|left, right| left.0.total_cmp(&right.0).then(left.1.cmp(&right.1)))
+        .map(|(_, _, session_id)| session_id.clone());
+    let Some(session_id) = victim else {
+        return false;
+    };
+    idle_index.remove(&session_id);
+    actors.remove(&session_id);
+    true
+}
+
+pub fn tenant_session_key(tenant: Option<&TenantScope>, session_id: String) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{session_id}", tenant.name),
+        None => session_id,
+    }
+}
+
+fn drain_finished_events(
+    finished_receiver: &Receiver<ActorFinished>,
+    actors: &mut HashMap<String, ActorEntry>,
+    idle_index: &mut HashSet<String>,
+    max_batch: usize,
+) -> usize {
+    let mut drained = 0usize;
+    while drained < max_batch {
+        let finished = match finished_receiver.try_recv() {
+            Ok(finished) => finished,
+            Err(_) => break,
+        };
+        drained += 1;
+        let Some(entry) = actors.get_mut(&finished.session_id) else {
             continue;
+        };
+        entry.pending = entry.pending.saturating_sub(1);
+        entry.last_active = Instant::now();
+        if entry.pending == 0 {
+            entry.state = SessionActorState::Idle;
+            idle_index.insert(finished.session_id);
+        } else {
+            entry.state = SessionActorState::Busy;
         }
-        let is_idle = actors
-            .get(&session_id)
-            .is_some_and(|entry| entry.pending == 0);
-        if !is_idle {
-            continue;
+    }
+    drained
+}
+
+fn reap_idle_actors(
+    actors: &mut HashMap<String, ActorEntry>,
+    idle_index: &mut HashSet<String>,
+    ttl: Duration,
+) -> usize {
+    let now = Instant::now();
+    let expired: Vec<String> = actors
+        .iter()
+        .filter(|(_, entry)| entry.pending == 0 && now.duration_since(entry.last_active) >= ttl)
+        .map(|(session_id, _)| session_id.clone())
+        .collect();
+    for session_id in &expired {
+        // Dropping the actor's sender ends its loop, which retires the sandbox.
+        actors.remove(session_id);
+        idle_index.remove(session_id);
+    }
+    expired.len()
+}
+
+/// Cheap estimate of a context's encoded size.
+fn approx_json_bytes(value: &Value) -> usize {
+    match value {
+        Value::String(text) => text.len() + 2,
+        Value::Array(items) => items.iter().map(approx_json_bytes).sum::<usize>() + items.len() + 1,
+        Value::Object(map) => {
+            map.iter()
+                .map(|(key, value)| key.len() + 4 + approx_json_bytes(value))
+                .sum::<usize>()
+                + 1
         }
-        actors.remove(&session_id);
-        return true;
+        _ => 8,
     }
-    false
 }
 
-fn remove_from_idle_lru(idle_index: &mut HashSet<String>, session_id: &str) {
+fn remove_from_idle_set(idle_index: &mut HashSet<String>, session_id: &str) {
     idle_index.remove(session_id);
 }
 
-fn spawn_pool_broker(mut pool: SandboxPool) -> Result<Sender<PoolCommand>, String> {
+fn spawn_pool_broker(
+    launcher: Box<dyn SandboxLauncher>,
+    config: PoolConfig,
+) -> Result<(Sender<PoolCommand>, SandboxPoolMonitor), String> {
     let (sender, receiver) = mpsc::channel::<PoolCommand>();
+    let event_sender = sender.clone();
+    let mut pool = SandboxPool::new(launcher, config, move |event| {
+        let _ = event_sender.send(PoolCommand::Event(event));
+    })?;
+    let monitor = pool.monitor();
     thread::Builder::new()
         .name("pool-broker".to_owned())
         .spawn(move || {
-            while let Ok(command) = receiver.recv() {
+            loop {
+                let command = match pool.next_maintenance() {
+                    Some(at) => {
+                        match receiver.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                            Ok(command) => Some(command),
+                            Err(RecvTimeoutError::Timeout) => None,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    None => match receiver.recv() {
+                        Ok(command) => Some(command),
+                        Err(_) => break,
+                    },
+                };
+                pool.maintain();
+                let Some(command) = command else {
+                    pool.publish_stats();
+                    continue;
+                };
                 match command {
                     PoolCommand::Acquire { respond_to } => {
-                        let _ = respond_to.send(pool.acquire());
+                        pool.acquire(respond_to);
+                    }
+                    PoolCommand::Event(event) => {
+                        pool.receive(event);
                     }
                     PoolCommand::Retire { handle } => {
                         pool.retire(handle);
                     }
+                    PoolCommand::CheckReady {
+                        deadline,
+                        respond_to,
+                    } => {
+                        pool.check_ready(deadline, move |result| {
+                            let _ = respond_to.send(result);
+                        });
+                    }
+                    PoolCommand::Shutdown { respond_to } => {
+                        pool.shutdown();
+                        pool.publish_stats();
+                        let _ = respond_to.send(());
+                        break;
+                    }
                 }
+                pool.publish_stats();
             }
         })
         .map_err(|err| format!("failed to spawn pool broker: {err}"))?;
-    Ok(sender)
+    Ok((sender, monitor))
 }
 
 fn spawn_session_actor(
     session_id: String,
     finished_sender: Sender<ActorFinished>,
     pool_sender: Sender<PoolCommand>,
+    snapshots: Option<Arc<SnapshotStore>>,
+    redactor: Option<Arc<dyn Redactor>>,
+    pool: PoolConfig,
+    stats: Arc<Mutex<SessionStats>>,
 ) -> Result<Sender<ActorMessage>, String> {
     let (sender, receiver) = mpsc::channel::<ActorMessage>();
     thread::Builder::new()
         .name(format!("session-actor-{session_id}"))
         .spawn(move || {
-            run_session_actor_loop(session_id, receiver, finished_sender, pool_sender);
+            run_session_actor_loop(
+                session_id,
+                receiver,
+                finished_sender,
+                pool_sender,
+                snapshots,
+                redactor,
+                pool,
+                &stats,
+            );
         })
         .map_err(|err| format!("failed to spawn session actor: {err}"))?;
     Ok(sender)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_session_actor_loop(
     session_id: String,
     receiver: Receiver<ActorMessage>,
     finished_sender: Sender<ActorFinished>,
     pool_sender: Sender<PoolCommand>,
+    snapshots: Option<Arc<SnapshotStore>>,
+    redactor: Option<Arc<dyn Redactor>>,
+    pool: PoolConfig,
+    stats: &Mutex<SessionStats>,
 ) {
-    let mut session: Option<(Box<dyn SandboxHandle>, bool)> = None;
+    let mut session: Option<ActorSession> = None;
+    let mut redaction = redactor.map(|redactor| Arc::new(Redaction::new(Some(redactor))));
 
-    while let Ok(message) = receiver.recv() {
-        let ActorMessage::Run(request) = message;
-        let _ = run_actor_request(&pool_sender, &mut session, request);
+    loop {
+        // A held sandbox gets the same health checks as idle ones in the pool.
+        let message = match pool.health_interval.filter(|_| session.is_some()) {
+            Some(interval) => match receiver.recv_timeout(interval) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    check_held_sandbox(&pool_sender, &session_id, &mut session);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
+        let (kind, request_id, parent) = match &message {
+            ActorMessage::Run(request) => ("run", &request.request_id, &request.span),
+            ActorMessage::GetVariable(request) => {
+                ("get_variable", &request.request_id, &request.span)
+            }
+            ActorMessage::Snapshot(request) => ("snapshot", &request.request_id, &request.span),
+        };
+        let _span = tracing::info_span!(
+            parent: parent,
+            "session",
+            session_id = %session_id,
+            request_id = request_id.as_deref().unwrap_or("-"),
+            kind
+        )
+        .entered();
+        let start = Instant::now();
+        let result = match message {
+            ActorMessage::Run(request) => run_actor_request(
+                &pool_sender,
+                &mut session,
+                &mut redaction,
+                snapshots.as_deref(),
+                pool,
+                stats,
+                &session_id,
+                *request,
+            ),
+            ActorMessage::GetVariable(request) => {
+                read_actor_variable(&pool_sender, &mut session, redaction.as_deref(), request)
+            }
+            ActorMessage::Snapshot(request) => {
+                snapshot_actor(&pool_sender, &mut session, &redaction, request)
+            }
+        };
+        record_outcome(kind, start.elapsed(), result, stats);
         let _ = finished_sender.send(ActorFinished {
             session_id: session_id.clone(),
         });
     }
 
-    if let Some((handle, _)) = session.take() {
-        retire_handle(&pool_sender, handle);
+    if let Some(session) = session.take() {
+        retire_handle(&pool_sender, session.handle);
+    }
+}
+
+/// Logs how a request ended and counts failed runs. A run the client
+/// abandoned is not a failure.
+fn record_outcome(
+    kind: &str,
+    latency: Duration,
+    result: Result<(), SessionError>,
+    stats: &Mutex<SessionStats>,
+) {
+    let latency_ms = latency.as_millis() as u64;
+    match result {
+        Ok(()) => tracing::info!(latency_ms, "session request finished"),
+        Err(err) if err.kind == SessionErrorKind::Cancelled => {
+            tracing::info!(latency_ms, "session request cancelled by the client");
+        }
+        Err(err) => {
+            tracing::warn!(
+                latency_ms,
+                error = %err.message,
+                "session request failed"
+            );
+            if kind == "run" {
+                let mut stats = stats.lock().expect("session stats lock poisoned");
+                stats.failed_runs += 1;
+                stats.last_error = Some(err.message);
+            }
+        }
     }
 }
 
+/// Pings the sandbox an idle session holds and drops it if it fails; the
+/// next run starts in a fresh sandbox, from the session's snapshot if any.
+fn check_held_sandbox(
+    pool_sender: &Sender<PoolCommand>,
+    session_id: &str,
+    session: &mut Option<ActorSession>,
+) {
+    let Some(active) = session else {
+        return;
+    };
+    let result = if active.handle.exited() {
+        Err(SandboxError::Unresponsive(
+            "sandbox process exited".to_owned(),
+        ))
+    } else {
+        active
+            .handle
+            .ping(Some(Instant::now() + HEALTH_CHECK_TIMEOUT))
+    };
+    if let Err(err) = result {
+        tracing::warn!(
+            session_id,
+            sandbox = %active.handle.identifier(),
+            error = %err,
+            "held sandbox failed health check; dropping it"
+        );
+        if let Some(active) = session.take() {
+            retire_handle(pool_sender, active.handle);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_actor_request(
     pool_sender: &Sender<PoolCommand>,
-    session: &mut Option<(Box<dyn SandboxHandle>, bool)>,
-    request: ActorRequest,
+    session: &mut Option<ActorSession>,
+    redaction: &mut Option<Arc<Redaction>>,
+    snapshots: Option<&SnapshotStore>,
+    pool: PoolConfig,
+    stats: &Mutex<SessionStats>,
+    session_key: &str,
+    mut request: ActorRequest,
 ) -> Result<(), SessionError> {
-    if request.reset
-        && let Some((handle, _)) = session.take()
-    {
-        retire_handle(pool_sender, handle);
+    if deadline_expired(request.deadline) {
+        let err = SessionError::timeout("request timed out before it could run");
+        let _ = request.respond_to.send(Err(err.clone()));
+        return Err(err);
+    }
+    if request.respond_to.is_closed() {
+        return Err(SessionError::cancelled(
+            "client disconnected before the request ran",
+        ));
+    }
+    if let Some(source) = request.fork_from.take() {
+        let result = fork_actor(
+            pool_sender,
+            session,
+            redaction,
+            snapshots,
+            session_key,
+            &request,
+            source,
+        );
+        let status = result.as_ref().map(|_| ()).map_err(Clone::clone);
+        let _ = request.respond_to.send(result);
+        return status;
+    }
+
+    let context_changed = request.context_id.is_some()
+        && session
+            .as_ref()
+            .is_some_and(|session| session.initialized && session.context_id != request.context_id);
+    if request.reset || context_changed {
+        if let Some(mut active) = session.take() {
+            // A sandbox the pool would reuse anyway is wiped in place, which
+            // saves the session a trip through the pool.
+            let reusable = pool.reuse && !pool.recycle.worn_out(&active.handle.usage());
+            match reusable.then(|| active.handle.reset(request.deadline)) {
+                Some(Ok(())) => {
+                    active.initialized = false;
+                    active.context_id = None;
+                    *session = Some(active);
+                }
+                Some(Err(err)) => {
+                    tracing::warn!(error = %err, "failed to reset sandbox in place");
+                    retire_handle(pool_sender, active.handle);
+                }
+                None => retire_handle(pool_sender, active.handle),
+            }
+        }
+        if let Some(snapshots) = snapshots {
+            snapshots.remove(session_key);
+        }
+        // A fresh map, rather than clearing the old one, which a forked
+        // session may share.
+        *redaction = redaction
+            .as_deref()
+            .map(|redaction| Arc::new(redaction.renewed()));
     }
 
     if session.is_none() {
-        let handle = acquire_handle(pool_sender).map_err(SessionError::internal)?;
-        *session = Some((handle, false));
+        match open_actor_session(pool_sender, snapshots, session_key, &request) {
+            Ok(active) => *session = Some(active),
+            Err(err) => {
+                let _ = request.respond_to.send(Err(err.clone()));
+                return Err(err);
+            }
+        }
     }
 
-    let (handle, initialized) = session.as_mut().expect("session initialized");
-    let initialize = !*initialized;
+    // Code is audited as the caller sent it, before redaction.
+    let audit_code = request.audit.as_ref().and(request.code.clone());
+    if let Some(redaction) = redaction.as_deref() {
+        request.query = redaction.redact_text(&request.query);
+        if let Some(context) = &mut request.context {
+            redaction.redact_value(context);
+        }
+        request.code = request.code.map(|code| redaction.redact_text(&code));
+    }
+    let active = session.as_mut().expect("session initialized");
+    active.handle.set_redaction(redaction.clone());
+    let initialize = !active.initialized;
+    let request_id = request.request_id.clone();
     let run_request = SandboxRunRequest {
         initialize,
         query: request.query,
         context: request.context,
+        context_path: None,
+        staged_context: false,
         code: request.code,
+        request_id: request.request_id,
+        trace_context: None,
+        include_trace: request.include_trace,
+        deadline_ms: remaining_ms(request.deadline),
+        overrides: request.overrides,
     };
 
-    match handle.run(run_request) {
-        Ok(result) => {
+    let respond_to = &request.respond_to;
+    let cancelled = || respond_to.is_closed();
+    let progress = |event: ProgressEvent| tracing::debug!(?event, "sandbox progress");
+    let start = Instant::now();
+    // A session without REPL state loses nothing by moving to another
+    // sandbox, so a wedged one gets a single retry.
+    let retry_request = initialize.then(|| run_request.clone());
+    let mut result = active
+        .handle
+        .run(run_request, request.deadline, &cancelled, &progress);
+    if let Err(err) = &result
+        && err.is_retryable()
+        && let Some(mut retry_request) = retry_request
+        && !deadline_expired(request.deadline)
+    {
+        tracing::warn!(error = %err, "sandbox unresponsive; retrying in a fresh sandbox");
+        match acquire_handle(pool_sender, session_key, request.deadline) {
+            Ok(mut handle) => {
+                handle.set_redaction(redaction.clone());
+                retire_handle(pool_sender, std::mem::replace(&mut active.handle, handle));
+                retry_request.deadline_ms = remaining_ms(request.deadline);
+                result = active
+                    .handle
+                    .run(retry_request, request.deadline, &cancelled, &progress);
+            }
+            Err(err) => tracing::warn!(error = %err.message, "no sandbox to retry in"),
+        }
+    }
+    let session_stats = {
+        let mut stats = stats.lock().expect("session stats lock poisoned");
+        stats.runs += 1;
+        stats.repl_time_ms += start.elapsed().as_millis() as u64;
+        match &result {
+            Ok(result) => {
+                stats.llm_subcalls += result.llm_subcalls;
+                stats.rlm_subcalls += result.rlm_subcalls;
+                stats.prompt_tokens += result.usage.prompt_tokens;
+                stats.completion_tokens += result.usage.completion_tokens;
+                stats.sandbox = result.stats;
+            }
+            Err(err) => {
+                let usage = err.usage().unwrap_or_default();
+                stats.prompt_tokens += usage.prompt_tokens;
+                stats.completion_tokens += usage.completion_tokens;
+            }
+        }
+        stats.clone()
+    };
+    match result {
+        Ok(mut result) => {
             if initialize {
-                *initialized = true;
+                active.initialized = true;
+                active.context_id = request.context_id;
+            }
+            if let Some(redaction) = redaction.as_deref() {
+                restore_result(redaction, &mut result);
             }
-            let _ = request.respond_to.send(Ok(SessionResponse {
+            if let Some(audit) = &request.audit {
+                match (&audit_code, &result.trace) {
+                    (Some(code), _) => {
+                        let output = format!(
+                            "{}{}",
+                            result.stdout.as_deref().unwrap_or_default(),
+                            result.stderr.as_deref().unwrap_or_default()
+                        );
+                        audit.execute(code, &output, "ok", None);
+                    }
+                    (None, Some(trace)) => audit.completion(trace, "ok", None),
+                    (None, None) => {}
+                }
+            }
+            let sent = request.respond_to.send(Ok(SessionResponse {
                 response: result.response,
                 stdout: result.stdout,
                 stderr: result.stderr,
+                locals: result.locals,
+                trace: result.trace,
+                finish_reason: result.finish_reason,
+                used_fallback: result.used_fallback,
+                usage: result.usage,
+                stats: session_stats,
             }));
+            if let (Err(Ok(unsent)), Some(sink)) = (sent, &request.usage_sink) {
+                sink.charge(unsent.usage);
+            }
+            if let Some(snapshots) = snapshots {
+                save_snapshot(active, snapshots, session_key, request_id);
+                // The saved snapshot carries the session into a fresh
+                // sandbox on its next run.
+                if pool.recycle.worn_out(&active.handle.usage())
+                    && snapshots.get(session_key).is_some()
+                    && let Some(worn) = session.take()
+                {
+                    tracing::info!("recycling worn sandbox");
+                    retire_handle(pool_sender, worn.handle);
+                }
+            } else if pool.recycle.worn_out(&active.handle.usage()) {
+                move_to_fresh_sandbox(pool_sender, active, session_key, request_id);
+            }
             Ok(())
         }
         Err(err) => {
-            if let Some((failed_handle, _)) = session.take() {
-                retire_handle(pool_sender, failed_handle);
+            if let Some(failed) = session.take() {
+                retire_handle(pool_sender, failed.handle);
+            }
+            if let (Some(usage), Some(sink)) = (err.usage(), &request.usage_sink) {
+                sink.charge(usage);
+            }
+            let mut trace = err.report().and_then(|report| report.trace.clone());
+            let err = sandbox_session_error(err, request.deadline);
+            if let Some(audit) = &request.audit {
+                let outcome = err.kind.as_str();
+                match (&audit_code, &mut trace) {
+                    (Some(code), _) => audit.execute(code, "", outcome, Some(&err.message)),
+                    (None, Some(trace)) => {
+                        if let Some(redaction) = redaction.as_deref() {
+                            restore_trace(redaction, trace);
+                        }
+                        audit.completion(trace, outcome, Some(&err.message));
+                    }
+                    (None, None) => {}
+                }
+            }
+            let _ = request.respond_to.send(Err(err.clone()));
+            Err(err)
+        }
+    }
+}
+
+/// Starts a sandbox for the session, restored from its snapshot when one
+/// matches the request.
+fn open_actor_session(
+    pool_sender: &Sender<PoolCommand>,
+    snapshots: Option<&SnapshotStore>,
+    session_key: &str,
+    request: &ActorRequest,
+) -> Result<ActorSession, SessionError> {
+    let mut active = ActorSession {
+        handle: acquire_handle(pool_sender, session_key, request.deadline)?,
+        initialized: false,
+        context_id: None,
+    };
+    if !request.reset
+        && let Some(saved) = snapshots.and_then(|snapshots| snapshots.get(session_key))
+        && (request.context_id.is_none() || saved.context_id == request.context_id)
+    {
+        match active.handle.restore(
+            saved.snapshot.clone(),
+            request.request_id.clone(),
+            request.deadline,
+        ) {
+            Ok(()) => {
+                tracing::info!("restored session from snapshot");
+                active.initialized = true;
+                active.context_id = saved.context_id.clone();
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to restore session snapshot; starting fresh");
+                retire_handle(pool_sender, active.handle);
+                active = ActorSession {
+                    handle: acquire_handle(pool_sender, session_key, request.deadline)?,
+                    initialized: false,
+                    context_id: None,
+                };
             }
-            let _ = request
-                .respond_to
-                .send(Err(SessionError::internal(err.clone())));
-            Err(SessionError::internal(err))
         }
     }
+    Ok(active)
 }
 
-fn acquire_handle(pool_sender: &Sender<PoolCommand>) -> Result<Box<dyn SandboxHandle>, String> {
+/// Seeds a new session with a copy of `source`'s REPL state.
+fn fork_actor(
+    pool_sender: &Sender<PoolCommand>,
+    session: &mut Option<ActorSession>,
+    redaction: &mut Option<Arc<Redaction>>,
+    snapshots: Option<&SnapshotStore>,
+    session_key: &str,
+    request: &ActorRequest,
+    source: Sender<ActorMessage>,
+) -> Result<SessionResponse, SessionError> {
+    let (respond_to, response) = mpsc::channel();
+    source
+        .send(ActorMessage::Snapshot(ActorSnapshotRequest {
+            request_id: request.request_id.clone(),
+            span: tracing::Span::current(),
+            deadline: request.deadline,
+            respond_to,
+        }))
+        .map_err(|_| SessionError::not_found("session not found"))?;
+    drop(source);
+    let ForkSource {
+        saved,
+        redaction: source_redaction,
+    } = match request.deadline {
+        Some(deadline) => response
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| SessionError::timeout("timed out waiting for the source session"))?,
+        None => response
+            .recv()
+            .map_err(|_| SessionError::internal("source session dropped the snapshot"))?,
+    }?;
+
+    if let Some(previous) = session.take() {
+        retire_handle(pool_sender, previous.handle);
+    }
+    let mut handle = acquire_handle(pool_sender, session_key, request.deadline)?;
+    if let Err(err) = handle.restore(
+        saved.snapshot.clone(),
+        request.request_id.clone(),
+        request.deadline,
+    ) {
+        retire_handle(pool_sender, handle);
+        return Err(sandbox_session_error(err, request.deadline));
+    }
+    *session = Some(ActorSession {
+        handle,
+        initialized: true,
+        context_id: saved.context_id.clone(),
+    });
+    if redaction.is_some() {
+        *redaction = source_redaction;
+    }
+    if let Some(snapshots) = snapshots
+        && let Err(err) = snapshots.insert(session_key, saved)
+    {
+        tracing::warn!(error = %err, "failed to snapshot session");
+    }
+    Ok(SessionResponse {
+        response: None,
+        stdout: None,
+        stderr: None,
+        locals: None,
+        trace: None,
+        finish_reason: None,
+        used_fallback: false,
+        usage: CompletionUsage::default(),
+        stats: SessionStats::default(),
+    })
+}
+
+fn snapshot_actor(
+    pool_sender: &Sender<PoolCommand>,
+    session: &mut Option<ActorSession>,
+    redaction: &Option<Arc<Redaction>>,
+    request: ActorSnapshotRequest,
+) -> Result<(), SessionError> {
+    let active = match session.as_mut() {
+        Some(session) if session.initialized => session,
+        _ => {
+            let err = SessionError::not_found("session has no repl state");
+            let _ = request.respond_to.send(Err(err.clone()));
+            return Err(err);
+        }
+    };
+    let result = match active
+        .handle
+        .snapshot(request.request_id.clone(), request.deadline)
+    {
+        Ok(snapshot) => Ok(ForkSource {
+            saved: SessionSnapshot {
+                snapshot,
+                context_id: active.context_id.clone(),
+            },
+            redaction: redaction.clone(),
+        }),
+        Err(err) => {
+            if let Some(failed) = session.take() {
+                retire_handle(pool_sender, failed.handle);
+            }
+            Err(sandbox_session_error(err, request.deadline))
+        }
+    };
+    let status = result.as_ref().map(|_| ()).map_err(Clone::clone);
+    let _ = request.respond_to.send(result);
+    status
+}
+
+fn save_snapshot(
+    active: &mut ActorSession,
+    snapshots: &SnapshotStore,
+    session_key: &str,
+    request_id: Option<String>,
+) {
+    let deadline = Instant::now() + SNAPSHOT_TIMEOUT;
+    let result = active
+        .handle
+        .snapshot(request_id, Some(deadline))
+        .map_err(|err| err.to_string())
+        .and_then(|snapshot| {
+            snapshots.insert(
+                session_key,
+                SessionSnapshot {
+                    snapshot,
+                    context_id: active.context_id.clone(),
+                },
+            )
+        });
+    if let Err(err) = result {
+        // An older snapshot would silently roll the session back on restore.
+        snapshots.remove(session_key);
+        tracing::warn!(error = %err, "failed to snapshot session");
+    }
+}
+
+/// Copies a session's REPL state out of its worn sandbox into a fresh one,
+/// for sessions without a snapshot store to carry them over. If any step
+/// fails the session keeps the worn sandbox and tries again after its next
+/// run.
+fn move_to_fresh_sandbox(
+    pool_sender: &Sender<PoolCommand>,
+    active: &mut ActorSession,
+    session_key: &str,
+    request_id: Option<String>,
+) {
+    let deadline = Some(Instant::now() + SNAPSHOT_TIMEOUT);
+    let snapshot = match active.handle.snapshot(request_id.clone(), deadline) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to snapshot worn sandbox; keeping it");
+            return;
+        }
+    };
+    let mut fresh = match acquire_handle(pool_sender, session_key, deadline) {
+        Ok(handle) => handle,
+        Err(err) => {
+            tracing::warn!(error = %err.message, "no sandbox to replace worn one; keeping it");
+            return;
+        }
+    };
+    if let Err(err) = fresh.restore(snapshot, request_id, deadline) {
+        tracing::warn!(error = %err, "failed to move session out of worn sandbox; keeping it");
+        retire_handle(pool_sender, fresh);
+        return;
+    }
+    tracing::info!("recycling worn sandbox");
+    retire_handle(pool_sender, std::mem::replace(&mut active.handle, fresh));
+}
+
+fn read_actor_variable(
+    pool_sender: &Sender<PoolCommand>,
+    session: &mut Option<ActorSession>,
+    redaction: Option<&Redaction>,
+    request: VariableRequest,
+) -> Result<(), SessionError> {
+    let handle = match session.as_mut() {
+        Some(session) if session.initialized => &mut session.handle,
+        _ => {
+            let err = SessionError::not_found("session has no repl state");
+            let _ = request.respond_to.send(Err(err.clone()));
+            return Err(err);
+        }
+    };
+
+    let result =
+        match handle.get_variable(&request.name, request.request_id.clone(), request.deadline) {
+            Ok(Some(value)) => Ok(match redaction {
+                Some(redaction) => redaction.restore(&value),
+                None => value,
+            }),
+            Ok(None) => Err(SessionError::not_found(format!(
+                "variable {} not found",
+                request.name
+            ))),
+            Err(err) => {
+                if let Some(failed) = session.take() {
+                    retire_handle(pool_sender, failed.handle);
+                }
+                Err(sandbox_session_error(err, request.deadline))
+            }
+        };
+    let status = result.as_ref().map(|_| ()).map_err(Clone::clone);
+    let _ = request.respond_to.send(result);
+    status
+}
+
+/// Puts the session's redacted values back into what the sandbox returned.
+fn restore_result(redaction: &Redaction, result: &mut SandboxRunResult) {
+    for text in [&mut result.response, &mut result.stdout, &mut result.stderr]
+        .into_iter()
+        .flatten()
+    {
+        *text = redaction.restore(text);
+    }
+    for local in result.locals.iter_mut().flatten() {
+        local.repr = redaction.restore(&local.repr);
+        local.string_value = local
+            .string_value
+            .as_deref()
+            .map(|value| redaction.restore(value));
+    }
+    if let Some(trace) = &mut result.trace {
+        restore_trace(redaction, trace);
+    }
+}
+
+fn restore_trace(redaction: &Redaction, trace: &mut CompletionTrace) {
+    if let Ok(mut value) = serde_json::to_value(&*trace) {
+        redaction.restore_value(&mut value);
+        if let Ok(restored) = serde_json::from_value(value) {
+            *trace = restored;
+        }
+    }
+}
+
+fn deadline_expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+fn remaining_ms(deadline: Option<Instant>) -> Option<u64> {
+    deadline.map(|deadline| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u64
+    })
+}
+
+/// Maps a failed sandbox request; the sandbox itself has been retired.
+fn sandbox_session_error(err: SandboxError, deadline: Option<Instant>) -> SessionError {
+    let code = match &err {
+        SandboxError::Worker(err) => err.code,
+        SandboxError::TimedOut(_) => WorkerErrorCode::Timeout,
+        SandboxError::Cancelled(_) => WorkerErrorCode::Cancelled,
+        _ => WorkerErrorCode::Internal,
+    };
+    if code == WorkerErrorCode::Timeout || deadline_expired(deadline) {
+        return SessionError::timeout(format!("request timed out: {err}"));
+    }
+    match code {
+        WorkerErrorCode::ContextTooLarge => SessionError::too_large(err.to_string()),
+        WorkerErrorCode::LlmError => SessionError::upstream(err.to_string()),
+        WorkerErrorCode::Cancelled => SessionError::cancelled(err.to_string()),
+        _ => SessionError::internal(err.to_string()),
+    }
+}
+
+fn acquire_handle(
+    pool_sender: &Sender<PoolCommand>,
+    session_key: &str,
+    deadline: Option<Instant>,
+) -> Result<Box<dyn SandboxHandle>, SessionError> {
     let (respond_to, response) = mpsc::channel();
     pool_sender
         .send(PoolCommand::Acquire { respond_to })
-        .map_err(|_| "pool broker unavailable".to_owned())?;
-    response
-        .recv()
-        .map_err(|_| "pool broker acquire response dropped".to_owned())?
+        .map_err(|_| SessionError::internal("pool broker unavailable"))?;
+    let result = match deadline {
+        Some(deadline) => response
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => {
+                    // A sandbox sent just as the wait ended would otherwise be
+                    // dropped with the channel, still counted as in use.
+                    if let Ok(Ok(handle)) = response.try_recv() {
+                        retire_handle(pool_sender, handle);
+                    }
+                    SessionError::timeout("timed out waiting for a sandbox")
+                }
+                RecvTimeoutError::Disconnected => {
+                    SessionError::internal("pool broker acquire response dropped")
+                }
+            })?,
+        None => response
+            .recv()
+            .map_err(|_| SessionError::internal("pool broker acquire response dropped"))?,
+    };
+    let mut handle = result.map_err(|err| match err {
+        AcquireError::AtLimit => SessionError::overloaded("sandbox limit reached; retry later"),
+        AcquireError::Launch(err) => SessionError::internal(err),
+    })?;
+    handle.set_session(Some(session_key));
+    Ok(handle)
 }
 
 fn retire_handle(pool_sender: &Sender<PoolCommand>, handle: Box<dyn SandboxHandle>) {
     let _ = pool_sender.send(PoolCommand::Retire { handle });
 }
+
+pub fn validate_session_id(value: &str) -> Option<String> {
+    let mut value = value.trim();
+    value = value.trim_matches('"');
+    value = value.trim_matches('\'');
+    if value.is_empty() || value.len() > MAX_SESSION_ID_LEN || !value.is_ascii() {
+        return None;
+    }
+    Uuid::parse_str(value).ok()?;
+    Some(value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use rlm::repl::ReplSnapshot;
+    use rlm::trace::{CodeBlockTrace, IterationTrace};
+
+    use super::*;
+    use crate::audit::AuditLog;
+    use crate::pool::{RecyclePolicy, SandboxLimitAction};
+    use crate::protocol::WorkerError;
+    use crate::{LaunchError, SandboxUsage};
+
+    /// Answers every run with `result`, after hanging up on the caller if
+    /// it holds the caller's receiver. With `busy`, a run reports that it
+    /// started and then runs until its caller goes away.
+    struct FakeHandle {
+        result: Result<SandboxRunResult, SandboxError>,
+        hang_up: Option<oneshot::Receiver<Result<SessionResponse, SessionError>>>,
+        busy: Option<mpsc::Sender<()>>,
+        usage: SandboxUsage,
+        restore_fails: bool,
+    }
+
+    impl FakeHandle {
+        fn new(result: Result<SandboxRunResult, SandboxError>) -> Self {
+            Self {
+                result,
+                hang_up: None,
+                busy: None,
+                usage: SandboxUsage::default(),
+                restore_fails: false,
+            }
+        }
+    }
+
+    impl SandboxHandle for FakeHandle {
+        fn run(
+            &mut self,
+            _request: SandboxRunRequest,
+            _deadline: Option<Instant>,
+            cancelled: &dyn Fn() -> bool,
+            _progress: &dyn Fn(ProgressEvent),
+        ) -> Result<SandboxRunResult, SandboxError> {
+            self.hang_up.take();
+            if let Some(busy) = &self.busy {
+                let _ = busy.send(());
+                while !cancelled() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                return Err(SandboxError::Cancelled(None));
+            }
+            self.result.clone()
+        }
+
+        fn get_variable(
+            &mut self,
+            _name: &str,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<Option<String>, SandboxError> {
+            Ok(None)
+        }
+
+        fn snapshot(
+            &mut self,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<ReplSnapshot, SandboxError> {
+            Ok(ReplSnapshot::default())
+        }
+
+        fn restore(
+            &mut self,
+            _snapshot: ReplSnapshot,
+            _request_id: Option<String>,
+            _deadline: Option<Instant>,
+        ) -> Result<(), SandboxError> {
+            if self.restore_fails {
+                return Err(SandboxError::TimedOut(None));
+            }
+            Ok(())
+        }
+
+        fn ping(&mut self, _deadline: Option<Instant>) -> Result<(), SandboxError> {
+            Ok(())
+        }
+
+        fn reset(&mut self, _deadline: Option<Instant>) -> Result<(), SandboxError> {
+            Ok(())
+        }
+
+        fn usage(&self) -> SandboxUsage {
+            self.usage
+        }
+
+        fn terminate(&mut self) {}
+
+        fn identifier(&self) -> String {
+            "fake".to_owned()
+        }
+    }
+
+    /// Launches sandboxes whose runs keep their sessions busy.
+    struct BusyLauncher(mpsc::Sender<()>);
+
+    impl SandboxLauncher for BusyLauncher {
+        fn launch(&self) -> Result<Box<dyn SandboxHandle>, LaunchError> {
+            Ok(Box::new(FakeHandle {
+                busy: Some(self.0.clone()),
+                ..FakeHandle::new(Ok(answer(CompletionUsage::default())))
+            }))
+        }
+    }
+
+    fn answer(usage: CompletionUsage) -> SandboxRunResult {
+        SandboxRunResult {
+            response: Some("42".to_owned()),
+            stdout: None,
+            stderr: None,
+            locals: None,
+            trace: None,
+            finish_reason: None,
+            used_fallback: false,
+            llm_subcalls: 0,
+            rlm_subcalls: 0,
+            rss_bytes: None,
+            usage,
+            stats: None,
+        }
+    }
+
+    fn spent(prompt_tokens: u64, completion_tokens: u64) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            ..CompletionUsage::default()
+        }
+    }
+
+    fn actor_request(
+        respond_to: oneshot::Sender<Result<SessionResponse, SessionError>>,
+        usage_sink: Option<UsageSink>,
+    ) -> ActorRequest {
+        ActorRequest {
+            reset: false,
+            query: "question".to_owned(),
+            context: None,
+            context_id: None,
+            code: None,
+            include_trace: false,
+            overrides: RunOverrides::default(),
+            request_id: None,
+            span: tracing::Span::none(),
+            deadline: None,
+            fork_from: None,
+            usage_sink,
+            audit: None,
+            respond_to,
+        }
+    }
+
+    fn pool_config(target_idle: usize) -> PoolConfig {
+        PoolConfig {
+            target_idle,
+            launch_parallelism: 1,
+            launch_retries: 0,
+            max_total: 0,
+            at_limit: SandboxLimitAction::Wait,
+            health_interval: None,
+            reuse: false,
+            recycle: RecyclePolicy::default(),
+            autoscale: None,
+        }
+    }
+
+    /// Runs `request` in a session that already holds `handle`.
+    fn run_in_session(
+        handle: FakeHandle,
+        request: ActorRequest,
+    ) -> (Result<(), SessionError>, SessionStats) {
+        let (pool_sender, _pool) = mpsc::channel();
+        let mut session = Some(ActorSession {
+            handle: Box::new(handle),
+            initialized: true,
+            context_id: None,
+        });
+        let pool = pool_config(0);
+        let stats = Mutex::new(SessionStats::default());
+        let result = run_actor_request(
+            &pool_sender,
+            &mut session,
+            &mut None,
+            None,
+            pool,
+            &stats,
+            "session",
+            request,
+        );
+        (result, stats.into_inner().expect("stats"))
+    }
+
+    fn recording_sink() -> (UsageSink, Arc<Mutex<Vec<CompletionUsage>>>) {
+        let charged = Arc::new(Mutex::new(Vec::new()));
+        let sink = UsageSink::new({
+            let charged = charged.clone();
+            move |usage| charged.lock().expect("charged").push(usage)
+        });
+        (sink, charged)
+    }
+
+    #[test]
+    fn failed_runs_charge_what_they_spent() {
+        let (sink, charged) = recording_sink();
+        let (respond_to, mut response) = oneshot::channel();
+        let failure = SandboxError::Worker(WorkerError {
+            usage: Some(spent(120, 30)),
+            ..WorkerError::new(WorkerErrorCode::LlmError, "upstream down")
+        });
+        let (result, stats) = run_in_session(
+            FakeHandle::new(Err(failure)),
+            actor_request(respond_to, Some(sink)),
+        );
+        assert_eq!(result.expect_err("failed").kind, SessionErrorKind::Upstream);
+        assert!(response.try_recv().expect("answered").is_err());
+        assert_eq!(*charged.lock().expect("charged"), [spent(120, 30)]);
+        assert_eq!(stats.prompt_tokens, 120);
+
+        // A worker killed mid-run reports nothing to charge.
+        let (sink, charged) = recording_sink();
+        let (respond_to, _response) = oneshot::channel();
+        let (result, _) = run_in_session(
+            FakeHandle::new(Err(SandboxError::TimedOut(None))),
+            actor_request(respond_to, Some(sink)),
+        );
+        assert_eq!(result.expect_err("timed out").kind, SessionErrorKind::Timeout);
+        assert!(charged.lock().expect("charged").is_empty());
+    }
+
+    #[test]
+    fn answers_nobody_took_are_charged() {
+        let (sink, charged) = recording_sink();
+        let (respond_to, mut response) = oneshot::channel();
+        run_in_session(
+            FakeHandle::new(Ok(answer(spent(80, 20)))),
+            actor_request(respond_to, Some(sink.clone())),
+        )
+        .0
+        .expect("answered");
+        let answered = response.try_recv().expect("answered").expect("ok");
+        assert_eq!(answered.usage, spent(80, 20));
+        assert!(charged.lock().expect("charged").is_empty());
+
+        let (respond_to, response) = oneshot::channel();
+        let handle = FakeHandle {
+            hang_up: Some(response),
+            ..FakeHandle::new(Ok(answer(spent(80, 20))))
+        };
+        run_in_session(handle, actor_request(respond_to, Some(sink)))
+            .0
+            .expect("answered");
+        assert_eq!(*charged.lock().expect("charged"), [spent(80, 20)]);
+    }
+
+    #[test]
+    fn failed_completions_audit_what_they_ran() {
+        let dir = std::env::temp_dir().join(format!("rlm-audit-{}", Uuid::new_v4().simple()));
+        let audit = RunAudit {
+            log: Arc::new(AuditLog::open(dir.clone(), 0).expect("audit log")),
+            session_id: "session".to_owned(),
+            request_id: "request".to_owned(),
+            tenant: None,
+        };
+        let trace = CompletionTrace {
+            iterations: vec![IterationTrace {
+                iteration: 0,
+                response_excerpt: String::new(),
+                code_blocks: vec![CodeBlockTrace {
+                    code: "print(len(context))".to_owned(),
+                    output_excerpt: "12".to_owned(),
+                }],
+                llm_subcalls: 0,
+                rlm_subcalls: 0,
+            }],
+            ..CompletionTrace::default()
+        };
+        let failure = SandboxError::Cancelled(Some(WorkerError {
+            trace: Some(Box::new(trace)),
+            ..WorkerError::new(WorkerErrorCode::LlmError, "upstream down")
+        }));
+        let (respond_to, _response) = oneshot::channel();
+        let request = ActorRequest {
+            audit: Some(audit),
+            ..actor_request(respond_to, None)
+        };
+        run_in_session(FakeHandle::new(Err(failure)), request)
+            .0
+            .expect_err("cancelled");
+
+        let entries: Vec<Value> = std::fs::read_dir(&dir)
+            .expect("audit dir")
+            .flat_map(|entry| std::fs::read_to_string(entry.expect("entry").path()))
+            .flat_map(|text| {
+                text.lines()
+                    .map(|line| serde_json::from_str(line).expect("json"))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["code"], "print(len(context))");
+        assert_eq!(entries[0]["outcome"], "cancelled");
+        assert_eq!(entries[0]["error"], "sandbox run cancelled");
+    }
+
+    #[test]
+    fn abandoned_runs_are_not_failures() {
+        let (respond_to, _response) = oneshot::channel();
+        let (result, _) = run_in_session(
+            FakeHandle::new(Err(SandboxError::Cancelled(None))),
+            actor_request(respond_to, None),
+        );
+        let err = result.expect_err("cancelled");
+        assert_eq!(err.kind, SessionErrorKind::Cancelled);
+        let (respond_to, response) = oneshot::channel();
+        drop(response);
+        let (gone, _) = run_in_session(
+            FakeHandle::new(Ok(answer(CompletionUsage::default()))),
+            actor_request(respond_to, None),
+        );
+        assert_eq!(
+            gone.expect_err("cancelled").kind,
+            SessionErrorKind::Cancelled
+        );
+        let worker_cancelled = SandboxError::Worker(WorkerError::new(
+            WorkerErrorCode::Cancelled,
+            "run cancelled",
+        ));
+        assert_eq!(
+            sandbox_session_error(worker_cancelled, None).kind,
+            SessionErrorKind::Cancelled
+        );
+
+        let stats = Mutex::new(SessionStats::default());
+        record_outcome("run", Duration::ZERO, Err(err), &stats);
+        let recorded = stats.lock().expect("stats");
+        assert_eq!(recorded.failed_runs, 0);
+        assert_eq!(recorded.last_error, None);
+        drop(recorded);
+
+        record_outcome(
+            "run",
+            Duration::ZERO,
+            Err(SessionError::internal("worker crashed")),
+            &stats,
+        );
+        let recorded = stats.lock().expect("stats");
+        assert_eq!(recorded.failed_runs, 1);
+        assert_eq!(recorded.last_error.as_deref(), Some("worker crashed"));
+    }
+
+    fn session_request(
+        session_id: &str,
+        respond_to: oneshot::Sender<Result<SessionResponse, SessionError>>,
+    ) -> SessionRequest {
+        SessionRequest {
+            session_id: session_id.to_owned(),
+            tenant: None,
+            reset: false,
+            query: "question".to_owned(),
+            context: None,
+            context_id: None,
+            code: None,
+            include_trace: false,
+            overrides: RunOverrides::default(),
+            request_id: None,
+            span: tracing::Span::none(),
+            deadline: None,
+            priority: Priority::Normal,
+            fork_from: None,
+            usage_sink: None,
+            audit: None,
+            respond_to,
+        }
+    }
+
+    #[test]
+    fn sessions_waiting_for_room_are_bounded() {
+        let (started, running) = mpsc::channel();
+        let config = SessionConfig {
+            max_sessions: 1,
+            ingress_capacity: 2,
+            max_pending_per_session: 0,
+            pool: pool_config(1),
+            idle_ttl: None,
+            eviction: EvictionPolicyKind::Lru,
+            admission_wait: Duration::from_secs(60),
+        };
+        let manager = spawn_session_manager(config, Box::new(BusyLauncher(started)), None, None)
+            .expect("manager starts");
+        let (respond_to, busy) = oneshot::channel();
+        manager
+            .try_dispatch(session_request("busy", respond_to))
+            .expect("dispatched");
+        running
+            .recv_timeout(Duration::from_secs(5))
+            .expect("busy session running");
+
+        let mut parked = Vec::new();
+        for session_id in ["first", "second"] {
+            let (respond_to, response) = oneshot::channel();
+            manager
+                .try_dispatch(session_request(session_id, respond_to))
+                .expect("dispatched");
+            parked.push(response);
+        }
+        for session_id in ["third", "fourth", "fifth"] {
+            let (respond_to, response) = oneshot::channel();
+            let err = match manager.try_dispatch(session_request(session_id, respond_to)) {
+                Ok(()) => response
+                    .blocking_recv()
+                    .expect("answered")
+                    .expect_err("turned away"),
+                Err(err) => err,
+            };
+            assert_eq!(err.kind, SessionErrorKind::Overloaded, "{}", err.message);
+        }
+        for response in &mut parked {
+            assert!(matches!(
+                response.try_recv(),
+                Err(oneshot::error::TryRecvError::Empty)
+            ));
+        }
+        drop(busy);
+    }
+
+    /// A pool broker stand-in that hands out `fresh()` sandboxes and passes
+    /// retired ones to the returned receiver.
+    fn serve_pool(
+        fresh: impl Fn() -> FakeHandle + Send + 'static,
+    ) -> (Sender<PoolCommand>, Receiver<Box<dyn SandboxHandle>>) {
+        let (pool_sender, commands) = mpsc::channel();
+        let (retire, retired) = mpsc::channel();
+        thread::spawn(move || {
+            for command in commands {
+                match command {
+                    PoolCommand::Acquire { respond_to } => {
+                        let _ = respond_to.send(Ok(Box::new(fresh())));
+                    }
+                    PoolCommand::Retire { handle } => {
+                        let _ = retire.send(handle);
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (pool_sender, retired)
+    }
+
+    fn worn_session_run(fresh: impl Fn() -> FakeHandle + Send + 'static) -> (u64, u64) {
+        let (pool_sender, retired) = serve_pool(fresh);
+        let worn = FakeHandle {
+            usage: SandboxUsage {
+                runs: 5,
+                ..SandboxUsage::default()
+            },
+            ..FakeHandle::new(Ok(answer(CompletionUsage::default())))
+        };
+        let mut session = Some(ActorSession {
+            handle: Box::new(worn),
+            initialized: true,
+            context_id: None,
+        });
+        let pool = PoolConfig {
+            recycle: RecyclePolicy {
+                max_runs: 5,
+                ..RecyclePolicy::default()
+            },
+            ..pool_config(0)
+        };
+        let (respond_to, _response) = oneshot::channel();
+        run_actor_request(
+            &pool_sender,
+            &mut session,
+            &mut None,
+            None,
+            pool,
+            &Mutex::new(SessionStats::default()),
+            "session",
+            actor_request(respond_to, None),
+        )
+        .expect("answered");
+        let session = session.expect("session kept");
+        assert!(session.initialized);
+        let retired = retired
+            .recv_timeout(Duration::from_secs(5))
+            .expect("a sandbox retired");
+        (session.handle.usage().runs, retired.usage().runs)
+    }
+
+    #[test]
+    fn worn_sandboxes_are_recycled_without_snapshots() {
+        let fresh = || FakeHandle::new(Ok(answer(CompletionUsage::default())));
+        assert_eq!(worn_session_run(fresh), (0, 5));
+
+        // A fresh sandbox that cannot take the state goes back instead.
+        let broken = || FakeHandle {
+            restore_fails: true,
+            ..FakeHandle::new(Ok(answer(CompletionUsage::default())))
+        };
+        assert_eq!(worn_session_run(broken), (5, 0));
+    }
+
+    fn usage(idle_secs: u64, runs: u64, context_bytes: usize) -> SessionUsage {
+        SessionUsage {
+            idle_for: Duration::from_secs(idle_secs),
+            runs,
+            context_bytes,
+        }
+    }
+
+    /// Index of the session `policy` would evict first.
+    fn victim(policy: &dyn EvictionPolicy, sessions: &[SessionUsage]) -> usize {
+        sessions
+            .iter()
+            .enumerate()
+            .min_by(|left, right| {
+                policy
+                    .retention(left.1)
+                    .total_cmp(&policy.retention(right.1))
+            })
+            .map(|(index, _)| index)
+            .expect("sessions")
+    }
+
+    #[test]
+    fn lru_evicts_the_longest_idle() {
+        let sessions = [usage(10, 9, 0), usage(300, 9, 0), usage(60, 0, 0)];
+        assert_eq!(victim(&LruEviction, &sessions), 1);
+    }
+
+    #[test]
+    fn lfu_evicts_the_fewest_runs() {
+        let sessions = [usage(10, 9, 0), usage(300, 5, 0), usage(1, 2, 0)];
+        assert_eq!(victim(&LfuEviction, &sessions), 2);
+    }
+
+    #[test]
+    fn ttl_weighted_lets_quiet_sessions_decay() {
+        let policy = TtlWeightedEviction {
+            half_life: Duration::from_secs(60),
+        };
+        // Eight runs an hour ago count for less than two just now.
+        let sessions = [usage(3600, 8, 0), usage(0, 2, 0)];
+        assert_eq!(victim(&policy, &sessions), 0);
+        // Within a half-life the busier session still wins.
+        let sessions = [usage(30, 8, 0), usage(0, 2, 0)];
+        assert_eq!(victim(&policy, &sessions), 1);
+    }
+
+    #[test]
+    fn cost_weighted_keeps_expensive_contexts() {
+        let sessions = [usage(10, 0, 1 << 20), usage(10, 50, 1 << 10)];
+        assert_eq!(victim(&CostWeightedEviction, &sessions), 1);
+        // Idleness discounts even a large context.
+        let sessions = [usage(100_000, 0, 1 << 20), usage(0, 0, 1 << 10)];
+        assert_eq!(victim(&CostWeightedEviction, &sessions), 0);
+    }
+
+    #[test]
+    fn ttl_weighted_half_life_follows_idle_ttl() {
+        let policy = EvictionPolicyKind::TtlWeighted.build(Some(Duration::from_secs(100)));
+        assert_eq!(policy.retention(&usage(100, 8, 0)), 4.0);
+        let policy = EvictionPolicyKind::TtlWeighted.build(None);
+        let half_life = DEFAULT_EVICTION_HALF_LIFE.as_secs();
+        assert_eq!(policy.retention(&usage(half_life, 8, 0)), 4.0);
+    }
+
+    #[test]
+    fn eviction_policy_kinds_round_trip() {
+        for kind in [
+            EvictionPolicyKind::Lru,
+            EvictionPolicyKind::Lfu,
+            EvictionPolicyKind::TtlWeighted,
+            EvictionPolicyKind::CostWeighted,
+        ] {
+            assert_eq!(kind.to_string().parse(), Ok(kind));
+        }
+        assert_eq!(" LFU ".parse(), Ok(EvictionPolicyKind::Lfu));
+        assert!("fifo".parse::<EvictionPolicyKind>().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::audit::unix_millis;
+
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Persisted facts about one session; REPL state itself is not kept.
+#[derive(Debug, Serialize)]
+pub struct SessionMetadata {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_hash: Option<String>,
+    pub created_at_ms: u64,
+    pub last_run_at_ms: u64,
+    pub run_count: u64,
+}
+
+pub struct RunRecord {
+    pub run_count: u64,
+    /// The session was known before this process started, so its sandbox
+    /// state was lost and this run started from a fresh interpreter.
+    pub restored: bool,
+}
+
+/// SQLite-backed session metadata that survives restarts.
+pub struct SessionStore {
+    #[cfg(feature = "sqlite")]
+    connection: Mutex<rusqlite::Connection>,
+    /// Sessions from earlier processes not yet seen by this one.
+    previous: Mutex<HashSet<(String, String)>>,
+}
+
+impl SessionStore {
+    /// `retention_days` of 0 keeps every row.
+    pub fn open(path: &Path, retention_days: u64) -> Result<Self, String> {
+        #[cfg(feature = "sqlite")]
+        {
+            let connection = rusqlite::Connection::open(path)
+                .map_err(|err| format!("failed to open {}: {err}", path.display()))?;
+            connection
+                .execute_batch(
+                    "CREATE TABLE IF NOT EXISTS sessions (
+                        tenant TEXT NOT NULL,
+                        id TEXT NOT NULL,
+                        context_hash TEXT,
+                        created_at_ms INTEGER NOT NULL,
+                        last_run_at_ms INTEGER NOT NULL,
+                        run_count INTEGER NOT NULL,
+                        PRIMARY KEY (tenant, id)
+                    )",
+                )
+                .map_err(|err| format!("failed to initialize {}: {err}", path.display()))?;
+            if retention_days > 0 {
+                let cutoff = unix_millis().saturating_sub(retention_days * MILLIS_PER_DAY);
+                connection
+                    .execute(
+                        "DELETE FROM sessions WHERE last_run_at_ms < ?1",
+                        [cutoff as i64],
+                    )
+                    .map_err(|err| format!("failed to prune {}: {err}", path.display()))?;
+            }
+            let previous = connection
+                .prepare("SELECT tenant, id FROM sessions")
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                        .collect::<Result<HashSet<_>, _>>()
+                })
+                .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+            Ok(Self {
+                connection: Mutex::new(connection),
+                previous: Mutex::new(previous),
+            })
+        }
+        #[cfg(not(feature = "sqlite"))]
+        {
+            let _ = (path, retention_days, MILLIS_PER_DAY);
+            Err("session store requires building with the `sqlite` feature".to_owned())
+        }
+    }
+
+    /// Sessions left over from earlier processes.
+    pub fn previous_count(&self) -> usize {
+        self.previous
+            .lock()
+            .expect("session store lock poisoned")
+            .len()
+    }
+
+    /// Counts a completed run. `context_hash` replaces the stored hash when
+    /// set.
+    pub fn record_run(
+        &self,
+        tenant: Option<&str>,
+        id: &str,
+        context_hash: Option<&str>,
+    ) -> Result<RunRecord, String> {
+        let tenant = tenant.unwrap_or_default();
+        let restored = self
+            .previous
+            .lock()
+            .expect("session store lock poisoned")
+            .remove(&(tenant.to_owned(), id.to_owned()));
+        let run_count = self.upsert(tenant, id, context_hash, unix_millis())?;
+        Ok(RunRecord {
+            run_count,
+            restored,
+        })
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn upsert(
+        &self,
+        tenant: &str,
+        id: &str,
+        context_hash: Option<&str>,
+        now: u64,
+    ) -> Result<u64, String> {
+        let connection = self.connection.lock().expect("session store lock poisoned");
+        connection
+            .query_row(
+                "INSERT INTO sessions
+                    (tenant, id, context_hash, created_at_ms, last_run_at_ms, run_count)
+                 VALUES (?1, ?2, ?3, ?4, ?4, 1)
+                 ON CONFLICT(tenant, id) DO UPDATE SET
+                    context_hash = COALESCE(excluded.context_hash, context_hash),
+                    last_run_at_ms = excluded.last_run_at_ms,
+                    run_count = run_count + 1
+                 RETURNING run_count",
+                rusqlite::params![tenant, id, context_hash, now as i64],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count as u64)
+            .map_err(|err| format!("session store write failed: {err}"))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn upsert(
+        &self,
+        _tenant: &str,
+        _id: &str,
+        _context_hash: Option<&str>,
+        _now: u64,
+    ) -> Result<u64, String> {
+        Ok(0)
+    }
+
+    /// Most recently used sessions first.
+    #[cfg(feature = "sqlite")]
+    pub fn list(&self, limit: usize) -> Result<Vec<SessionMetadata>, String> {
+        let connection = self.connection.lock().expect("session store lock poisoned");
+        let mut statement = connection
+            .prepare(
+                "SELECT tenant, id, context_hash, created_at_ms, last_run_at_ms, run_count
+                 FROM sessions ORDER BY last_run_at_ms DESC LIMIT ?1",
+            )
+            .map_err(|err| format!("session store read failed: {err}"))?;
+        statement
+            .query_map([limit as i64], |row| {
+                let tenant: String = row.get(0)?;
+                Ok(SessionMetadata {
+                    id: row.get(1)?,
+                    tenant: (!tenant.is_empty()).then_some(tenant),
+                    context_hash: row.get(2)?,
+                    created_at_ms: row.get::<_, i64>(3)? as u64,
+                    last_run_at_ms: row.get::<_, i64>(4)? as u64,
+                    run_count: row.get::<_, i64>(5)? as u64,
+                })
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(|err| format!("session store read failed: {err}"))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    pub fn list(&self, _limit: usize) -> Result<Vec<SessionMetadata>, String> {
+        Ok(Vec::new())
+    }
+}
+
+/// Stable FNV-1a digest of a context's JSON encoding.
+pub fn context_hash(context: &Value) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in serde_json::to_vec(context).unwrap_or_default() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
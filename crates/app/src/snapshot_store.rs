@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rlm::repl::ReplSnapshot;
+
+#[derive(Debug)]
+pub struct SessionSnapshot {
+    pub snapshot: ReplSnapshot,
+    /// Stored context the session was initialized from, if any.
+    pub context_id: Option<String>,
+}
+
+/// Latest REPL snapshot per session, so a session whose sandbox was evicted or
+/// crashed can be rebuilt in a fresh one.
+pub struct SnapshotStore {
+    max_bytes: usize,
+    state: Mutex<SnapshotState>,
+}
+
+#[derive(Default)]
+struct SnapshotState {
+    total_bytes: usize,
+    entries: HashMap<String, StoredSnapshot>,
+}
+
+struct StoredSnapshot {
+    snapshot: Arc<SessionSnapshot>,
+    bytes: usize,
+    last_used: Instant,
+}
+
+impl SnapshotStore {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::new(SnapshotState::default()),
+        }
+    }
+
+    /// Replaces the session's snapshot, evicting the least recently used
+    /// snapshots to make room.
+    pub fn insert(&self, session_key: &str, snapshot: SessionSnapshot) -> Result<(), String> {
+        let bytes = serde_json::to_vec(&snapshot.snapshot)
+            .map_err(|err| format!("failed to encode snapshot: {err}"))?
+            .len();
+        let mut state = self.state.lock().expect("snapshot store lock poisoned");
+        state.remove(session_key);
+        if bytes > self.max_bytes {
+            return Err(format!(
+                "snapshot too large for store; {bytes} bytes, max {}",
+                self.max_bytes
+            ));
+        }
+        while state.total_bytes + bytes > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        state.total_bytes += bytes;
+        state.entries.insert(
+            session_key.to_owned(),
+            StoredSnapshot {
+                snapshot: Arc::new(snapshot),
+                bytes,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, session_key: &str) -> Option<Arc<SessionSnapshot>> {
+        let mut state = self.state.lock().expect("snapshot store lock poisoned");
+        let entry = state.entries.get_mut(session_key)?;
+        entry.last_used = Instant::now();
+        Some(entry.snapshot.clone())
+    }
+
+    pub fn remove(&self, session_key: &str) {
+        self.state
+            .lock()
+            .expect("snapshot store lock poisoned")
+            .remove(session_key);
+    }
+}
+
+impl SnapshotState {
+    fn remove(&mut self, session_key: &str) {
+        if let Some(entry) = self.entries.remove(session_key) {
+            self.total_bytes -= entry.bytes;
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use tokio::runtime::Handle;
+use tokio::time::MissedTickBehavior;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub const RUNTIME_METRICS_INTERVAL_ENV: &str = "RLM_RUNTIME_METRICS_INTERVAL_MS";
+pub const LOG_LEVEL_ENV: &str = "RLM_LOG";
+pub const LOG_FORMAT_ENV: &str = "RLM_LOG_FORMAT";
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("invalid log format {other}; expected text or json")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => f.write_str("text"),
+            Self::Json => f.write_str("json"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TracingGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+// Logs go to stderr: the sandbox worker speaks its protocol over stdout.
+pub fn init_tracing(
+    service_name: &'static str,
+    level: &str,
+    format: LogFormat,
+) -> Result<TracingGuard, String> {
+    let filter =
+        EnvFilter::try_new(level).map_err(|err| format!("invalid log level {level}: {err}"))?;
+    let fmt_layer = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_ansi(io::stderr().is_terminal())
+            .with_writer(io::stderr)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(io::stderr)
+            .boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(fmt_layer.with_filter(filter));
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    #[cfg(feature = "otel")]
+    let (registry, guard) = {
+        use opentelemetry::trace::TracerProvider;
+
+        let provider = otlp_tracer_provider(service_name)?;
+        let layer = match &provider {
+            Some(provider) => {
+                let filter = EnvFilter::try_new(level)
+                    .map_err(|err| format!("invalid log level {level}: {err}"))?;
+                let layer = tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer(service_name))
+                    .with_filter(filter);
+                Some(layer)
+            }
+            None => None,
+        };
+        (registry.with(layer), TracingGuard { provider })
+    };
+    #[cfg(not(feature = "otel"))]
+    let guard = {
+        let _ = service_name;
+        TracingGuard::default()
+    };
+    registry
+        .try_init()
+        .map_err(|err| format!("failed to initialize tracing: {err}"))?;
+    Ok(guard)
+}
+
+#[cfg(feature = "otel")]
+fn otlp_tracer_provider(
+    service_name: &'static str,
+) -> Result<Option<opentelemetry_sdk::trace::SdkTracerProvider>, String> {
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    if env::var_os(OTLP_ENDPOINT_ENV).is_none() {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|err| format!("failed to build OTLP exporter: {err}"))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(provider))
+}
+
+#[cfg(feature = "otel")]
+pub fn current_trace_context() -> Option<HashMap<String, String>> {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = tracing::Span::current().context();
+    let mut carrier = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut carrier);
+    });
+    (!carrier.is_empty()).then_some(carrier)
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn current_trace_context() -> Option<HashMap<String, String>> {
+    None
+}
+
+#[cfg(feature = "otel")]
+pub fn set_trace_parent(span: &tracing::Span, carrier: &HashMap<String, String>) {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context =
+        opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    let _ = span.set_parent(context);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn set_trace_parent(_span: &tracing::Span, _carrier: &HashMap<String, String>) {}
+
+pub fn tracing_config_from_env() -> Result<(String, LogFormat), String> {
+    let level = env::var(LOG_LEVEL_ENV).unwrap_or_else(|_| DEFAULT_LOG_LEVEL.to_owned());
+    let format = match env::var(LOG_FORMAT_ENV) {
+        Ok(value) => value.parse()?,
+        Err(_) => LogFormat::default(),
+    };
+    Ok((level, format))
+}
+
+pub fn runtime_metrics_interval() -> Result<Option<Duration>, String> {
+    let Ok(value) = env::var(RUNTIME_METRICS_INTERVAL_ENV) else {
+        return Ok(None);
+    };
+    let millis = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid {RUNTIME_METRICS_INTERVAL_ENV}: {value}"))?;
+    if millis == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Duration::from_millis(millis)))
+}
+
+pub async fn monitor_runtime<F: Future>(
+    label: &'static str,
+    interval: Option<Duration>,
+    future: F,
+) -> F::Output {
+    let Some(interval) = interval else {
+        return future.await;
+    };
+    let handle = Handle::current();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut last_tick = Instant::now();
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            output = &mut future => return output,
+            _ = ticker.tick() => {
+                let lag = last_tick.elapsed().saturating_sub(interval);
+                last_tick = Instant::now();
+                let metrics = handle.metrics();
+                tracing::info!(
+                    runtime = label,
+                    workers = metrics.num_workers(),
+                    alive_tasks = metrics.num_alive_tasks(),
+                    global_queue_depth = metrics.global_queue_depth(),
+                    tick_lag_ms = lag.as_millis() as u64,
+                    "runtime metrics"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::session::TenantScope;
+
+/// Limits and upstream credentials shared by every inbound key of a tenant.
+#[derive(Deserialize)]
+pub struct Tenant {
+    #[serde(skip)]
+    pub name: String,
+    pub keys: Vec<String>,
+    /// Model names the tenant may request; empty allows every route.
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    #[serde(default)]
+    pub max_context_bytes: Option<usize>,
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// Upstream key used for this tenant's LLM calls instead of the server's.
+    #[serde(default)]
+    pub upstream_api_key: Option<String>,
+}
+
+impl Tenant {
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty() || self.allowed_models.iter().any(|name| name == model)
+    }
+
+    pub fn scope(&self) -> TenantScope {
+        TenantScope {
+            name: self.name.clone(),
+            max_sessions: self.max_sessions,
+        }
+    }
+}
+
+pub struct Tenants {
+    by_key: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Loads `{"name": {"keys": [...], ...}}` profiles from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let tenants: BTreeMap<String, Tenant> = serde_json::from_str(&raw)
+            .map_err(|err| format!("invalid tenants in {}: {err}", path.display()))?;
+        let mut by_key = HashMap::new();
+        for (name, mut tenant) in tenants {
+            if name.trim().is_empty() || name.contains('/') {
+                return Err(format!("invalid tenant name {name:?}"));
+            }
+            if tenant.keys.is_empty() {
+                return Err(format!("tenant {name} has no keys"));
+            }
+            if tenant.max_sessions == Some(0) {
+                return Err(format!(
+                    "tenant {name}: max_sessions must be greater than zero"
+                ));
+            }
+            tenant.name = name;
+            let tenant = Arc::new(tenant);
+            for key in &tenant.keys {
+                let key = key.trim();
+                if key.is_empty() {
+                    return Err(format!("tenant {} has an empty key", tenant.name));
+                }
+                if by_key.insert(key.to_owned(), tenant.clone()).is_some() {
+                    return Err(format!("key of tenant {} is already assigned", tenant.name));
+                }
+            }
+        }
+        Ok(Self { by_key })
+    }
+
+    pub fn authenticate(&self, key: &str) -> Option<Arc<Tenant>> {
+        self.by_key.get(key).cloned()
+    }
+}
@@ -0,0 +1,316 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+pub const ANONYMOUS_CLIENT_KEY: &str = "anonymous";
+/// Report row for keys folded together to bound the tracker's memory.
+pub const OTHER_KEYS: &str = "other";
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const BYTES_PER_TOKEN: u64 = 4;
+// Past this many keys in a day, keys that have spent nothing are folded into
+// one row.
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UsageConfig {
+    pub daily_token_budget: u64,
+    pub daily_cost_budget_usd: f64,
+    pub prompt_price_per_million: f64,
+    pub completion_price_per_million: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaExceeded {
+    pub retry_after: Duration,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub period_start: u64,
+    pub resets_in_secs: u64,
+    pub daily_token_budget: Option<u64>,
+    pub daily_cost_budget_usd: Option<f64>,
+    pub keys: Vec<KeyUsageReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsageReport {
+    pub key: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+}
+
+pub struct UsageTracker {
+    config: UsageConfig,
+    state: Mutex<UsageState>,
+}
+
+struct UsageState {
+    day: u64,
+    keys: HashMap<String, KeyUsage>,
+    other: KeyUsage,
+}
+
+#[derive(Default)]
+struct KeyUsage {
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost_usd: f64,
+}
+
+impl UsageTracker {
+    pub fn new(config: UsageConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(UsageState {
+                day: current_day(),
+                keys: HashMap::new(),
+                other: KeyUsage::default(),
+            }),
+        }
+    }
+
+    pub fn check_budget(&self, key: &str) -> Result<(), QuotaExceeded> {
+        let mut state = self.state.lock().expect("usage lock poisoned");
+        state.roll_over();
+        let Some(usage) = state.keys.get(key) else {
+            return Ok(());
+        };
+        let budget = self.config.daily_token_budget;
+        if budget > 0 && usage.total_tokens() >= budget {
+            return Err(QuotaExceeded {
+                retry_after: until_reset(),
+                message: format!("daily token budget of {budget} exhausted for this key"),
+            });
+        }
+        let budget = self.config.daily_cost_budget_usd;
+        if budget > 0.0 && usage.cost_usd >= budget {
+            return Err(QuotaExceeded {
+                retry_after: until_reset(),
+                message: format!("daily cost budget of ${budget:.2} exhausted for this key"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn record(&self, key: &str, tokens: TokenUsage) {
+        let cost_usd = self.cost_usd(tokens);
+        let mut state = self.state.lock().expect("usage lock poisoned");
+        state.roll_over();
+        if state.keys.len() >= PRUNE_THRESHOLD && !state.keys.contains_key(key) {
+            state.fold_unspent();
+        }
+        let usage = state.keys.entry(key.to_owned()).or_default();
+        usage.requests += 1;
+        usage.prompt_tokens += tokens.prompt_tokens;
+        usage.completion_tokens += tokens.completion_tokens;
+        usage.cost_usd += cost_usd;
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let mut state = self.state.lock().expect("usage lock poisoned");
+        state.roll_over();
+        let mut keys: Vec<KeyUsageReport> = state
+            .keys
+            .iter()
+            .map(|(key, usage)| usage.report(mask_key(key)))
+            .collect();
+        if state.other.requests > 0 {
+            keys.push(state.other.report(OTHER_KEYS.to_owned()));
+        }
+        keys.sort_by_key(|usage| Reverse(usage.total_tokens));
+        UsageReport {
+            period_start: state.day * SECONDS_PER_DAY,
+            resets_in_secs: until_reset().as_secs(),
+            daily_token_budget: (self.config.daily_token_budget > 0)
+                .then_some(self.config.daily_token_budget),
+            daily_cost_budget_usd: (self.config.daily_cost_budget_usd > 0.0)
+                .then_some(self.config.daily_cost_budget_usd),
+            keys,
+        }
+    }
+
+    fn cost_usd(&self, tokens: TokenUsage) -> f64 {
+        (tokens.prompt_tokens as f64 * self.config.prompt_price_per_million
+            + tokens.completion_tokens as f64 * self.config.completion_price_per_million)
+            / 1_000_000.0
+    }
+}
+
+impl KeyUsage {
+    fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn report(&self, key: String) -> KeyUsageReport {
+        KeyUsageReport {
+            key,
+            requests: self.requests,
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            total_tokens: self.total_tokens(),
+            cost_usd: self.cost_usd,
+        }
+    }
+}
+
+impl UsageState {
+    fn roll_over(&mut self) {
+        let today = current_day();
+        if today != self.day {
+            self.day = today;
+            self.keys.clear();
+            self.other = KeyUsage::default();
+        }
+    }
+
+    /// Moves keys that have spent nothing into `other`, keeping the day's
+    /// request count. Keys with spend keep their own totals until the day
+    /// rolls over, so their budgets hold and `/admin/usage` stays exact; the
+    /// map can therefore outgrow the threshold when that many keys spend.
+    fn fold_unspent(&mut self) {
+        let other = &mut self.other;
+        self.keys.retain(|_, usage| {
+            let unspent = usage.total_tokens() == 0 && usage.cost_usd == 0.0;
+            if unspent {
+                other.requests += usage.requests;
+            }
+            !unspent
+        });
+    }
+}
+
+// Rough estimate until the worker reports real token counts.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.len() as u64).div_ceil(BYTES_PER_TOKEN)
+}
+
+fn mask_key(key: &str) -> String {
+    if key == ANONYMOUS_CLIENT_KEY {
+        return key.to_owned();
+    }
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "****".to_owned();
+    }
+    let prefix: String = chars[..3].iter().collect();
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{prefix}...{suffix}")
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn current_day() -> u64 {
+    unix_seconds() / SECONDS_PER_DAY
+}
+
+fn until_reset() -> Duration {
+    Duration::from_secs(SECONDS_PER_DAY - unix_seconds() % SECONDS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(daily_token_budget: u64, daily_cost_budget_usd: f64) -> UsageTracker {
+        UsageTracker::new(UsageConfig {
+            daily_token_budget,
+            daily_cost_budget_usd,
+            prompt_price_per_million: 1.0,
+            completion_price_per_million: 2.0,
+        })
+    }
+
+    fn tokens(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn token_budget_is_per_key() {
+        let tracker = tracker(100, 0.0);
+        tracker.record("sk-first-key", tokens(60, 30));
+        assert!(tracker.check_budget("sk-first-key").is_ok());
+        tracker.record("sk-first-key", tokens(10, 0));
+        let exceeded = tracker.check_budget("sk-first-key").expect_err("exceeded");
+        assert!(exceeded.retry_after <= Duration::from_secs(SECONDS_PER_DAY));
+        assert!(tracker.check_budget("sk-other-key").is_ok());
+    }
+
+    #[test]
+    fn cost_budget_uses_prices() {
+        let tracker = tracker(0, 3.0);
+        tracker.record("key", tokens(1_000_000, 0));
+        assert!(tracker.check_budget("key").is_ok());
+        tracker.record("key", tokens(0, 1_000_000));
+        assert!(tracker.check_budget("key").is_err());
+    }
+
+    #[test]
+    fn report_masks_and_sorts_keys() {
+        let tracker = tracker(0, 0.0);
+        tracker.record("sk-small-usage-key", tokens(1, 1));
+        tracker.record("sk-large-usage-key", tokens(500, 500));
+        tracker.record(ANONYMOUS_CLIENT_KEY, tokens(10, 0));
+        let report = tracker.report();
+        let keys: Vec<&str> = report.keys.iter().map(|key| key.key.as_str()).collect();
+        assert_eq!(keys, ["sk-...-key", ANONYMOUS_CLIENT_KEY, "sk-...-key"]);
+        assert_eq!(report.keys[0].total_tokens, 1000);
+        assert_eq!(report.keys[0].requests, 1);
+        assert_eq!(report.daily_token_budget, None);
+    }
+
+    #[test]
+    fn unspent_keys_fold_without_losing_spend() {
+        let tracker = tracker(100, 0.0);
+        tracker.record("exhausted", tokens(100, 0));
+        tracker.record("light", tokens(1, 0));
+        for key in 2..PRUNE_THRESHOLD {
+            tracker.record(&key.to_string(), tokens(0, 0));
+        }
+        tracker.record("new", tokens(0, 0));
+        assert_eq!(tracker.state.lock().unwrap().keys.len(), 3);
+        assert!(tracker.check_budget("exhausted").is_err());
+        let report = tracker.report();
+        let requests: u64 = report.keys.iter().map(|key| key.requests).sum();
+        assert_eq!(requests, PRUNE_THRESHOLD as u64 + 1);
+        let other = report.keys.iter().find(|key| key.key == OTHER_KEYS);
+        assert_eq!(
+            other.map(|key| key.requests),
+            Some(PRUNE_THRESHOLD as u64 - 2)
+        );
+    }
+
+    #[test]
+    fn short_keys_are_fully_masked() {
+        assert_eq!(mask_key("secret"), "****");
+        assert_eq!(mask_key("sk-abcdefgh1234"), "sk-...1234");
+    }
+
+    #[test]
+    fn tokens_are_estimated_from_bytes() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcde"), 2);
+    }
+}
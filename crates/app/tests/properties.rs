@@ -0,0 +1,76 @@
+//! The `fuzz/` targets' invariants, checked on every `cargo test`.
+
+use std::io::BufReader;
+
+use app::protocol::{Framing, WorkerRequest, WorkerResponse, encode_frame, read_frame};
+use app::session::{MAX_SESSION_ID_LEN, validate_session_id};
+use proptest::prelude::*;
+
+fn request() -> impl Strategy<Value = WorkerRequest> {
+    prop_oneof![
+        Just(WorkerRequest::Ping),
+        Just(WorkerRequest::Reset),
+        Just(WorkerRequest::Close),
+        (".*", prop::option::of(".*")).prop_map(|(name, request_id)| {
+            WorkerRequest::GetVariable {
+                name,
+                request_id,
+                trace_context: None,
+            }
+        }),
+        prop::option::of(".*").prop_map(|request_id| WorkerRequest::Snapshot { request_id }),
+        any::<u64>().prop_map(|total_bytes| WorkerRequest::BeginContext { total_bytes }),
+        ".*".prop_map(|data| WorkerRequest::ContextChunk { data }),
+    ]
+}
+
+fn framing() -> impl Strategy<Value = Framing> {
+    prop_oneof![Just(Framing::JsonLines), Just(Framing::MessagePack)]
+}
+
+proptest! {
+    #[test]
+    fn valid_session_ids_are_stable(raw in prop_oneof![
+        ".*",
+        any::<u128>().prop_map(|n| format!(" \"{}\" ", uuid::Uuid::from_u128(n))),
+    ]) {
+        if let Some(session_id) = validate_session_id(&raw) {
+            prop_assert!(session_id.is_ascii());
+            prop_assert!(!session_id.is_empty() && session_id.len() <= MAX_SESSION_ID_LEN);
+            prop_assert_eq!(validate_session_id(&session_id), Some(session_id.clone()));
+        }
+    }
+
+    #[test]
+    fn frames_round_trip(requests in prop::collection::vec(request(), 1..4), framing in framing()) {
+        let mut bytes = Vec::new();
+        for request in &requests {
+            bytes.extend(encode_frame(request, framing).unwrap());
+        }
+        let mut input = BufReader::new(bytes.as_slice());
+        for request in &requests {
+            let frame = read_frame(&mut input).unwrap().expect("frame");
+            let decoded: WorkerRequest = frame.decode().unwrap();
+            prop_assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(request).unwrap()
+            );
+        }
+        prop_assert!(read_frame(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn decoded_messages_re_encode(data in prop::collection::vec(any::<u8>(), 0..256)) {
+        let mut input = BufReader::new(data.as_slice());
+        while let Ok(Some(frame)) = read_frame(&mut input) {
+            if let Ok(request) = frame.decode::<WorkerRequest>() {
+                let line = serde_json::to_string(&request).unwrap();
+                prop_assert!(serde_json::from_str::<WorkerRequest>(&line).is_ok());
+            }
+            if let Ok(response) = frame.decode::<WorkerResponse>() {
+                let line = serde_json::to_string(&response).unwrap();
+                prop_assert!(serde_json::from_str::<WorkerResponse>(&line).is_ok());
+            }
+        }
+    }
+}
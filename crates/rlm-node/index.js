@@ -0,0 +1,45 @@
+const { EventEmitter } = require("node:events");
+
+const native = require("./rlm-node.node");
+
+class RlmRepl extends EventEmitter {
+  constructor(options) {
+    super();
+    this.inner = new native.RlmRepl(options, (event, payload) => this.emit(event, payload));
+  }
+
+  completion(context, query) {
+    return this.inner.completion(context, query);
+  }
+
+  chat(query) {
+    return this.inner.chat(query);
+  }
+
+  setupContext(context, query) {
+    return this.inner.setupContext(context, query);
+  }
+
+  executeCode(code) {
+    return this.inner.executeCode(code);
+  }
+
+  costSummary() {
+    return this.inner.costSummary();
+  }
+
+  lastJson() {
+    return this.inner.lastJson();
+  }
+
+  reset() {
+    return this.inner.reset();
+  }
+}
+
+module.exports = {
+  RlmRepl,
+  findCodeBlocks: native.findCodeBlocks,
+  findFinalAnswer: native.findFinalAnswer,
+  convertContext: native.convertContext,
+};
@@ -0,0 +1,253 @@
+use std::sync::Arc;
+
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use rlm::rlm::{RlmConfig, RlmRepl};
+use rlm::trace::ProgressEvent;
+use rlm::utils::{self, FinalAnswerKind, context_from_value, convert_context_for_repl};
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+
+type EventCallback = ThreadsafeFunction<(String, Value), ErrorStrategy::Fatal>;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct RlmOptions {
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub recursive_model: Option<String>,
+    pub max_iterations: Option<u32>,
+    pub depth: Option<u32>,
+    pub enable_logging: Option<bool>,
+    pub disable_recursive: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[napi(object)]
+pub struct ReplResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub locals: Vec<Vec<String>>,
+    pub execution_time: f64,
+}
+
+#[napi(object)]
+pub struct FinalAnswer {
+    pub kind: String,
+    pub content: String,
+}
+
+#[napi(object)]
+pub struct ReplContext {
+    pub json: Option<Value>,
+    pub text: Option<String>,
+}
+
+#[napi(js_name = "RlmRepl")]
+pub struct JsRlmRepl {
+    inner: Arc<Mutex<RlmRepl>>,
+    on_event: Option<EventCallback>,
+}
+
+#[napi]
+impl JsRlmRepl {
+    #[napi(constructor)]
+    pub fn new(options: Option<RlmOptions>, on_event: Option<EventCallback>) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        let mut builder = RlmConfig::builder();
+        if let Some(api_key) = options
+            .api_key
+            .or_else(|| std::env::var("OPENAI_API_KEY").ok())
+        {
+            builder = builder.api_key(api_key);
+        }
+        if let Some(base_url) = options.base_url {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(model) = options.model {
+            builder = builder.model(model);
+        }
+        if let Some(model) = options.recursive_model {
+            builder = builder.recursive_model(model);
+        }
+        if let Some(max_iterations) = options.max_iterations {
+            builder = builder.max_iterations(max_iterations as usize);
+        }
+        if let Some(depth) = options.depth {
+            builder = builder.depth(depth as usize);
+        }
+        if let Some(enabled) = options.enable_logging {
+            builder = builder.enable_logging(enabled);
+        }
+        if let Some(disabled) = options.disable_recursive {
+            builder = builder.disable_recursive(disabled);
+        }
+        let config = builder.build().map_err(generic_error)?;
+        let repl = RlmRepl::new(config).map_err(generic_error)?;
+        // Each progress event is emitted under its own name, e.g.
+        // `iteration_started`, with the rest of its fields as the payload.
+        if let Some(on_event) = on_event.clone() {
+            repl.set_progress(Some(Arc::new(move |event: &ProgressEvent| {
+                if let Ok(Value::Object(mut payload)) = serde_json::to_value(event)
+                    && let Some(Value::String(name)) = payload.remove("event")
+                {
+                    on_event.call(
+                        (name, Value::Object(payload)),
+                        ThreadsafeFunctionCallMode::NonBlocking,
+                    );
+                }
+            })));
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(repl)),
+            on_event,
+        })
+    }
+
+    #[napi]
+    pub async fn completion(&self, context: Value, query: Option<String>) -> Result<String> {
+        self.emit("start", json!({ "query": query }));
+        let mut repl = self.inner.lock().await;
+        match repl
+            .completion(context_from_value(Some(context)), query.as_deref())
+            .await
+        {
+            Ok(answer) => {
+                self.emit("final", json!({ "answer": answer }));
+                Ok(answer)
+            }
+            Err(err) => {
+                self.emit("error", json!({ "message": err.to_string() }));
+                Err(generic_error(err))
+            }
+        }
+    }
+
+    #[napi]
+    pub async fn chat(&self, query: String) -> Result<String> {
+        self.emit("start", json!({ "query": query }));
+        let mut repl = self.inner.lock().await;
+        match repl.chat(&query).await {
+            Ok(answer) => {
+                self.emit("final", json!({ "answer": answer }));
+                Ok(answer)
+            }
+            Err(err) => {
+                self.emit("error", json!({ "message": err.to_string() }));
+                Err(generic_error(err))
+            }
+        }
+    }
+
+    #[napi]
+    pub async fn setup_context(
+        &self,
+        context: Value,
+        query: Option<String>,
+    ) -> Result<Vec<ChatMessage>> {
+        let mut repl = self.inner.lock().await;
+        let messages = repl
+            .setup_context(context_from_value(Some(context)), query.as_deref())
+            .await
+            .map_err(generic_error)?;
+        Ok(messages
+            .into_iter()
+            .map(|message| ChatMessage {
+                role: message.role,
+                content: message.content,
+            })
+            .collect())
+    }
+
+    #[napi]
+    pub async fn execute_code(&self, code: String) -> Result<ReplResult> {
+        let mut repl = self.inner.lock().await;
+        let result = repl.execute_code(&code).await.map_err(generic_error)?;
+        Ok(ReplResult {
+            stdout: result.stdout,
+            stderr: result.stderr,
+            locals: result
+                .locals
+                .into_iter()
+                .map(|local| vec![local.name, local.repr])
+                .collect(),
+            execution_time: result.execution_time,
+        })
+    }
+
+    #[napi]
+    pub fn cost_summary(&self) -> Result<Value> {
+        let repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| Error::from_reason("RlmRepl is busy"))?;
+        serde_json::to_value(repl.cost_summary()).map_err(generic_error)
+    }
+
+    #[napi]
+    pub fn last_json(&self) -> Result<Option<Value>> {
+        let repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| Error::from_reason("RlmRepl is busy"))?;
+        Ok(repl.last_json().cloned())
+    }
+
+    #[napi]
+    pub fn reset(&self) -> Result<()> {
+        let mut repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| Error::from_reason("RlmRepl is busy"))?;
+        repl.reset();
+        Ok(())
+    }
+
+    fn emit(&self, event: &str, payload: Value) {
+        if let Some(on_event) = &self.on_event {
+            on_event.call(
+                (event.to_owned(), payload),
+                ThreadsafeFunctionCallMode::NonBlocking,
+            );
+        }
+    }
+}
+
+#[napi]
+pub fn find_code_blocks(text: String) -> Vec<String> {
+    utils::find_code_blocks(&text)
+}
+
+#[napi]
+pub fn find_final_answer(text: String) -> Option<FinalAnswer> {
+    let (kind, content) = utils::find_final_answer(&text)?;
+    let kind = match kind {
+        FinalAnswerKind::Final => "final",
+        FinalAnswerKind::FinalVar => "final_var",
+        FinalAnswerKind::FinalJson => "final_json",
+    };
+    Some(FinalAnswer {
+        kind: kind.to_owned(),
+        content,
+    })
+}
+
+#[napi]
+pub fn convert_context(context: Value) -> ReplContext {
+    let data = convert_context_for_repl(context_from_value(Some(context)));
+    ReplContext {
+        json: data.json,
+        text: data.text,
+    }
+}
+
+fn generic_error(err: impl std::fmt::Display) -> Error {
+    Error::from_reason(err.to_string())
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use rlm::rlm::{RlmConfig, RlmRepl};
+use rlm::utils::{ContextInput, context_from_value};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+#[pyclass(name = "ReplResult", get_all)]
+struct PyReplResult {
+    stdout: String,
+    stderr: String,
+    locals: Vec<(String, String)>,
+    execution_time: f64,
+}
+
+#[pyclass(name = "RlmRepl")]
+struct PyRlmRepl {
+    inner: Arc<Mutex<RlmRepl>>,
+}
+
+#[pymethods]
+impl PyRlmRepl {
+    #[new]
+    #[pyo3(signature = (
+        api_key=None,
+        base_url=None,
+        model=None,
+        recursive_model=None,
+        max_iterations=None,
+        depth=None,
+        enable_logging=None,
+        disable_recursive=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        api_key: Option<String>,
+        base_url: Option<String>,
+        model: Option<String>,
+        recursive_model: Option<String>,
+        max_iterations: Option<usize>,
+        depth: Option<usize>,
+        enable_logging: Option<bool>,
+        disable_recursive: Option<bool>,
+    ) -> PyResult<Self> {
+        let mut builder = RlmConfig::builder();
+        if let Some(api_key) = api_key.or_else(|| std::env::var("OPENAI_API_KEY").ok()) {
+            builder = builder.api_key(api_key);
+        }
+        if let Some(base_url) = base_url {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(model) = model {
+            builder = builder.model(model);
+        }
+        if let Some(model) = recursive_model {
+            builder = builder.recursive_model(model);
+        }
+        if let Some(max_iterations) = max_iterations {
+            builder = builder.max_iterations(max_iterations);
+        }
+        if let Some(depth) = depth {
+            builder = builder.depth(depth);
+        }
+        if let Some(enabled) = enable_logging {
+            builder = builder.enable_logging(enabled);
+        }
+        if let Some(disabled) = disable_recursive {
+            builder = builder.disable_recursive(disabled);
+        }
+        let config = builder
+            .build()
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        let repl = RlmRepl::new(config).map_err(runtime_error)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(repl)),
+        })
+    }
+
+    #[pyo3(signature = (context, query=None))]
+    fn completion<'py>(
+        &self,
+        py: Python<'py>,
+        context: &Bound<'py, PyAny>,
+        query: Option<String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let context = context_from_py(context)?;
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut repl = inner.lock().await;
+            repl.completion(context, query.as_deref())
+                .await
+                .map_err(runtime_error)
+        })
+    }
+
+    fn chat<'py>(&self, py: Python<'py>, query: String) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut repl = inner.lock().await;
+            repl.chat(&query).await.map_err(runtime_error)
+        })
+    }
+
+    #[pyo3(signature = (context, query=None))]
+    fn setup_context<'py>(
+        &self,
+        py: Python<'py>,
+        context: &Bound<'py, PyAny>,
+        query: Option<String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let context = context_from_py(context)?;
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut repl = inner.lock().await;
+            let messages = repl
+                .setup_context(context, query.as_deref())
+                .await
+                .map_err(runtime_error)?;
+            Ok(messages
+                .into_iter()
+                .map(|message| {
+                    HashMap::from([
+                        ("role".to_owned(), message.role),
+                        ("content".to_owned(), message.content),
+                    ])
+                })
+                .collect::<Vec<_>>())
+        })
+    }
+
+    fn execute_code<'py>(&self, py: Python<'py>, code: String) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut repl = inner.lock().await;
+            let result = repl.execute_code(&code).await.map_err(runtime_error)?;
+            Ok(PyReplResult {
+                stdout: result.stdout,
+                stderr: result.stderr,
+                locals: result
+                    .locals
+                    .into_iter()
+                    .map(|local| (local.name, local.repr))
+                    .collect(),
+                execution_time: result.execution_time,
+            })
+        })
+    }
+
+    fn cost_summary<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| PyRuntimeError::new_err("RlmRepl is busy"))?;
+        let json = serde_json::to_string(&repl.cost_summary())
+            .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+        py.import("json")?.call_method1("loads", (json,))
+    }
+
+    fn last_json<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        let repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| PyRuntimeError::new_err("RlmRepl is busy"))?;
+        let Some(value) = repl.last_json() else {
+            return Ok(None);
+        };
+        let json = value.to_string();
+        py.import("json")?.call_method1("loads", (json,)).map(Some)
+    }
+
+    fn reset(&self) -> PyResult<()> {
+        let mut repl = self
+            .inner
+            .try_lock()
+            .map_err(|_| PyRuntimeError::new_err("RlmRepl is busy"))?;
+        repl.reset();
+        Ok(())
+    }
+}
+
+fn context_from_py(context: &Bound<'_, PyAny>) -> PyResult<ContextInput> {
+    if let Ok(text) = context.extract::<String>() {
+        return Ok(ContextInput::Text(text));
+    }
+    let json = context
+        .py()
+        .import("json")?
+        .call_method1("dumps", (context,))?
+        .extract::<String>()?;
+    let value: Value = serde_json::from_str(&json)
+        .map_err(|err| PyValueError::new_err(format!("invalid context: {err}")))?;
+    Ok(context_from_value(Some(value)))
+}
+
+fn runtime_error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(err.to_string())
+}
+
+#[pymodule]
+fn rlm_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyRlmRepl>()?;
+    m.add_class::<PyReplResult>()?;
+    Ok(())
+}
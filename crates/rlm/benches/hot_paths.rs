@@ -0,0 +1,123 @@
+use std::hint::black_box;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rlm::llm::{LlmClient, LlmError, Message};
+use rlm::repl::{ReplEnv, RuntimeServices, SharedProgramState};
+use rlm::utils::{ContextData, find_code_blocks, find_final_answer};
+use tokio::runtime::Runtime;
+
+const MB: usize = 1024 * 1024;
+
+struct NoopLlm;
+
+#[async_trait]
+impl LlmClient for NoopLlm {
+    async fn completion(
+        &self,
+        _messages: &[Message],
+        _max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        Ok(String::new())
+    }
+}
+
+fn services(runtime: &Runtime) -> RuntimeServices {
+    RuntimeServices {
+        llm_client: Arc::new(NoopLlm),
+        recursive_runner: None,
+        recursion_depth: 0,
+        shared_state: SharedProgramState::new(),
+        runtime_handle: runtime.handle().clone(),
+    }
+}
+
+fn text_context(bytes: usize) -> ContextData {
+    ContextData {
+        json: None,
+        text: Some("x".repeat(bytes)),
+    }
+}
+
+fn long_response(blocks: usize) -> String {
+    let mut text = String::new();
+    for idx in 0..blocks {
+        text.push_str(&format!(
+            "Step {idx}: inspect the next chunk.\n\n```repl\nchunk = \
+             context[{idx}]\nprint(len(chunk))\n```\n\n"
+        ));
+    }
+    text.push_str("FINAL(the answer is 42)\n");
+    text
+}
+
+fn bench_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parsing");
+    for blocks in [10, 100, 1_000] {
+        let text = long_response(blocks);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("find_code_blocks", blocks),
+            &text,
+            |b, text| b.iter(|| find_code_blocks(black_box(text))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("find_final_answer", blocks),
+            &text,
+            |b, text| b.iter(|| find_final_answer(black_box(text))),
+        );
+    }
+    group.finish();
+}
+
+fn bench_repl_init(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let services = services(&runtime);
+    let mut group = c.benchmark_group("repl_init");
+    group.sample_size(10);
+    group.bench_function("create", |b| {
+        b.iter(|| ReplEnv::create(&services).expect("create repl"))
+    });
+    for size in [MB, 10 * MB, 100 * MB] {
+        let context = text_context(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("context_write", size / MB),
+            &context,
+            |b, context| {
+                b.iter_batched(
+                    || {
+                        (
+                            ReplEnv::create(&services).expect("create repl"),
+                            context.clone(),
+                        )
+                    },
+                    |(mut env, context)| env.init(context, None).expect("init repl"),
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_locals(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let services = services(&runtime);
+    let mut group = c.benchmark_group("locals");
+    group.sample_size(20);
+    for count in [10, 100, 1_000] {
+        let mut env = ReplEnv::create(&services).expect("create repl");
+        env.init(text_context(0), None).expect("init repl");
+        let setup: String = (0..count).map(|idx| format!("v{idx} = {idx}\n")).collect();
+        env.execute(&setup).expect("populate locals");
+        group.bench_function(BenchmarkId::new("execute_collect", count), |b| {
+            b.iter(|| env.execute(black_box("x = 1")).expect("execute"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsing, bench_repl_init, bench_locals);
+criterion_main!(benches);
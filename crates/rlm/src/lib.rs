@@ -1,6 +1,9 @@
 pub mod llm;
 pub mod logger;
 pub mod prompts;
+pub mod redact;
 pub mod repl;
 pub mod rlm;
+pub mod schema;
+pub mod trace;
 pub mod utils;
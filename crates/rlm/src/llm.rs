@@ -1,9 +1,14 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
 
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::Instrument;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
@@ -34,6 +39,32 @@ impl Message {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    #[default]
+    Stop,
+    Length,
+}
+
+/// Token counts a backend reported for one call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub content: String,
+    pub finish_reason: FinishReason,
+    /// `None` when the backend did not report usage.
+    pub usage: Option<TokenUsage>,
+}
+
 #[derive(Debug, Error)]
 pub enum LlmError {
     #[error("missing api key")]
@@ -42,15 +73,64 @@ pub enum LlmError {
     Http(#[from] reqwest::Error),
     #[error("invalid response")]
     InvalidResponse,
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    #[error("token or cost budget exhausted")]
+    BudgetExhausted,
+    /// Reported by whatever served the request on this client's behalf.
+    #[error("{message}")]
+    Remote { message: String, upstream: bool },
 }
 
-#[async_trait]
+impl LlmError {
+    /// Rate limits, 5xx responses and transport failures, which another
+    /// backend may not share.
+    pub fn is_upstream_failure(&self) -> bool {
+        let err = match self {
+            Self::Http(err) => err,
+            Self::Remote { upstream, .. } => return *upstream,
+            _ => return false,
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        if err.is_connect() || err.is_timeout() {
+            return true;
+        }
+        err.status().is_some_and(|status| {
+            status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const PRIMARY_ATTEMPTS: usize = 2;
+#[cfg(not(target_arch = "wasm32"))]
+const PRIMARY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 pub trait LlmClient: Send + Sync {
     async fn completion(
         &self,
         messages: &[Message],
         max_completion_tokens: Option<u32>,
     ) -> Result<String, LlmError>;
+
+    /// Like `completion`, but also reports whether the output hit the token
+    /// cap.
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let content = self.completion(messages, max_completion_tokens).await?;
+        Ok(Completion {
+            content,
+            finish_reason: FinishReason::Stop,
+            usage: None,
+        })
+    }
 }
 
 pub struct LlmClientImpl {
@@ -62,10 +142,12 @@ pub struct LlmClientImpl {
 
 impl LlmClientImpl {
     pub fn new(api_key: String, base_url: String, model: String) -> Result<Self, LlmError> {
-        let client = Client::builder()
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(300))
-            .build()?;
+        let builder = Client::builder();
+        #[cfg(not(target_arch = "wasm32"))]
+        let builder = builder
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT);
+        let client = builder.build()?;
         Ok(Self {
             client,
             api_key,
@@ -88,11 +170,14 @@ struct ChatRequest<'a> {
 #[derive(Deserialize)]
 struct ChatResponse {
     choices: Vec<ChatChoice>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
 }
 
 #[derive(Deserialize)]
 struct ChatChoice {
     message: ChatMessage,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -100,13 +185,24 @@ struct ChatMessage {
     content: Option<String>,
 }
 
-#[async_trait]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl LlmClient for LlmClientImpl {
     async fn completion(
         &self,
         messages: &[Message],
         max_completion_tokens: Option<u32>,
     ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
         let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
         let body = ChatRequest {
             model: &self.model,
@@ -115,23 +211,421 @@ impl LlmClient for LlmClientImpl {
             max_tokens: max_completion_tokens,
         };
 
-        let response = self
-            .client
-            .post(url)
-            .bearer_auth(&self.api_key)
-            .json(&body)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let parsed: ChatResponse = response.json().await?;
-        let content = parsed
-            .choices
-            .into_iter()
-            .next()
-            .and_then(|choice| choice.message.content)
-            .ok_or(LlmError::InvalidResponse)?;
-
-        Ok(content)
+        let span = tracing::info_span!("llm_completion", model = %self.model);
+        async {
+            let request = self.client.post(url).bearer_auth(&self.api_key).json(&body);
+            #[cfg(target_arch = "wasm32")]
+            let request = request.timeout(REQUEST_TIMEOUT);
+            let response = inject_trace_context(request)
+                .send()
+                .await?
+                .error_for_status()?;
+
+            let parsed: ChatResponse = response.json().await?;
+            let choice = parsed
+                .choices
+                .into_iter()
+                .next()
+                .ok_or(LlmError::InvalidResponse)?;
+            let finish_reason = match choice.finish_reason.as_deref() {
+                Some("length") => FinishReason::Length,
+                _ => FinishReason::Stop,
+            };
+            // Reasoning models can spend the whole cap before emitting any
+            // content.
+            let content = match (choice.message.content, finish_reason) {
+                (Some(content), _) => content,
+                (None, FinishReason::Length) => String::new(),
+                (None, FinishReason::Stop) => return Err(LlmError::InvalidResponse),
+            };
+            Ok(Completion {
+                content,
+                finish_reason,
+                usage: parsed.usage,
+            })
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Builds the client for one model at one endpoint, for the root, fallback
+/// and recursive calls of a REPL and its `rlm_query` children.
+pub trait LlmClientFactory: Send + Sync {
+    fn client(
+        &self,
+        model: &str,
+        api_key: Option<&str>,
+        base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError>;
+}
+
+/// Calls the endpoint directly over HTTP.
+pub struct HttpLlmClientFactory;
+
+impl LlmClientFactory for HttpLlmClientFactory {
+    fn client(
+        &self,
+        model: &str,
+        api_key: Option<&str>,
+        base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError> {
+        let api_key = api_key.ok_or(LlmError::MissingApiKey)?;
+        Ok(Arc::new(LlmClientImpl::new(
+            api_key.to_owned(),
+            base_url.to_owned(),
+            model.to_owned(),
+        )?))
+    }
+}
+
+/// Delegates to a client that can be replaced while REPLs hold a handle to it.
+pub(crate) struct SwappableLlmClient {
+    inner: RwLock<Arc<dyn LlmClient>>,
+}
+
+impl SwappableLlmClient {
+    pub(crate) fn new(inner: Arc<dyn LlmClient>) -> Self {
+        Self {
+            inner: RwLock::new(inner),
+        }
+    }
+
+    pub(crate) fn swap(&self, inner: Arc<dyn LlmClient>) {
+        *self.inner.write().expect("llm client lock poisoned") = inner;
+    }
+
+    fn current(&self) -> Arc<dyn LlmClient> {
+        self.inner.read().expect("llm client lock poisoned").clone()
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl LlmClient for SwappableLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.current()
+            .completion(messages, max_completion_tokens)
+            .await
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        self.current()
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+    }
+}
+
+#[cfg(feature = "otel")]
+fn inject_trace_context(request: RequestBuilder) -> RequestBuilder {
+    use std::collections::HashMap;
+
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = tracing::Span::current().context();
+    let mut headers = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut headers);
+    });
+    headers.into_iter().fold(request, |request, (name, value)| {
+        request.header(name, value)
+    })
+}
+
+#[cfg(not(feature = "otel"))]
+fn inject_trace_context(request: RequestBuilder) -> RequestBuilder {
+    request
+}
+
+/// Retries upstream failures on `primary`, then sends the request to
+/// `fallback`, recording in `used` that it did.
+pub(crate) struct FallbackLlmClient {
+    primary: Arc<dyn LlmClient>,
+    fallback: Arc<dyn LlmClient>,
+    used: Arc<AtomicBool>,
+}
+
+impl FallbackLlmClient {
+    pub(crate) fn new(
+        primary: Arc<dyn LlmClient>,
+        fallback: Arc<dyn LlmClient>,
+        used: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            primary,
+            fallback,
+            used,
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl LlmClient for FallbackLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        for attempt in 1..=PRIMARY_ATTEMPTS {
+            match self
+                .primary
+                .completion_with_finish_reason(messages, max_completion_tokens)
+                .await
+            {
+                Err(err) if err.is_upstream_failure() => {
+                    tracing::warn!(attempt, error = %err, "primary llm request failed");
+                }
+                result => return result,
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if attempt < PRIMARY_ATTEMPTS {
+                tokio::time::sleep(PRIMARY_RETRY_DELAY).await;
+            }
+        }
+        self.used.store(true, Ordering::Relaxed);
+        self.fallback
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+    }
+}
+
+/// Cut-off shared by every LLM call in a REPL tree; unset means calls are
+/// bounded only by the HTTP timeout.
+#[derive(Clone, Default)]
+pub(crate) struct Deadline {
+    #[cfg(not(target_arch = "wasm32"))]
+    at: Arc<RwLock<Option<Instant>>>,
+}
+
+impl Deadline {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn set(&self, at: Option<Instant>) {
+        *self.at.write().expect("deadline lock poisoned") = at;
+    }
+
+    /// Moves the deadline to at most `max` from now until the returned
+    /// guard is dropped, which restores the current one.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn tighten(&self, max: Duration) -> DeadlineGuard {
+        let outer = self.get();
+        let at = Instant::now() + max;
+        self.set(Some(outer.map_or(at, |outer| outer.min(at))));
+        DeadlineGuard {
+            deadline: self.clone(),
+            outer,
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn get(&self) -> Option<Instant> {
+        *self.at.read().expect("deadline lock poisoned")
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn wrap(&self, inner: Arc<dyn LlmClient>) -> Arc<dyn LlmClient> {
+        Arc::new(DeadlineLlmClient {
+            inner,
+            deadline: self.clone(),
+        })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn wrap(&self, inner: Arc<dyn LlmClient>) -> Arc<dyn LlmClient> {
+        inner
+    }
+
+    /// Tracks iteration lengths so the loop can stop while the final answer
+    /// still fits.
+    pub(crate) fn iterations(&self) -> IterationBudget {
+        IterationBudget {
+            #[cfg(not(target_arch = "wasm32"))]
+            deadline: self.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            started: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            longest: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct DeadlineGuard {
+    deadline: Deadline,
+    outer: Option<Instant>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        self.deadline.set(self.outer);
+    }
+}
+
+pub(crate) struct IterationBudget {
+    #[cfg(not(target_arch = "wasm32"))]
+    deadline: Deadline,
+    #[cfg(not(target_arch = "wasm32"))]
+    started: Option<Instant>,
+    #[cfg(not(target_arch = "wasm32"))]
+    longest: Duration,
+}
+
+impl IterationBudget {
+    /// Starts the next iteration, or returns false when the time left is
+    /// shorter than the slowest iteration so far.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn start(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(started) = self.started {
+            self.longest = self.longest.max(now - started);
+        }
+        self.started = Some(now);
+        self.deadline
+            .get()
+            .is_none_or(|at| at.saturating_duration_since(now) > self.longest)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn start(&mut self) -> bool {
+        true
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct DeadlineLlmClient {
+    inner: Arc<dyn LlmClient>,
+    deadline: Deadline,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl LlmClient for DeadlineLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let request = self
+            .inner
+            .completion_with_finish_reason(messages, max_completion_tokens);
+        match self.deadline.get() {
+            Some(at) => tokio::time::timeout_at(at.into(), request)
+                .await
+                .map_err(|_| LlmError::DeadlineExceeded)?,
+            None => request.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// Answers every request with `answer`, or `InvalidResponse` without one.
+    struct Fixed {
+        answer: Option<&'static str>,
+        calls: AtomicUsize,
+    }
+
+    impl Fixed {
+        fn new(answer: Option<&'static str>) -> Arc<Self> {
+            Arc::new(Self {
+                answer,
+                calls: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl LlmClient for Fixed {
+        async fn completion(&self, _: &[Message], _: Option<u32>) -> Result<String, LlmError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.answer
+                .map(str::to_owned)
+                .ok_or(LlmError::InvalidResponse)
+        }
+    }
+
+    fn fallback_client(
+        primary: Arc<dyn LlmClient>,
+        fallback: Arc<dyn LlmClient>,
+    ) -> (FallbackLlmClient, Arc<AtomicBool>) {
+        let used = Arc::new(AtomicBool::new(false));
+        (
+            FallbackLlmClient::new(primary, fallback, used.clone()),
+            used,
+        )
+    }
+
+    #[tokio::test]
+    async fn unreachable_primary_falls_back() {
+        // Nothing listens on port 1, so every attempt fails to connect.
+        let primary = LlmClientImpl::new(
+            "key".to_owned(),
+            "http://127.0.0.1:1".to_owned(),
+            "model".to_owned(),
+        )
+        .unwrap();
+        let fallback = Fixed::new(Some("from fallback"));
+        let (client, used) = fallback_client(Arc::new(primary), fallback.clone());
+        let answer = client.completion(&[Message::user("hi")], None).await;
+        assert_eq!(answer.unwrap(), "from fallback");
+        assert!(used.load(Ordering::Relaxed));
+        assert_eq!(fallback.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let primary = Fixed::new(None);
+        let fallback = Fixed::new(Some("from fallback"));
+        let (client, used) = fallback_client(primary.clone(), fallback.clone());
+        let err = client
+            .completion(&[Message::user("hi")], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::InvalidResponse));
+        assert!(!err.is_upstream_failure());
+        assert_eq!(primary.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(fallback.calls.load(Ordering::Relaxed), 0);
+        assert!(!used.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn healthy_primary_answers() {
+        let (client, used) = fallback_client(Fixed::new(Some("primary")), Fixed::new(None));
+        let answer = client.completion(&[Message::user("hi")], None).await;
+        assert_eq!(answer.unwrap(), "primary");
+        assert!(!used.load(Ordering::Relaxed));
     }
 }
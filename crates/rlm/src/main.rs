@@ -48,16 +48,14 @@ async fn main() -> anyhow::Result<()> {
     })
     .await?;
 
-    let config = RlmConfig {
-        api_key: Some(std::env::var("OPENAI_API_KEY")?),
-        base_url: "https://api.openai.com/v1".to_owned(),
-        model: "gpt-5".to_owned(),
-        recursive_model: "gpt-5-nano".to_owned(),
-        depth: 0,
-        enable_logging: true,
-        max_iterations: 10,
-        disable_recursive: false,
-    };
+    let config = RlmConfig::builder()
+        .api_key(std::env::var("OPENAI_API_KEY")?)
+        .model("gpt-5")
+        .recursive_model("gpt-5-nano")
+        .depth(0)
+        .enable_logging(true)
+        .max_iterations(10)
+        .build()?;
     let mut rlm = RlmRepl::new(config)?;
     let query = "I'm looking for a magic number. What is it?";
     let start = Instant::now();
@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
 use crate::llm::Message;
 
 pub const DEFAULT_QUERY: &str = "Please read through the context and answer any queries or \
@@ -53,11 +56,112 @@ const USER_PROMPT: &str = "Think step-by-step on what to do using the REPL envir
                            needed, avoid exhaustive loops, and stop once you have enough \
                            information. Your next action:";
 
+const VERIFIER_SYSTEM_PROMPT: &str =
+    "You review answers produced by an assistant that explored a context in a Python REPL. Check \
+     whether the answer responds to the query and is supported by the REPL history you are given. \
+     Reply with PASS on its own if it is. Otherwise reply with FAIL, then a short explanation of \
+     what is wrong or missing.";
+
+// Most characters of REPL history a verifier is shown; the oldest are
+// dropped first.
+const MAX_VERIFIER_EVIDENCE: usize = 40_000;
+
+/// Replacements for the built-in prompts; unset fields keep the defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PromptConfig {
+    /// Used instead of `REPL_SYSTEM_PROMPT`.
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Appended to the system prompt, e.g. deployment-specific guidance.
+    #[serde(default)]
+    pub system_prompt_suffix: Option<String>,
+    /// Instruction sent with each iteration instead of the built-in one;
+    /// `{query}` and `{iteration}` are filled in.
+    #[serde(default)]
+    pub next_action_template: Option<String>,
+}
+
+impl PromptConfig {
+    pub fn system_prompt(&self) -> String {
+        let base = self.system_prompt.as_deref().unwrap_or(REPL_SYSTEM_PROMPT);
+        match &self.system_prompt_suffix {
+            Some(suffix) => format!("{base}\n\n{suffix}"),
+            None => base.to_owned(),
+        }
+    }
+
+    pub fn next_action(&self, query: &str, iteration: usize, final_answer: bool) -> Message {
+        let template = self.next_action_template.as_deref().unwrap_or(USER_PROMPT);
+        action_prompt(template, query, iteration, final_answer)
+    }
+}
+
 pub fn build_system_prompt() -> Vec<Message> {
     vec![Message::system(REPL_SYSTEM_PROMPT)]
 }
 
 pub fn next_action_prompt(query: &str, iteration: usize, final_answer: bool) -> Message {
+    action_prompt(USER_PROMPT, query, iteration, final_answer)
+}
+
+/// Added to the system prompt when final answers must match a schema.
+pub fn response_schema_prompt(schema: &Value) -> String {
+    format!(
+        "Your final answer must be JSON matching this schema:\n{schema}\nGive it with \
+         FINAL_JSON(value), where value is either a JSON literal or the name of a REPL variable \
+         holding the answer, e.g. FINAL_JSON(result). Do not use FINAL or FINAL_VAR."
+    )
+}
+
+/// Asks a verifier to check `answer` against the REPL history in `history`.
+pub fn verifier_prompt(query: &str, history: &[Message], answer: &str) -> Vec<Message> {
+    let evidence = history
+        .iter()
+        .filter(|message| message.role != "system")
+        .map(|message| message.content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let skip = evidence
+        .chars()
+        .count()
+        .saturating_sub(MAX_VERIFIER_EVIDENCE);
+    let evidence = match evidence.char_indices().nth(skip) {
+        Some((start, _)) if skip > 0 => format!("...{}", &evidence[start..]),
+        _ => evidence,
+    };
+    vec![
+        Message::system(VERIFIER_SYSTEM_PROMPT),
+        Message::user(format!(
+            "Query:\n{query}\n\nREPL history:\n{evidence}\n\nAnswer to check:\n{answer}"
+        )),
+    ]
+}
+
+/// The verifier's objection, or `None` when it accepted the answer. Replies
+/// that do not start with FAIL count as accepted.
+pub fn verifier_rejection(reply: &str) -> Option<String> {
+    let reply = reply.trim();
+    let reason = reply
+        .get(..4)
+        .filter(|verdict| verdict.eq_ignore_ascii_case("FAIL"))
+        .map(|_| reply[4..].trim_start_matches([':', '-', ' ', '\n']).trim())?;
+    Some(if reason.is_empty() {
+        "no reason given".to_owned()
+    } else {
+        reason.to_owned()
+    })
+}
+
+/// Sent after the verifier rejects a final answer.
+pub fn verifier_feedback(reason: &str) -> Message {
+    Message::user(format!(
+        "A reviewer rejected your final answer: {reason}\n\nCheck it against the REPL and give a \
+         corrected final answer."
+    ))
+}
+
+fn action_prompt(template: &str, query: &str, iteration: usize, final_answer: bool) -> Message {
     if final_answer {
         return Message::user(
             "Based on all the information you have, provide a final answer to the user's query.",
@@ -69,11 +173,17 @@ pub fn next_action_prompt(query: &str, iteration: usize, final_answer: bool) ->
                          final answer yet.\n\n";
         return Message::user(format!(
             "{safeguard}{}",
-            USER_PROMPT.replace("{query}", query)
+            fill_template(template, query, iteration)
         ));
     }
     Message::user(format!(
         "The history before is your previous interactions with the REPL environment. {}",
-        USER_PROMPT.replace("{query}", query)
+        fill_template(template, query, iteration)
     ))
 }
+
+fn fill_template(template: &str, query: &str, iteration: usize) -> String {
+    template
+        .replace("{iteration}", &iteration.to_string())
+        .replace("{query}", query)
+}
@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::llm::{Completion, LlmClient, LlmError, Message};
+
+static PLACEHOLDER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[REDACTED_[A-Z0-9_]+_[0-9]+\]").expect("regex"));
+
+/// Finds text that must not reach the upstream LLM.
+pub trait Redactor: Send + Sync {
+    fn find(&self, text: &str) -> Vec<RedactionSpan>;
+}
+
+#[derive(Clone, Debug)]
+pub struct RedactionSpan {
+    pub range: Range<usize>,
+    /// Kind of value hidden, e.g. `EMAIL`; shown in its placeholder.
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RedactionConfig {
+    #[serde(default)]
+    pub patterns: Vec<RedactionPattern>,
+    /// Literal terms, matched case-insensitively.
+    #[serde(default)]
+    pub deny_list: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RedactionPattern {
+    pub label: String,
+    pub pattern: String,
+}
+
+/// Regex and deny-list redaction built from a `RedactionConfig`.
+pub struct PatternRedactor {
+    rules: Vec<(String, Regex)>,
+}
+
+impl PatternRedactor {
+    pub fn new(config: &RedactionConfig) -> Result<Self, regex::Error> {
+        let mut rules = Vec::with_capacity(config.patterns.len() + 1);
+        for pattern in &config.patterns {
+            rules.push((
+                placeholder_label(&pattern.label),
+                Regex::new(&pattern.pattern)?,
+            ));
+        }
+        let terms: Vec<String> = config
+            .deny_list
+            .iter()
+            .map(|term| term.trim())
+            .filter(|term| !term.is_empty())
+            .map(regex::escape)
+            .collect();
+        if !terms.is_empty() {
+            rules.push((
+                "TERM".to_owned(),
+                Regex::new(&format!("(?i){}", terms.join("|")))?,
+            ));
+        }
+        Ok(Self { rules })
+    }
+}
+
+impl Redactor for PatternRedactor {
+    fn find(&self, text: &str) -> Vec<RedactionSpan> {
+        self.rules
+            .iter()
+            .flat_map(|(label, regex)| {
+                regex.find_iter(text).map(|found| RedactionSpan {
+                    range: found.range(),
+                    label: label.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Active redactor and the placeholder map for one REPL tree, or for one
+/// server session when the server redacts before anything reaches a
+/// sandbox. The map never leaves the process that owns it.
+#[derive(Default)]
+pub struct Redaction {
+    redactor: RwLock<Option<Arc<dyn Redactor>>>,
+    map: Mutex<RedactionMap>,
+}
+
+#[derive(Default)]
+struct RedactionMap {
+    by_original: HashMap<String, String>,
+    by_placeholder: HashMap<String, String>,
+}
+
+impl fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redaction").finish_non_exhaustive()
+    }
+}
+
+impl Redaction {
+    pub fn new(redactor: Option<Arc<dyn Redactor>>) -> Self {
+        Self {
+            redactor: RwLock::new(redactor),
+            map: Mutex::default(),
+        }
+    }
+
+    /// An empty map with the same redactor.
+    pub fn renewed(&self) -> Self {
+        Self::new(self.redactor())
+    }
+
+    pub fn set_redactor(&self, redactor: Option<Arc<dyn Redactor>>) {
+        *self.redactor.write().expect("redactor lock poisoned") = redactor;
+    }
+
+    pub fn clear(&self) {
+        *self.map.lock().expect("redaction map lock poisoned") = RedactionMap::default();
+    }
+
+    /// `text` with every match of the active redactor replaced by its
+    /// placeholder.
+    pub fn redact_text(&self, text: &str) -> String {
+        match self.redactor() {
+            Some(redactor) => self.redact(redactor.as_ref(), text),
+            None => text.to_owned(),
+        }
+    }
+
+    /// Redacts every string in `value`, object keys included.
+    pub fn redact_value(&self, value: &mut Value) {
+        if let Some(redactor) = self.redactor() {
+            map_strings(value, &|text| self.redact(redactor.as_ref(), text));
+        }
+    }
+
+    /// Puts back the values behind placeholders this map handed out. Text
+    /// that only looks like a placeholder is left alone.
+    pub fn restore(&self, text: &str) -> String {
+        self.restore_where(text, |_| true)
+    }
+
+    pub fn restore_value(&self, value: &mut Value) {
+        map_strings(value, &|text| self.restore(text));
+    }
+
+    fn redactor(&self) -> Option<Arc<dyn Redactor>> {
+        self.redactor
+            .read()
+            .expect("redactor lock poisoned")
+            .clone()
+    }
+
+    fn redact(&self, redactor: &dyn Redactor, text: &str) -> String {
+        let mut spans = redactor.find(text);
+        if spans.is_empty() {
+            return text.to_owned();
+        }
+        spans.sort_by_key(|span| (span.range.start, std::cmp::Reverse(span.range.end)));
+        let mut map = self.map.lock().expect("redaction map lock poisoned");
+        let mut redacted = String::with_capacity(text.len());
+        let mut cursor = 0;
+        for span in spans {
+            if span.range.start < cursor || span.range.is_empty() {
+                continue;
+            }
+            redacted.push_str(&text[cursor..span.range.start]);
+            let original = &text[span.range.clone()];
+            let placeholder = match map.by_original.get(original) {
+                Some(placeholder) => placeholder.clone(),
+                None => {
+                    let placeholder =
+                        format!("[REDACTED_{}_{}]", span.label, map.by_placeholder.len() + 1);
+                    map.by_original
+                        .insert(original.to_owned(), placeholder.clone());
+                    map.by_placeholder
+                        .insert(placeholder.clone(), original.to_owned());
+                    placeholder
+                }
+            };
+            redacted.push_str(&placeholder);
+            cursor = span.range.end;
+        }
+        redacted.push_str(&text[cursor..]);
+        redacted
+    }
+
+    fn restore_where(&self, text: &str, issued: impl Fn(&str) -> bool) -> String {
+        let map = self.map.lock().expect("redaction map lock poisoned");
+        PLACEHOLDER_RE
+            .replace_all(text, |captures: &regex::Captures<'_>| {
+                let placeholder = &captures[0];
+                map.by_placeholder
+                    .get(placeholder)
+                    .filter(|_| issued(placeholder))
+                    .cloned()
+                    .unwrap_or_else(|| placeholder.to_owned())
+            })
+            .into_owned()
+    }
+}
+
+fn map_strings(value: &mut Value, map: &impl Fn(&str) -> String) {
+    match value {
+        Value::String(text) => *text = map(text),
+        Value::Array(items) => items.iter_mut().for_each(|item| map_strings(item, map)),
+        Value::Object(object) => {
+            *object = std::mem::take(object)
+                .into_iter()
+                .map(|(key, mut item)| {
+                    let key = map(&key);
+                    map_strings(&mut item, map);
+                    (key, item)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
+/// Redacts outgoing messages and restores placeholders in the reply.
+pub(crate) struct RedactingLlmClient {
+    inner: Arc<dyn LlmClient>,
+    redaction: Arc<Redaction>,
+}
+
+impl RedactingLlmClient {
+    pub(crate) fn new(inner: Arc<dyn LlmClient>, redaction: Arc<Redaction>) -> Self {
+        Self { inner, redaction }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl LlmClient for RedactingLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let Some(redactor) = self.redaction.redactor() else {
+            return self
+                .inner
+                .completion_with_finish_reason(messages, max_completion_tokens)
+                .await;
+        };
+        let redacted: Vec<Message> = messages
+            .iter()
+            .map(|message| Message {
+                role: message.role.clone(),
+                content: self.redaction.redact(redactor.as_ref(), &message.content),
+            })
+            .collect();
+        let completion = self
+            .inner
+            .completion_with_finish_reason(&redacted, max_completion_tokens)
+            .await?;
+        // Only placeholders this call showed the model stand for anything;
+        // others in the reply are the model's own text.
+        let sent: HashSet<&str> = redacted
+            .iter()
+            .flat_map(|message| PLACEHOLDER_RE.find_iter(&message.content))
+            .map(|found| found.as_str())
+            .collect();
+        Ok(Completion {
+            content: self
+                .redaction
+                .restore_where(&completion.content, |placeholder| {
+                    sent.contains(placeholder)
+                }),
+            finish_reason: completion.finish_reason,
+            usage: completion.usage,
+        })
+    }
+}
+
+fn placeholder_label(label: &str) -> String {
+    let label: String = label
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() {
+                ch.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if label.is_empty() {
+        "PII".to_owned()
+    } else {
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redaction() -> Redaction {
+        let config = RedactionConfig {
+            patterns: vec![RedactionPattern {
+                label: "email".to_owned(),
+                pattern: r"[\w.+-]+@[\w.-]+".to_owned(),
+            }],
+            deny_list: vec!["Project Falcon".to_owned()],
+        };
+        Redaction::new(Some(Arc::new(PatternRedactor::new(&config).unwrap())))
+    }
+
+    #[test]
+    fn redact_and_restore_round_trip() {
+        let redaction = redaction();
+        let text = "mail a@b.io about project falcon, cc a@b.io";
+        let redacted = redaction.redact_text(text);
+        assert_eq!(
+            redacted,
+            "mail [REDACTED_EMAIL_1] about [REDACTED_TERM_2], cc [REDACTED_EMAIL_1]"
+        );
+        assert_eq!(redaction.restore(&redacted), text);
+    }
+
+    #[test]
+    fn restore_leaves_unissued_placeholders() {
+        let redaction = redaction();
+        redaction.redact_text("a@b.io");
+        assert_eq!(
+            redaction.restore("[REDACTED_EMAIL_1] [REDACTED_EMAIL_7]"),
+            "a@b.io [REDACTED_EMAIL_7]"
+        );
+        assert_eq!(
+            redaction.renewed().restore("[REDACTED_EMAIL_1]"),
+            "[REDACTED_EMAIL_1]"
+        );
+    }
+
+    #[test]
+    fn values_are_redacted_keys_included() {
+        let redaction = redaction();
+        let mut value = serde_json::json!({"a@b.io": ["Project Falcon", 3]});
+        redaction.redact_value(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({"[REDACTED_EMAIL_1]": ["[REDACTED_TERM_2]", 3]})
+        );
+        redaction.restore_value(&mut value);
+        assert_eq!(value, serde_json::json!({"a@b.io": ["Project Falcon", 3]}));
+    }
+
+    #[test]
+    fn labels_become_placeholder_safe() {
+        assert_eq!(placeholder_label("phone-number"), "PHONE_NUMBER");
+        assert_eq!(placeholder_label(""), "PII");
+    }
+}
@@ -0,0 +1,1281 @@
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use rustpython_pylib;
+use rustpython_stdlib;
+use rustpython_vm as vm;
+use rustpython_vm::builtins::{PyBaseException, PyDictRef};
+use rustpython_vm::scope::Scope;
+use rustpython_vm::{Interpreter, InterpreterBuilder};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tempfile::TempDir;
+use tokio::runtime::Handle;
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio::task::JoinSet;
+
+use super::{
+    CodeExecutor, CodeRuntime, CodeRuntimeFactory, LocalValue, RecursiveRunner, ReplResult,
+    RuntimeServices, RuntimeSnapshot, SharedProgramState,
+};
+use crate::llm::{LlmClient, Message};
+use crate::trace::{CostLedger, CostSummary};
+use crate::utils::{ContextData, context_from_value};
+
+#[derive(Debug, Deserialize)]
+struct RlmQueryPayload {
+    query: Option<String>,
+    context: Option<Value>,
+}
+
+const EXECUTION_TIMEOUT_SECS: f64 = 10.0;
+const MAX_SUBCALL_TOTAL_TOKENS_APPROX: usize = 90_000;
+const MAX_SUBCALL_MESSAGE_TOKENS_APPROX: usize = 80_000;
+const MAX_SUBCALL_TOTAL_CHARS: usize = 360_000;
+const MAX_SUBCALL_MESSAGE_CHARS: usize = 320_000;
+
+enum ReplCommand {
+    Init {
+        context: ContextData,
+        setup_code: Option<String>,
+        response: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Execute {
+        code: String,
+        response: oneshot::Sender<anyhow::Result<ReplResult>>,
+    },
+    GetVariable {
+        name: String,
+        response: oneshot::Sender<anyhow::Result<Option<String>>>,
+    },
+    Snapshot {
+        response: oneshot::Sender<anyhow::Result<RuntimeSnapshot>>,
+    },
+    Reset {
+        response: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Shutdown {
+        response: oneshot::Sender<()>,
+    },
+}
+
+#[derive(Clone)]
+pub struct ReplHandle {
+    sender: mpsc::UnboundedSender<ReplCommand>,
+}
+
+struct ReplCore {
+    factory: Arc<dyn CodeRuntimeFactory>,
+    services: RuntimeServices,
+    runtime: Option<Box<dyn CodeRuntime>>,
+}
+
+pub struct PythonRuntimeFactory;
+
+pub struct ReplEnv {
+    interpreter: Interpreter,
+    scope: Scope,
+    temp_dir: TempDir,
+    llm_client: Arc<dyn LlmClient>,
+    cost: CostLedger,
+    runtime_handle: Handle,
+    recursive_runner: Option<Arc<dyn RecursiveRunner>>,
+    recursion_depth: usize,
+    shared_state: SharedProgramState,
+    execution_lock: Mutex<()>,
+    last_hydrated_revision: AtomicU64,
+    collect_detailed_locals: bool,
+}
+
+impl ReplEnv {
+    pub fn new(
+        context: ContextData,
+        llm_client: Arc<dyn LlmClient>,
+        recursive_runner: Option<Arc<dyn RecursiveRunner>>,
+        recursion_depth: usize,
+        shared_state: SharedProgramState,
+        setup_code: Option<&str>,
+        runtime_handle: Handle,
+    ) -> anyhow::Result<Self> {
+        let mut env = Self::create(&RuntimeServices {
+            llm_client,
+            recursive_runner,
+            recursion_depth,
+            shared_state,
+            runtime_handle,
+        })?;
+        env.init(context, setup_code)?;
+        Ok(env)
+    }
+
+    pub fn create(services: &RuntimeServices) -> anyhow::Result<Self> {
+        let builder = InterpreterBuilder::new();
+        let interpreter = init_stdlib(builder).interpreter();
+        let scope = interpreter
+            .enter(|vm: &vm::VirtualMachine| {
+                let scope = vm.new_scope_with_builtins();
+                Ok(scope)
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!("python init error: {err:?}")
+            })?;
+        let temp_dir = TempDir::new()?;
+
+        let initial_revision = services.shared_state.revision();
+        // The client's model is not known here, so `llm_query` calls are
+        // counted under an unnamed model and left unpriced.
+        let cost = CostLedger::default();
+        Ok(Self {
+            interpreter,
+            scope,
+            temp_dir,
+            llm_client: cost.wrap_llm(services.llm_client.clone(), 1, ""),
+            cost,
+            runtime_handle: services.runtime_handle.clone(),
+            recursive_runner: services.recursive_runner.clone(),
+            recursion_depth: services.recursion_depth,
+            shared_state: services.shared_state.clone(),
+            execution_lock: Mutex::new(()),
+            last_hydrated_revision: AtomicU64::new(initial_revision),
+            collect_detailed_locals: cfg!(debug_assertions),
+        })
+    }
+
+    pub fn init(&mut self, context: ContextData, setup_code: Option<&str>) -> anyhow::Result<()> {
+        self.initialize(context)?;
+        if let Some(code) = setup_code {
+            self.execute(code)?;
+        }
+        Ok(())
+    }
+
+    fn initialize(&mut self, context: ContextData) -> anyhow::Result<()> {
+        let llm_client = self.llm_client.clone();
+        let runtime_handle = self.runtime_handle.clone();
+        let recursive_runner = self.recursive_runner.clone();
+        let recursion_depth = self.recursion_depth;
+        let shared_state_revision = self.shared_state.revision();
+        let shared_state_json = self.shared_state.snapshot_json_string()?;
+        let scope = self.scope.clone();
+        let temp_dir = self.temp_dir.path().to_path_buf();
+        let temp_dir_str = temp_dir.to_string_lossy().to_string();
+        let mut json_path: Option<String> = None;
+        let mut text_path: Option<String> = None;
+
+        if let Some(json_value) = context.json {
+            let path = temp_dir.join("context.json");
+            let payload = serde_json::to_vec_pretty(&json_value)?;
+            fs::write(&path, payload)?;
+            json_path = Some(path.to_string_lossy().to_string());
+        }
+
+        if let Some(text) = context.text {
+            let path = temp_dir.join("context.txt");
+            fs::write(&path, text)?;
+            text_path = Some(path.to_string_lossy().to_string());
+        }
+
+        self.interpreter
+            .enter(move |vm: &vm::VirtualMachine| -> vm::PyResult<()> {
+                scope.globals.set_item(
+                    "__rlm_temp_dir",
+                    vm.ctx.new_str(temp_dir_str.as_str()).into(),
+                    vm,
+                )?;
+                scope.globals.set_item(
+                    "__rlm_shared_state_json",
+                    vm.ctx.new_str(shared_state_json.as_str()).into(),
+                    vm,
+                )?;
+                let llm_runtime_handle = runtime_handle.clone();
+                let llm_fn = vm.new_function(
+                    "__rlm_llm_query",
+                    move |prompt: String| -> vm::PyResult<String> {
+                        let messages = parse_llm_prompt(&prompt);
+                        if let Err(err) = validate_subcall_messages(&messages) {
+                            return Ok(format!("Error making LLM query: {err}"));
+                        }
+                        let llm_client = llm_client.clone();
+                        let runtime_handle = llm_runtime_handle.clone();
+                        let response = runtime_handle.block_on(async move {
+                            llm_client
+                                .completion(&messages, None)
+                                .await
+                                .unwrap_or_else(|err| format!("Error making LLM query: {err}"))
+                        });
+                        Ok(response)
+                    },
+                );
+                scope
+                    .globals
+                    .set_item("__rlm_llm_query", llm_fn.into(), vm)?;
+                let recursive_runner_many = recursive_runner.clone();
+                let rlm_runtime_handle = runtime_handle.clone();
+                let rlm_fn = vm.new_function(
+                    "__rlm_rlm_query",
+                    move |payload_json: String| -> vm::PyResult<String> {
+                        if recursion_depth == 0 || recursive_runner_many.is_none() {
+                            return Ok(
+                                "Error: rlm_query disabled at depth 0; increase depth to enable."
+                                    .to_owned(),
+                            );
+                        }
+                        let runner = recursive_runner_many.clone().expect("recursive runner");
+                        let payloads: Vec<RlmQueryPayload> = match serde_json::from_str(&payload_json)
+                        {
+                            Ok(payloads) => payloads,
+                            Err(err) => {
+                                return Ok(format!("Error parsing rlm_query payloads: {err}"));
+                            }
+                        };
+                        if payloads.is_empty() {
+                            return Ok("[]".to_owned());
+                        }
+                        let runtime_handle = rlm_runtime_handle.clone();
+                        let outputs = runtime_handle.block_on(run_rlm_queries(runner, payloads));
+                        Ok(serde_json::to_string(&outputs).unwrap_or_else(|_| "[]".to_owned()))
+                    },
+                );
+                scope
+                    .globals
+                    .set_item("__rlm_rlm_query", rlm_fn.into(), vm)?;
+            let init_segments = [
+                (
+                    "builtins_ref",
+                    r#"__rlm_builtins = __builtins__
+if isinstance(__rlm_builtins, dict):
+    def __rlm_get_builtin(name):
+        return __rlm_builtins.get(name)
+else:
+    def __rlm_get_builtin(name):
+        return getattr(__rlm_builtins, name, None)
+"#,
+                ),
+                (
+                    "builtin_refs",
+                    "__rlm_exec_builtin = __rlm_get_builtin('exec')\n__rlm_eval_builtin = __rlm_get_builtin('eval')\n__rlm_globals_builtin = __rlm_get_builtin('globals')\n",
+                ),
+                (
+                    "safe_list",
+                    r#"__rlm_safe_builtin_names = [
+    "print", "len", "str", "int", "float", "list", "dict", "set", "tuple", "bool",
+    "type", "isinstance", "enumerate", "zip", "map", "filter", "sorted", "min", "max",
+    "sum", "abs", "round", "chr", "ord", "hex", "bin", "oct", "repr", "ascii", "format",
+    "__import__", "open", "any", "all", "hasattr", "getattr", "setattr", "delattr", "dir",
+    "vars", "range", "reversed", "slice", "iter", "next", "pow", "divmod", "complex",
+    "bytes", "bytearray", "memoryview", "hash", "id", "callable", "issubclass", "super",
+    "property", "staticmethod", "classmethod", "object", "BaseException", "ArithmeticError",
+    "LookupError", "EnvironmentError", "AssertionError", "NotImplementedError", "UnicodeError",
+    "Warning", "UserWarning", "DeprecationWarning", "PendingDeprecationWarning", "SyntaxWarning",
+    "RuntimeWarning", "FutureWarning", "ImportWarning", "UnicodeWarning", "BytesWarning",
+    "ResourceWarning", "Exception", "ValueError", "TypeError", "KeyError", "IndexError",
+    "AttributeError", "FileNotFoundError", "OSError", "IOError", "RuntimeError", "NameError",
+    "ImportError", "StopIteration", "GeneratorExit", "SystemExit", "KeyboardInterrupt",
+    "__build_class__",
+]"#,
+                ),
+                (
+                    "safe_builtins",
+                    "__rlm_safe_builtins = {}\nfor __rlm_name in __rlm_safe_builtin_names:\n    __rlm_value = __rlm_get_builtin(__rlm_name)\n    if __rlm_value is not None:\n        __rlm_safe_builtins[__rlm_name] = __rlm_value\n",
+                ),
+                (
+                    "safe_blocklist",
+                    "for __rlm_name in [\"input\", \"eval\", \"exec\", \"compile\", \"globals\", \"locals\"]:\n    __rlm_safe_builtins[__rlm_name] = None\n",
+                ),
+                (
+                    "safe_imports",
+                    r#"__rlm_allowed_modules = {
+    "json", "math", "statistics", "random", "re", "itertools", "functools",
+    "collections", "datetime", "decimal", "fractions", "io", "sys", "time"
+}
+__rlm_import_builtin = __rlm_get_builtin('__import__')
+def __rlm_safe_import(name, globals=None, locals=None, fromlist=(), level=0, _import=__rlm_import_builtin):
+    root = name.split('.')[0]
+    if root not in __rlm_allowed_modules:
+        raise ImportError(f"Import of '{root}' is blocked")
+    return _import(name, globals, locals, fromlist, level)
+"#,
+                ),
+                (
+                    "safe_open",
+                    r#"__rlm_open_builtin = __rlm_get_builtin('open')
+def __rlm_safe_open(path, *args, _import=__rlm_import_builtin, _open=__rlm_open_builtin, _root=__rlm_temp_dir, **kwargs):
+    __rlm_os = _import('os')
+    __rlm_root = __rlm_os.path.abspath(_root)
+    __rlm_path = str(path)
+    if not __rlm_os.path.isabs(__rlm_path):
+        __rlm_path = __rlm_os.path.join(__rlm_root, __rlm_path)
+    __rlm_path = __rlm_os.path.abspath(__rlm_path)
+    if not (__rlm_path == __rlm_root or __rlm_path.startswith(__rlm_root + __rlm_os.sep)):
+        raise PermissionError("open restricted to temp dir")
+    return _open(__rlm_path, *args, **kwargs)
+"#,
+                ),
+                (
+                    "safe_cleanup",
+                    "del __rlm_import_builtin\ndel __rlm_open_builtin\n",
+                ),
+                (
+                    "safe_overrides",
+                    "__rlm_safe_builtins['__import__'] = __rlm_safe_import\n__rlm_safe_builtins['open'] = __rlm_safe_open\n",
+                ),
+                ("builtins_assign", "__builtins__ = __rlm_safe_builtins\n"),
+                ("locals_init", "__rlm_locals = {}\n"),
+                (
+                    "state_init",
+                    r#"import json
+__name__ = '__main__'
+__rlm_state_deleted_keys = set()
+__rlm_state_dirty_keys = set()
+
+class __rlm_TrackingDict(dict):
+    def __setitem__(self, key, value):
+        key = str(key)
+        __rlm_state_deleted_keys.discard(key)
+        __rlm_state_dirty_keys.add(key)
+        return super().__setitem__(key, value)
+
+    def __delitem__(self, key):
+        key = str(key)
+        __rlm_state_dirty_keys.discard(key)
+        __rlm_state_deleted_keys.add(key)
+        return super().__delitem__(key)
+
+    def pop(self, key, default=None):
+        key = str(key)
+        __rlm_state_dirty_keys.discard(key)
+        __rlm_state_deleted_keys.add(key)
+        return super().pop(key, default)
+
+    def clear(self):
+        for key in list(self.keys()):
+            __rlm_state_deleted_keys.add(str(key))
+            __rlm_state_dirty_keys.discard(str(key))
+        return super().clear()
+
+    def update(self, other=(), **kwargs):
+        if hasattr(other, "items"):
+            items = other.items()
+        else:
+            items = other
+        for key, value in items:
+            self[str(key)] = value
+        for key, value in kwargs.items():
+            self[str(key)] = value
+
+    def setdefault(self, key, default=None):
+        key = str(key)
+        if key not in self:
+            self[key] = default
+        return self[key]
+
+def __rlm_replace_state(payload):
+    state.clear()
+    for key, value in payload.items():
+        dict.__setitem__(state, str(key), value)
+    __rlm_state_deleted_keys.clear()
+    __rlm_state_dirty_keys.clear()
+
+state = __rlm_TrackingDict(json.loads(__rlm_shared_state_json))
+
+def state_get(key, default=None):
+    return state.get(str(key), default)
+
+def state_set(key, value):
+    key = str(key)
+    if key in __rlm_state_deleted_keys:
+        __rlm_state_deleted_keys.remove(key)
+    state[key] = value
+    return value
+
+def state_del(key):
+    key = str(key)
+    __rlm_state_deleted_keys.add(key)
+    return state.pop(key, None)
+
+def state_keys():
+    return list(state.keys())
+"#,
+                ),
+                (
+                    "llm_query",
+                    r#"__rlm_json = __rlm_get_builtin('__import__')('json')
+__rlm_sys = __rlm_get_builtin('__import__')('sys')
+
+def llm_query(prompts):
+    if isinstance(prompts, list):
+        payload = __rlm_json.dumps(prompts, default=str)
+    else:
+        payload = __rlm_json.dumps([prompts], default=str)
+    __rlm_gettrace = getattr(__rlm_sys, 'gettrace', None)
+    __rlm_settrace = getattr(__rlm_sys, 'settrace', None)
+    prev_trace = None
+    if __rlm_settrace is not None:
+        prev_trace = __rlm_gettrace() if __rlm_gettrace is not None else None
+        __rlm_settrace(None)
+    try:
+        return __rlm_llm_query(payload)
+    finally:
+        if __rlm_settrace is not None:
+            __rlm_settrace(prev_trace)
+"#,
+                ),
+                (
+                    "rlm_query",
+                    r#"def rlm_query(query, context=None):
+    if isinstance(query, list) and context is None:
+        items = query
+        unwrap_single = False
+    else:
+        items = [query]
+        unwrap_single = True
+    __rlm_json = __rlm_get_builtin('__import__')('json')
+    __rlm_globals = __rlm_globals_builtin()
+    payload_items = []
+    for item in items:
+        if isinstance(item, dict):
+            q = item.get("query")
+            ctx = item.get("context")
+        elif isinstance(item, (list, tuple)) and len(item) == 2:
+            q, ctx = item
+        else:
+            q = item
+            ctx = context
+        if ctx is None:
+            ctx = context
+        if ctx is None:
+            ctx = __rlm_globals.get("context")
+        payload_items.append({"query": str(q), "context": ctx})
+    payload = __rlm_json.dumps(payload_items, default=str)
+    response = __rlm_rlm_query(payload)
+    try:
+        parsed = __rlm_json.loads(response)
+    except Exception:
+        return response
+    if unwrap_single and isinstance(parsed, list) and len(parsed) == 1:
+        return parsed[0]
+    return parsed
+"#,
+                ),
+                (
+                    "final_var",
+                    r#"def FINAL_VAR(name):
+    name = name.strip().strip('"').strip("'").strip('\n').strip('\r')
+    if name in __rlm_locals:
+        return __rlm_locals[name]
+    return f"Error: Variable '{name}' not found in REPL environment"
+"#,
+                ),
+                (
+                    "rlm_exec",
+                    r#"def __rlm_exec(code):
+    __rlm_globals = __rlm_globals_builtin()
+    lines = code.split('\n')
+    import_lines = []
+    other_lines = []
+    for line in lines:
+        if line.startswith(('import ', 'from ')) and not line.startswith('#'):
+            import_lines.append(line)
+        else:
+            other_lines.append(line)
+
+    if import_lines:
+        import_code = '\n'.join(import_lines)
+        __rlm_exec_builtin(import_code, __rlm_globals, __rlm_globals)
+
+    if other_lines:
+        other_code = '\n'.join(other_lines)
+        combined_namespace = {**__rlm_globals, **__rlm_locals}
+        non_comment_lines = [line for line in other_lines if line and not line.startswith('#')]
+
+        if non_comment_lines:
+            last_line = non_comment_lines[-1]
+            is_expression = (
+                not last_line.startswith(('import ', 'from ', 'def ', 'class ', 'if ', 'for ', 'while ', 'try:', 'with ', 'return ', 'yield ', 'break', 'continue', 'pass')) and
+                '=' not in last_line.split('#')[0] and
+                not last_line.endswith(':') and
+                not last_line.startswith('print(')
+            )
+
+            if is_expression:
+                try:
+                    if len(non_comment_lines) > 1:
+                        last_line_start = -1
+                        for i, line in enumerate(other_lines):
+                            if line == last_line:
+                                last_line_start = i
+                                break
+                        if last_line_start > 0:
+                            statements_code = '\n'.join(other_lines[:last_line_start])
+                            __rlm_exec_builtin(statements_code, combined_namespace, combined_namespace)
+
+                    result = __rlm_eval_builtin(last_line, combined_namespace, combined_namespace)
+                    if result is not None:
+                        print(repr(result))
+                except Exception:
+                    __rlm_exec_builtin(other_code, combined_namespace, combined_namespace)
+            else:
+                __rlm_exec_builtin(other_code, combined_namespace, combined_namespace)
+        else:
+            __rlm_exec_builtin(other_code, combined_namespace, combined_namespace)
+
+        for key, value in combined_namespace.items():
+            if key not in __rlm_globals:
+                __rlm_locals[key] = value
+"#,
+                ),
+            ];
+
+            for (label, code) in init_segments {
+                vm.run_string(scope.clone(), code, format!("<rlm_init_{label}>"))?;
+            }
+            if let Some(ref path_str) = json_path {
+                scope
+                    .globals
+                    .set_item(
+                        "__rlm_context_json_path",
+                        vm.ctx.new_str(path_str.as_str()).into(),
+                        vm,
+                    )?;
+                let code =
+                    "import json\nwith open(__rlm_context_json_path, \"r\") as f:\n    context = json.load(f)\n";
+                vm.run_string(scope.clone(), code, "<rlm_context_json>".to_owned())?;
+            }
+
+            if let Some(ref path_str) = text_path {
+                scope
+                    .globals
+                    .set_item(
+                        "__rlm_context_text_path",
+                        vm.ctx.new_str(path_str.as_str()).into(),
+                        vm,
+                    )?;
+                let code = "with open(__rlm_context_text_path, \"r\") as f:\n    context = f.read()\n";
+                vm.run_string(scope.clone(), code, "<rlm_context_text>".to_owned())?;
+            }
+                Ok(())
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| anyhow::anyhow!("python init error: {err:?}"))?;
+
+        self.last_hydrated_revision
+            .store(shared_state_revision, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn execute(&mut self, code: &str) -> anyhow::Result<ReplResult> {
+        let _lock = self
+            .execution_lock
+            .lock()
+            .map_err(|_| anyhow::anyhow!("repl lock poisoned"))?;
+        self.hydrate_shared_state()?;
+        let scope = self.scope.clone();
+        let temp_dir = self.temp_dir.path().to_path_buf();
+        let collect_detailed_locals = self.collect_detailed_locals;
+        let start = Instant::now();
+
+        let mut result = self
+            .interpreter
+            .enter(|vm: &vm::VirtualMachine| -> vm::PyResult<ReplResult> {
+                let temp_dir_str = temp_dir.to_string_lossy().to_string();
+                scope.globals.set_item(
+                    "__rlm_temp_dir",
+                    vm.ctx.new_str(temp_dir_str.as_str()).into(),
+                    vm,
+                )?;
+                let preamble = format!(
+                    "import io, sys, time\n__rlm_old_stdout = sys.stdout\n__rlm_old_stderr = \
+                     sys.stderr\n__rlm_stdout = io.StringIO()\n__rlm_stderr = \
+                     io.StringIO()\nsys.stdout = __rlm_stdout\nsys.stderr = \
+                     __rlm_stderr\n__rlm_exec_deadline = time.time() + \
+                     {EXECUTION_TIMEOUT_SECS}\n\ndef __rlm_trace(frame, event, arg):\n    if \
+                     time.time() > __rlm_exec_deadline:\n        raise TimeoutError('Execution \
+                     time limit exceeded')\n    return __rlm_trace\n\nsys.settrace(__rlm_trace)\n"
+                );
+                vm.run_string(scope.clone(), &preamble, "<rlm_preamble>".to_owned())?;
+                scope
+                    .globals
+                    .set_item("__rlm_code", vm.ctx.new_str(code).into(), vm)?;
+                match vm.run_string(
+                    scope.clone(),
+                    "__rlm_exec(__rlm_code)\n",
+                    "<rlm_exec>".to_owned(),
+                ) {
+                    Ok(_) => {}
+                    Err(exc) => {
+                        vm.print_exception(exc);
+                    }
+                }
+
+                let postamble =
+                    "import sys\nsys.settrace(None)\nsys.stdout = __rlm_old_stdout\nsys.stderr = \
+                     __rlm_old_stderr\n__rlm_stdout_value = \
+                     __rlm_stdout.getvalue()\n__rlm_stderr_value = \
+                     __rlm_stderr.getvalue()\n__rlm_locals['_stdout'] = \
+                     __rlm_stdout_value\n__rlm_locals['_stderr'] = __rlm_stderr_value\n";
+                vm.run_string(scope.clone(), postamble, "<rlm_postamble>".to_owned())?;
+
+                let stdout = get_string_from_scope(vm, &scope, "__rlm_stdout_value");
+                let stderr = get_string_from_scope(vm, &scope, "__rlm_stderr_value");
+                let locals = collect_locals(vm, &scope, collect_detailed_locals);
+                let locals_map = if collect_detailed_locals {
+                    collect_locals_map(vm, &scope)
+                } else {
+                    Vec::new()
+                };
+                Ok(ReplResult {
+                    stdout,
+                    stderr,
+                    locals,
+                    locals_map,
+                    execution_time: start.elapsed().as_secs_f64(),
+                })
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!("python exec error: {err:?}")
+            })?;
+
+        self.sync_shared_state()?;
+        result.execution_time = start.elapsed().as_secs_f64();
+        Ok(result)
+    }
+
+    pub fn get_variable(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let scope = self.scope.clone();
+        self.interpreter
+            .enter(|vm: &vm::VirtualMachine| -> vm::PyResult<Option<String>> {
+                let locals = get_locals_dict(vm, &scope);
+                let value = locals.and_then(|dict| dict.get_item(name, vm).ok());
+                if let Some(value) = value {
+                    let text = match value.str(vm) {
+                        Ok(py_str) => py_str.as_str().to_owned(),
+                        Err(_) => value.repr(vm)?.as_str().to_owned(),
+                    };
+                    Ok(Some(text))
+                } else {
+                    Ok(None)
+                }
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!("python variable error: {err:?}")
+            })
+    }
+
+    pub fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        let scope = self.scope.clone();
+        let snapshot_code = "import json\n__rlm_snapshot = {}\nfor __rlm_key, __rlm_value in \
+                             __rlm_locals.items():\n    if __rlm_key.startswith('_'):\n        \
+                             continue\n    try:\n        json.dumps(__rlm_value)\n    except \
+                             Exception:\n        continue\n    __rlm_snapshot[__rlm_key] = \
+                             __rlm_value\n__rlm_snapshot_payload = json.dumps(__rlm_snapshot)\n";
+        let locals_json = self
+            .interpreter
+            .enter(|vm: &vm::VirtualMachine| -> vm::PyResult<String> {
+                vm.run_string(scope.clone(), snapshot_code, "<rlm_snapshot>".to_owned())?;
+                Ok(get_string_from_scope(vm, &scope, "__rlm_snapshot_payload"))
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!("python snapshot error: {err:?}")
+            })?;
+        let locals: Map<String, Value> = serde_json::from_str(&locals_json)
+            .map_err(|err| anyhow::anyhow!("snapshot parse error: {err}"))?;
+        Ok(RuntimeSnapshot { locals })
+    }
+
+    /// Calls and tokens spent by `llm_query` in this environment.
+    pub fn get_cost_summary(&self) -> CostSummary {
+        self.cost.summary(&Default::default())
+    }
+
+    fn hydrate_shared_state(&self) -> anyhow::Result<()> {
+        let revision = self.shared_state.revision();
+        if revision == self.last_hydrated_revision.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let scope = self.scope.clone();
+        let shared_state_json = self.shared_state.snapshot_json_string()?;
+        self.interpreter
+            .enter(|vm: &vm::VirtualMachine| -> vm::PyResult<()> {
+                scope.globals.set_item(
+                    "__rlm_shared_state_json",
+                    vm.ctx.new_str(shared_state_json.as_str()).into(),
+                    vm,
+                )?;
+                let hydrate_code = "import json\n__rlm_state_incoming = \
+                                    json.loads(__rlm_shared_state_json)\nif '__rlm_replace_state' \
+                                    in globals():\n    \
+                                    __rlm_replace_state(__rlm_state_incoming)\nelse:\n    \
+                                    state.clear()\n    state.update(__rlm_state_incoming)\n";
+                vm.run_string(
+                    scope.clone(),
+                    hydrate_code,
+                    "<rlm_state_hydrate>".to_owned(),
+                )?;
+                Ok(())
+            })
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!("shared state hydrate error: {err:?}")
+            })?;
+        self.last_hydrated_revision
+            .store(revision, Ordering::Release);
+        Ok(())
+    }
+
+    fn sync_shared_state(&self) -> anyhow::Result<()> {
+        let scope = self.scope.clone();
+        let (delta_json, deleted_json, fallback_flag) = self
+            .interpreter
+            .enter(
+                |vm: &vm::VirtualMachine| -> vm::PyResult<(String, String, String)> {
+                    let sync_code =
+                        "import json\n__rlm_state_sync_fallback = '0'\nif '__rlm_TrackingDict' in \
+                         globals() and isinstance(state, __rlm_TrackingDict):\n    \
+                         __rlm_state_delta_payload = json.dumps({key: state.get(key) for key in \
+                         __rlm_state_dirty_keys})\n    __rlm_state_deleted_payload = \
+                         json.dumps(list(__rlm_state_deleted_keys))\n    \
+                         __rlm_state_dirty_keys.clear()\n    \
+                         __rlm_state_deleted_keys.clear()\nelse:\n    __rlm_state_sync_fallback = \
+                         '1'\n    __rlm_state_delta_payload = '{}'\n    \
+                         __rlm_state_deleted_payload = '[]'\n";
+                    vm.run_string(scope.clone(), sync_code, "<rlm_state_sync>".to_owned())?;
+                    let delta_json = get_string_from_scope(vm, &scope, "__rlm_state_delta_payload");
+                    let deleted_json =
+                        get_string_from_scope(vm, &scope, "__rlm_state_deleted_payload");
+                    let fallback_flag =
+                        get_string_from_scope(vm, &scope, "__rlm_state_sync_fallback");
+                    Ok((delta_json, deleted_json, fallback_flag))
+                },
+            )
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!(
+                    "shared state sync error (values must be JSON serializable): {err:?}"
+                )
+            })?;
+        if fallback_flag == "1" {
+            self.sync_shared_state_full(&scope)?;
+            self.last_hydrated_revision
+                .store(self.shared_state.revision(), Ordering::Release);
+            return Ok(());
+        }
+        let changed_values: Value = serde_json::from_str(&delta_json)
+            .map_err(|err| anyhow::anyhow!("shared state delta parse error: {err}"))?;
+        let deleted_keys: Vec<String> = serde_json::from_str(&deleted_json)
+            .map_err(|err| anyhow::anyhow!("shared state delete parse error: {err}"))?;
+        self.shared_state
+            .apply_delta_from_json(changed_values, &deleted_keys)?;
+        self.last_hydrated_revision
+            .store(self.shared_state.revision(), Ordering::Release);
+        Ok(())
+    }
+
+    fn sync_shared_state_full(&self, scope: &Scope) -> anyhow::Result<()> {
+        let (state_json, deleted_json) = self
+            .interpreter
+            .enter(
+                |vm: &vm::VirtualMachine| -> vm::PyResult<(String, String)> {
+                    let sync_code = "import json\n__rlm_state_sync_payload = \
+                                     json.dumps(state)\n__rlm_state_deleted_payload = \
+                                     json.dumps(list(__rlm_state_deleted_keys))\nif \
+                                     '__rlm_state_dirty_keys' in globals():\n    \
+                                     __rlm_state_dirty_keys.clear()\n__rlm_state_deleted_keys.\
+                                     clear()\n";
+                    vm.run_string(scope.clone(), sync_code, "<rlm_state_sync_full>".to_owned())?;
+                    let state_json = get_string_from_scope(vm, scope, "__rlm_state_sync_payload");
+                    let deleted_json =
+                        get_string_from_scope(vm, scope, "__rlm_state_deleted_payload");
+                    Ok((state_json, deleted_json))
+                },
+            )
+            .map_err(|err: vm::PyRef<PyBaseException>| {
+                anyhow::anyhow!(
+                    "shared state full sync error (values must be JSON serializable): {err:?}"
+                )
+            })?;
+        let state_value: Value = serde_json::from_str(&state_json)
+            .map_err(|err| anyhow::anyhow!("shared state sync parse error: {err}"))?;
+        let deleted_keys: Vec<String> = serde_json::from_str(&deleted_json)
+            .map_err(|err| anyhow::anyhow!("shared state delete parse error: {err}"))?;
+        self.shared_state
+            .merge_from_json(state_value, &deleted_keys)
+    }
+}
+
+impl ReplCore {
+    fn new(factory: Arc<dyn CodeRuntimeFactory>, services: RuntimeServices) -> Self {
+        Self {
+            factory,
+            services,
+            runtime: None,
+        }
+    }
+
+    fn init(&mut self, context: ContextData, setup_code: Option<String>) -> anyhow::Result<()> {
+        let mut runtime = self.factory.create(&self.services)?;
+        runtime.init(context, setup_code.as_deref())?;
+        self.runtime = Some(runtime);
+        Ok(())
+    }
+
+    fn execute(&mut self, code: String) -> anyhow::Result<ReplResult> {
+        let runtime = self
+            .runtime
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        runtime.execute(&code)
+    }
+
+    fn get_variable(&self, name: String) -> anyhow::Result<Option<String>> {
+        let runtime = self
+            .runtime
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        runtime.get_variable(&name)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        let runtime = self
+            .runtime
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        runtime.snapshot()
+    }
+
+    fn reset(&mut self) {
+        self.runtime = None;
+    }
+}
+
+impl ReplHandle {
+    pub fn new(
+        llm_client: Arc<dyn LlmClient>,
+        recursive_runner: Option<Arc<dyn RecursiveRunner>>,
+        recursion_depth: usize,
+        shared_state: SharedProgramState,
+    ) -> anyhow::Result<Self> {
+        Self::with_runtime(
+            Arc::new(PythonRuntimeFactory),
+            llm_client,
+            recursive_runner,
+            recursion_depth,
+            shared_state,
+        )
+    }
+
+    pub fn with_runtime(
+        factory: Arc<dyn CodeRuntimeFactory>,
+        llm_client: Arc<dyn LlmClient>,
+        recursive_runner: Option<Arc<dyn RecursiveRunner>>,
+        recursion_depth: usize,
+        shared_state: SharedProgramState,
+    ) -> anyhow::Result<Self> {
+        let runtime_handle = Handle::try_current()
+            .map_err(|err| anyhow::anyhow!("tokio runtime handle unavailable: {err}"))?;
+        let services = RuntimeServices {
+            llm_client,
+            recursive_runner,
+            recursion_depth,
+            shared_state,
+            runtime_handle,
+        };
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        thread::Builder::new()
+            .name("rlm-repl-worker".to_owned())
+            .spawn(move || {
+                let mut core = ReplCore::new(factory, services);
+                while let Some(command) = receiver.blocking_recv() {
+                    match command {
+                        ReplCommand::Init {
+                            context,
+                            setup_code,
+                            response,
+                        } => {
+                            let _ = response.send(core.init(context, setup_code));
+                        }
+                        ReplCommand::Execute { code, response } => {
+                            let _ = response.send(core.execute(code));
+                        }
+                        ReplCommand::GetVariable { name, response } => {
+                            let _ = response.send(core.get_variable(name));
+                        }
+                        ReplCommand::Snapshot { response } => {
+                            let _ = response.send(core.snapshot());
+                        }
+                        ReplCommand::Reset { response } => {
+                            core.reset();
+                            let _ = response.send(Ok(()));
+                        }
+                        ReplCommand::Shutdown { response } => {
+                            let _ = response.send(());
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self { sender })
+    }
+
+    pub async fn init(
+        &self,
+        context: ContextData,
+        setup_code: Option<String>,
+    ) -> anyhow::Result<()> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::Init {
+                context,
+                setup_code,
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send init command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped init response"))?
+    }
+
+    pub async fn execute(&self, code: String) -> anyhow::Result<ReplResult> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::Execute {
+                code,
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send execute command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped execute response"))?
+    }
+
+    pub async fn get_variable(&self, name: String) -> anyhow::Result<Option<String>> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::GetVariable {
+                name,
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send get_variable command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped get_variable response"))?
+    }
+
+    pub async fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::Snapshot {
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send snapshot command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped snapshot response"))?
+    }
+
+    pub async fn reset(&self) -> anyhow::Result<()> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::Reset {
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send reset command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped reset response"))?
+    }
+
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.sender
+            .send(ReplCommand::Shutdown {
+                response: response_tx,
+            })
+            .map_err(|_| anyhow::anyhow!("failed to send shutdown command to repl worker"))?;
+        response_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("repl worker dropped shutdown response"))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CodeExecutor for ReplHandle {
+    async fn init(&self, context: ContextData, setup_code: Option<String>) -> anyhow::Result<()> {
+        ReplHandle::init(self, context, setup_code).await
+    }
+
+    async fn execute(&self, code: String) -> anyhow::Result<ReplResult> {
+        ReplHandle::execute(self, code).await
+    }
+
+    async fn get_variable(&self, name: String) -> anyhow::Result<Option<String>> {
+        ReplHandle::get_variable(self, name).await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        ReplHandle::snapshot(self).await
+    }
+}
+
+impl CodeRuntimeFactory for PythonRuntimeFactory {
+    fn create(&self, services: &RuntimeServices) -> anyhow::Result<Box<dyn CodeRuntime>> {
+        Ok(Box::new(ReplEnv::create(services)?))
+    }
+}
+
+impl CodeRuntime for ReplEnv {
+    fn init(&mut self, context: ContextData, setup_code: Option<&str>) -> anyhow::Result<()> {
+        ReplEnv::init(self, context, setup_code)
+    }
+
+    fn execute(&mut self, code: &str) -> anyhow::Result<ReplResult> {
+        ReplEnv::execute(self, code)
+    }
+
+    fn get_variable(&self, name: &str) -> anyhow::Result<Option<String>> {
+        ReplEnv::get_variable(self, name)
+    }
+
+    fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        ReplEnv::snapshot(self)
+    }
+}
+
+fn init_stdlib(builder: InterpreterBuilder) -> InterpreterBuilder {
+    let defs = rustpython_stdlib::stdlib_module_defs(&builder.ctx);
+    builder
+        .add_native_modules(&defs)
+        .add_frozen_modules(rustpython_pylib::FROZEN_STDLIB)
+        .init_hook(set_frozen_stdlib_dir)
+}
+
+fn set_frozen_stdlib_dir(vm: &mut vm::VirtualMachine) {
+    use rustpython_vm::common::rc::PyRc;
+
+    let state = PyRc::get_mut(&mut vm.state).expect("vm state");
+    state.config.paths.stdlib_dir = Some(rustpython_pylib::LIB_PATH.to_owned());
+}
+
+fn get_string_from_scope(vm: &vm::VirtualMachine, scope: &Scope, name: &str) -> String {
+    scope
+        .globals
+        .get_item(name, vm)
+        .ok()
+        .and_then(|value| value.try_to_value::<String>(vm).ok())
+        .unwrap_or_default()
+}
+
+fn get_locals_dict(vm: &vm::VirtualMachine, scope: &Scope) -> Option<PyDictRef> {
+    scope
+        .globals
+        .get_item("__rlm_locals", vm)
+        .ok()
+        .and_then(|value| value.downcast::<vm::builtins::PyDict>().ok())
+}
+
+fn collect_locals(vm: &vm::VirtualMachine, scope: &Scope, detailed: bool) -> Vec<LocalValue> {
+    let dict = match get_locals_dict(vm, scope) {
+        Some(dict) => dict,
+        None => return Vec::new(),
+    };
+    let types = &vm.ctx.types;
+    dict.into_iter()
+        .filter_map(|(key, value)| {
+            let name = key.try_to_value::<String>(vm).ok()?;
+            let is_simple = is_simple_type(vm, &value);
+            let is_string = value
+                .is_instance(types.str_type.as_ref(), vm)
+                .unwrap_or(false);
+            let string_value = if is_string {
+                value.try_to_value::<String>(vm).ok()
+            } else {
+                None
+            };
+            let repr = if detailed || is_simple {
+                value
+                    .repr(vm)
+                    .map(|py_str| py_str.as_str().to_owned())
+                    .unwrap_or_else(|_| format!("<{}>", value.class().name()))
+            } else {
+                format!("<{}>", value.class().name())
+            };
+            Some(LocalValue {
+                name,
+                repr,
+                is_simple,
+                string_value,
+            })
+        })
+        .collect()
+}
+
+fn collect_locals_map(vm: &vm::VirtualMachine, scope: &Scope) -> Vec<(String, String)> {
+    let dict = match get_locals_dict(vm, scope) {
+        Some(dict) => dict,
+        None => return Vec::new(),
+    };
+    dict.into_iter()
+        .filter_map(|(key, value)| {
+            let name = key.try_to_value::<String>(vm).ok()?;
+            let repr = value
+                .repr(vm)
+                .map(|py_str| py_str.as_str().to_owned())
+                .unwrap_or_else(|_| format!("<{}>", value.class().name()));
+            Some((name, repr))
+        })
+        .collect()
+}
+
+fn is_simple_type(vm: &vm::VirtualMachine, value: &vm::PyObjectRef) -> bool {
+    let types = &vm.ctx.types;
+    let candidates = [
+        types.str_type.as_ref(),
+        types.int_type.as_ref(),
+        types.float_type.as_ref(),
+        types.bool_type.as_ref(),
+        types.list_type.as_ref(),
+        types.dict_type.as_ref(),
+        types.tuple_type.as_ref(),
+    ];
+    candidates
+        .iter()
+        .any(|ty| value.is_instance(ty, vm).unwrap_or(false))
+}
+
+/// Runs the calls of one `rlm_query` batch, at most `max_parallel` of them
+/// at once, and returns their answers in the order they were asked.
+async fn run_rlm_queries(
+    runner: Arc<dyn RecursiveRunner>,
+    payloads: Vec<RlmQueryPayload>,
+) -> Vec<String> {
+    let slots = Arc::new(Semaphore::new(runner.max_parallel().max(1)));
+    let mut outputs =
+        vec!["Error running rlm_query: sub-query did not finish".to_owned(); payloads.len()];
+    // Dropping the set, e.g. when the completion is cancelled, aborts every
+    // call still running.
+    let mut calls = JoinSet::new();
+    for (index, payload) in payloads.into_iter().enumerate() {
+        let runner = runner.clone();
+        let slots = slots.clone();
+        calls.spawn(async move {
+            let _slot = slots.acquire_owned().await;
+            let query = payload
+                .query
+                .unwrap_or_else(|| crate::prompts::DEFAULT_QUERY.to_owned());
+            let context = context_from_value(payload.context);
+            let output = match runner.completion(query, context).await {
+                Ok(result) => result,
+                Err(err) => format!("Error running rlm_query: {err}"),
+            };
+            (index, output)
+        });
+    }
+    while let Some(joined) = calls.join_next().await {
+        if let Ok((index, output)) = joined {
+            outputs[index] = output;
+        }
+    }
+    outputs
+}
+
+fn parse_llm_prompt(prompt: &str) -> Vec<Message> {
+    let trimmed = prompt.trim_start();
+    if !(trimmed.starts_with('{') || trimmed.starts_with('[')) {
+        return vec![Message::user(prompt)];
+    }
+    match serde_json::from_str::<serde_json::Value>(prompt) {
+        Ok(value) => messages_from_json(value).unwrap_or_else(|| vec![Message::user(prompt)]),
+        Err(_) => vec![Message::user(prompt)],
+    }
+}
+
+fn validate_subcall_messages(messages: &[Message]) -> Result<(), String> {
+    let total_chars: usize = messages.iter().map(|msg| msg.content.len()).sum();
+    let total_tokens_approx = estimate_tokens(total_chars);
+    if total_chars > MAX_SUBCALL_TOTAL_CHARS {
+        return Err(format!(
+            "sub-query too large ({total_chars} chars > {MAX_SUBCALL_TOTAL_CHARS}). Chunk the \
+             context before calling llm_query."
+        ));
+    }
+    if total_tokens_approx > MAX_SUBCALL_TOTAL_TOKENS_APPROX {
+        return Err(format!(
+            "sub-query too large (~{total_tokens_approx} tokens > \
+             {MAX_SUBCALL_TOTAL_TOKENS_APPROX}). Chunk the context before calling llm_query."
+        ));
+    }
+    if let Some(oversized) = messages
+        .iter()
+        .map(|msg| msg.content.len())
+        .max()
+        .filter(|len| *len > MAX_SUBCALL_MESSAGE_CHARS)
+    {
+        return Err(format!(
+            "single sub-query message too large ({oversized} chars > \
+             {MAX_SUBCALL_MESSAGE_CHARS}). Chunk the context before calling llm_query."
+        ));
+    }
+    if let Some(oversized_tokens) = messages
+        .iter()
+        .map(|msg| estimate_tokens(msg.content.len()))
+        .max()
+        .filter(|tokens| *tokens > MAX_SUBCALL_MESSAGE_TOKENS_APPROX)
+    {
+        return Err(format!(
+            "single sub-query message too large (~{oversized_tokens} tokens > \
+             {MAX_SUBCALL_MESSAGE_TOKENS_APPROX}). Chunk the context before calling llm_query."
+        ));
+    }
+    Ok(())
+}
+
+fn estimate_tokens(char_count: usize) -> usize {
+    char_count.div_ceil(4)
+}
+
+fn messages_from_json(value: serde_json::Value) -> Option<Vec<Message>> {
+    match value {
+        serde_json::Value::Array(items) => {
+            let mut messages = Vec::new();
+            for item in items {
+                if let serde_json::Value::String(text) = item {
+                    messages.push(Message::user(text));
+                    continue;
+                }
+                if let serde_json::Value::Object(map) = item
+                    && let Some(message) = message_from_map(&map)
+                {
+                    messages.push(message);
+                    continue;
+                }
+                return None;
+            }
+            Some(messages)
+        }
+        serde_json::Value::Object(map) => {
+            if let Some(messages) = map.get("messages") {
+                return messages_from_json(messages.clone());
+            }
+            message_from_map(&map).map(|msg| vec![msg])
+        }
+        serde_json::Value::String(text) => Some(vec![Message::user(text)]),
+        _ => None,
+    }
+}
+
+fn message_from_map(map: &serde_json::Map<String, serde_json::Value>) -> Option<Message> {
+    let content_value = map.get("content")?;
+    let content = match content_value {
+        serde_json::Value::String(text) => text.to_owned(),
+        other => other.to_string(),
+    };
+    let role = map
+        .get("role")
+        .and_then(|value| value.as_str())
+        .unwrap_or("user")
+        .to_owned();
+    Some(Message { role, content })
+}
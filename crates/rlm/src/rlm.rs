@@ -1,84 +1,810 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use crate::llm::{LlmClient, LlmClientImpl, Message};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::Instrument;
+
+use crate::llm::{
+    Deadline, FallbackLlmClient, FinishReason, HttpLlmClientFactory, LlmClient, LlmClientFactory,
+    LlmError, Message, SwappableLlmClient,
+};
 use crate::logger::{Logger, ReplEnvLogger};
-use crate::prompts::{DEFAULT_QUERY, REPL_SYSTEM_PROMPT, build_system_prompt, next_action_prompt};
-use crate::repl::{RecursiveRunner, ReplHandle, ReplResult, SharedProgramState};
+use crate::prompts::{
+    DEFAULT_QUERY, PromptConfig, response_schema_prompt, verifier_feedback, verifier_prompt,
+    verifier_rejection,
+};
+use crate::redact::{PatternRedactor, RedactingLlmClient, Redaction, RedactionConfig, Redactor};
+use crate::repl::{CodeExecutor, ReplResult, ReplSnapshot, SharedProgramState};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::repl::{CodeRuntimeFactory, PythonRuntimeFactory, RecursiveRunner, ReplHandle};
+use crate::schema;
+use crate::trace::{
+    CompletionTrace, CompletionUsage, CostLedger, CostSummary, ModelPrice, Progress, ProgressEvent,
+    ProgressHook, SpendLimit, SubcallCounters, UsageCounters,
+};
 use crate::utils::{
-    ContextInput, check_for_final_answer, convert_context_for_repl, find_code_blocks,
-    process_code_execution_blocks,
+    ContextData, ContextInput, FinalAnswerKind, check_for_final_answer, convert_context_for_repl,
+    find_code_blocks, find_final_answer, process_code_execution_blocks, resolve_final_json,
 };
 
-#[derive(Clone)]
+const APPROX_BYTES_PER_TOKEN: usize = 4;
+
+/// `RlmConfig` defaults, shared with front ends that restate them.
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-5";
+pub const DEFAULT_RECURSIVE_MODEL: &str = "gpt-5-mini";
+pub const DEFAULT_MAX_ITERATIONS: usize = 20;
+pub const DEFAULT_DEPTH: usize = 1;
+pub const DEFAULT_MAX_PARALLEL_SUBCALLS: usize = 4;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct RlmConfig {
+    #[serde(default, skip_serializing)]
     pub api_key: Option<String>,
     pub base_url: String,
     pub model: String,
     pub recursive_model: String,
     pub max_iterations: usize,
     pub depth: usize,
+    /// Most `rlm_query` children of one batched call to run at once.
+    #[serde(default = "default_max_parallel_subcalls")]
+    pub max_parallel_subcalls: usize,
     pub enable_logging: bool,
+    /// Turns `rlm_query` off whatever `depth` says.
     pub disable_recursive: bool,
+    /// Token cap for the final answer; `None` leaves it uncapped.
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    /// Model that serves root completions once the primary keeps failing with
+    /// 429/5xx; `None` disables fallback.
+    #[serde(default)]
+    pub fallback_model: Option<String>,
+    /// Endpoint for `fallback_model`; `None` reuses `base_url`.
+    #[serde(default)]
+    pub fallback_base_url: Option<String>,
+    /// Patterns hidden from every upstream LLM call; `None` sends text as is.
+    #[serde(default)]
+    pub redaction: Option<RedactionConfig>,
+    /// Prices by model name for `RlmRepl::cost_summary`; models left out are
+    /// counted but not priced.
+    #[serde(default)]
+    pub pricing: BTreeMap<String, ModelPrice>,
+    /// Prompt plus completion tokens one completion may spend, children
+    /// included, before it asks for a final answer; `None` leaves it
+    /// uncapped.
+    #[serde(default)]
+    pub max_total_tokens: Option<u64>,
+    /// Like `max_total_tokens`, in USD by `pricing`.
+    #[serde(default)]
+    pub max_cost_usd: Option<f64>,
+    /// Overrides for the system prompt and per-iteration instruction.
+    #[serde(default)]
+    pub prompts: PromptConfig,
+    /// Wall-clock time one root completion may take. `rlm_query` children
+    /// share their root's deadline instead of starting their own. `None`
+    /// leaves it bounded only by `RlmRepl::set_deadline`. Native builds
+    /// only; wasm has no timer to enforce it, so it is rejected there.
+    #[serde(default)]
+    pub max_duration: Option<Duration>,
+    /// JSON Schema final answers must match; the model is asked to answer
+    /// with `FINAL_JSON` and re-prompted when the value does not fit.
+    #[serde(default)]
+    pub response_schema: Option<Value>,
+    /// Has a verifier check each final answer against the REPL history; a
+    /// rejected answer gets one more iteration to be corrected.
+    #[serde(default)]
+    pub verify_answers: bool,
+    /// Model that verifies answers; `None` uses the root model.
+    #[serde(default)]
+    pub verifier_model: Option<String>,
+}
+
+/// Upstream endpoint and models for later completions; unset fields fall back
+/// to the config.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ModelRoute {
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub recursive_model: Option<String>,
+    #[serde(default)]
+    pub fallback_model: Option<String>,
+    #[serde(default)]
+    pub fallback_base_url: Option<String>,
+}
+
+impl std::fmt::Debug for ModelRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModelRoute")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("base_url", &self.base_url)
+            .field("model", &self.model)
+            .field("recursive_model", &self.recursive_model)
+            .field("fallback_model", &self.fallback_model)
+            .field("fallback_base_url", &self.fallback_base_url)
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct ActiveRoute {
+    api_key: Option<String>,
+    base_url: String,
+    model: String,
+    recursive_model: String,
+    fallback_model: Option<String>,
+    fallback_base_url: Option<String>,
+}
+
+impl ActiveRoute {
+    fn from_config(config: &RlmConfig) -> Self {
+        Self {
+            api_key: config.api_key.clone(),
+            base_url: config.base_url.clone(),
+            model: config.model.clone(),
+            recursive_model: config.recursive_model.clone(),
+            fallback_model: config.fallback_model.clone(),
+            fallback_base_url: config.fallback_base_url.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RlmConfigError {
+    #[error("max_iterations must be greater than zero")]
+    ZeroMaxIterations,
+    #[error("max_parallel_subcalls must be greater than zero")]
+    ZeroMaxParallelSubcalls,
+    #[error("max_completion_tokens must be greater than zero")]
+    ZeroMaxCompletionTokens,
+    #[error("invalid base_url {url}: {reason}")]
+    InvalidBaseUrl { url: String, reason: String },
+    #[error("fallback_base_url requires fallback_model")]
+    FallbackWithoutModel,
+    #[error("verifier_model requires verify_answers")]
+    VerifierWithoutVerify,
+    #[error("invalid redaction pattern: {0}")]
+    InvalidRedaction(String),
+    #[error("prices for {0} must be finite and not negative")]
+    InvalidPrice(String),
+    #[error("max_total_tokens must be greater than zero")]
+    ZeroMaxTotalTokens,
+    #[error("max_cost_usd must be a positive number")]
+    InvalidMaxCost,
+    #[error("max_duration must be greater than zero")]
+    ZeroMaxDuration,
+    #[error("max_duration is not supported on wasm32")]
+    MaxDurationUnsupported,
+    #[error("prompts.next_action_template must contain {{query}}")]
+    TemplateWithoutQuery,
+    #[error("invalid response_schema: {0}")]
+    InvalidResponseSchema(String),
+    #[error("{0} must not be empty")]
+    EmptyField(&'static str),
+    #[error("{0} is required")]
+    MissingEnv(&'static str),
+    #[error("invalid value for {name}: {value}")]
+    InvalidEnv { name: &'static str, value: String },
+}
+
+impl Default for RlmConfig {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            model: DEFAULT_MODEL.to_owned(),
+            recursive_model: DEFAULT_RECURSIVE_MODEL.to_owned(),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            depth: DEFAULT_DEPTH,
+            max_parallel_subcalls: default_max_parallel_subcalls(),
+            enable_logging: false,
+            disable_recursive: false,
+            max_completion_tokens: None,
+            fallback_model: None,
+            fallback_base_url: None,
+            redaction: None,
+            pricing: BTreeMap::new(),
+            max_total_tokens: None,
+            max_cost_usd: None,
+            prompts: PromptConfig::default(),
+            max_duration: None,
+            response_schema: None,
+            verify_answers: false,
+            verifier_model: None,
+        }
+    }
+}
+
+impl RlmConfig {
+    pub fn builder() -> RlmConfigBuilder {
+        RlmConfigBuilder::default()
+    }
+
+    pub fn from_env() -> Result<Self, RlmConfigError> {
+        let api_key = std::env::var("OPENAI_API_KEY")
+            .map_err(|_| RlmConfigError::MissingEnv("OPENAI_API_KEY"))?;
+        let mut config = Self::from_env_without_api_key()?;
+        config.api_key = Some(api_key);
+        Ok(config)
+    }
+
+    /// Like `from_env`, for callers whose LLM clients need no key of their
+    /// own, e.g. ones built by an `LlmClientFactory` that brokers calls.
+    pub fn from_env_without_api_key() -> Result<Self, RlmConfigError> {
+        let mut builder = Self::builder();
+        if let Some(base_url) = env_string("RLM_BASE_URL") {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(model) = env_string("RLM_MODEL") {
+            builder = builder.model(model);
+        }
+        if let Some(model) = env_string("RLM_RECURSIVE_MODEL") {
+            builder = builder.recursive_model(model);
+        }
+        if let Some(max_iterations) = env_parse("RLM_MAX_ITERATIONS")? {
+            builder = builder.max_iterations(max_iterations);
+        }
+        if let Some(depth) = env_parse("RLM_DEPTH")? {
+            builder = builder.depth(depth);
+        }
+        if let Some(max_parallel) = env_parse("RLM_MAX_PARALLEL_SUBCALLS")? {
+            builder = builder.max_parallel_subcalls(max_parallel);
+        }
+        if let Some(enabled) = env_bool("RLM_ENABLE_LOGGING")? {
+            builder = builder.enable_logging(enabled);
+        }
+        if let Some(disabled) = env_bool("RLM_DISABLE_RECURSIVE")? {
+            builder = builder.disable_recursive(disabled);
+        }
+        if let Some(max_completion_tokens) = env_parse("RLM_MAX_COMPLETION_TOKENS")? {
+            builder = builder.max_completion_tokens(max_completion_tokens);
+        }
+        if let Some(model) = env_string("RLM_FALLBACK_MODEL") {
+            builder = builder.fallback_model(model);
+        }
+        if let Some(base_url) = env_string("RLM_FALLBACK_BASE_URL") {
+            builder = builder.fallback_base_url(base_url);
+        }
+        if let Some(value) = env_string("RLM_REDACTION") {
+            let redaction =
+                serde_json::from_str(&value).map_err(|_| RlmConfigError::InvalidEnv {
+                    name: "RLM_REDACTION",
+                    value,
+                })?;
+            builder = builder.redaction(redaction);
+        }
+        if let Some(value) = env_string("RLM_PRICING") {
+            let pricing = serde_json::from_str(&value).map_err(|_| RlmConfigError::InvalidEnv {
+                name: "RLM_PRICING",
+                value,
+            })?;
+            builder = builder.pricing(pricing);
+        }
+        if let Some(max_total_tokens) = env_parse("RLM_MAX_TOTAL_TOKENS")? {
+            builder = builder.max_total_tokens(max_total_tokens);
+        }
+        if let Some(max_cost_usd) = env_parse("RLM_MAX_COST_USD")? {
+            builder = builder.max_cost_usd(max_cost_usd);
+        }
+        if let Some(value) = env_string("RLM_PROMPTS") {
+            let prompts = serde_json::from_str(&value).map_err(|_| RlmConfigError::InvalidEnv {
+                name: "RLM_PROMPTS",
+                value,
+            })?;
+            builder = builder.prompts(prompts);
+        }
+        if let Some(secs) = env_parse("RLM_MAX_DURATION_SECS")? {
+            builder = builder.max_duration(Duration::from_secs(secs));
+        }
+        if let Some(value) = env_string("RLM_RESPONSE_SCHEMA") {
+            let schema = serde_json::from_str(&value).map_err(|_| RlmConfigError::InvalidEnv {
+                name: "RLM_RESPONSE_SCHEMA",
+                value,
+            })?;
+            builder = builder.response_schema(schema);
+        }
+        if let Some(enabled) = env_bool("RLM_VERIFY_ANSWERS")? {
+            builder = builder.verify_answers(enabled);
+        }
+        if let Some(model) = env_string("RLM_VERIFIER_MODEL") {
+            builder = builder.verifier_model(model);
+        }
+        builder.build()
+    }
+
+    /// Depth available to `rlm_query`: `depth`, or 0 with
+    /// `disable_recursive`.
+    pub fn recursion_depth(&self) -> usize {
+        if self.disable_recursive {
+            0
+        } else {
+            self.depth
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), RlmConfigError> {
+        if self.max_iterations == 0 {
+            return Err(RlmConfigError::ZeroMaxIterations);
+        }
+        if self.max_parallel_subcalls == 0 {
+            return Err(RlmConfigError::ZeroMaxParallelSubcalls);
+        }
+        if self.max_completion_tokens == Some(0) {
+            return Err(RlmConfigError::ZeroMaxCompletionTokens);
+        }
+        if self.model.trim().is_empty() {
+            return Err(RlmConfigError::EmptyField("model"));
+        }
+        if self.recursive_model.trim().is_empty() {
+            return Err(RlmConfigError::EmptyField("recursive_model"));
+        }
+        validate_base_url(&self.base_url)?;
+        if self
+            .fallback_model
+            .as_ref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            return Err(RlmConfigError::EmptyField("fallback_model"));
+        }
+        if let Some(base_url) = &self.fallback_base_url {
+            if self.fallback_model.is_none() {
+                return Err(RlmConfigError::FallbackWithoutModel);
+            }
+            validate_base_url(base_url)?;
+        }
+        if let Some(redaction) = &self.redaction {
+            PatternRedactor::new(redaction)
+                .map_err(|err| RlmConfigError::InvalidRedaction(err.to_string()))?;
+        }
+        for (model, price) in &self.pricing {
+            let valid = |value: f64| value.is_finite() && value >= 0.0;
+            if !valid(price.prompt_per_million) || !valid(price.completion_per_million) {
+                return Err(RlmConfigError::InvalidPrice(model.clone()));
+            }
+        }
+        if self.max_total_tokens == Some(0) {
+            return Err(RlmConfigError::ZeroMaxTotalTokens);
+        }
+        if self
+            .max_cost_usd
+            .is_some_and(|max| !max.is_finite() || max <= 0.0)
+        {
+            return Err(RlmConfigError::InvalidMaxCost);
+        }
+        if self
+            .prompts
+            .system_prompt
+            .as_ref()
+            .is_some_and(|prompt| prompt.trim().is_empty())
+        {
+            return Err(RlmConfigError::EmptyField("prompts.system_prompt"));
+        }
+        if self
+            .prompts
+            .next_action_template
+            .as_ref()
+            .is_some_and(|template| !template.contains("{query}"))
+        {
+            return Err(RlmConfigError::TemplateWithoutQuery);
+        }
+        if self.max_duration == Some(Duration::ZERO) {
+            return Err(RlmConfigError::ZeroMaxDuration);
+        }
+        #[cfg(target_arch = "wasm32")]
+        if self.max_duration.is_some() {
+            return Err(RlmConfigError::MaxDurationUnsupported);
+        }
+        if let Some(schema) = &self.response_schema {
+            schema::check_schema(schema).map_err(RlmConfigError::InvalidResponseSchema)?;
+        }
+        if let Some(model) = &self.verifier_model {
+            if model.trim().is_empty() {
+                return Err(RlmConfigError::EmptyField("verifier_model"));
+            }
+            if !self.verify_answers {
+                return Err(RlmConfigError::VerifierWithoutVerify);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_base_url(base_url: &str) -> Result<(), RlmConfigError> {
+    let url = Url::parse(base_url).map_err(|err| RlmConfigError::InvalidBaseUrl {
+        url: base_url.to_owned(),
+        reason: err.to_string(),
+    })?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(RlmConfigError::InvalidBaseUrl {
+            url: base_url.to_owned(),
+            reason: "scheme must be http or https".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+pub struct RlmConfigBuilder {
+    config: RlmConfig,
+}
+
+impl RlmConfigBuilder {
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.config.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.config.base_url = base_url.into();
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.config.model = model.into();
+        self
+    }
+
+    pub fn recursive_model(mut self, model: impl Into<String>) -> Self {
+        self.config.recursive_model = model.into();
+        self
+    }
+
+    pub fn max_iterations(mut self, max_iterations: usize) -> Self {
+        self.config.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.config.depth = depth;
+        self
+    }
+
+    pub fn max_parallel_subcalls(mut self, max_parallel: usize) -> Self {
+        self.config.max_parallel_subcalls = max_parallel;
+        self
+    }
+
+    pub fn enable_logging(mut self, enabled: bool) -> Self {
+        self.config.enable_logging = enabled;
+        self
+    }
+
+    pub fn disable_recursive(mut self, disabled: bool) -> Self {
+        self.config.disable_recursive = disabled;
+        self
+    }
+
+    pub fn max_completion_tokens(mut self, max_completion_tokens: u32) -> Self {
+        self.config.max_completion_tokens = Some(max_completion_tokens);
+        self
+    }
+
+    pub fn fallback_model(mut self, model: impl Into<String>) -> Self {
+        self.config.fallback_model = Some(model.into());
+        self
+    }
+
+    pub fn fallback_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.config.fallback_base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn redaction(mut self, redaction: RedactionConfig) -> Self {
+        self.config.redaction = Some(redaction);
+        self
+    }
+
+    pub fn pricing(mut self, pricing: BTreeMap<String, ModelPrice>) -> Self {
+        self.config.pricing = pricing;
+        self
+    }
+
+    pub fn max_total_tokens(mut self, max_total_tokens: u64) -> Self {
+        self.config.max_total_tokens = Some(max_total_tokens);
+        self
+    }
+
+    pub fn max_cost_usd(mut self, max_cost_usd: f64) -> Self {
+        self.config.max_cost_usd = Some(max_cost_usd);
+        self
+    }
+
+    pub fn prompts(mut self, prompts: PromptConfig) -> Self {
+        self.config.prompts = prompts;
+        self
+    }
+
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.config.max_duration = Some(max_duration);
+        self
+    }
+
+    pub fn response_schema(mut self, schema: Value) -> Self {
+        self.config.response_schema = Some(schema);
+        self
+    }
+
+    pub fn verify_answers(mut self, enabled: bool) -> Self {
+        self.config.verify_answers = enabled;
+        self
+    }
+
+    pub fn verifier_model(mut self, model: impl Into<String>) -> Self {
+        self.config.verifier_model = Some(model.into());
+        self
+    }
+
+    pub fn build(self) -> Result<RlmConfig, RlmConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
 }
 
 pub struct RlmRepl {
+    config: RlmConfig,
     llm: Arc<dyn LlmClient>,
+    root_client: Arc<SwappableLlmClient>,
+    // What the REPL's `llm_query` and `rlm_query` use; wasm hosts bring
+    // their own executor, so only native builds keep these.
+    #[cfg(not(target_arch = "wasm32"))]
     recursive_llm: Arc<dyn LlmClient>,
+    recursive_client: Arc<SwappableLlmClient>,
+    clients: Arc<dyn LlmClientFactory>,
+    // Builds the clients of `rlm_query` children; `clients` unless
+    // `with_clients` pinned them.
+    child_clients: Arc<dyn LlmClientFactory>,
+    redaction: Arc<Redaction>,
+    deadline: Deadline,
+    route: Arc<RwLock<ActiveRoute>>,
+    used_fallback: Arc<AtomicBool>,
+    #[cfg(not(target_arch = "wasm32"))]
     depth: usize,
     max_iterations: usize,
+    max_completion_tokens: Option<u32>,
+    #[cfg(not(target_arch = "wasm32"))]
+    max_duration: Option<Duration>,
     logger: Logger,
     repl_env_logger: ReplEnvLogger,
     messages: Vec<Message>,
-    repl_env: Option<ReplHandle>,
+    // Questions and answers of earlier `chat` turns, replayed on the next.
+    turns: Vec<Message>,
+    repl_env: Option<Arc<dyn CodeExecutor>>,
+    // Context the REPL was last initialized with, kept for snapshots.
+    context: Option<ContextData>,
+    executor: Option<Arc<dyn CodeExecutor>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    runtime_factory: Option<Arc<dyn CodeRuntimeFactory>>,
     query: Option<String>,
     disable_recursive: bool,
+    #[cfg(not(target_arch = "wasm32"))]
     recursive_runner: Option<Arc<dyn RecursiveRunner>>,
     shared_state: SharedProgramState,
+    subcalls: SubcallCounters,
+    progress: Progress,
+    usage: UsageCounters,
+    cost: CostLedger,
+    // Recursion level of this REPL's own completions, for cost breakdowns.
+    cost_depth: usize,
+    spend_limit: SpendLimit,
+    last_trace: CompletionTrace,
+    last_finish_reason: FinishReason,
+    last_usage: CompletionUsage,
+    last_json: Option<Value>,
 }
 
 impl RlmRepl {
     pub fn new(config: RlmConfig) -> anyhow::Result<Self> {
-        Self::new_with_shared_state(config, SharedProgramState::new())
+        Self::new_with_client_factory(config, Arc::new(HttpLlmClientFactory))
+    }
+
+    /// Builds every LLM client, including those of `rlm_query` children and
+    /// later route changes, through `clients` instead of direct HTTP.
+    pub fn new_with_client_factory(
+        config: RlmConfig,
+        clients: Arc<dyn LlmClientFactory>,
+    ) -> anyhow::Result<Self> {
+        let redaction = Arc::new(Redaction::default());
+        if let Some(redaction_config) = &config.redaction {
+            redaction.set_redactor(Some(Arc::new(PatternRedactor::new(redaction_config)?)));
+        }
+        Self::new_with_shared_state(
+            config,
+            clients,
+            SharedProgramState::new(),
+            redaction,
+            Deadline::default(),
+            CostLedger::default(),
+            0,
+        )
     }
 
     pub(crate) fn new_with_shared_state(
         config: RlmConfig,
+        clients: Arc<dyn LlmClientFactory>,
         shared_state: SharedProgramState,
+        redaction: Arc<Redaction>,
+        deadline: Deadline,
+        cost: CostLedger,
+        cost_depth: usize,
     ) -> anyhow::Result<Self> {
-        let llm = make_client(
-            &config.model,
-            config.api_key.clone(),
-            config.base_url.clone(),
-        )?;
-        let recursive_llm = make_client(
+        config.validate()?;
+        let subcalls = SubcallCounters::default();
+        let progress = Progress::default();
+        // Every client, children's included, is built through the metered
+        // factory, so their calls also count towards this REPL's usage.
+        let usage = UsageCounters::default();
+        let clients = usage.wrap_factory(clients);
+        let used_fallback = Arc::new(AtomicBool::new(false));
+        let root_client = Arc::new(SwappableLlmClient::new(make_root_client(
+            clients.as_ref(),
+            &cost,
+            cost_depth,
+            &ActiveRoute::from_config(&config),
+            &used_fallback,
+        )?));
+        let llm = deadline.wrap(Arc::new(RedactingLlmClient::new(
+            root_client.clone(),
+            redaction.clone(),
+        )));
+        let recursive_client = Arc::new(SwappableLlmClient::new(make_client(
+            clients.as_ref(),
+            &cost,
+            cost_depth + 1,
             &config.recursive_model,
-            config.api_key.clone(),
-            config.base_url.clone(),
-        )?;
-        let recursive_runner: Option<Arc<dyn RecursiveRunner>> = if config.depth > 0 {
-            Some(Arc::new(RlmRecursiveRunner::new(
-                config.clone(),
-                shared_state.clone(),
-            )))
+            config.api_key.as_deref(),
+            &config.base_url,
+        )?));
+        let spend_limit = spend_limit(&config, &cost);
+        #[cfg(not(target_arch = "wasm32"))]
+        let recursive_llm = subcalls.wrap_llm(
+            spend_limit.wrap_llm(deadline.wrap(Arc::new(RedactingLlmClient::new(
+                recursive_client.clone(),
+                redaction.clone(),
+            )))),
+            &progress,
+        );
+        let route = Arc::new(RwLock::new(ActiveRoute::from_config(&config)));
+        #[cfg(not(target_arch = "wasm32"))]
+        let recursive_runner: Option<Arc<dyn RecursiveRunner>> = if config.recursion_depth() > 0 {
+            Some(subcalls.wrap_runner(
+                Arc::new(RlmRecursiveRunner::new(
+                    config.clone(),
+                    clients.clone(),
+                    route.clone(),
+                    shared_state.clone(),
+                    redaction.clone(),
+                    deadline.clone(),
+                    cost.clone(),
+                    cost_depth + 1,
+                    None,
+                )),
+                &progress,
+            ))
         } else {
             None
         };
         Ok(Self {
             llm,
+            root_client,
+            #[cfg(not(target_arch = "wasm32"))]
             recursive_llm,
-            depth: config.depth,
+            recursive_client,
+            child_clients: clients.clone(),
+            clients,
+            redaction,
+            deadline,
+            route,
+            used_fallback,
+            #[cfg(not(target_arch = "wasm32"))]
+            depth: config.recursion_depth(),
             max_iterations: config.max_iterations,
+            #[cfg(not(target_arch = "wasm32"))]
+            max_duration: config.max_duration,
+            max_completion_tokens: config.max_completion_tokens,
             logger: Logger::new(config.enable_logging),
             repl_env_logger: ReplEnvLogger::new(config.enable_logging),
             messages: Vec::new(),
+            turns: Vec::new(),
             repl_env: None,
+            context: None,
+            executor: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            runtime_factory: None,
             query: None,
             disable_recursive: config.disable_recursive,
+            #[cfg(not(target_arch = "wasm32"))]
             recursive_runner,
             shared_state,
+            subcalls,
+            progress,
+            usage,
+            cost,
+            cost_depth,
+            spend_limit,
+            last_trace: CompletionTrace::default(),
+            last_finish_reason: FinishReason::Stop,
+            last_json: None,
+            last_usage: CompletionUsage::default(),
+            config,
         })
     }
 
+    /// Replaces the configured redaction for every upstream call made by this
+    /// REPL and its `rlm_query` children.
+    pub fn with_redactor(self, redactor: Arc<dyn Redactor>) -> Self {
+        self.redaction.set_redactor(Some(redactor));
+        self
+    }
+
+    pub fn with_executor(mut self, executor: Arc<dyn CodeExecutor>) -> Self {
+        self.executor = Some(executor);
+        self
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_runtime(mut self, factory: Arc<dyn CodeRuntimeFactory>) -> Self {
+        self.runtime_factory = Some(factory);
+        self.rebuild_recursive_runner();
+        self
+    }
+
+    /// Sends root completions to `root`, and `llm_query` calls and every call
+    /// of `rlm_query` children to `recursive`, instead of clients built from
+    /// the config. A later `set_route` builds fresh clients for this REPL
+    /// again.
+    pub fn with_clients(mut self, root: Arc<dyn LlmClient>, recursive: Arc<dyn LlmClient>) -> Self {
+        self.root_client.swap(self.cost.wrap_llm(
+            self.usage.wrap_llm(root),
+            self.cost_depth,
+            &self.config.model,
+        ));
+        self.recursive_client.swap(self.cost.wrap_llm(
+            self.usage.wrap_llm(recursive.clone()),
+            self.cost_depth + 1,
+            &self.config.recursive_model,
+        ));
+        // Children only ever use the recursive model, so one client serves
+        // all of their calls.
+        self.child_clients = self
+            .usage
+            .wrap_factory(Arc::new(FixedClientFactory(recursive)));
+        #[cfg(not(target_arch = "wasm32"))]
+        self.rebuild_recursive_runner();
+        self
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rebuild_recursive_runner(&mut self) {
+        if self.recursive_runner.is_some() {
+            self.recursive_runner = Some(self.subcalls.wrap_runner(
+                Arc::new(RlmRecursiveRunner::new(
+                    self.config.clone(),
+                    self.child_clients.clone(),
+                    self.route.clone(),
+                    self.shared_state.clone(),
+                    self.redaction.clone(),
+                    self.deadline.clone(),
+                    self.cost.clone(),
+                    self.cost_depth + 1,
+                    self.runtime_factory.clone(),
+                )),
+                &self.progress,
+            ));
+        }
+    }
+
     pub async fn setup_context(
         &mut self,
         context: impl Into<ContextInput>,
@@ -88,27 +814,70 @@ impl RlmRepl {
         self.query = Some(query.clone());
         self.logger.log_query_start(&query);
 
+        self.turns.clear();
         self.reset_messages_to_system_prompt();
         self.logger.log_initial_messages(&self.messages);
 
         let context_data = convert_context_for_repl(context.into());
         if self.repl_env.is_none() {
-            self.repl_env = Some(ReplHandle::new(
-                self.recursive_llm.clone(),
-                self.recursive_runner.clone(),
-                self.depth,
-                self.shared_state.clone(),
-            )?);
+            self.repl_env = Some(self.usage.wrap_executor(self.spawn_executor()?));
         }
         let repl_env = self
             .repl_env
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
-        repl_env.init(context_data, None).await?;
+        repl_env.init(context_data.clone(), None).await?;
+        self.context = Some(context_data);
 
         Ok(self.messages.clone())
     }
 
+    /// Captures the REPL's context and JSON-serializable locals. Anything else
+    /// (functions, modules, open files) is not carried over.
+    pub async fn snapshot(&self) -> anyhow::Result<ReplSnapshot> {
+        let repl_env = self
+            .repl_env
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        let runtime = repl_env.snapshot().await?;
+        let context = self.context.clone();
+        Ok(ReplSnapshot {
+            context_json: context.as_ref().and_then(|context| context.json.clone()),
+            context_text: context.and_then(|context| context.text),
+            locals: runtime.locals,
+        })
+    }
+
+    /// Re-initializes the REPL from `snapshot`, as if its context had been set
+    /// up and its locals assigned.
+    pub async fn restore(&mut self, snapshot: ReplSnapshot) -> anyhow::Result<()> {
+        self.turns.clear();
+        self.reset_messages_to_system_prompt();
+        let context_data = ContextData {
+            json: snapshot.context_json,
+            text: snapshot.context_text,
+        };
+        let setup_code = if snapshot.locals.is_empty() {
+            None
+        } else {
+            let payload = serde_json::to_string(&snapshot.locals)?;
+            Some(format!(
+                "import json\n__rlm_locals.update(json.loads({}))\n",
+                serde_json::to_string(&payload)?
+            ))
+        };
+        if self.repl_env.is_none() {
+            self.repl_env = Some(self.usage.wrap_executor(self.spawn_executor()?));
+        }
+        let repl_env = self
+            .repl_env
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        repl_env.init(context_data.clone(), setup_code).await?;
+        self.context = Some(context_data);
+        Ok(())
+    }
+
     pub async fn completion(
         &mut self,
         context: impl Into<ContextInput>,
@@ -123,6 +892,22 @@ impl RlmRepl {
         self.run_completion_loop(&query).await
     }
 
+    /// Like `completion`, returning the final answer as JSON: the value of
+    /// `FINAL_JSON`, checked against `response_schema` when one is set.
+    pub async fn completion_json(
+        &mut self,
+        context: impl Into<ContextInput>,
+        query: Option<&str>,
+    ) -> anyhow::Result<Value> {
+        self.completion(context, query).await?;
+        self.last_json
+            .clone()
+            .ok_or_else(|| match self.config.response_schema {
+                Some(_) => anyhow::anyhow!("the final answer did not match the response schema"),
+                None => anyhow::anyhow!("the final answer was not JSON"),
+            })
+    }
+
     pub async fn completion_with_existing(
         &mut self,
         query: Option<&str>,
@@ -138,23 +923,239 @@ impl RlmRepl {
         self.run_completion_loop(&query).await
     }
 
-    pub async fn execute_code(&self, code: &str) -> anyhow::Result<ReplResult> {
+    /// Asks `query` as the next turn of a conversation over the context set
+    /// up by `setup_context` or `completion`. Earlier `chat` questions and
+    /// answers are sent along, and REPL variables persist between turns.
+    pub async fn chat(&mut self, query: &str) -> anyhow::Result<String> {
+        if self.repl_env.is_none() {
+            anyhow::bail!("repl env not initialized");
+        }
+        self.query = Some(query.to_owned());
+        self.logger.log_query_start(query);
+        self.reset_messages_to_system_prompt();
+        self.messages.extend(self.turns.iter().cloned());
+        self.logger.log_initial_messages(&self.messages);
+        let answer = self.run_completion_loop(query).await?;
+        self.turns.push(Message::user(query));
+        self.turns.push(Message::assistant(answer.clone()));
+        Ok(answer)
+    }
+
+    /// Questions and answers of the conversation so far, oldest first.
+    pub fn turns(&self) -> &[Message] {
+        &self.turns
+    }
+
+    /// Starts a new conversation on the same context; REPL variables are
+    /// kept.
+    pub fn clear_turns(&mut self) {
+        self.turns.clear();
+    }
+
+    pub async fn execute_code(&mut self, code: &str) -> anyhow::Result<ReplResult> {
         let repl_env = self
             .repl_env
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
-        repl_env.execute(code.to_owned()).await
+        self.mark_spend_start();
+        let start = self.usage.snapshot();
+        let result = repl_env
+            .execute(code.to_owned())
+            .instrument(tracing::info_span!("repl_execute"))
+            .await;
+        self.last_usage = self.usage.since(start);
+        result
     }
 
-    async fn run_completion_loop(&mut self, query: &str) -> anyhow::Result<String> {
+    pub async fn get_variable(&self, name: &str) -> anyhow::Result<Option<String>> {
         let repl_env = self
             .repl_env
             .as_ref()
-            .cloned()
             .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        repl_env.get_variable(name.to_owned()).await
+    }
+
+    /// Overrides the iteration budget for later completions; `None` restores
+    /// the configured value.
+    pub fn set_max_iterations(&mut self, max_iterations: Option<usize>) {
+        self.max_iterations = max_iterations.unwrap_or(self.config.max_iterations);
+    }
+
+    /// Switches the endpoint and models used by later completions, including
+    /// `llm_query` and `rlm_query`; fields left `None` restore the config.
+    pub fn set_route(&mut self, route: &ModelRoute) -> anyhow::Result<()> {
+        let next = RlmConfig {
+            api_key: route
+                .api_key
+                .clone()
+                .or_else(|| self.config.api_key.clone()),
+            base_url: route
+                .base_url
+                .clone()
+                .unwrap_or_else(|| self.config.base_url.clone()),
+            model: route
+                .model
+                .clone()
+                .unwrap_or_else(|| self.config.model.clone()),
+            recursive_model: route
+                .recursive_model
+                .clone()
+                .unwrap_or_else(|| self.config.recursive_model.clone()),
+            fallback_model: route
+                .fallback_model
+                .clone()
+                .or_else(|| self.config.fallback_model.clone()),
+            fallback_base_url: route
+                .fallback_base_url
+                .clone()
+                .or_else(|| self.config.fallback_base_url.clone()),
+            ..self.config.clone()
+        };
+        next.validate()?;
+        let next = ActiveRoute::from_config(&next);
+        let mut current = self.route.write().expect("model route lock poisoned");
+        if *current == next {
+            return Ok(());
+        }
+        let endpoint_changed = current.api_key != next.api_key || current.base_url != next.base_url;
+        if endpoint_changed
+            || current.model != next.model
+            || current.fallback_model != next.fallback_model
+            || current.fallback_base_url != next.fallback_base_url
+        {
+            self.root_client.swap(make_root_client(
+                self.clients.as_ref(),
+                &self.cost,
+                self.cost_depth,
+                &next,
+                &self.used_fallback,
+            )?);
+        }
+        if endpoint_changed || current.recursive_model != next.recursive_model {
+            self.recursive_client.swap(make_client(
+                self.clients.as_ref(),
+                &self.cost,
+                self.cost_depth + 1,
+                &next.recursive_model,
+                next.api_key.as_deref(),
+                &next.base_url,
+            )?);
+        }
+        *current = next;
+        Ok(())
+    }
+
+    /// Bounds later completions, including `llm_query` and `rlm_query` calls:
+    /// the loop asks for a final answer early once another iteration would
+    /// not fit, and LLM calls still running at `deadline` fail.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_deadline(&mut self, deadline: Option<std::time::Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    /// Overrides how long each later completion may run; `None` restores
+    /// the configured value. An earlier `set_deadline` still applies.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_max_duration(&mut self, max_duration: Option<Duration>) {
+        self.max_duration = max_duration.or(self.config.max_duration);
+    }
+
+    /// Caps the final answer of later completions; `None` restores the
+    /// configured cap.
+    pub fn set_max_completion_tokens(&mut self, max_completion_tokens: Option<u32>) {
+        self.max_completion_tokens = max_completion_tokens.or(self.config.max_completion_tokens);
+    }
+
+    /// Whether any root completion in the most recent loop was served by the
+    /// fallback model.
+    pub fn last_used_fallback(&self) -> bool {
+        self.used_fallback.load(Ordering::Relaxed)
+    }
+
+    /// Whether the most recent final answer was cut short by the token cap.
+    pub fn last_finish_reason(&self) -> FinishReason {
+        self.last_finish_reason
+    }
+
+    /// Parsed final answer of the most recent completion, when it was JSON.
+    pub fn last_json(&self) -> Option<&Value> {
+        self.last_json.as_ref()
+    }
+
+    /// Calls `hook` with each [`ProgressEvent`] of later completions, from
+    /// whichever thread the event happens on; `None` stops reporting.
+    pub fn set_progress(&self, hook: Option<Arc<ProgressHook>>) {
+        self.progress.set(hook);
+    }
+
+    /// Trace of the most recent completion loop.
+    pub fn last_trace(&self) -> &CompletionTrace {
+        &self.last_trace
+    }
+
+    /// Tokens and REPL time spent by the most recent completion loop or
+    /// `execute_code`, even one that failed.
+    pub fn last_usage(&self) -> CompletionUsage {
+        self.last_usage
+    }
+
+    /// Tokens and REPL time spent since the REPL was built, counting
+    /// completions that were cancelled before they returned.
+    pub fn total_usage(&self) -> CompletionUsage {
+        self.usage.snapshot()
+    }
+
+    async fn run_completion_loop(&mut self, query: &str) -> anyhow::Result<String> {
+        self.mark_spend_start();
+        // Children share the root's deadline, so only the root sets one.
+        #[cfg(not(target_arch = "wasm32"))]
+        let _deadline = self
+            .max_duration
+            .filter(|_| self.cost_depth == 0)
+            .map(|max_duration| self.deadline.tighten(max_duration));
+        let start = self.usage.snapshot();
+        let result = self.completion_loop(query).await;
+        self.last_usage = self.usage.since(start);
+        result
+    }
+
+    async fn completion_loop(&mut self, query: &str) -> anyhow::Result<String> {
+        let repl_env = self
+            .repl_env
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("repl env not initialized"))?;
+        self.last_trace = CompletionTrace::default();
+        self.last_finish_reason = FinishReason::Stop;
+        self.last_json = None;
+        self.used_fallback.store(false, Ordering::Relaxed);
 
-        for iteration in 0..self.max_iterations {
-            let prompt = next_action_prompt(query, iteration, false);
+        // Later conversation turns have already seen the REPL, so they skip
+        // the first iteration's warning to look before answering.
+        let prompt_offset = usize::from(self.messages.len() > 1);
+        let mut budget = self.deadline.iterations();
+        // A verifier rejection is allowed once per completion, and earns an
+        // iteration past `max_iterations` when it comes on the last one.
+        let mut verify = self.config.verify_answers;
+        let mut iterations = self.max_iterations;
+        let mut last_response = None;
+        for iteration in 0.. {
+            if iteration >= iterations {
+                break;
+            }
+            if !budget.start() {
+                tracing::info!(iteration, "deadline near; requesting final answer");
+                break;
+            }
+            if self.spend_limit.exhausted() {
+                tracing::info!(iteration, "spend limit reached; requesting final answer");
+                break;
+            }
+            self.progress
+                .emit(ProgressEvent::IterationStarted { iteration });
+            let prompt = self
+                .config
+                .prompts
+                .next_action(query, iteration + prompt_offset, false);
             self.messages.push(prompt);
 
             let response = self.llm.completion(&self.messages, None).await?;
@@ -162,107 +1163,526 @@ impl RlmRepl {
             let code_blocks = find_code_blocks(&response);
             self.logger
                 .log_model_response(&response, !code_blocks.is_empty());
+            self.progress.emit(ProgressEvent::model_responded(
+                iteration,
+                &response,
+                code_blocks.len(),
+            ));
 
-            if !code_blocks.is_empty() {
-                process_code_execution_blocks(
-                    &code_blocks,
-                    &mut self.messages,
-                    &repl_env,
-                    &mut self.repl_env_logger,
-                    &self.logger,
-                    self.disable_recursive,
-                )
-                .await;
+            let subcalls_start = self.subcalls.snapshot();
+            let outputs = if !code_blocks.is_empty() {
+                // One block at a time, so each output is reported as soon as
+                // it exists.
+                let mut outputs = Vec::with_capacity(code_blocks.len());
+                for (block, code) in code_blocks.iter().enumerate() {
+                    let output = process_code_execution_blocks(
+                        std::slice::from_ref(code),
+                        &mut self.messages,
+                        repl_env.as_ref(),
+                        &mut self.repl_env_logger,
+                        &self.logger,
+                        self.disable_recursive,
+                    )
+                    .await;
+                    for output in &output {
+                        self.progress
+                            .emit(ProgressEvent::code_executed(iteration, block, output));
+                    }
+                    outputs.extend(output);
+                }
+                outputs
             } else {
                 self.messages.push(Message::assistant(format!(
                     "You responded with:\n{response}"
                 )));
-            }
+                Vec::new()
+            };
+            self.last_trace.push_iteration(
+                iteration,
+                &response,
+                &code_blocks,
+                &outputs,
+                self.subcalls.since(subcalls_start),
+            );
+            last_response = Some(response.clone());
 
-            if let Some(final_answer) =
-                check_for_final_answer(&response, &repl_env, &self.logger).await
+            if let Some((kind, content)) = find_final_answer(&response)
+                && (self.config.response_schema.is_some()
+                    || matches!(kind, FinalAnswerKind::FinalJson))
+            {
+                match self.final_json(&kind, &content, repl_env.as_ref()).await {
+                    Ok(value) => {
+                        if verify {
+                            verify = false;
+                            if let Some(reason) = self.verify(query, &value.to_string()).await {
+                                self.messages.push(verifier_feedback(&reason));
+                                iterations = iterations.max(iteration + 2);
+                                continue;
+                            }
+                        }
+                        return Ok(self.finish_json(value));
+                    }
+                    Err(err) => {
+                        self.logger.log_tool_execution("FINAL_JSON", &err);
+                        self.messages.push(Message::user(format!(
+                            "Your final answer was rejected: {err}. Fix it and answer again with \
+                             FINAL_JSON(...)."
+                        )));
+                    }
+                }
+            } else if let Some(final_answer) =
+                check_for_final_answer(&response, repl_env.as_ref(), &self.logger).await
             {
+                let final_answer = self.cap_final_answer(final_answer);
+                if verify {
+                    verify = false;
+                    if let Some(reason) = self.verify(query, &final_answer).await {
+                        self.messages.push(verifier_feedback(&reason));
+                        iterations = iterations.max(iteration + 2);
+                        continue;
+                    }
+                }
                 self.logger.log_final_response(&final_answer);
+                self.progress.emit(ProgressEvent::finished(&final_answer));
                 return Ok(final_answer);
             }
         }
 
-        println!("No final answer found in any iteration");
-        let final_prompt = next_action_prompt(query, self.max_iterations, true);
+        tracing::info!(
+            iterations,
+            "no final answer after the last iteration; forcing one"
+        );
+        // Forcing an answer costs another call that a spent budget no
+        // longer covers.
+        if self.spend_limit.exhausted() {
+            return self.partial_answer(last_response, LlmError::BudgetExhausted);
+        }
+        let final_prompt = self.config.prompts.next_action(query, iterations, true);
         self.messages.push(final_prompt);
-        let final_answer = self.llm.completion(&self.messages, None).await?;
+        // `llm` fails the call at the deadline, so a forced answer that does
+        // not arrive in time falls back to the last response.
+        let completion = match self
+            .llm
+            .completion_with_finish_reason(&self.messages, self.max_completion_tokens)
+            .await
+        {
+            Ok(completion) => completion,
+            Err(err @ LlmError::DeadlineExceeded) => {
+                return self.partial_answer(last_response, err);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.last_finish_reason = completion.finish_reason;
+        let final_answer = completion.content;
+        self.last_trace
+            .push_iteration(iterations, &final_answer, &[], &[], Default::default());
+        // Out of iterations, so there is no retry; a forced answer that
+        // still fails the schema is returned as text.
+        if self.config.response_schema.is_some() {
+            let (kind, content) = find_final_answer(&final_answer)
+                .unwrap_or((FinalAnswerKind::Final, final_answer.clone()));
+            if let Ok(value) = self.final_json(&kind, &content, repl_env.as_ref()).await {
+                return Ok(self.finish_json(value));
+            }
+        }
         self.logger.log_final_response(&final_answer);
+        self.progress.emit(ProgressEvent::finished(&final_answer));
         Ok(final_answer)
     }
 
-    pub fn cost_summary(&self) -> anyhow::Result<()> {
-        anyhow::bail!("Cost tracking not implemented for RLM REPL.")
+    /// The last model response, standing in for the final answer there was
+    /// no time or budget left to ask for; `err` when there is none.
+    fn partial_answer(
+        &mut self,
+        last_response: Option<String>,
+        err: LlmError,
+    ) -> anyhow::Result<String> {
+        let answer = self.cap_final_answer(last_response.ok_or(err)?);
+        self.last_finish_reason = FinishReason::Length;
+        self.logger.log_final_response(&answer);
+        self.progress.emit(ProgressEvent::finished(&answer));
+        Ok(answer)
+    }
+
+    // Spend limits cover a whole root completion, so children count from
+    // where their root started.
+    fn mark_spend_start(&self) {
+        if self.cost_depth == 0 {
+            self.cost.mark();
+        }
+    }
+
+    // FINAL/FINAL_VAR answers come from uncapped iterations, so trim them to
+    // the cap using the same ~4 bytes per token estimate as sub-call
+    // limits.
+    fn cap_final_answer(&mut self, mut answer: String) -> String {
+        let Some(max_tokens) = self.max_completion_tokens else {
+            return answer;
+        };
+        let max_bytes = (max_tokens as usize).saturating_mul(APPROX_BYTES_PER_TOKEN);
+        if answer.len() > max_bytes {
+            let mut end = max_bytes;
+            while !answer.is_char_boundary(end) {
+                end -= 1;
+            }
+            answer.truncate(end);
+            self.last_finish_reason = FinishReason::Length;
+        }
+        answer
+    }
+
+    /// Calls, tokens and USD spent since the last reset, `llm_query` and
+    /// `rlm_query` children included, priced with `RlmConfig::pricing`.
+    pub fn cost_summary(&self) -> CostSummary {
+        self.cost.summary(&self.config.pricing)
     }
 
     pub fn reset(&mut self) {
         self.messages.clear();
+        self.turns.clear();
         self.repl_env = None;
+        self.context = None;
         self.query = None;
+        self.last_trace = CompletionTrace::default();
+        self.last_finish_reason = FinishReason::Stop;
+        self.last_usage = CompletionUsage::default();
+        self.last_json = None;
+        self.cost.clear();
+        self.used_fallback.store(false, Ordering::Relaxed);
         self.repl_env_logger.clear();
         self.shared_state.clear();
+        self.redaction.clear();
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_executor(&self) -> anyhow::Result<Arc<dyn CodeExecutor>> {
+        if let Some(executor) = &self.executor {
+            return Ok(executor.clone());
+        }
+        let factory = self
+            .runtime_factory
+            .clone()
+            .unwrap_or_else(|| Arc::new(PythonRuntimeFactory));
+        Ok(Arc::new(ReplHandle::with_runtime(
+            factory,
+            self.recursive_llm.clone(),
+            self.recursive_runner.clone(),
+            self.depth,
+            self.shared_state.clone(),
+        )?))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn spawn_executor(&self) -> anyhow::Result<Arc<dyn CodeExecutor>> {
+        self.executor
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("no code executor configured; use with_executor"))
+    }
+
+    /// Asks the verifier whether `answer` holds up against the REPL history,
+    /// returning its objection if not. An unreachable verifier accepts the
+    /// answer rather than failing the completion.
+    async fn verify(&self, query: &str, answer: &str) -> Option<String> {
+        let verifier = match &self.config.verifier_model {
+            Some(model) => {
+                let route = self
+                    .route
+                    .read()
+                    .expect("model route lock poisoned")
+                    .clone();
+                let client = match make_client(
+                    self.clients.as_ref(),
+                    &self.cost,
+                    self.cost_depth,
+                    model,
+                    route.api_key.as_deref(),
+                    &route.base_url,
+                ) {
+                    Ok(client) => client,
+                    Err(err) => {
+                        tracing::warn!(error = %err, "failed to create verifier client");
+                        return None;
+                    }
+                };
+                self.deadline.wrap(Arc::new(RedactingLlmClient::new(
+                    client,
+                    self.redaction.clone(),
+                )))
+            }
+            None => self.llm.clone(),
+        };
+        let prompt = verifier_prompt(query, &self.messages, answer);
+        match verifier.completion(&prompt, None).await {
+            Ok(reply) => {
+                let rejection = verifier_rejection(&reply);
+                if let Some(reason) = &rejection {
+                    tracing::info!(reason = %reason, "verifier rejected final answer");
+                    self.logger.log_tool_execution("VERIFY", reason);
+                }
+                rejection
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "verifier call failed; accepting answer");
+                None
+            }
+        }
+    }
+
+    /// Parses a JSON final answer and checks it against the response
+    /// schema, or says what is wrong with it.
+    async fn final_json(
+        &self,
+        kind: &FinalAnswerKind,
+        content: &str,
+        repl_env: &dyn CodeExecutor,
+    ) -> Result<Value, String> {
+        let value = resolve_final_json(kind, content, repl_env).await?;
+        if let Some(schema) = &self.config.response_schema {
+            schema::validate(schema, &value)?;
+        }
+        Ok(value)
+    }
+
+    fn finish_json(&mut self, value: Value) -> String {
+        let final_answer = value.to_string();
+        self.logger.log_final_response(&final_answer);
+        self.progress.emit(ProgressEvent::finished(&final_answer));
+        self.last_json = Some(value);
+        final_answer
     }
 
     fn reset_messages_to_system_prompt(&mut self) {
+        let mut system_prompt = self.config.prompts.system_prompt();
+        if let Some(schema) = &self.config.response_schema {
+            system_prompt.push_str("\n\n");
+            system_prompt.push_str(&response_schema_prompt(schema));
+        }
         if let Some(first) = self.messages.first()
             && first.role == "system"
-            && first.content == REPL_SYSTEM_PROMPT
+            && first.content == system_prompt
         {
             self.messages.truncate(1);
             return;
         }
-        self.messages = build_system_prompt();
+        self.messages = vec![Message::system(system_prompt)];
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[derive(Clone)]
 struct RlmRecursiveRunner {
     config: RlmConfig,
+    clients: Arc<dyn LlmClientFactory>,
+    route: Arc<RwLock<ActiveRoute>>,
     shared_state: SharedProgramState,
+    redaction: Arc<Redaction>,
+    deadline: Deadline,
+    cost: CostLedger,
+    cost_depth: usize,
+    runtime_factory: Option<Arc<dyn CodeRuntimeFactory>>,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl RlmRecursiveRunner {
-    fn new(config: RlmConfig, shared_state: SharedProgramState) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: RlmConfig,
+        clients: Arc<dyn LlmClientFactory>,
+        route: Arc<RwLock<ActiveRoute>>,
+        shared_state: SharedProgramState,
+        redaction: Arc<Redaction>,
+        deadline: Deadline,
+        cost: CostLedger,
+        cost_depth: usize,
+        runtime_factory: Option<Arc<dyn CodeRuntimeFactory>>,
+    ) -> Self {
         Self {
             config,
+            clients,
+            route,
             shared_state,
+            redaction,
+            deadline,
+            cost,
+            cost_depth,
+            runtime_factory,
         }
     }
 
     fn child_config(&self) -> RlmConfig {
         let depth = self.config.depth.saturating_sub(1);
+        let route = self
+            .route
+            .read()
+            .expect("model route lock poisoned")
+            .clone();
         RlmConfig {
-            api_key: self.config.api_key.clone(),
-            base_url: self.config.base_url.clone(),
-            model: self.config.recursive_model.clone(),
-            recursive_model: self.config.recursive_model.clone(),
-            max_iterations: self.config.max_iterations,
+            api_key: route.api_key,
+            base_url: route.base_url,
+            model: route.recursive_model.clone(),
+            recursive_model: route.recursive_model,
             depth,
-            enable_logging: self.config.enable_logging,
-            disable_recursive: self.config.disable_recursive,
+            max_completion_tokens: None,
+            fallback_model: None,
+            fallback_base_url: None,
+            response_schema: None,
+            verify_answers: false,
+            verifier_model: None,
+            ..self.config.clone()
         }
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait::async_trait]
 impl RecursiveRunner for RlmRecursiveRunner {
     async fn completion(&self, query: String, context: ContextInput) -> anyhow::Result<String> {
-        let mut repl =
-            RlmRepl::new_with_shared_state(self.child_config(), self.shared_state.clone())?;
+        if spend_limit(&self.config, &self.cost).exhausted() {
+            return Err(LlmError::BudgetExhausted.into());
+        }
+        let mut repl = RlmRepl::new_with_shared_state(
+            self.child_config(),
+            self.clients.clone(),
+            self.shared_state.clone(),
+            self.redaction.clone(),
+            self.deadline.clone(),
+            self.cost.clone(),
+            self.cost_depth,
+        )?;
+        if let Some(factory) = &self.runtime_factory {
+            repl = repl.with_runtime(factory.clone());
+        }
         repl.completion(context, Some(&query)).await
     }
+
+    fn max_parallel(&self) -> usize {
+        self.config.max_parallel_subcalls
+    }
+}
+
+fn spend_limit(config: &RlmConfig, cost: &CostLedger) -> SpendLimit {
+    SpendLimit::new(
+        cost.clone(),
+        &config.pricing,
+        config.max_total_tokens,
+        config.max_cost_usd,
+    )
+}
+
+/// Hands out the same client whatever model or endpoint is asked for.
+struct FixedClientFactory(Arc<dyn LlmClient>);
+
+impl LlmClientFactory for FixedClientFactory {
+    fn client(
+        &self,
+        _model: &str,
+        _api_key: Option<&str>,
+        _base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError> {
+        Ok(self.0.clone())
+    }
 }
 
 fn make_client(
+    clients: &dyn LlmClientFactory,
+    cost: &CostLedger,
+    cost_depth: usize,
     model: &str,
-    api_key: Option<String>,
-    base_url: String,
+    api_key: Option<&str>,
+    base_url: &str,
+) -> anyhow::Result<Arc<dyn LlmClient>> {
+    let client = clients.client(model, api_key, base_url)?;
+    Ok(cost.wrap_llm(client, cost_depth, model))
+}
+
+fn make_root_client(
+    clients: &dyn LlmClientFactory,
+    cost: &CostLedger,
+    cost_depth: usize,
+    route: &ActiveRoute,
+    used_fallback: &Arc<AtomicBool>,
 ) -> anyhow::Result<Arc<dyn LlmClient>> {
-    let api_key = api_key.ok_or(crate::llm::LlmError::MissingApiKey)?;
-    let client = LlmClientImpl::new(api_key, base_url, model.to_owned())?;
-    Ok(Arc::new(client))
+    let primary = make_client(
+        clients,
+        cost,
+        cost_depth,
+        &route.model,
+        route.api_key.as_deref(),
+        &route.base_url,
+    )?;
+    let Some(fallback_model) = &route.fallback_model else {
+        return Ok(primary);
+    };
+    let fallback_base_url = route
+        .fallback_base_url
+        .as_deref()
+        .unwrap_or(&route.base_url);
+    let fallback = make_client(
+        clients,
+        cost,
+        cost_depth,
+        fallback_model,
+        route.api_key.as_deref(),
+        fallback_base_url,
+    )?;
+    Ok(Arc::new(FallbackLlmClient::new(
+        primary,
+        fallback,
+        used_fallback.clone(),
+    )))
+}
+
+fn default_max_parallel_subcalls() -> usize {
+    DEFAULT_MAX_PARALLEL_SUBCALLS
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+fn env_parse<T: FromStr>(name: &'static str) -> Result<Option<T>, RlmConfigError> {
+    let Some(value) = env_string(name) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| RlmConfigError::InvalidEnv { name, value })
+}
+
+fn env_bool(name: &'static str) -> Result<Option<bool>, RlmConfigError> {
+    let Some(value) = env_string(name) else {
+        return Ok(None);
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(Some(true)),
+        "0" | "false" | "no" | "off" => Ok(Some(false)),
+        _ => Err(RlmConfigError::InvalidEnv { name, value }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disable_recursive_wins_over_depth() {
+        let config = RlmConfig::builder()
+            .disable_recursive(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.depth, DEFAULT_DEPTH);
+        assert_eq!(config.recursion_depth(), 0);
+
+        let config = RlmConfig::builder()
+            .depth(3)
+            .disable_recursive(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.recursion_depth(), 0);
+        assert_eq!(RlmConfig::default().recursion_depth(), DEFAULT_DEPTH);
+    }
 }
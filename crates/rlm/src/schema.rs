@@ -0,0 +1,240 @@
+use serde_json::{Map, Value};
+
+/// Whether `schema` is something `validate` can check against.
+pub fn check_schema(schema: &Value) -> Result<(), String> {
+    match schema {
+        Value::Object(_) | Value::Bool(_) => Ok(()),
+        _ => Err("a schema must be an object or a boolean".to_owned()),
+    }
+}
+
+/// Returns the first way `value` breaks `schema`, with the path to it.
+/// Covers the JSON Schema subset response formats usually use: `type`,
+/// `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items`, `anyOf`, and the length and range bounds. Other keywords are
+/// ignored.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(schema, value, "$")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{path}: no value is allowed here")),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+    if let Some(expected) = schema.get("type") {
+        check_type(expected, value, path)?;
+    }
+    if let Some(Value::Array(options)) = schema.get("enum")
+        && !options.contains(value)
+    {
+        return Err(format!(
+            "{path}: must be one of {}",
+            Value::Array(options.clone())
+        ));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        return Err(format!("{path}: must be {expected}"));
+    }
+    if let Some(Value::Array(options)) = schema.get("anyOf")
+        && !options
+            .iter()
+            .any(|option| validate_at(option, value, path).is_ok())
+    {
+        return Err(format!("{path}: matches none of the allowed shapes"));
+    }
+    match value {
+        Value::Object(object) => validate_object(schema, object, path),
+        Value::Array(items) => validate_array(schema, items, path),
+        Value::String(text) => {
+            let len = text.chars().count() as u64;
+            check_bounds(schema, "minLength", "maxLength", len, "characters", path)
+        }
+        Value::Number(number) => {
+            let Some(number) = number.as_f64() else {
+                return Ok(());
+            };
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+                && number < min
+            {
+                return Err(format!("{path}: must be at least {min}"));
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+                && number > max
+            {
+                return Err(format!("{path}: must be at most {max}"));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_type(expected: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let matches = |name: &str| match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    };
+    let ok = match expected {
+        Value::String(name) => matches(name),
+        Value::Array(names) => names.iter().filter_map(Value::as_str).any(matches),
+        _ => true,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("{path}: must be of type {expected}"))
+    }
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(format!("{path}: missing required property \"{name}\""));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let child_path = format!("{path}.{name}");
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property) => validate_at(property, value, &child_path)?,
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    validate_at(additional, value, &child_path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_array(schema: &Map<String, Value>, items: &[Value], path: &str) -> Result<(), String> {
+    check_bounds(
+        schema,
+        "minItems",
+        "maxItems",
+        items.len() as u64,
+        "items",
+        path,
+    )?;
+    if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+            validate_at(item_schema, item, &format!("{path}[{index}]"))?;
+        }
+    }
+    Ok(())
+}
+
+fn check_bounds(
+    schema: &Map<String, Value>,
+    min_key: &str,
+    max_key: &str,
+    len: u64,
+    unit: &str,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(min) = schema.get(min_key).and_then(Value::as_u64)
+        && len < min
+    {
+        return Err(format!("{path}: must have at least {min} {unit}"));
+    }
+    if let Some(max) = schema.get(max_key).and_then(Value::as_u64)
+        && len > max
+    {
+        return Err(format!("{path}: must have at most {max} {unit}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn answer_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["answer", "tags"],
+            "additionalProperties": false,
+            "properties": {
+                "answer": {"type": "string", "minLength": 1},
+                "score": {"type": "integer", "minimum": 0, "maximum": 10},
+                "tags": {"type": "array", "maxItems": 2, "items": {"enum": ["a", "b"]}},
+                "source": {"anyOf": [{"type": "null"}, {"const": "web"}]},
+            },
+        })
+    }
+
+    #[test]
+    fn matching_values_pass() {
+        let value = json!({"answer": "42", "score": 7, "tags": ["a"], "source": null});
+        assert_eq!(validate(&answer_schema(), &value), Ok(()));
+        assert_eq!(validate(&json!(true), &value), Ok(()));
+    }
+
+    #[test]
+    fn errors_name_the_failing_path() {
+        let schema = answer_schema();
+        for (value, error) in [
+            (json!([]), "$: must be of type \"object\""),
+            (
+                json!({"answer": "x"}),
+                "$: missing required property \"tags\"",
+            ),
+            (
+                json!({"answer": "", "tags": []}),
+                "$.answer: must have at least 1 characters",
+            ),
+            (
+                json!({"answer": "x", "tags": [], "score": 1.5}),
+                "$.score: must be of type \"integer\"",
+            ),
+            (
+                json!({"answer": "x", "tags": [], "score": 11}),
+                "$.score: must be at most 10",
+            ),
+            (
+                json!({"answer": "x", "tags": ["a", "c"]}),
+                "$.tags[1]: must be one of [\"a\",\"b\"]",
+            ),
+            (
+                json!({"answer": "x", "tags": ["a", "a", "a"]}),
+                "$.tags: must have at most 2 items",
+            ),
+            (
+                json!({"answer": "x", "tags": [], "source": "book"}),
+                "$.source: matches none of the allowed shapes",
+            ),
+            (
+                json!({"answer": "x", "tags": [], "extra": 1}),
+                "$.extra: no value is allowed here",
+            ),
+        ] {
+            assert_eq!(validate(&schema, &value), Err(error.to_owned()));
+        }
+    }
+
+    #[test]
+    fn schemas_must_be_objects_or_booleans() {
+        assert!(check_schema(&json!({})).is_ok());
+        assert!(check_schema(&json!(false)).is_ok());
+        assert!(check_schema(&json!("object")).is_err());
+    }
+}
@@ -0,0 +1,851 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::llm::{Completion, LlmClient, LlmClientFactory, LlmError, Message};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::repl::RecursiveRunner;
+use crate::repl::{CodeExecutor, ReplResult, RuntimeSnapshot};
+use crate::utils::ContextData;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::utils::ContextInput;
+
+const TRACE_EXCERPT_CHARS: usize = 2000;
+
+/// Iterations of the most recent completion loop, kept for debugging.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CompletionTrace {
+    pub iterations: Vec<IterationTrace>,
+    pub llm_subcalls: u64,
+    pub rlm_subcalls: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IterationTrace {
+    pub iteration: usize,
+    pub response_excerpt: String,
+    pub code_blocks: Vec<CodeBlockTrace>,
+    pub llm_subcalls: u64,
+    pub rlm_subcalls: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CodeBlockTrace {
+    pub code: String,
+    pub output_excerpt: String,
+}
+
+impl CompletionTrace {
+    /// Trace of a single block run directly rather than by a completion loop.
+    pub fn for_code(code: &str, result: &ReplResult) -> Self {
+        let mut trace = Self::default();
+        let output = format!("{}{}", result.stdout, result.stderr);
+        trace.push_iteration(
+            0,
+            "",
+            &[code.to_owned()],
+            &[output],
+            SubcallCounts::default(),
+        );
+        trace
+    }
+
+    pub(crate) fn push_iteration(
+        &mut self,
+        iteration: usize,
+        response: &str,
+        code_blocks: &[String],
+        outputs: &[String],
+        subcalls: SubcallCounts,
+    ) {
+        self.llm_subcalls += subcalls.llm;
+        self.rlm_subcalls += subcalls.rlm;
+        self.iterations.push(IterationTrace {
+            iteration,
+            response_excerpt: excerpt(response),
+            code_blocks: code_blocks
+                .iter()
+                .zip(outputs)
+                .map(|(code, output)| CodeBlockTrace {
+                    code: code.clone(),
+                    output_excerpt: excerpt(output),
+                })
+                .collect(),
+            llm_subcalls: subcalls.llm,
+            rlm_subcalls: subcalls.rlm,
+        });
+    }
+}
+
+fn excerpt(text: &str) -> String {
+    match text.char_indices().nth(TRACE_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+/// What a completion loop is doing, reported as it happens so callers can
+/// show status during long runs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    IterationStarted {
+        iteration: usize,
+    },
+    /// The model answered an iteration; `code_blocks` is how many blocks it
+    /// asked to run.
+    ModelResponded {
+        iteration: usize,
+        response_excerpt: String,
+        code_blocks: usize,
+    },
+    /// One code block finished; `output_excerpt` is the start of what it
+    /// printed.
+    CodeExecuted {
+        iteration: usize,
+        block: usize,
+        output_excerpt: String,
+    },
+    SubcallStarted {
+        subcall: SubcallKind,
+    },
+    /// The loop settled on its answer, which the completion then returns.
+    Finished {
+        answer_excerpt: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SubcallKind {
+    Llm,
+    Rlm,
+}
+
+pub type ProgressHook = dyn Fn(&ProgressEvent) + Send + Sync;
+
+/// Where a REPL tree reports its [`ProgressEvent`]s; shared with the
+/// clients that report sub-calls.
+#[derive(Clone, Default)]
+pub(crate) struct Progress {
+    hook: Arc<RwLock<Option<Arc<ProgressHook>>>>,
+}
+
+impl Progress {
+    pub(crate) fn set(&self, hook: Option<Arc<ProgressHook>>) {
+        *self.hook.write().expect("progress lock poisoned") = hook;
+    }
+
+    pub(crate) fn emit(&self, event: ProgressEvent) {
+        let hook = self.hook.read().expect("progress lock poisoned").clone();
+        if let Some(hook) = hook {
+            hook(&event);
+        }
+    }
+}
+
+impl ProgressEvent {
+    pub(crate) fn model_responded(iteration: usize, response: &str, code_blocks: usize) -> Self {
+        Self::ModelResponded {
+            iteration,
+            response_excerpt: excerpt(response),
+            code_blocks,
+        }
+    }
+
+    pub(crate) fn finished(answer: &str) -> Self {
+        Self::Finished {
+            answer_excerpt: excerpt(answer),
+        }
+    }
+
+    pub(crate) fn code_executed(iteration: usize, block: usize, output: &str) -> Self {
+        Self::CodeExecuted {
+            iteration,
+            block,
+            output_excerpt: excerpt(output),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SubcallCounts {
+    llm: u64,
+    rlm: u64,
+}
+
+/// Counts `llm_query`/`rlm_query` calls made from the REPL.
+#[derive(Clone, Default)]
+pub(crate) struct SubcallCounters {
+    llm: Arc<AtomicU64>,
+    rlm: Arc<AtomicU64>,
+}
+
+impl SubcallCounters {
+    pub(crate) fn snapshot(&self) -> SubcallCounts {
+        SubcallCounts {
+            llm: self.llm.load(Ordering::Relaxed),
+            rlm: self.rlm.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn since(&self, start: SubcallCounts) -> SubcallCounts {
+        let now = self.snapshot();
+        SubcallCounts {
+            llm: now.llm - start.llm,
+            rlm: now.rlm - start.rlm,
+        }
+    }
+
+    /// Counts calls through `inner` and reports each to `progress`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn wrap_llm(
+        &self,
+        inner: Arc<dyn LlmClient>,
+        progress: &Progress,
+    ) -> Arc<dyn LlmClient> {
+        Arc::new(CountingLlmClient {
+            inner,
+            counters: self.clone(),
+            progress: progress.clone(),
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn wrap_runner(
+        &self,
+        inner: Arc<dyn RecursiveRunner>,
+        progress: &Progress,
+    ) -> Arc<dyn RecursiveRunner> {
+        Arc::new(CountingRecursiveRunner {
+            inner,
+            counters: self.clone(),
+            progress: progress.clone(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct CountingLlmClient {
+    inner: Arc<dyn LlmClient>,
+    counters: SubcallCounters,
+    progress: Progress,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CountingLlmClient {
+    fn count(&self) {
+        self.counters.llm.fetch_add(1, Ordering::Relaxed);
+        self.progress.emit(ProgressEvent::SubcallStarted {
+            subcall: SubcallKind::Llm,
+        });
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl LlmClient for CountingLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.count();
+        self.inner.completion(messages, max_completion_tokens).await
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        self.count();
+        self.inner
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct CountingRecursiveRunner {
+    inner: Arc<dyn RecursiveRunner>,
+    counters: SubcallCounters,
+    progress: Progress,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl RecursiveRunner for CountingRecursiveRunner {
+    async fn completion(&self, query: String, context: ContextInput) -> anyhow::Result<String> {
+        self.counters.rlm.fetch_add(1, Ordering::Relaxed);
+        self.progress.emit(ProgressEvent::SubcallStarted {
+            subcall: SubcallKind::Rlm,
+        });
+        self.inner.completion(query, context).await
+    }
+
+    fn max_parallel(&self) -> usize {
+        self.inner.max_parallel()
+    }
+}
+
+/// Tokens and REPL time spent by a completion loop, its `rlm_query` children
+/// included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CompletionUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// LLM calls whose backend reported no token counts, which the totals
+    /// above leave out.
+    pub unreported_calls: u64,
+    /// Time spent executing code blocks, which covers any children run from
+    /// them.
+    pub repl_time_ms: u64,
+}
+
+impl CompletionUsage {
+    /// What was spent between `start` and these totals.
+    pub fn since(self, start: CompletionUsage) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens: self.prompt_tokens - start.prompt_tokens,
+            completion_tokens: self.completion_tokens - start.completion_tokens,
+            unreported_calls: self.unreported_calls - start.unreported_calls,
+            repl_time_ms: self.repl_time_ms - start.repl_time_ms,
+        }
+    }
+}
+
+/// Running totals behind [`CompletionUsage`], shared by every client a REPL
+/// and its children build.
+#[derive(Clone, Default)]
+pub(crate) struct UsageCounters {
+    prompt_tokens: Arc<AtomicU64>,
+    completion_tokens: Arc<AtomicU64>,
+    unreported_calls: Arc<AtomicU64>,
+    repl_time_us: Arc<AtomicU64>,
+}
+
+impl UsageCounters {
+    pub(crate) fn snapshot(&self) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            completion_tokens: self.completion_tokens.load(Ordering::Relaxed),
+            unreported_calls: self.unreported_calls.load(Ordering::Relaxed),
+            repl_time_ms: self.repl_time_us.load(Ordering::Relaxed) / 1000,
+        }
+    }
+
+    pub(crate) fn since(&self, start: CompletionUsage) -> CompletionUsage {
+        self.snapshot().since(start)
+    }
+
+    pub(crate) fn wrap_factory(
+        &self,
+        inner: Arc<dyn LlmClientFactory>,
+    ) -> Arc<dyn LlmClientFactory> {
+        Arc::new(MeteredClientFactory {
+            inner,
+            usage: self.clone(),
+        })
+    }
+
+    pub(crate) fn wrap_llm(&self, inner: Arc<dyn LlmClient>) -> Arc<dyn LlmClient> {
+        Arc::new(MeteredLlmClient {
+            inner,
+            usage: self.clone(),
+        })
+    }
+
+    pub(crate) fn wrap_executor(&self, inner: Arc<dyn CodeExecutor>) -> Arc<dyn CodeExecutor> {
+        Arc::new(MeteredExecutor {
+            inner,
+            usage: self.clone(),
+        })
+    }
+
+    fn record(&self, completion: &Completion) {
+        match completion.usage {
+            Some(usage) => {
+                self.prompt_tokens
+                    .fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+                self.completion_tokens
+                    .fetch_add(usage.completion_tokens, Ordering::Relaxed);
+            }
+            None => {
+                self.unreported_calls.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct MeteredClientFactory {
+    inner: Arc<dyn LlmClientFactory>,
+    usage: UsageCounters,
+}
+
+impl LlmClientFactory for MeteredClientFactory {
+    fn client(
+        &self,
+        model: &str,
+        api_key: Option<&str>,
+        base_url: &str,
+    ) -> Result<Arc<dyn LlmClient>, LlmError> {
+        Ok(self
+            .usage
+            .wrap_llm(self.inner.client(model, api_key, base_url)?))
+    }
+}
+
+struct MeteredLlmClient {
+    inner: Arc<dyn LlmClient>,
+    usage: UsageCounters,
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl LlmClient for MeteredLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let completion = self
+            .inner
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await?;
+        self.usage.record(&completion);
+        Ok(completion)
+    }
+}
+
+struct MeteredExecutor {
+    inner: Arc<dyn CodeExecutor>,
+    usage: UsageCounters,
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl CodeExecutor for MeteredExecutor {
+    async fn init(&self, context: ContextData, setup_code: Option<String>) -> anyhow::Result<()> {
+        self.inner.init(context, setup_code).await
+    }
+
+    async fn execute(&self, code: String) -> anyhow::Result<ReplResult> {
+        let result = self.inner.execute(code).await?;
+        self.usage.repl_time_us.fetch_add(
+            (result.execution_time * 1_000_000.0) as u64,
+            Ordering::Relaxed,
+        );
+        Ok(result)
+    }
+
+    async fn get_variable(&self, name: String) -> anyhow::Result<Option<String>> {
+        self.inner.get_variable(name).await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<RuntimeSnapshot> {
+        self.inner.snapshot().await
+    }
+}
+
+/// USD per million tokens for one model.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ModelPrice {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl ModelPrice {
+    fn cost_usd(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.prompt_per_million
+            + completion_tokens as f64 * self.completion_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// LLM calls made by a REPL and its `rlm_query` children since the last
+/// reset, priced with the configured table.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CostSummary {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Calls whose backend reported no token counts, which cost nothing here.
+    pub unreported_calls: u64,
+    pub cost_usd: f64,
+    /// Models with calls but no entry in the pricing table; their tokens are
+    /// counted but not priced. Calls to an unnamed model are left out.
+    pub unpriced_models: Vec<String>,
+    pub by_depth: Vec<DepthCost>,
+}
+
+/// Calls made at one level of recursion: 0 is the root completion loop, 1
+/// its `llm_query` calls and `rlm_query` children, and so on.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DepthCost {
+    pub depth: usize,
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub unreported_calls: u64,
+    pub cost_usd: f64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct CallTotals {
+    calls: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    unreported_calls: u64,
+}
+
+type CallsByModel = BTreeMap<(usize, String), CallTotals>;
+
+/// Calls per depth and model, shared by a REPL and all of its children.
+#[derive(Clone, Default)]
+pub(crate) struct CostLedger {
+    calls: Arc<Mutex<CallsByModel>>,
+    // Calls as of the start of the current root completion, which spend
+    // limits count from.
+    mark: Arc<Mutex<CallsByModel>>,
+}
+
+impl CostLedger {
+    /// Records every completion `inner` returns as a call to `model` at
+    /// `depth`.
+    pub(crate) fn wrap_llm(
+        &self,
+        inner: Arc<dyn LlmClient>,
+        depth: usize,
+        model: &str,
+    ) -> Arc<dyn LlmClient> {
+        Arc::new(CostedLlmClient {
+            inner,
+            ledger: self.clone(),
+            depth,
+            model: model.to_owned(),
+        })
+    }
+
+    pub(crate) fn clear(&self) {
+        self.calls
+            .lock()
+            .expect("cost ledger lock poisoned")
+            .clear();
+        self.mark.lock().expect("cost ledger lock poisoned").clear();
+    }
+
+    /// Starts counting towards spend limits from now.
+    pub(crate) fn mark(&self) {
+        let calls = self
+            .calls
+            .lock()
+            .expect("cost ledger lock poisoned")
+            .clone();
+        *self.mark.lock().expect("cost ledger lock poisoned") = calls;
+    }
+
+    /// Tokens and USD spent since the last `mark`.
+    fn spent_since_mark(&self, pricing: &BTreeMap<String, ModelPrice>) -> (u64, f64) {
+        let calls = self.calls.lock().expect("cost ledger lock poisoned");
+        let mark = self.mark.lock().expect("cost ledger lock poisoned");
+        let mut tokens = 0;
+        let mut cost_usd = 0.0;
+        for (key, totals) in calls.iter() {
+            let before = mark.get(key).copied().unwrap_or_default();
+            let prompt_tokens = totals.prompt_tokens - before.prompt_tokens;
+            let completion_tokens = totals.completion_tokens - before.completion_tokens;
+            tokens += prompt_tokens + completion_tokens;
+            cost_usd += pricing.get(&key.1).map_or(0.0, |price| {
+                price.cost_usd(prompt_tokens, completion_tokens)
+            });
+        }
+        (tokens, cost_usd)
+    }
+
+    pub(crate) fn summary(&self, pricing: &BTreeMap<String, ModelPrice>) -> CostSummary {
+        let calls = self.calls.lock().expect("cost ledger lock poisoned");
+        let mut summary = CostSummary::default();
+        for ((depth, model), totals) in calls.iter() {
+            let cost_usd = match pricing.get(model) {
+                Some(price) => price.cost_usd(totals.prompt_tokens, totals.completion_tokens),
+                None => {
+                    if !model.is_empty() && !summary.unpriced_models.contains(model) {
+                        summary.unpriced_models.push(model.clone());
+                    }
+                    0.0
+                }
+            };
+            if summary
+                .by_depth
+                .last()
+                .is_none_or(|last| last.depth != *depth)
+            {
+                summary.by_depth.push(DepthCost {
+                    depth: *depth,
+                    ..DepthCost::default()
+                });
+            }
+            let level = summary.by_depth.last_mut().expect("depth entry pushed");
+            level.calls += totals.calls;
+            level.prompt_tokens += totals.prompt_tokens;
+            level.completion_tokens += totals.completion_tokens;
+            level.unreported_calls += totals.unreported_calls;
+            level.cost_usd += cost_usd;
+            summary.calls += totals.calls;
+            summary.prompt_tokens += totals.prompt_tokens;
+            summary.completion_tokens += totals.completion_tokens;
+            summary.unreported_calls += totals.unreported_calls;
+            summary.cost_usd += cost_usd;
+        }
+        summary.unpriced_models.sort();
+        summary
+    }
+
+    fn record(&self, depth: usize, model: &str, completion: &Completion) {
+        let mut calls = self.calls.lock().expect("cost ledger lock poisoned");
+        let totals = calls.entry((depth, model.to_owned())).or_default();
+        totals.calls += 1;
+        match completion.usage {
+            Some(usage) => {
+                totals.prompt_tokens += usage.prompt_tokens;
+                totals.completion_tokens += usage.completion_tokens;
+            }
+            None => totals.unreported_calls += 1,
+        }
+    }
+}
+
+struct CostedLlmClient {
+    inner: Arc<dyn LlmClient>,
+    ledger: CostLedger,
+    depth: usize,
+    model: String,
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl LlmClient for CostedLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        let completion = self
+            .inner
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await?;
+        self.ledger.record(self.depth, &self.model, &completion);
+        Ok(completion)
+    }
+}
+
+/// Caps on the tokens and USD one root completion may spend, `llm_query`
+/// calls and `rlm_query` children included; `None` leaves that side
+/// uncapped.
+#[derive(Clone)]
+pub(crate) struct SpendLimit {
+    ledger: CostLedger,
+    pricing: Arc<BTreeMap<String, ModelPrice>>,
+    max_total_tokens: Option<u64>,
+    max_cost_usd: Option<f64>,
+}
+
+impl SpendLimit {
+    pub(crate) fn new(
+        ledger: CostLedger,
+        pricing: &BTreeMap<String, ModelPrice>,
+        max_total_tokens: Option<u64>,
+        max_cost_usd: Option<f64>,
+    ) -> Self {
+        Self {
+            ledger,
+            pricing: Arc::new(pricing.clone()),
+            max_total_tokens,
+            max_cost_usd,
+        }
+    }
+
+    pub(crate) fn exhausted(&self) -> bool {
+        if self.max_total_tokens.is_none() && self.max_cost_usd.is_none() {
+            return false;
+        }
+        let (tokens, cost_usd) = self.ledger.spent_since_mark(&self.pricing);
+        self.max_total_tokens.is_some_and(|max| tokens >= max)
+            || self.max_cost_usd.is_some_and(|max| cost_usd >= max)
+    }
+
+    /// Fails calls through `inner` with `BudgetExhausted` once the limit is
+    /// reached, instead of sending them.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn wrap_llm(&self, inner: Arc<dyn LlmClient>) -> Arc<dyn LlmClient> {
+        Arc::new(LimitedLlmClient {
+            inner,
+            limit: self.clone(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct LimitedLlmClient {
+    inner: Arc<dyn LlmClient>,
+    limit: SpendLimit,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl LlmClient for LimitedLlmClient {
+    async fn completion(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<String, LlmError> {
+        self.completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    async fn completion_with_finish_reason(
+        &self,
+        messages: &[Message],
+        max_completion_tokens: Option<u32>,
+    ) -> Result<Completion, LlmError> {
+        if self.limit.exhausted() {
+            return Err(LlmError::BudgetExhausted);
+        }
+        self.inner
+            .completion_with_finish_reason(messages, max_completion_tokens)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{FinishReason, TokenUsage};
+
+    fn completion(prompt_tokens: u64, completion_tokens: u64) -> Completion {
+        Completion {
+            content: String::new(),
+            finish_reason: FinishReason::Stop,
+            usage: Some(TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+            }),
+        }
+    }
+
+    fn pricing() -> BTreeMap<String, ModelPrice> {
+        BTreeMap::from([(
+            "big".to_owned(),
+            ModelPrice {
+                prompt_per_million: 2.0,
+                completion_per_million: 4.0,
+            },
+        )])
+    }
+
+    #[test]
+    fn costs_are_priced_per_model_and_depth() {
+        let ledger = CostLedger::default();
+        ledger.record(0, "big", &completion(1_000_000, 500_000));
+        ledger.record(1, "big", &completion(500_000, 0));
+        ledger.record(1, "small", &completion(10, 10));
+        ledger.record(
+            1,
+            "",
+            &Completion {
+                usage: None,
+                ..completion(0, 0)
+            },
+        );
+        let summary = ledger.summary(&pricing());
+        assert_eq!(summary.calls, 4);
+        assert_eq!(summary.prompt_tokens, 1_500_010);
+        assert_eq!(summary.unreported_calls, 1);
+        assert_eq!(summary.cost_usd, 5.0);
+        assert_eq!(summary.unpriced_models, ["small"]);
+        let levels: Vec<_> = summary
+            .by_depth
+            .iter()
+            .map(|level| (level.depth, level.calls, level.cost_usd))
+            .collect();
+        assert_eq!(levels, [(0, 1, 4.0), (1, 3, 1.0)]);
+
+        ledger.clear();
+        assert_eq!(ledger.summary(&pricing()), CostSummary::default());
+    }
+
+    #[test]
+    fn spend_limits_count_from_the_mark() {
+        let ledger = CostLedger::default();
+        ledger.record(0, "big", &completion(900, 0));
+        ledger.mark();
+        let tokens = SpendLimit::new(ledger.clone(), &pricing(), Some(1000), None);
+        let cost = SpendLimit::new(ledger.clone(), &pricing(), None, Some(0.01));
+        let unlimited = SpendLimit::new(ledger.clone(), &pricing(), None, None);
+        assert!(!tokens.exhausted());
+        ledger.record(1, "big", &completion(600, 400));
+        assert!(tokens.exhausted());
+        assert!(!cost.exhausted());
+        ledger.record(1, "big", &completion(0, 2500));
+        assert!(cost.exhausted());
+        assert!(!unlimited.exhausted());
+        ledger.mark();
+        assert!(!tokens.exhausted());
+    }
+
+    struct Answer;
+
+    #[async_trait]
+    impl LlmClient for Answer {
+        async fn completion(&self, _: &[Message], _: Option<u32>) -> Result<String, LlmError> {
+            Ok("answer".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn limited_clients_stop_calling_once_exhausted() {
+        let ledger = CostLedger::default();
+        let client =
+            SpendLimit::new(ledger.clone(), &pricing(), Some(10), None).wrap_llm(Arc::new(Answer));
+        assert_eq!(client.completion(&[], None).await.unwrap(), "answer");
+        ledger.record(0, "big", &completion(10, 0));
+        assert!(matches!(
+            client.completion(&[], None).await,
+            Err(LlmError::BudgetExhausted)
+        ));
+    }
+}
@@ -2,10 +2,11 @@ use std::sync::LazyLock;
 
 use regex::Regex;
 use serde_json::Value;
+use tracing::Instrument;
 
 use crate::llm::Message;
 use crate::logger::{Logger, ReplEnvLogger};
-use crate::repl::{ReplHandle, ReplResult};
+use crate::repl::{CodeExecutor, ReplResult};
 
 static CODE_BLOCK_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"```repl\s*\n(?s:(.*?))\n```").expect("regex"));
@@ -13,6 +14,8 @@ static FINAL_VAR_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"(?ms)^\s*FINAL_VAR\((.*?)\)").expect("regex"));
 static FINAL_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"(?ms)^\s*FINAL\((.*?)\)").expect("regex"));
+static FINAL_JSON_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*FINAL_JSON\(").expect("regex"));
 
 #[derive(Clone, Debug)]
 pub enum ContextInput {
@@ -175,18 +178,88 @@ pub fn find_code_blocks(text: &str) -> Vec<String> {
 pub enum FinalAnswerKind {
     Final,
     FinalVar,
+    FinalJson,
 }
 
 pub fn find_final_answer(text: &str) -> Option<(FinalAnswerKind, String)> {
     if let Some(cap) = FINAL_VAR_RE.captures(text) {
         return Some((FinalAnswerKind::FinalVar, cap[1].trim().to_owned()));
     }
+    if let Some(content) = find_final_json(text) {
+        return Some((FinalAnswerKind::FinalJson, content));
+    }
     if let Some(cap) = FINAL_RE.captures(text) {
         return Some((FinalAnswerKind::Final, cap[1].trim().to_owned()));
     }
     None
 }
 
+/// `FINAL_JSON(...)` holds either a JSON literal or a variable name. A
+/// literal may contain `)` itself, so it is read as one JSON value rather
+/// than up to the first closing parenthesis.
+fn find_final_json(text: &str) -> Option<String> {
+    let rest = &text[FINAL_JSON_RE.find(text)?.end()..];
+    let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+    if let Some(Ok(_)) = values.next() {
+        let end = values.byte_offset();
+        if rest[end..].trim_start().starts_with(')') {
+            return Some(rest[..end].trim().to_owned());
+        }
+    }
+    let end = rest.find(')')?;
+    Some(rest[..end].trim().to_owned())
+}
+
+fn final_var_name(content: &str) -> &str {
+    content
+        .trim()
+        .trim_matches('"')
+        .trim_matches('\'')
+        .trim_matches('\n')
+        .trim_matches('\r')
+}
+
+/// The JSON value of a final answer: a `FINAL_JSON` literal, the REPL
+/// variable a `FINAL_JSON` or `FINAL_VAR` names, or the text of `FINAL`.
+pub async fn resolve_final_json(
+    kind: &FinalAnswerKind,
+    content: &str,
+    repl_env: &dyn CodeExecutor,
+) -> Result<Value, String> {
+    match (kind, serde_json::from_str::<Value>(content)) {
+        (FinalAnswerKind::Final, literal) => {
+            literal.map_err(|err| format!("the answer is not valid JSON: {err}"))
+        }
+        (FinalAnswerKind::FinalJson, Ok(value)) => Ok(value),
+        _ => variable_json(final_var_name(content), repl_env).await,
+    }
+}
+
+async fn variable_json(name: &str, repl_env: &dyn CodeExecutor) -> Result<Value, String> {
+    let is_identifier = name
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_alphabetic() || first == '_')
+        && name.chars().all(|ch| ch.is_alphanumeric() || ch == '_');
+    if !is_identifier {
+        return Err(format!(
+            "'{name}' is neither a JSON value nor a variable name"
+        ));
+    }
+    let result = repl_env
+        .execute(format!("print(__import__('json').dumps({name}))"))
+        .await
+        .map_err(|err| format!("failed to read variable '{name}': {err}"))?;
+    if !result.stderr.trim().is_empty() {
+        return Err(format!(
+            "variable '{name}' could not be encoded as JSON: {}",
+            result.stderr.trim()
+        ));
+    }
+    serde_json::from_str(result.stdout.trim())
+        .map_err(|err| format!("variable '{name}' did not encode to JSON: {err}"))
+}
+
 pub fn add_execution_result_to_messages(
     messages: &mut Vec<Message>,
     code: &str,
@@ -276,12 +349,15 @@ fn escape_string(value: &str) -> String {
 }
 
 pub async fn execute_code(
-    repl_env: &ReplHandle,
+    repl_env: &dyn CodeExecutor,
     code: &str,
     repl_env_logger: &mut ReplEnvLogger,
     logger: &Logger,
 ) -> String {
-    match repl_env.execute(code.to_owned()).await {
+    let execution = repl_env
+        .execute(code.to_owned())
+        .instrument(tracing::info_span!("repl_execute"));
+    match execution.await {
         Ok(result) => {
             let output = format_execution_result(&result);
             repl_env_logger.log_execution(
@@ -302,7 +378,7 @@ pub async fn execute_code(
 pub async fn process_code_execution(
     response: &str,
     messages: &mut Vec<Message>,
-    repl_env: &ReplHandle,
+    repl_env: &dyn CodeExecutor,
     repl_env_logger: &mut ReplEnvLogger,
     logger: &Logger,
     disable_recursive: bool,
@@ -322,11 +398,12 @@ pub async fn process_code_execution(
 pub async fn process_code_execution_blocks(
     code_blocks: &[String],
     messages: &mut Vec<Message>,
-    repl_env: &ReplHandle,
+    repl_env: &dyn CodeExecutor,
     repl_env_logger: &mut ReplEnvLogger,
     logger: &Logger,
     disable_recursive: bool,
-) {
+) -> Vec<String> {
+    let mut outputs = Vec::with_capacity(code_blocks.len());
     for code in code_blocks {
         let execution_result = execute_code(repl_env, code, repl_env_logger, logger).await;
         let max_len = if disable_recursive {
@@ -335,24 +412,28 @@ pub async fn process_code_execution_blocks(
             100_000
         };
         add_execution_result_to_messages(messages, code, &execution_result, max_len);
+        outputs.push(execution_result);
     }
+    outputs
 }
 
 pub async fn check_for_final_answer(
     response: &str,
-    repl_env: &ReplHandle,
+    repl_env: &dyn CodeExecutor,
     logger: &Logger,
 ) -> Option<String> {
     let (kind, content) = find_final_answer(response)?;
     match kind {
         FinalAnswerKind::Final => Some(content),
+        FinalAnswerKind::FinalJson => match resolve_final_json(&kind, &content, repl_env).await {
+            Ok(value) => Some(value.to_string()),
+            Err(err) => {
+                logger.log_tool_execution("FINAL_JSON", &err);
+                None
+            }
+        },
         FinalAnswerKind::FinalVar => {
-            let variable_name = content
-                .trim()
-                .trim_matches('"')
-                .trim_matches('\'')
-                .trim_matches('\n')
-                .trim_matches('\r');
+            let variable_name = final_var_name(&content);
             match repl_env.get_variable(variable_name.to_owned()).await {
                 Ok(Some(value)) => Some(value),
                 Ok(None) => {
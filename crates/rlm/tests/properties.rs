@@ -0,0 +1,70 @@
+//! The `fuzz/` targets' invariants, checked on every `cargo test`.
+
+use proptest::prelude::*;
+use rlm::utils::{
+    context_from_value, convert_context_for_repl, find_code_blocks, find_final_answer,
+};
+use serde_json::Value;
+
+fn json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<i64>().prop_map(Value::from),
+        any::<f64>()
+            .prop_filter("finite", |n| n.is_finite())
+            .prop_map(Value::from),
+        ".*".prop_map(Value::String),
+    ];
+    leaf.prop_recursive(4, 32, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+            prop::collection::hash_map(".*", inner, 0..8)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+/// Mostly model-reply-shaped text, so the extractors see real matches.
+fn reply() -> impl Strategy<Value = String> {
+    let piece = prop_oneof![
+        ".*",
+        Just("```repl\n".to_owned()),
+        Just("```python\n".to_owned()),
+        Just("```".to_owned()),
+        Just("FINAL(".to_owned()),
+        Just("FINAL_VAR(".to_owned()),
+        Just("FINAL_JSON(".to_owned()),
+        Just(")".to_owned()),
+        Just("\n".to_owned()),
+        Just("{\"a\": [1, \")\"]}".to_owned()),
+    ];
+    prop::collection::vec(piece, 0..12).prop_map(|pieces| pieces.concat())
+}
+
+proptest! {
+    #[test]
+    fn code_blocks_come_from_the_text(text in reply()) {
+        for block in find_code_blocks(&text) {
+            prop_assert_eq!(&block, block.trim());
+            prop_assert!(text.contains(&block));
+        }
+    }
+
+    #[test]
+    fn final_answers_come_from_the_text(text in reply()) {
+        if let Some((_, content)) = find_final_answer(&text) {
+            prop_assert_eq!(&content, content.trim());
+            prop_assert!(text.contains(&content));
+        }
+    }
+
+    #[test]
+    fn contexts_normalize_to_text_or_json(value in json()) {
+        let context = convert_context_for_repl(context_from_value(Some(value)));
+        prop_assert!(context.json.is_some() != context.text.is_some());
+        if let Some(json) = context.json {
+            prop_assert!(serde_json::to_vec_pretty(&json).is_ok());
+        }
+    }
+}
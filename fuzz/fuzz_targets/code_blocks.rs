@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlm::utils::find_code_blocks;
+
+fuzz_target!(|text: &str| {
+    for block in find_code_blocks(text) {
+        assert_eq!(block, block.trim());
+        assert!(text.contains(&block));
+    }
+});
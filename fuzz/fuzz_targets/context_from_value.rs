@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlm::utils::{context_from_value, convert_context_for_repl};
+use serde_json::Value;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(value) = serde_json::from_slice::<Value>(data) else {
+        return;
+    };
+    let context = convert_context_for_repl(context_from_value(Some(value)));
+    assert!(context.json.is_some() != context.text.is_some());
+    if let Some(json) = context.json {
+        serde_json::to_vec_pretty(&json).expect("normalized context serializes");
+    }
+});
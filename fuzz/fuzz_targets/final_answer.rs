@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlm::utils::find_final_answer;
+
+fuzz_target!(|text: &str| {
+    if let Some((_, content)) = find_final_answer(text) {
+        assert_eq!(content, content.trim());
+        assert!(text.contains(&content));
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use app::session::{MAX_SESSION_ID_LEN, validate_session_id};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|raw: &str| {
+    if let Some(session_id) = validate_session_id(raw) {
+        assert!(session_id.is_ascii());
+        assert!(!session_id.is_empty() && session_id.len() <= MAX_SESSION_ID_LEN);
+        assert_eq!(validate_session_id(&session_id), Some(session_id.clone()));
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use app::protocol::{WorkerRequest, WorkerResponse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = serde_json::from_slice::<WorkerRequest>(data) {
+        let line = serde_json::to_string(&request).expect("request re-encodes");
+        serde_json::from_str::<WorkerRequest>(&line).expect("request round-trips");
+    }
+    if let Ok(response) = serde_json::from_slice::<WorkerResponse>(data) {
+        let line = serde_json::to_string(&response).expect("response re-encodes");
+        serde_json::from_str::<WorkerResponse>(&line).expect("response round-trips");
+    }
+});